@@ -1,19 +1,42 @@
-use std::{collections::HashMap, io};
+use std::{collections::{HashMap, HashSet}, io};
 
 use gen86::writer::Condition;
 use gen86::{gp_regs::*, mem::Mem, writer::X86Writer};
 use gen86::nasm::NasmWriter;
-use crate::frontend::{BinOp, FunTyID, Global, GlobalID, GlobalValue, IntTy, JumpTarget, Ty, UnOp, Value, Values};
-use crate::{frontend::{BlockID, FunID, Function, Instruction, Module, RegID, VarID}, layout::TyLayout};
+use crate::frontend::{BinOp, CallConvention, FunTyID, Global, GlobalID, GlobalValue, IntTy, JumpTarget, Linkage, StructTyID, Ty, UnOp, Value, Values};
+use crate::{frontend::{terminator_diagnostics, BlockID, FunID, Function, Instruction, Module, RegID, VarID}, layout::TyLayout};
+use parallel_copy::CopyStep;
+
+mod assembler;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod difftest;
+mod elf;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod jit;
+mod parallel_copy;
+mod regalloc;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub use difftest::{DiffTest, End, Outcome, Verdict};
+pub use elf::ObjectGen;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub use jit::{Jit, JitGen};
+pub use regalloc::RegAllocMode;
 
 pub struct CodeGen<'a, O> {
     module: &'a Module,
     o: NasmWriter<O>,
+    reg_alloc: RegAllocMode,
 
+    fun: Option<FunID>,
     rsp: i64,
+    saved: Vec<Reg>,
+    sret: Option<Mem<'static>>,
+    jump_temp: Option<Mem<'static>>,
 
     known_ptrs: HashMap<RegID, Mem<'static>>,
     regs: HashMap<RegID, Mem<'static>>,
+    phys: HashMap<RegID, Reg>,
     vars: HashMap<VarID, Mem<'static>>,
 
     local_counter: usize,
@@ -21,6 +44,7 @@ pub struct CodeGen<'a, O> {
 
     internal_counter: usize,
 
+    functions: HashMap<FunID, String>,
     globals: HashMap<GlobalID, String>,
 }
 impl<'a, O: io::Write> CodeGen<'a, O> {
@@ -28,55 +52,176 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         Self {
             module,
             o: NasmWriter::new(o),
+            reg_alloc: RegAllocMode::LinearScan,
 
+            fun: None,
             rsp: 0,
+            saved: Vec::new(),
+            sret: None,
+            jump_temp: None,
 
             known_ptrs: HashMap::new(),
             regs: HashMap::new(),
+            phys: HashMap::new(),
             vars: HashMap::new(),
 
             local_counter: 0,
             blocks: HashMap::new(),
 
             internal_counter: 0,
+            functions: HashMap::new(),
             globals: HashMap::new(),
         }
     }
 
-    pub fn gen_code(mut self) -> io::Result<()> {
-        for global in self.module.globals() {
-            self.gen_global(global)?;
-        }
+    pub fn with_reg_alloc(mut self, mode: RegAllocMode) -> Self {
+        self.reg_alloc = mode;
+        self
+    }
 
+    pub fn gen_code(self) -> io::Result<()> {
+        self.gen_labelled_code()?;
+        Ok(())
+    }
+    /// Like `gen_code`, but also returns the labels the functions and globals ended up with.
+    pub(super) fn gen_labelled_code(mut self) -> io::Result<Labels> {
+        self.assign_labels();
+        self.gen_symbols()?;
         self.o.blank()?;
 
+        for section in [".data", ".rodata", ".bss"] {
+            let globals: Vec<_> = self.module.globals()
+                .iter()
+                .filter(|g| global_section(g) == Some(section))
+                .collect();
+            if globals.is_empty() { continue };
+
+            self.o.section(section)?;
+            for global in globals {
+                self.gen_global(global)?;
+            }
+            self.o.blank()?;
+        }
+
+        self.o.section(".text")?;
         for function in self.module.functions() {
             self.gen_function(function)?;
             self.o.blank()?;
         }
 
+        Ok(Labels {
+            functions: self.functions,
+            globals: self.globals,
+        })
+    }
+    fn assign_labels(&mut self) {
+        for function in self.module.functions() {
+            let label = match function.linkage {
+                Linkage::Internal => self.make_internal_label(),
+                Linkage::Export | Linkage::Import => function.name.clone(),
+            };
+            self.functions.insert(function.id, label);
+        }
+        for global in self.module.globals() {
+            let label = match (global.linkage, &global.name) {
+                (Linkage::Export | Linkage::Import, Some(name)) => name.clone(),
+                _ => self.make_internal_label(),
+            };
+            self.globals.insert(global.id, label);
+        }
+    }
+    fn gen_symbols(&mut self) -> io::Result<()> {
+        for function in self.module.functions() {
+            let label = &self.functions[&function.id];
+            match (function.linkage, function.entry_block) {
+                (Linkage::Import, _) | (_, None) => self.o.extern_(label)?,
+                (Linkage::Export, Some(_)) => self.o.global(label)?,
+                (Linkage::Internal, Some(_)) => (),
+            }
+        }
+        for global in self.module.globals() {
+            let label = &self.globals[&global.id];
+            match global.linkage {
+                Linkage::Import => self.o.extern_(label)?,
+                Linkage::Export => self.o.global(label)?,
+                Linkage::Internal => (),
+            }
+        }
+
         Ok(())
     }
     fn gen_global(&mut self, global: &Global) -> io::Result<()> {
-        let label = if let Some(label) = &global.name {
-            label.clone()
-        }
-        else {
-            self.make_internal_label()
+        let label = self.globals[&global.id].clone();
+
+        let layout = self.module.ty_layout(global.ty);
+        let size = layout.size() as usize;
+        self.o.align(layout.align())?;
+
+        let Some(value) = &global.value else {
+            self.o.label(&label)?;
+            self.o.resb(layout.size())?;
+            return Ok(());
         };
-        self.globals.insert(global.id, label.clone());
 
-        if let Some(value) = &global.value {
-            match value {
-                GlobalValue::String(src) => {
-                    self.o.db(&label, &[src.as_bytes()])?;
-                }
-                _ => todo!(),
+        let mut bytes = vec![0; size];
+        let mut addrs = Vec::new();
+        self.lay_out_global_value(global.ty, value, 0, &mut bytes, &mut addrs);
+
+        // Addresses are only known to the linker,
+        // so the bytes around them are emitted in pieces.
+        let mut label = label.as_str();
+        let mut start = 0;
+        for (offset, target) in addrs {
+            if offset > start {
+                self.o.db(label, &[&bytes[start..offset]])?;
+                label = "";
             }
+            self.o.dq_label(label, &target)?;
+            label = "";
+            start = offset + 8;
+        }
+        if start < bytes.len() || !label.is_empty() {
+            self.o.db(label, &[&bytes[start..]])?;
         }
 
         Ok(())
     }
+    fn lay_out_global_value(&self, ty: Ty, value: &GlobalValue, offset: usize, bytes: &mut Vec<u8>, addrs: &mut Vec<(usize, String)>) {
+        let mut write = |src: &[u8]| {
+            let end = offset + src.len();
+            if bytes.len() < end {
+                bytes.resize(end, 0);
+            }
+            bytes[offset..end].copy_from_slice(src);
+        };
+
+        match value {
+            &GlobalValue::Bool(value) => write(&[value as u8]),
+            GlobalValue::Int(value) => {
+                let size = self.module.ty_layout(ty).size() as usize;
+                write(&value.to_le_bytes()[..size.min(8)]);
+            }
+            GlobalValue::String(src) => write(src.as_bytes()),
+            GlobalValue::Struct(values) => {
+                let Ty::Struct(sid) = ty else { unreachable!() };
+                let members = &self.module[sid].members;
+                let offsets = self.module.struct_member_offsets(sid);
+                for ((&member, member_offset), value) in members.iter().zip(offsets).zip(values) {
+                    self.lay_out_global_value(member, value, offset + member_offset as usize, bytes, addrs);
+                }
+            }
+            GlobalValue::Array(values) => {
+                let Ty::Array(aid) = ty else { unreachable!() };
+                let element = self.module[aid].element;
+                let stride = self.module.ty_layout(element).pad_to_align().size() as usize;
+                for (i, value) in values.iter().enumerate() {
+                    self.lay_out_global_value(element, value, offset + i * stride, bytes, addrs);
+                }
+            }
+            GlobalValue::GlobalAddr(gid) => addrs.push((offset, self.globals[gid].clone())),
+            GlobalValue::FunAddr(fid) => addrs.push((offset, self.functions[fid].clone())),
+        }
+    }
     fn make_internal_label(&mut self) -> String {
         let id = self.internal_counter;
         self.internal_counter += 1;
@@ -86,13 +231,27 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
     fn gen_function(&mut self, fun: &Function) -> io::Result<()> {
         if fun.entry_block.is_none() { return Ok(()) };
 
-        self.o.label(&fun.name)?;
+        self.init_func(fun.id);
+        self.register_params(fun.id);
+        self.assign_phys_regs(fun.id);
+
+        let label = self.functions[&fun.id].clone();
+        self.o.label(&label)?;
         self.o.push(RBP)?;
         self.o.mov(RBP, RSP)?;
-
-        self.init_func();
-        self.register_params(fun.id);
+        self.o.push(RBX)?;
+        for reg in self.saved.clone() {
+            self.o.push(reg)?;
+            self.rsp -= 8;
+        }
+        if fun.call_convention == CallConvention::SysV && self.sysv_class(fun.ret_ty) == SysVClass::Memory {
+            // The caller's return buffer has to be handed back in RAX.
+            self.o.push(RDI)?;
+            self.rsp -= 8;
+            self.sret = Some(RBP.mem() + self.rsp);
+        }
         self.alloc_regs_vars(fun.id)?;
+        self.spill_sysv_params(fun.id)?;
         self.collect_known_ptrs(fun.id);
 
         let entry = fun.entry_block.unwrap();
@@ -110,20 +269,62 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
 
         Ok(())
     }
-    fn init_func(&mut self) {
-        self.rsp = 0;
+    fn init_func(&mut self, fid: FunID) {
+        self.fun = Some(fid);
+        // RBX is callee saved and lives right below RBP.
+        self.rsp = -8;
+        self.saved.clear();
+        self.sret = None;
+        self.jump_temp = None;
         self.regs.clear();
+        self.phys.clear();
         self.vars.clear();
         self.known_ptrs.clear();
     }
     fn register_params(&mut self, fid: FunID) {
-        let (_, offsets) = self.get_fid_staging_layout(fid);
+        match self.module[fid].call_convention {
+            CallConvention::Simple => {
+                let (_, offsets) = self.get_fid_staging_layout(fid);
 
-        for (&p, offs) in self.module[fid].parameters.iter().zip(offsets) {
-            let mem = RBP.mem() + 16 + offs;
-            self.regs.insert(p, mem);
+                for (&p, offs) in self.module[fid].parameters.iter().zip(offsets) {
+                    let mem = RBP.mem() + 16 + offs;
+                    self.regs.insert(p, mem);
+                }
+            }
+            CallConvention::SysV => {
+                let frame = self.get_fid_sysv_frame(fid);
+
+                for (&p, param) in self.module[fid].parameters.iter().zip(frame.params) {
+                    if let SysVParam::Stack(offs) = param {
+                        let mem = RBP.mem() + 16 + offs;
+                        self.regs.insert(p, mem);
+                    }
+                }
+            }
         }
     }
+    fn assign_phys_regs(&mut self, fid: FunID) {
+        if self.reg_alloc == RegAllocMode::StackSlots { return };
+
+        let fixed: HashSet<_> = self.regs.keys().copied().collect();
+        let allocation = regalloc::linear_scan(self.module, fid, &fixed);
+        self.phys = allocation.regs;
+        self.saved = allocation.callee_saved;
+    }
+    /// Parameters passed in registers are moved to their homes,
+    /// which requires the stack slots to already be allocated.
+    fn spill_sysv_params(&mut self, fid: FunID) -> io::Result<()> {
+        if self.module[fid].call_convention != CallConvention::SysV { return Ok(()) };
+        let frame = self.get_fid_sysv_frame(fid);
+
+        for (&p, param) in self.module[fid].parameters.iter().zip(frame.params) {
+            if let SysVParam::Regs(regs) = param {
+                self.store_sysv_eightbytes_to_reg(p, &regs)?;
+            }
+        }
+
+        Ok(())
+    }
     fn alloc_regs_vars(&mut self, fid: FunID) -> io::Result<()> {
         let mut layout = TyLayout::new(0, 1);
         
@@ -131,7 +332,7 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         let mut regs: Vec<_> = self.module[fid].registers.iter().copied().collect();
         regs.sort_by_key(|r| r.0);
         for reg in regs {
-            if self.regs.contains_key(&reg) { continue; }
+            if self.regs.contains_key(&reg) || self.phys.contains_key(&reg) { continue; }
 
             let ty = self.module[reg].ty;
             let ty_layout = self.module.ty_layout(ty);
@@ -151,10 +352,29 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             var_offsets.insert(var, offset);
         }
 
+        // Cycles between block arguments and parameters are broken
+        // through a slot big enough for any parameter.
+        let mut temp_layout = TyLayout::new(0, 1);
+        for &block in &self.module[fid].blocks {
+            for &param in &self.module[block].parameters {
+                let param_layout = self.module.ty_layout(self.module[param].ty);
+                let size = temp_layout.size().max(param_layout.size());
+                let align = temp_layout.align().max(param_layout.align());
+                temp_layout = TyLayout::new(size, align);
+            }
+        }
+        let mut temp_offset = None;
+        if temp_layout.size() != 0 {
+            let (next_layout, offset) = layout.extend(temp_layout);
+            layout = next_layout;
+            temp_offset = Some(offset);
+        }
 
-        layout = layout.align_to(16).pad_to_align();
 
-        let (size, _align) = layout.bytes_signed();
+        // Whatever was pushed in the prologue counts towards
+        // keeping RSP 16 byte aligned.
+        let pushed = -self.rsp as u64;
+        let size = ((layout.size() + pushed).next_multiple_of(16) - pushed) as i64;
         if size != 0 {
             self.o.add(RSP, -size)?;
         }
@@ -170,6 +390,8 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             self.vars.insert(var, mem);
         }
 
+        self.jump_temp = temp_offset.map(|offs| RBP.mem() + self.rsp + offs);
+
 
         Ok(())
     }
@@ -193,23 +415,60 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         self.get_staging_layout(ty.ret, &ty.params)
     }
     fn get_staging_layout(&self, ret: Ty, params: &[Ty]) -> (TyLayout, Vec<i64>) {
-        let mut layout = self.module.ty_layout(ret);
-        let mut offsets = Vec::new();
+        staging_layout(self.module, ret, params)
+    }
 
+    fn get_fid_sysv_frame(&self, fid: FunID) -> SysVFrame {
+        let fun = &self.module[fid];
+        let params: Vec<_> = fun.parameters.iter().map(|&p| self.module[p].ty).collect();
+        self.get_sysv_frame(fun.ret_ty, &params)
+    }
+    /// Assigns every parameter to argument registers or the stack,
+    /// following the classification of the System V AMD64 ABI.
+    /// Without floating point types, every eightbyte is of class INTEGER.
+    fn get_sysv_frame(&self, ret: Ty, params: &[Ty]) -> SysVFrame {
+        let ret = self.sysv_class(ret);
+        let mut next_reg = if ret == SysVClass::Memory { 1 } else { 0 };
+        let mut stack_size = 0;
+
+        let mut locations = Vec::with_capacity(params.len());
         for &ty in params {
-            let p_layout = self.module.ty_layout(ty);
-            let (next_layout, offset) = layout.extend(p_layout);
-            layout = next_layout;
-            offsets.push(offset as i64);
+            let location = match self.sysv_class(ty) {
+                SysVClass::Ignore => SysVParam::Ignore,
+                SysVClass::Regs(n) if next_reg + n <= SYSV_ARG_REGS.len() => {
+                    let regs = SYSV_ARG_REGS[next_reg..next_reg + n].to_vec();
+                    next_reg += n;
+                    SysVParam::Regs(regs)
+                }
+                SysVClass::Regs(_) | SysVClass::Memory => {
+                    let offset = stack_size as i64;
+                    let size = self.module.ty_layout(ty).size();
+                    stack_size += size.next_multiple_of(8);
+                    SysVParam::Stack(offset)
+                }
+            };
+            locations.push(location);
         }
 
-        let layout = layout.align_to(16).pad_to_align();
-        let layout = layout.pad_to_align();
-
-        (layout, offsets)
+        SysVFrame {
+            ret,
+            params: locations,
+            stack_size: stack_size.next_multiple_of(16) as i64,
+        }
+    }
+    fn sysv_class(&self, ty: Ty) -> SysVClass {
+        let size = self.module.ty_layout(ty).size();
+        match size {
+            0 => SysVClass::Ignore,
+            1..=16 => SysVClass::Regs(size.div_ceil(8) as usize),
+            _ => SysVClass::Memory,
+        }
     }
 
     fn gen_block(&mut self, bid: BlockID) -> io::Result<()> {
+        if let Some((_, kind)) = terminator_diagnostics(&self.module[bid]).into_iter().next() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{kind:?}")));
+        };
         let name = self.register_block(bid);
         self.o.label(&name)?;
 
@@ -239,6 +498,7 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         let debug = format!("{instr:?}");
         self.o.comment(&debug)?;
         match *instr {
+            Set(dst, value) => self.mov_value_to_reg(dst, value)?,
             SetGlobalPtr(dst, gid) => self.gen_set_global_ptr(dst, gid)?,
             SetFunPtr(dst, fid) => self.gen_set_fun_ptr(dst, fid)?,
             SetStruct(dst, ref values) => self.gen_set_struct(dst, values)?,
@@ -246,9 +506,13 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             SetArraySplat(dst, value) => self.gen_set_array_splat(dst, value)?,
             Binary(BinOp::Add, dst, a, b) => self.gen_add(dst, a, b)?,
             Binary(BinOp::Sub, dst, a, b) => self.gen_sub(dst, a, b)?,
+            Binary(BinOp::Mul, dst, a, b) => self.gen_mul(dst, a, b)?,
+            Binary(BinOp::IDiv, dst, a, b) => self.gen_idiv(dst, a, b)?,
             Binary(BinOp::UDiv, dst, a, b) => self.gen_udiv(dst, a, b)?,
             Binary(BinOp::UMod, dst, a, b) => self.gen_umod(dst, a, b)?,
             Binary(BinOp::IMod, dst, a, b) => self.gen_imod(dst, a, b)?,
+            Binary(op @ (BinOp::And | BinOp::Or | BinOp::Xor), dst, a, b) => self.gen_bitwise(op, dst, a, b)?,
+            Binary(op @ (BinOp::Shl | BinOp::Shr | BinOp::Sar), dst, a, b) => self.gen_shift(op, dst, a, b)?,
             Binary(BinOp::Equal, dst, a, b) => self.gen_test(Condition::E, dst, a, b)?,
             Binary(BinOp::NotEqual, dst, a, b) => self.gen_test(Condition::NE, dst, a, b)?,
             Binary(BinOp::Less, dst, a, b) => self.gen_test(Condition::L, dst, a, b)?,
@@ -262,7 +526,12 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             Unary(UnOp::Neg, dst, a) => self.gen_neg(dst, a)?,
             Unary(UnOp::Sext, dst, a) => self.gen_sext(dst, a)?,
             Unary(UnOp::Trunc, dst, a) => self.gen_trunc(dst, a)?,
+            Unary(UnOp::Not, dst, a) => self.gen_not(dst, a)?,
+            Unary(UnOp::Zext | UnOp::IntToPtr, dst, a) => self.gen_zext(dst, a)?,
+            Unary(UnOp::PtrToInt, dst, a) => self.gen_trunc(dst, a)?,
+            Poison(_) => (),
             Select(dst, c, a, b) => self.gen_select(dst, c, a, b)?,
+            Freeze(dst, a) => self.mov_value_to_reg(dst, a)?,
             GetVarAddr(dst, var) => self.gen_get_var_addr(dst, var)?,
             Load { dst, ptr } => self.gen_load(dst, ptr)?,
             Store { ptr, value } => self.gen_store(ptr, value)?,
@@ -273,9 +542,12 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             CallPtr(dst, ptr, fun_ty, ref args) => self.gen_call_ptr(dst, ptr, fun_ty, args)?,
             Ret(value) => self.gen_ret(value)?,
             IndexArray { dst, ptr, element_ty, index } => self.gen_index_array(dst, ptr, element_ty, index)?,
+            IndexStruct { dst, ptr, struct_ty, index } => self.gen_index_struct(dst, ptr, struct_ty, index)?,
             GetStructMember { dst, strct, index } => self.gen_get_struct_member(dst, strct, index)?,
+            SetStructMember { dst, strct, value, index } => self.gen_set_struct_member(dst, strct, value, index)?,
+            GetArrayElement { dst, array, index } => self.gen_get_array_element(dst, array, index)?,
+            SetArrayElement { dst, array, value, index } => self.gen_set_array_element(dst, array, value, index)?,
             SyscallLinux64 { dst, call_number, ref args } => self.gen_syscall_linux64(dst, call_number, args)?,
-            ref or => todo!("Cannot compile {or:?}"),
         }
 
         Ok(())
@@ -288,8 +560,8 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         Ok(())
     }
     fn gen_set_fun_ptr(&mut self, dst: RegID, fid: FunID) -> io::Result<()> {
-        let name = &self.module[fid].name;
-        self.o.lea(RBX, Mem::new() + name)?;
+        let label = &self.functions[&fid];
+        self.o.lea(RBX, Mem::new() + label)?;
         self.place_register_in_reg(dst, RBX)?;
         Ok(())
     }
@@ -317,6 +589,50 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
 
         Ok(())
     }
+    fn gen_mul(&mut self, dst: RegID, a: Value, b: Value) -> io::Result<()> {
+        let Ty::Int(ty) = self.module[dst].ty else { unreachable!() };
+        let size = int_rsize(ty);
+        let rax = RAX + size;
+        let rcx = RCX + size;
+        self.place_value_in_register(rax, a)?;
+        self.place_value_in_register(rcx, b)?;
+
+        // There is no two-operand imul for bytes.
+        // The low half of the product is the same for signed and unsigned operands,
+        // so the widening AX = AL * CL works for both.
+        if ty == IntTy::I8 {
+            self.o.mul(CL)?;
+        }
+        else {
+            self.o.imul(rax, rcx)?;
+        }
+        self.place_register_in_reg(dst, rax)?;
+
+        Ok(())
+    }
+    fn gen_idiv(&mut self, dst: RegID, a: Value, b: Value) -> io::Result<()> {
+        let Ty::Int(ty) = self.module[dst].ty else { unreachable!() };
+        self.o.xor(EAX, EAX)?;
+        self.o.xor(EDX, EDX)?;
+        self.o.xor(ECX, ECX)?;
+
+        let size = int_rsize(ty);
+        let rax = RAX + size;
+        let rcx = RCX + size;
+        self.place_value_in_register(rax, a)?;
+        self.place_value_in_register(rcx, b)?;
+
+        match ty {
+            IntTy::I8 => self.o.movsx(AX, AL)?,
+            IntTy::I16 => self.o.cwd()?,
+            IntTy::I32 => self.o.cdq()?,
+            IntTy::I64 => self.o.cqo()?,
+        }
+        self.o.idiv(rcx)?;
+        self.place_register_in_reg(dst, rax)?;
+
+        Ok(())
+    }
     fn gen_udiv(&mut self, dst: RegID, a: Value, b: Value) -> io::Result<()> {
         let Ty::Int(ty) = self.module[dst].ty else { unreachable!() };
         self.o.xor(EAX, EAX)?;
@@ -387,6 +703,41 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         }
 
 
+        Ok(())
+    }
+    fn gen_bitwise(&mut self, op: BinOp, dst: RegID, a: Value, b: Value) -> io::Result<()> {
+        let Ty::Int(ty) = self.module[dst].ty else { unreachable!() };
+        let size = int_rsize(ty);
+        let rax = RAX + size;
+        let rdx = RDX + size;
+        self.place_value_in_register(rax, a)?;
+        self.place_value_in_register(rdx, b)?;
+        match op {
+            BinOp::And => self.o.and(rax, rdx)?,
+            BinOp::Or => self.o.or(rax, rdx)?,
+            BinOp::Xor => self.o.xor(rax, rdx)?,
+            _ => unreachable!(),
+        }
+        self.place_register_in_reg(dst, rax)?;
+
+        Ok(())
+    }
+    fn gen_shift(&mut self, op: BinOp, dst: RegID, a: Value, b: Value) -> io::Result<()> {
+        let Ty::Int(ty) = self.module[dst].ty else { unreachable!() };
+        let Ty::Int(count_ty) = b.ty(self.module) else { unreachable!() };
+        let rax = RAX + int_rsize(ty);
+        self.place_value_in_register(rax, a)?;
+
+        // Variable shift counts can only live in CL.
+        self.place_value_in_register(RCX + int_rsize(count_ty), b)?;
+        match op {
+            BinOp::Shl => self.o.shl(rax, CL)?,
+            BinOp::Shr => self.o.shr(rax, CL)?,
+            BinOp::Sar => self.o.sar(rax, CL)?,
+            _ => unreachable!(),
+        }
+        self.place_register_in_reg(dst, rax)?;
+
         Ok(())
     }
     fn gen_test(&mut self, cc: Condition, dst: RegID, a: Value, b: Value) -> io::Result<()> {
@@ -397,9 +748,9 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         self.place_value_in_register(rax, a)?;
         self.place_value_in_register(rdx, b)?;
         self.o.xor(ECX, ECX)?;
-        self.o.mov(EDI, 1)?;
+        self.o.mov(EBX, 1)?;
         self.o.cmp(rax, rdx)?;
-        self.o.cmov(cc, ECX, EDI)?;
+        self.o.cmov(cc, ECX, EBX)?;
         self.place_register_in_reg(dst, CL)?;
 
         Ok(())
@@ -422,22 +773,49 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         let to = RAX + to_size;
         let from = RAX + from_size;
         self.place_value_in_register(from, a)?;
-        self.o.movsx(to, from)?;
+        // There is no movsx between registers of the same size.
+        if from_size != to_size {
+            self.o.movsx(to, from)?;
+        }
         self.place_register_in_reg(dst, to)?;
 
         Ok(())
     }
+    fn gen_zext(&mut self, dst: RegID, a: Value) -> io::Result<()> {
+        let to_size = scalar_rsize(self.module[dst].ty);
+        let from_size = scalar_rsize(a.ty(self.module));
+
+        // Partial writes to AL and AX leave the upper bits alone,
+        // so clearing RAX first zero extends every source width.
+        self.o.xor(EAX, EAX)?;
+        self.place_value_in_register(RAX + from_size, a)?;
+        self.place_register_in_reg(dst, RAX + to_size)?;
+
+        Ok(())
+    }
     fn gen_trunc(&mut self, dst: RegID, a: Value) -> io::Result<()> {
-        let Ty::Int(to_ty) = self.module[dst].ty else { unreachable!() };
-        let Ty::Int(from_ty) = a.ty(self.module) else { unreachable!() };
-        let to_size = int_rsize(to_ty);
-        let from_size = int_rsize(from_ty);
+        let to_size = scalar_rsize(self.module[dst].ty);
+        let from_size = scalar_rsize(a.ty(self.module));
 
         self.place_value_in_register(RAX + from_size, a)?;
         self.place_register_in_reg(dst, RAX + to_size)?;
 
         Ok(())
     }
+    fn gen_not(&mut self, dst: RegID, a: Value) -> io::Result<()> {
+        let ty = self.module[dst].ty;
+        let rax = RAX + scalar_rsize(ty);
+        self.place_value_in_register(rax, a)?;
+        if ty == Ty::Bool {
+            self.o.xor(rax, 1)?;
+        }
+        else {
+            self.o.not(rax)?;
+        }
+        self.place_register_in_reg(dst, rax)?;
+
+        Ok(())
+    }
     fn gen_select(&mut self, dst: RegID, c: Value, a: Value, b: Value) -> io::Result<()> {
         let take_b = self.make_local_label();
         let end = self.make_local_label();
@@ -455,26 +833,20 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         Ok(())
     }
     fn gen_get_var_addr(&mut self, dst: RegID, var: VarID) -> io::Result<()> {
-        let dst_slot = self.regs[&dst];
         let var_slot = self.vars[&var];
 
         self.o.lea(RBX, var_slot)?;
-        self.o.mov(dst_slot, RBX)?;
+        self.place_register_in_reg(dst, RBX)?;
 
         Ok(())
     }
     fn gen_load(&mut self, dst: RegID, ptr: RegID) -> io::Result<()> {
-        let ty = self.module[dst].ty;
-        let layout = self.module.ty_layout(ty);
-        let ptr_slot = self.regs[&ptr];
-        let dst_slot = self.regs[&dst];
-
         if let Some(&mem) = self.known_ptrs.get(&ptr) {
-            self.memcpy(dst_slot, mem, layout)?;
+            self.mov_mem_to_reg(dst, mem)?;
         }
         else {   
-            self.o.mov(RBX, ptr_slot)?;
-            self.memcpy(dst_slot, RBX.mem(), layout)?;
+            self.place_value_in_register(RBX, ptr.into())?;
+            self.mov_mem_to_reg(dst, RBX.mem())?;
         }
 
         Ok(())
@@ -484,8 +856,7 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             self.mov_value_to_mem(mem, value)?;
         }
         else {   
-            let ptr_slot = self.regs[&ptr];
-            self.o.mov(RBX, ptr_slot)?;
+            self.place_value_in_register(RBX, ptr.into())?;
             self.mov_value_to_mem(RBX.mem(), value)?;
         }
 
@@ -536,6 +907,12 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         Ok(())
     }
     fn gen_call(&mut self, dst: RegID, fid: FunID, args: &Values) -> io::Result<()> {
+        if self.module[fid].call_convention == CallConvention::SysV {
+            let frame = self.get_fid_sysv_frame(fid);
+            let label = self.functions[&fid].clone();
+            return self.gen_sysv_call(dst, frame, CallTarget::Label(label), args);
+        }
+
         let (layout, offsets) = self.get_fid_staging_layout(fid);
 
         let (size, _align) = layout.bytes_signed();
@@ -547,8 +924,8 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             self.mov_value_to_mem(mem, arg)?;
         }
 
-        let name = &self.module[fid].name;
-        self.o.call(name)?;
+        let label = &self.functions[&fid];
+        self.o.call(label)?;
         self.mov_mem_to_reg(dst, RSP.mem())?;
 
         self.o.add(RSP, size)?;
@@ -558,6 +935,12 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         Ok(())
     }
     fn gen_call_ptr(&mut self, dst: RegID, ptr: RegID, fun_ty: FunTyID, args: &Values) -> io::Result<()> {
+        if self.module[fun_ty].call_convention == CallConvention::SysV {
+            let ty = &self.module[fun_ty];
+            let frame = self.get_sysv_frame(ty.ret, &ty.params);
+            return self.gen_sysv_call(dst, frame, CallTarget::Ptr(ptr), args);
+        }
+
         let (layout, offsets) = self.get_fty_staging_layout(fun_ty);
 
         let (size, _align) = layout.bytes_signed();
@@ -577,42 +960,156 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         self.rsp += size;
 
 
+        Ok(())
+    }
+    fn gen_sysv_call(&mut self, dst: RegID, frame: SysVFrame, target: CallTarget, args: &Values) -> io::Result<()> {
+        let size = frame.stack_size;
+        if size != 0 {
+            self.o.add(RSP, -size)?;
+        }
+        self.rsp += -size;
+
+        // Stack arguments are copied through RAX,
+        // so they go first to not clobber argument registers.
+        for (&arg, param) in args.0.iter().zip(&frame.params) {
+            if let &SysVParam::Stack(offs) = param {
+                self.mov_value_to_mem(RSP.mem() + offs, arg)?;
+            }
+        }
+
+        if frame.ret == SysVClass::Memory {
+            let dst_slot = self.regs[&dst];
+            self.o.lea(RDI, dst_slot)?;
+        }
+        for (&arg, param) in args.0.iter().zip(&frame.params) {
+            if let SysVParam::Regs(regs) = param {
+                self.load_sysv_eightbytes(regs, arg)?;
+            }
+        }
+
+        match target {
+            CallTarget::Label(label) => self.o.call(&label)?,
+            CallTarget::Ptr(ptr) => {
+                self.place_value_in_register(RBX, Value::Reg(ptr))?;
+                self.o.call(RBX)?;
+            }
+        }
+
+        if let SysVClass::Regs(n) = frame.ret {
+            self.store_sysv_eightbytes_to_reg(dst, &SYSV_RET_REGS[..n])?;
+        }
+
+        if size != 0 {
+            self.o.add(RSP, size)?;
+        }
+        self.rsp += size;
+
         Ok(())
     }
     fn gen_ret(&mut self, value: Value) -> io::Result<()> {
-        let to = RBP.mem() + 16;
-        self.mov_value_to_mem(to, value)?;
+        let fun = &self.module[self.fun.unwrap()];
+        match fun.call_convention {
+            CallConvention::Simple => {
+                let to = RBP.mem() + 16;
+                self.mov_value_to_mem(to, value)?;
+            }
+            CallConvention::SysV => match self.sysv_class(fun.ret_ty) {
+                SysVClass::Ignore => (),
+                SysVClass::Regs(n) => self.load_sysv_eightbytes(&SYSV_RET_REGS[..n], value)?,
+                SysVClass::Memory => {
+                    self.o.mov(RBX, self.sret.unwrap())?;
+                    self.mov_value_to_mem(RBX.mem(), value)?;
+                    self.o.mov(RAX, RBX)?;
+                }
+            },
+        }
+
+        self.o.mov(RBX, RBP.mem() + -8)?;
+        for (i, &reg) in self.saved.iter().enumerate() {
+            self.o.mov(reg, RBP.mem() + (-16 - 8 * i as i64))?;
+        }
         self.o.mov(RSP, RBP)?;
         self.o.pop(RBP)?;
         self.o.ret()?;
 
         Ok(())
     }
-    fn gen_index_array(&mut self, dst: RegID, ptr: RegID, elem_ty: Ty, index: Value) -> io::Result<()> {
-        let ptr_slot = self.regs[&ptr];
-        self.place_value_in_register(RDX, index)?;
-        self.o.mov(RBX, ptr_slot)?;
-
-        let layout = self.module.ty_layout(elem_ty).pad_to_align();
-        let size = layout.size();
-        
-        if size == 0 {
-            self.o.xor(EDX, EDX)?;
+    /// Scalars are zero extended to fill their register.
+    /// Aggregates are read an eightbyte at a time,
+    /// leaving whatever follows a partial eightbyte in its upper bits.
+    fn load_sysv_eightbytes(&mut self, regs: &[Reg], value: Value) -> io::Result<()> {
+        match value.ty(self.module) {
+            Ty::Bool => {
+                self.o.xor(regs[0] + RSize::DWord, regs[0] + RSize::DWord)?;
+                self.place_value_in_register(regs[0] + RSize::Byte, value)?;
+            }
+            Ty::Int(ty) => {
+                self.o.xor(regs[0] + RSize::DWord, regs[0] + RSize::DWord)?;
+                self.place_value_in_register(regs[0] + int_rsize(ty), value)?;
+            }
+            Ty::Ptr => self.place_value_in_register(regs[0], value)?,
+            Ty::Struct(_) | Ty::Array(_) => {
+                let Value::Reg(reg) = value else { unreachable!() };
+                let slot = self.regs[&reg];
+                for (i, &to) in regs.iter().enumerate() {
+                    self.o.mov(to, slot + 8 * i as i64)?;
+                }
+            }
+            Ty::Void => (),
         }
-        else if size == 1 {
 
+        Ok(())
+    }
+    fn store_sysv_eightbytes_to_reg(&mut self, to: RegID, regs: &[Reg]) -> io::Result<()> {
+        if let Some(&phys) = self.phys.get(&to) {
+            self.o.mov(phys, regs[0])?;
+            return Ok(());
         }
-        else if size.is_power_of_two() {
-            let shift = size.ilog2();
-            self.o.shl(RDX, shift)?;
-        }
-        else {
-            self.o.imul3(RDX, RDX, size)?;
+
+        let size = self.module.ty_layout(self.module[to].ty).size();
+        let slot = self.regs[&to];
+        self.store_sysv_eightbytes(slot, regs, size)
+    }
+    /// Writes exactly `size` bytes, so nothing next to the slot is overwritten.
+    fn store_sysv_eightbytes(&mut self, to: Mem<'static>, regs: &[Reg], size: u64) -> io::Result<()> {
+        for (i, &reg) in regs.iter().enumerate() {
+            let mut offset = 8 * i as u64;
+            let mut left = (size - offset).min(8);
+            while left != 0 {
+                let (rsize, bytes) = match left {
+                    8 => (RSize::QWord, 8),
+                    4..=7 => (RSize::DWord, 4),
+                    2..=3 => (RSize::Word, 2),
+                    _ => (RSize::Byte, 1),
+                };
+                self.o.mov(to + offset as i64, reg + rsize)?;
+                offset += bytes;
+                left -= bytes;
+                if left != 0 {
+                    self.o.shr(reg, bytes as u32 * 8)?;
+                }
+            }
         }
+
+        Ok(())
+    }
+    fn gen_index_array(&mut self, dst: RegID, ptr: RegID, elem_ty: Ty, index: Value) -> io::Result<()> {
+        self.place_index_in_register(RDX, index)?;
+        self.place_value_in_register(RBX, ptr.into())?;
+        self.scale_index(RDX, elem_ty)?;
         self.o.add(RBX, RDX)?;
+        self.place_register_in_reg(dst, RBX)?;
 
-        let dst_slot = self.regs[&dst];
-        self.o.mov(dst_slot, RBX)?;
+        Ok(())
+    }
+    fn gen_index_struct(&mut self, dst: RegID, ptr: RegID, sty: StructTyID, index: u64) -> io::Result<()> {
+        let offsets = self.module.struct_member_offsets(sty);
+        let offset = offsets[index as usize] as i64;
+        self.place_value_in_register(RBX, ptr.into())?;
+        if offset != 0 {
+            self.o.lea(RBX, RBX.mem() + offset)?;
+        }
+        self.place_register_in_reg(dst, RBX)?;
 
         Ok(())
     }
@@ -623,6 +1120,8 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         self.o.xor(EAX, EAX)?;
         self.place_value_in_register(RAX + call_num_size, call_number)?;
 
+        // The register allocator keeps the values passed here out of the argument registers,
+        // so zeroing one can't overwrite an argument that is yet to be placed.
         let regs = [RDI, RSI, RDX, R10, R8, R9];
         for (reg, &arg) in regs.into_iter().zip(&args.0) {
             let arg_ty = arg.ty(self.module);
@@ -705,7 +1204,8 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             self.o.div(RCX)?;
         }
 
-        self.place_register_in_reg(dst, RAX)?;
+        let size = scalar_rsize(self.module[dst].ty);
+        self.place_register_in_reg(dst, RAX + size)?;
 
         Ok(())
     }
@@ -728,6 +1228,81 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         self.mov_mem_to_reg(dst, mem)?;
         Ok(())
     }
+    fn gen_set_struct_member(&mut self, dst: RegID, strct: RegID, value: Value, index: u64) -> io::Result<()> {
+        let Ty::Struct(sty) = self.module[strct].ty else { panic!() };
+        let offsets = self.module.struct_member_offsets(sty);
+        let offset = offsets[index as usize];
+        self.mov_value_to_reg(dst, strct.into())?;
+        let mem = self.regs[&dst] + offset;
+        self.mov_value_to_mem(mem, value)?;
+        Ok(())
+    }
+    fn gen_get_array_element(&mut self, dst: RegID, array: RegID, index: Value) -> io::Result<()> {
+        let Ty::Array(arr) = self.module[array].ty else { panic!() };
+        let elem = self.module[arr].element;
+        let base = self.regs[&array];
+        let mem = self.array_element_mem(base, elem, index)?;
+        self.mov_mem_to_reg(dst, mem)?;
+        Ok(())
+    }
+    fn gen_set_array_element(&mut self, dst: RegID, array: RegID, value: Value, index: Value) -> io::Result<()> {
+        let Ty::Array(arr) = self.module[array].ty else { panic!() };
+        let elem = self.module[arr].element;
+        self.mov_value_to_reg(dst, array.into())?;
+        let base = self.regs[&dst];
+        let mem = self.array_element_mem(base, elem, index)?;
+        self.mov_value_to_mem(mem, value)?;
+        Ok(())
+    }
+    /// Addresses an element of the array stored at `base`.
+    /// Dynamic indices are computed into RBX.
+    fn array_element_mem(&mut self, base: Mem<'static>, elem_ty: Ty, index: Value) -> io::Result<Mem<'static>> {
+        if let Value::Int(_, index) = index {
+            let stride = self.module.ty_layout(elem_ty).pad_to_align().size() as i64;
+            return Ok(base + index * stride);
+        }
+
+        self.place_index_in_register(RDX, index)?;
+        self.scale_index(RDX, elem_ty)?;
+        self.o.lea(RBX, base)?;
+        self.o.add(RBX, RDX)?;
+        Ok(RBX.mem())
+    }
+    fn place_index_in_register(&mut self, to: Reg, index: Value) -> io::Result<()> {
+        match index {
+            Value::Reg(reg) => {
+                let Ty::Int(ty) = self.module[reg].ty else { unreachable!() };
+                match (ty, self.phys.get(&reg)) {
+                    (IntTy::I64, _) => self.place_value_in_register(to, index)?,
+                    (_, Some(&phys)) => self.o.movsx(to, phys + int_rsize(ty))?,
+                    (_, None) => self.o.movsx(to, self.regs[&reg] + int_rsize(ty))?,
+                }
+            }
+            _ => self.place_value_in_register(to, index)?,
+        }
+
+        Ok(())
+    }
+    fn scale_index(&mut self, index: Reg, elem_ty: Ty) -> io::Result<()> {
+        let layout = self.module.ty_layout(elem_ty).pad_to_align();
+        let size = layout.size();
+
+        if size == 0 {
+            self.o.xor(index + RSize::DWord, index + RSize::DWord)?;
+        }
+        else if size == 1 {
+
+        }
+        else if size.is_power_of_two() {
+            let shift = size.ilog2();
+            self.o.shl(index, shift)?;
+        }
+        else {
+            self.o.imul3(index, index, size)?;
+        }
+
+        Ok(())
+    }
 
     fn place_value_in_register(&mut self, to: Reg, value: Value) -> io::Result<()> {
         match value {
@@ -735,8 +1310,18 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             Value::Bool(value) => self.o.mov(to, value as i64)?,
             Value::Int(_int_ty, value) => self.o.mov(to, value)?,
             Value::Reg(reg_id) => {
-                let slot = self.regs[&reg_id];
-                self.o.mov(to, slot)?;
+                // Values are always read at the size of their type,
+                // so the physical register is sized to match.
+                if let Some(&phys) = self.phys.get(&reg_id) {
+                    let phys = phys + scalar_rsize(self.module[reg_id].ty);
+                    if phys != to {
+                        self.o.mov(to, phys)?;
+                    }
+                }
+                else {
+                    let slot = self.regs[&reg_id];
+                    self.o.mov(to, slot)?;
+                }
             }
         }
 
@@ -748,7 +1333,7 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
             Value::Bool(value) => self.o.mov(to + RSize::Byte, value as u8)?,
             Value::Int(int_ty, value) => {
                 let size = int_rsize(int_ty);
-                if fits_32_bit(value) {
+                if fits_mem_imm(value, size) {
                     self.o.mov(to + size, value)?;
                 }
                 else {
@@ -763,12 +1348,22 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
         Ok(())
     }
     fn mov_reg_to_mem(&mut self, to: Mem, reg: RegID) -> io::Result<()> {
+        if let Some(&phys) = self.phys.get(&reg) {
+            self.o.mov(to, phys + scalar_rsize(self.module[reg].ty))?;
+            return Ok(());
+        }
+
         let layout = self.module.ty_layout(self.module[reg].ty);
         let slot = self.regs[&reg];
         self.memcpy(to, slot, layout)?;
         Ok(())
     }
     fn mov_value_to_reg(&mut self, to: RegID, value: Value) -> io::Result<()> {
+        if let Some(&phys) = self.phys.get(&to) {
+            self.place_value_in_register(phys + scalar_rsize(self.module[to].ty), value)?;
+            return Ok(());
+        }
+
         let slot = self.regs[&to];
         self.mov_value_to_mem(slot, value)?;
         Ok(())
@@ -795,27 +1390,104 @@ impl<'a, O: io::Write> CodeGen<'a, O> {
     fn place_register_in_reg(&mut self, to: RegID, from: Reg) -> io::Result<()> {
         let size = self.module.ty_layout(self.module[to].ty).size();
         if size == 0 { return Ok(()) };
+        if let Some(&phys) = self.phys.get(&to) {
+            if phys + scalar_rsize(self.module[to].ty) != from {
+                self.o.mov(phys + scalar_rsize(self.module[to].ty), from)?;
+            }
+            return Ok(());
+        }
+
         let slot = self.regs[&to];
         self.o.mov(slot, from)?;
         Ok(())
     }
     fn mov_mem_to_reg(&mut self, to: RegID, from: Mem) -> io::Result<()> {
+        if let Some(&phys) = self.phys.get(&to) {
+            self.o.mov(phys + scalar_rsize(self.module[to].ty), from)?;
+            return Ok(());
+        }
+
         let layout = self.module.ty_layout(self.module[to].ty);
         let slot = self.regs[&to];
         self.memcpy(slot, from, layout)?;
         Ok(())
     }
 
+    /// Block arguments are assigned to the parameters all at once,
+    /// so a parameter may only be overwritten after every argument reading it.
     fn prepare_jump(&mut self, tgt: &JumpTarget) -> io::Result<()> {
         let params = &self.module[tgt.block].parameters;
-        for (&p, &a) in params.iter().zip(&tgt.args.0) {
-            self.mov_value_to_reg(p, a)?;
+        let copies: Vec<_> = params.iter().copied().zip(tgt.args.0.iter().copied()).collect();
+
+        for step in parallel_copy::sequence(&copies) {
+            match step {
+                CopyStep::Move(p, a) => self.mov_value_to_reg(p, a)?,
+                CopyStep::Save(p) => self.mov_reg_to_mem(self.jump_temp.unwrap(), p)?,
+                CopyStep::Restore(p) => self.mov_mem_to_reg(p, self.jump_temp.unwrap())?,
+            }
         }
 
         Ok(())
     }
 }
 
+const SYSV_ARG_REGS: [Reg; 6] = [RDI, RSI, RDX, RCX, R8, R9];
+const SYSV_RET_REGS: [Reg; 2] = [RAX, RDX];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SysVClass {
+    Ignore,
+    Regs(usize),
+    Memory,
+}
+enum SysVParam {
+    Ignore,
+    Regs(Vec<Reg>),
+    /// Offset from RSP at the call.
+    Stack(i64),
+}
+struct SysVFrame {
+    ret: SysVClass,
+    params: Vec<SysVParam>,
+    stack_size: i64,
+}
+enum CallTarget {
+    Label(String),
+    Ptr(RegID),
+}
+/// The assembly labels of the module's functions and globals.
+pub(super) struct Labels {
+    pub functions: HashMap<FunID, String>,
+    pub globals: HashMap<GlobalID, String>,
+}
+
+/// The area on the stack a `Simple` call passes the return value and parameters in,
+/// with the offset of every parameter. The return value lives at offset 0.
+pub(super) fn staging_layout(module: &Module, ret: Ty, params: &[Ty]) -> (TyLayout, Vec<i64>) {
+    let mut layout = module.ty_layout(ret);
+    let mut offsets = Vec::new();
+
+    for &ty in params {
+        let p_layout = module.ty_layout(ty);
+        let (next_layout, offset) = layout.extend(p_layout);
+        layout = next_layout;
+        offsets.push(offset as i64);
+    }
+
+    let layout = layout.align_to(16).pad_to_align();
+    let layout = layout.pad_to_align();
+
+    (layout, offsets)
+}
+fn global_section(global: &Global) -> Option<&'static str> {
+    if global.linkage == Linkage::Import { return None };
+
+    match (&global.value, global.constant) {
+        (None, _) => Some(".bss"),
+        (Some(_), false) => Some(".data"),
+        (Some(_), true) => Some(".rodata"),
+    }
+}
 fn int_rsize(ty: IntTy) -> RSize {
     match ty {
         IntTy::I8 => RSize::Byte,
@@ -824,8 +1496,360 @@ fn int_rsize(ty: IntTy) -> RSize {
         IntTy::I64 => RSize::QWord,
     }
 }
-fn fits_32_bit(value: i64) -> bool {
+fn scalar_rsize(ty: Ty) -> RSize {
+    match ty {
+        Ty::Bool => RSize::Byte,
+        Ty::Ptr => RSize::QWord,
+        Ty::Int(ty) => int_rsize(ty),
+        _ => unreachable!(),
+    }
+}
+/// Whether `mov` can store `value` to memory of `size` as an immediate.
+/// Qword stores sign-extend their 32 bit immediate, so they can't take the upper half of the u32 range.
+fn fits_mem_imm(value: i64, size: RSize) -> bool {
+    if size == RSize::QWord { return i32::try_from(value).is_ok() };
+
     let max = u32::MAX as i64;
     let min = i32::MIN as i64;
     min <= value && value <= max
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frontend::{Builder, DiagnosticKind},
+        target::Target,
+    };
+
+    #[test]
+    fn rejects_blocks_without_a_terminator() {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let f = b.begin_fun("f".into(), IntTy::I64);
+        b.begin_block();
+        b.set_entry_block();
+        b.ret(0i64);
+        let mut module = b.finish();
+        let entry = module[f].entry_block.unwrap();
+        module[entry].instructions.clear();
+
+        let error = CodeGen::new(&module, Vec::new()).gen_code().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), format!("{:?}", DiagnosticKind::MissingTerminator(entry)));
+    }
+
+    /// Calls in both directions between Rust and `SysV` functions of the JIT.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    mod sysv {
+        use std::{arch::asm, mem::transmute};
+
+        use super::*;
+        use crate::backend_86::{Jit, JitGen, RegAllocMode};
+
+        #[repr(C)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        struct Pair {
+            a: i32,
+            b: i64,
+        }
+        #[repr(C)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        struct Bytes {
+            a: i8,
+            b: i8,
+            c: i8,
+        }
+        #[repr(C)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        struct Triple {
+            a: i64,
+            b: i64,
+            c: i64,
+        }
+
+        type Many = extern "C" fn(i64, i32, i8, i16, i64, i64, i8, i32) -> i64;
+
+        extern "C" fn host_many(a: i64, b: i32, c: i8, d: i16, e: i64, f: i64, g: i8, h: i32) -> i64 {
+            [b as i64, c as i64, d as i64, e, f, g as i64, h as i64].into_iter().fold(a, |acc, x| acc * 10 + x)
+        }
+        extern "C" fn host_pair(pair: Pair, bytes: Bytes) -> Pair {
+            Pair { a: pair.a + bytes.a as i32, b: pair.b * bytes.b as i64 + bytes.c as i64 }
+        }
+        extern "C" fn host_triple(x: i64, triple: Triple) -> Triple {
+            Triple { a: triple.c + x, b: triple.b * x, c: triple.a - x }
+        }
+
+        const MANY: [Ty; 8] = [
+            Ty::Int(IntTy::I64),
+            Ty::Int(IntTy::I32),
+            Ty::Int(IntTy::I8),
+            Ty::Int(IntTy::I16),
+            Ty::Int(IntTy::I64),
+            Ty::Int(IntTy::I64),
+            Ty::Int(IntTy::I8),
+            Ty::Int(IntTy::I32),
+        ];
+
+        /// Declares a `SysV` function and begins its entry block, unless it's imported.
+        fn begin(b: &mut Builder, name: &str, linkage: Linkage, ret_ty: impl Into<Ty>, params: &[Ty]) -> (FunID, Vec<RegID>) {
+            let fid = b.begin_fun(name.into(), ret_ty);
+            b.set_linkage(linkage);
+            b.set_call_convention(CallConvention::SysV);
+            let params = params.iter().map(|&ty| b.create_param(ty)).collect();
+            if linkage != Linkage::Import {
+                b.begin_block();
+                b.set_entry_block();
+            }
+            (fid, params)
+        }
+        fn sext64(b: &mut Builder, reg: RegID) -> RegID {
+            if b.module[reg].ty == Ty::Int(IntTy::I64) { reg } else { b.sext(IntTy::I64, reg) }
+        }
+        fn pair_tys(b: &mut Builder) -> (StructTyID, StructTyID, StructTyID) {
+            let pair = b.module.add_struct_ty();
+            b.module.add_struct_member(pair, IntTy::I32.into());
+            b.module.add_struct_member(pair, IntTy::I64.into());
+            let bytes = b.module.add_struct_ty();
+            let triple = b.module.add_struct_ty();
+            for _ in 0..3 {
+                b.module.add_struct_member(bytes, IntTy::I8.into());
+                b.module.add_struct_member(triple, IntTy::I64.into());
+            }
+            (pair, bytes, triple)
+        }
+        /// Compiles the module with both register allocators.
+        fn jit(module: &Module) -> [Jit; 2] {
+            assert!(module.verify().is_empty(), "{:?}", module.verify());
+            [RegAllocMode::LinearScan, RegAllocMode::StackSlots].map(|mode| {
+                JitGen::new(module)
+                    .with_reg_alloc(mode)
+                    .with_symbol("host_many", host_many as *const u8)
+                    .with_symbol("host_pair", host_pair as *const u8)
+                    .with_symbol("host_triple", host_triple as *const u8)
+                    .gen_jit()
+                    .unwrap()
+            })
+        }
+
+        #[test]
+        fn passes_integer_arguments_on_the_stack() {
+            let mut b = Builder::new(Module::new(Target::LINUX_X64));
+            let (host, _) = begin(&mut b, "host_many", Linkage::Import, IntTy::I64, &MANY);
+
+            let (many, params) = begin(&mut b, "many", Linkage::Export, IntTy::I64, &MANY);
+            let mut acc = params[0];
+            for &param in &params[1..] {
+                let param = sext64(&mut b, param);
+                let shifted = b.mul(acc, 10i64);
+                acc = b.add(shifted, param);
+            }
+            b.ret(acc);
+
+            // Swaps the last two register arguments on the way.
+            let (forward, params) = begin(&mut b, "forward", Linkage::Export, IntTy::I64, &MANY);
+            let mut args: Vec<_> = params.into_iter().map(Value::Reg).collect();
+            args.swap(4, 5);
+            let r = b.call(host, args);
+            b.ret(r);
+            let module = b.finish();
+
+            for jit in jit(&module) {
+                let many: Many = unsafe { transmute(jit.fun_ptr(many)) };
+                let forward: Many = unsafe { transmute(jit.fun_ptr(forward)) };
+                assert_eq!(many(1, 2, 3, 4, 5, 6, -7, 8), host_many(1, 2, 3, 4, 5, 6, -7, 8));
+                assert_eq!(many(-1, 0, i8::MIN, -2, 0, 1, 9, i32::MIN), host_many(-1, 0, i8::MIN, -2, 0, 1, 9, i32::MIN));
+                assert_eq!(forward(1, 2, 3, 4, 5, 6, -7, i32::MAX), host_many(1, 2, 3, 4, 6, 5, -7, i32::MAX));
+            }
+        }
+
+        #[test]
+        fn passes_small_structs_in_registers() {
+            let mut b = Builder::new(Module::new(Target::LINUX_X64));
+            let (pair, bytes, _) = pair_tys(&mut b);
+            let (host, _) = begin(&mut b, "host_pair", Linkage::Import, pair, &[pair.into(), bytes.into()]);
+
+            let (mix, params) = begin(&mut b, "mix", Linkage::Export, pair, &[pair.into(), bytes.into()]);
+            let [p, by] = params[..] else { unreachable!() };
+            let pa = b.get_struct_member(p, 0);
+            let pb = b.get_struct_member(p, 1);
+            let [ba, bb, bc] = [0, 1, 2].map(|i| b.get_struct_member(by, i));
+            let ba = b.sext(IntTy::I32, ba);
+            let a = b.add(pa, ba);
+            let bb = sext64(&mut b, bb);
+            let bc = sext64(&mut b, bc);
+            let product = b.mul(pb, bb);
+            let c = b.add(product, bc);
+            let r = b.set_struct(pair, [Value::Reg(a), c.into()]);
+            b.ret(r);
+
+            let (forward, params) = begin(&mut b, "forward", Linkage::Export, pair, &[pair.into(), bytes.into()]);
+            let r = b.call(host, [Value::Reg(params[0]), params[1].into()]);
+            b.ret(r);
+
+            // Only one register is left for the pair, so all of it goes on the stack.
+            let mut params = vec![Ty::Int(IntTy::I64); 5];
+            params.push(pair.into());
+            let (late, params) = begin(&mut b, "late", Linkage::Export, IntTy::I64, &params);
+            let pa = b.get_struct_member(params[5], 0);
+            let pa = sext64(&mut b, pa);
+            let mut acc = b.get_struct_member(params[5], 1);
+            for reg in [pa, params[0], params[1], params[2], params[3], params[4]] {
+                let shifted = b.mul(acc, 10i64);
+                acc = b.add(shifted, reg);
+            }
+            b.ret(acc);
+            let module = b.finish();
+
+            let p = Pair { a: -3, b: 1 << 40 };
+            let by = Bytes { a: 100, b: -2, c: 7 };
+            for jit in jit(&module) {
+                let mix: extern "C" fn(Pair, Bytes) -> Pair = unsafe { transmute(jit.fun_ptr(mix)) };
+                let forward: extern "C" fn(Pair, Bytes) -> Pair = unsafe { transmute(jit.fun_ptr(forward)) };
+                let late: extern "C" fn(i64, i64, i64, i64, i64, Pair) -> i64 = unsafe { transmute(jit.fun_ptr(late)) };
+                assert_eq!(mix(p, by), host_pair(p, by));
+                assert_eq!(forward(p, by), host_pair(p, by));
+                assert_eq!(late(2, 3, 4, 5, 6, Pair { a: 1, b: 9 }), 9_123_456);
+            }
+        }
+
+        #[test]
+        fn returns_large_structs_through_memory() {
+            let mut b = Builder::new(Module::new(Target::LINUX_X64));
+            let (pair, _, triple) = pair_tys(&mut b);
+            let (host, _) = begin(&mut b, "host_triple", Linkage::Import, triple, &[IntTy::I64.into(), triple.into()]);
+
+            let (make, params) = begin(&mut b, "make", Linkage::Export, triple, &[IntTy::I64.into(), pair.into()]);
+            let pa = b.get_struct_member(params[1], 0);
+            let pa = sext64(&mut b, pa);
+            let pb = b.get_struct_member(params[1], 1);
+            let r = b.set_struct(triple, [Value::Reg(params[0]), pa.into(), pb.into()]);
+            b.ret(r);
+
+            // Calls the host twice, so the first result has to survive the second call.
+            let (forward, params) = begin(&mut b, "forward", Linkage::Export, triple, &[IntTy::I64.into(), triple.into()]);
+            let first = b.call(host, [Value::Reg(params[0]), params[1].into()]);
+            let second = b.call(host, [Value::Int(IntTy::I64, 1), params[1].into()]);
+            let a = b.get_struct_member(first, 0);
+            let c = b.get_struct_member(second, 2);
+            let r = b.set_struct_member(first, 2, c);
+            let r = b.set_struct_member(r, 1, a);
+            b.ret(r);
+            let module = b.finish();
+
+            let t = Triple { a: 10, b: -20, c: 30 };
+            for jit in jit(&module) {
+                let make: extern "C" fn(i64, Pair) -> Triple = unsafe { transmute(jit.fun_ptr(make)) };
+                let forward: extern "C" fn(i64, Triple) -> Triple = unsafe { transmute(jit.fun_ptr(forward)) };
+                assert_eq!(make(3, Pair { a: -4, b: 5 }), Triple { a: 3, b: -4, c: 5 });
+                assert_eq!(forward(2, t), Triple { a: 32, b: 32, c: 9 });
+            }
+        }
+
+        /// Calls `fun(arg)` with known values in every callee-saved register and
+        /// returns its result along with what those registers held afterwards.
+        unsafe fn call_with_callee_saved(fun: *const u8, arg: i64) -> (i64, [u64; 6]) {
+            let mut saved = [0u64; 6];
+            let result;
+            unsafe {
+                asm!(
+                    "push rbx",
+                    "push rbp",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+                    "mov rdx, rsp",
+                    "and rsp, -16",
+                    "push rdx",
+                    "push rsi",
+                    "mov rbx, 0x1111111111111111",
+                    "mov rbp, 0x2222222222222222",
+                    "mov r12, 0x3333333333333333",
+                    "mov r13, 0x4444444444444444",
+                    "mov r14, 0x5555555555555555",
+                    "mov r15, 0x6666666666666666",
+                    "call rax",
+                    "pop rsi",
+                    "mov [rsi], rbx",
+                    "mov [rsi + 8], rbp",
+                    "mov [rsi + 16], r12",
+                    "mov [rsi + 24], r13",
+                    "mov [rsi + 32], r14",
+                    "mov [rsi + 40], r15",
+                    "pop rsp",
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop rbp",
+                    "pop rbx",
+                    inout("rax") fun => result,
+                    in("rdi") arg,
+                    in("rsi") saved.as_mut_ptr(),
+                    clobber_abi("C"),
+                );
+            }
+            (result, saved)
+        }
+
+        #[test]
+        fn preserves_callee_saved_registers() {
+            let mut b = Builder::new(Module::new(Target::LINUX_X64));
+            let (host, _) = begin(&mut b, "host_many", Linkage::Import, IntTy::I64, &MANY);
+
+            // Keeps more values alive across the call than there are callee-saved registers.
+            let (pressure, params) = begin(&mut b, "pressure", Linkage::Export, IntTy::I64, &[IntTy::I64.into()]);
+            let x = params[0];
+            let values: Vec<_> = (1..=10i64).map(|i| b.mul(x, i)).collect();
+            let args = MANY.iter().zip(&values).map(|(&ty, &v)| {
+                let Ty::Int(ty) = ty else { unreachable!() };
+                if ty == IntTy::I64 { Value::Reg(v) } else { b.trunc(ty, v).into() }
+            });
+            let args: Vec<_> = args.collect();
+            let mut acc = b.call(host, args);
+            for v in values {
+                acc = b.add(acc, v);
+            }
+            b.ret(acc);
+            let module = b.finish();
+
+            let expected = host_many(1, 2, 3, 4, 5, 6, 7, 8) + 55;
+            let sentinels = [1, 2, 3, 4, 5, 6].map(|i| i * 0x1111111111111111);
+            for jit in jit(&module) {
+                let (result, saved) = unsafe { call_with_callee_saved(jit.fun_ptr(pressure), 1) };
+                assert_eq!(result, expected);
+                assert_eq!(saved, sentinels);
+            }
+        }
+
+        #[test]
+        fn calls_into_libc() {
+            let mut b = Builder::new(Module::new(Target::LINUX_X64));
+            let text_ty = b.module.add_array_ty(9, IntTy::I8.into());
+            let text = b.create_global(None, text_ty);
+            b.set_global(text, "  -1234x\0");
+            b.set_global_constant(text, true);
+            let (strtol, _) = begin(&mut b, "strtol", Linkage::Import, IntTy::I64, &[Ty::Ptr, Ty::Ptr, IntTy::I32.into()]);
+
+            // Returns the number and how many characters it took up in the upper digits.
+            let (parse, params) = begin(&mut b, "parse", Linkage::Export, IntTy::I64, &[IntTy::I32.into()]);
+            let end = b.create_var(Ty::Ptr);
+            let end = b.get_var_addr(end);
+            let start = b.set_global_ptr(text);
+            let number = b.call(strtol, [Value::Reg(start), end.into(), params[0].into()]);
+            let end = b.load(Ty::Ptr, end);
+            let length = b.ptr_diff(IntTy::I64, IntTy::I8, end, start);
+            let length = b.mul(length, 1_000_000i64);
+            let r = b.add(length, number);
+            b.ret(r);
+            let module = b.finish();
+
+            for jit in jit(&module) {
+                let parse: extern "C" fn(i32) -> i64 = unsafe { transmute(jit.fun_ptr(parse)) };
+                assert_eq!(parse(10), 7_000_000 - 1234);
+                assert_eq!(parse(16), 7_000_000 - 0x1234);
+                assert_eq!(parse(3), 5_000_000 - 5);
+            }
+        }
+    }
+}
@@ -0,0 +1,1163 @@
+use std::{collections::{HashMap, HashSet}, io};
+
+/// Machine code and data assembled from NASM source,
+/// with everything the linker still has to fill in.
+#[derive(Clone, Debug)]
+pub(super) struct Assembly {
+    pub sections: Vec<Section>,
+    /// Every label that doesn't start with a dot, followed by the external names.
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Section {
+    pub name: String,
+    /// Empty for sections that only reserve space.
+    pub data: Vec<u8>,
+    pub size: u64,
+    pub align: u64,
+    pub relocs: Vec<Reloc>,
+}
+impl Section {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            data: Vec::new(),
+            size: 0,
+            align: 1,
+            relocs: Vec::new(),
+        }
+    }
+
+    pub fn is_bss(&self) -> bool {
+        self.name == ".bss" || self.name.starts_with(".bss.")
+    }
+    pub fn is_code(&self) -> bool {
+        self.name == ".text" || self.name.starts_with(".text.")
+    }
+    pub fn is_writable(&self) -> bool {
+        !self.is_code() && self.name != ".rodata" && !self.name.starts_with(".rodata.")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Symbol {
+    pub name: String,
+    /// The section and offset, or `None` if the symbol is defined elsewhere.
+    pub definition: Option<(usize, u64)>,
+    pub global: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) struct Reloc {
+    pub offset: u64,
+    pub kind: RelocKind,
+    pub target: RelocTarget,
+    pub addend: i64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum RelocKind {
+    /// 64 bit absolute address.
+    Abs64,
+    /// 32 bit address relative to the relocated field.
+    Pc32,
+    /// Like `Pc32`, but may go through the procedure linkage table.
+    Plt32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum RelocTarget {
+    Symbol(usize),
+    Section(usize),
+}
+
+/// Assembles the subset of NASM syntax that `CodeGen` emits.
+pub(super) fn assemble(src: &str) -> io::Result<Assembly> {
+    let mut assembler = Assembler::new();
+    for (i, line) in src.lines().enumerate() {
+        assembler.line(line).map_err(|e| invalid(format!("line {}: {e}: {}", i + 1, line.trim())))?;
+    }
+    assembler.finish().map_err(invalid)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+type AsmResult<T> = Result<T, String>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Reg {
+    id: u8,
+    size: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Mem {
+    size: Option<u8>,
+    base: Option<u8>,
+    index: Option<(u8, u8)>,
+    disp: i64,
+    label: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Operand {
+    Reg(Reg),
+    Mem(Mem),
+    Imm(i64),
+    Label(String),
+}
+
+/// A reference to a label that is patched or relocated once every label is known.
+struct Fixup {
+    section: usize,
+    offset: u64,
+    label: String,
+    kind: RelocKind,
+    addend: i64,
+}
+
+struct Assembler {
+    sections: Vec<Section>,
+    current: usize,
+    labels: HashMap<String, (usize, u64)>,
+    /// Labels not starting with a dot, in order of definition.
+    symbols: Vec<String>,
+    globals: HashSet<String>,
+    externs: Vec<String>,
+    /// The label local labels are attached to.
+    scope: String,
+    fixups: Vec<Fixup>,
+}
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            sections: vec![Section::new(".text")],
+            current: 0,
+            labels: HashMap::new(),
+            symbols: Vec::new(),
+            globals: HashSet::new(),
+            externs: Vec::new(),
+            scope: String::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    fn line(&mut self, line: &str) -> AsmResult<()> {
+        let mut line = strip_comment(line).trim();
+        if let Some(inner) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            line = inner.trim();
+        }
+        if line.is_empty() { return Ok(()) };
+
+        let (mut word, mut rest) = split_word(line);
+        if let Some(label) = word.strip_suffix(':') {
+            self.define_label(label)?;
+            (word, rest) = split_word(rest);
+            if word.is_empty() { return Ok(()) };
+        }
+        else if is_data_directive(split_word(rest).0) {
+            // NASM allows labels without a colon in front of data.
+            self.define_label(word)?;
+            (word, rest) = split_word(rest);
+        }
+
+        let directive = word.to_ascii_lowercase();
+        match directive.as_str() {
+            "section" | "segment" => self.switch_section(split_word(rest).0),
+            "global" => {
+                for name in rest.split(',') {
+                    self.globals.insert(name.trim().to_string());
+                }
+                Ok(())
+            }
+            "extern" => {
+                for name in rest.split(',') {
+                    self.externs.push(name.trim().to_string());
+                }
+                Ok(())
+            }
+            "bits" | "default" => Ok(()),
+            "align" | "alignb" => self.align(rest),
+            "resb" | "resw" | "resd" | "resq" => self.reserve(&directive, rest),
+            "db" | "dw" | "dd" | "dq" => self.data(&directive, rest),
+            _ => self.instruction(&directive, rest),
+        }
+    }
+
+    fn define_label(&mut self, label: &str) -> AsmResult<()> {
+        let name = if label.starts_with('.') {
+            format!("{}{label}", self.scope)
+        }
+        else {
+            self.scope = label.to_string();
+            self.symbols.push(label.to_string());
+            label.to_string()
+        };
+
+        let offset = self.sections[self.current].size;
+        if self.labels.insert(name, (self.current, offset)).is_some() {
+            return Err(format!("label {label} is defined twice"));
+        }
+        Ok(())
+    }
+    fn resolve_name(&self, label: &str) -> String {
+        if label.starts_with('.') {
+            format!("{}{label}", self.scope)
+        }
+        else {
+            label.to_string()
+        }
+    }
+
+    fn switch_section(&mut self, name: &str) -> AsmResult<()> {
+        if name.is_empty() { return Err("missing section name".into()) };
+
+        self.current = match self.sections.iter().position(|s| s.name == name) {
+            Some(i) => i,
+            None => {
+                self.sections.push(Section::new(name));
+                self.sections.len() - 1
+            }
+        };
+        Ok(())
+    }
+    fn align(&mut self, rest: &str) -> AsmResult<()> {
+        let Some(&align) = split_operands(rest)?.first() else { return Err("missing alignment".into()) };
+        let align = parse_number(align)? as u64;
+        if !align.is_power_of_two() { return Err(format!("alignment {align} is not a power of two")) };
+
+        let section = &mut self.sections[self.current];
+        section.align = section.align.max(align);
+        let padding = section.size.next_multiple_of(align) - section.size;
+        // Code is padded with NOPs, so falling through the padding is harmless.
+        let fill = if section.is_code() { 0x90 } else { 0 };
+        self.emit(&vec![fill; padding as usize])
+    }
+    fn reserve(&mut self, directive: &str, rest: &str) -> AsmResult<()> {
+        let count = parse_number(rest.trim())?;
+        if count < 0 { return Err("negative reservation".into()) };
+
+        let size = data_size(directive) as u64 * count as u64;
+        self.emit(&vec![0; size as usize])
+    }
+    fn data(&mut self, directive: &str, rest: &str) -> AsmResult<()> {
+        let size = data_size(directive);
+        for item in split_operands(rest)? {
+            if let Some(string) = parse_string(item) {
+                let mut bytes = string?;
+                bytes.resize(bytes.len().next_multiple_of(size as usize), 0);
+                self.emit(&bytes)?;
+            }
+            else if let Ok(value) = parse_number(item) {
+                self.emit(&value.to_le_bytes()[..size as usize])?;
+            }
+            else {
+                if size != 8 { return Err(format!("addresses need 8 bytes, not {size}")) };
+                let (label, addend) = parse_label_offset(item)?;
+                self.fixup(0, label, RelocKind::Abs64, addend);
+                self.emit(&[0; 8])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> AsmResult<()> {
+        let section = &mut self.sections[self.current];
+        if section.is_bss() {
+            if bytes.iter().any(|&b| b != 0) {
+                return Err(format!("{} can only hold zeroes", section.name));
+            }
+        }
+        else {
+            section.data.extend_from_slice(bytes);
+        }
+        section.size += bytes.len() as u64;
+        Ok(())
+    }
+    /// Records a fixup `at` bytes after the current position.
+    fn fixup(&mut self, at: u64, label: &str, kind: RelocKind, addend: i64) {
+        self.fixups.push(Fixup {
+            section: self.current,
+            offset: self.sections[self.current].size + at,
+            label: self.resolve_name(label),
+            kind,
+            addend,
+        });
+    }
+
+    fn instruction(&mut self, mnemonic: &str, rest: &str) -> AsmResult<()> {
+        let mut encoder = Encoder::default();
+        let (mnemonic, rest) = match mnemonic {
+            "rep" | "repe" | "repz" => {
+                encoder.bytes.push(0xF3);
+                split_word(rest)
+            }
+            "repne" | "repnz" => {
+                encoder.bytes.push(0xF2);
+                split_word(rest)
+            }
+            _ => (mnemonic, rest),
+        };
+        let mnemonic = mnemonic.to_ascii_lowercase();
+
+        let operands = split_operands(rest)?
+            .into_iter()
+            .map(parse_operand)
+            .collect::<AsmResult<Vec<_>>>()?;
+
+        encoder.instruction(&mnemonic, &operands)?;
+        if self.sections[self.current].is_bss() {
+            return Err(format!("instructions can't go into {}", self.sections[self.current].name));
+        }
+
+        if let Some(field) = encoder.field {
+            // PC relative fields are relative to the end of the instruction.
+            let addend = field.addend - (encoder.bytes.len() - field.at) as i64;
+            self.fixup(field.at as u64, &field.label, field.kind, addend);
+        }
+        self.emit(&encoder.bytes)
+    }
+
+    fn finish(mut self) -> AsmResult<Assembly> {
+        let externs: HashSet<_> = self.externs.iter().cloned().collect();
+        let mut names: Vec<_> = self.symbols.clone();
+        names.extend(self.externs.iter().filter(|e| !self.labels.contains_key(*e)).cloned());
+        names.extend(
+            self.globals
+                .iter()
+                .filter(|g| !self.labels.contains_key(*g) && !externs.contains(*g))
+                .cloned(),
+        );
+        let symbol_ids: HashMap<_, _> = names.iter().enumerate().map(|(i, n)| (n.clone(), i)).collect();
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let definition = self.labels.get(&fixup.label).copied();
+            let pc_relative = fixup.kind != RelocKind::Abs64;
+
+            // Within one section the distance is already known.
+            if let Some((section, offset)) = definition && section == fixup.section && pc_relative {
+                let value = offset as i64 + fixup.addend - fixup.offset as i64;
+                let value = i32::try_from(value).map_err(|_| format!("{} is out of reach", fixup.label))?;
+                let at = fixup.offset as usize;
+                self.sections[section].data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+                continue;
+            }
+
+            let (target, addend) = match (definition, symbol_ids.get(&fixup.label)) {
+                (_, Some(&id)) => (RelocTarget::Symbol(id), fixup.addend),
+                (Some((section, offset)), None) => (RelocTarget::Section(section), fixup.addend + offset as i64),
+                (None, None) => return Err(format!("undefined label {}", fixup.label)),
+            };
+            self.sections[fixup.section].relocs.push(Reloc {
+                offset: fixup.offset,
+                kind: fixup.kind,
+                target,
+                addend,
+            });
+        }
+
+        let symbols = names
+            .into_iter()
+            .map(|name| Symbol {
+                definition: self.labels.get(&name).copied(),
+                global: self.globals.contains(&name) || externs.contains(&name),
+                name,
+            })
+            .collect();
+
+        Ok(Assembly { sections: self.sections, symbols })
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &line[..i],
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => (),
+        }
+    }
+    line
+}
+fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim_start()),
+        None => (line, ""),
+    }
+}
+/// Splits at the commas outside of quotes and brackets.
+fn split_operands(rest: &str) -> AsmResult<Vec<&str>> {
+    let rest = rest.trim();
+    if rest.is_empty() { return Ok(Vec::new()) };
+
+    let mut operands = Vec::new();
+    let mut quote = None;
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in rest.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => (),
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                operands.push(rest[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if quote.is_some() || depth != 0 { return Err("unbalanced quotes or brackets".into()) };
+
+    operands.push(rest[start..].trim());
+    Ok(operands)
+}
+
+fn is_data_directive(word: &str) -> bool {
+    matches!(
+        word.to_ascii_lowercase().as_str(),
+        "db" | "dw" | "dd" | "dq" | "resb" | "resw" | "resd" | "resq"
+    )
+}
+fn data_size(directive: &str) -> u8 {
+    match &directive[directive.len() - 1..] {
+        "b" => 1,
+        "w" => 2,
+        "d" => 4,
+        _ => 8,
+    }
+}
+
+fn parse_number(src: &str) -> AsmResult<i64> {
+    let src = src.trim();
+    let (negative, digits) = match src.strip_prefix('-') {
+        Some(digits) => (true, digits.trim_start()),
+        None => (false, src.strip_prefix('+').unwrap_or(src)),
+    };
+    let digits = digits.replace('_', "");
+
+    let lower = digits.to_ascii_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    }
+    else if let Some(hex) = lower.strip_suffix('h').filter(|h| h.starts_with(|c: char| c.is_ascii_digit())) {
+        u64::from_str_radix(hex, 16)
+    }
+    else if let Some(bin) = lower.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2)
+    }
+    else {
+        lower.parse::<u64>()
+    };
+
+    let value = parsed.map_err(|_| format!("invalid number {src}"))? as i64;
+    Ok(if negative { value.wrapping_neg() } else { value })
+}
+fn parse_string(src: &str) -> Option<AsmResult<Vec<u8>>> {
+    let quote = src.chars().next().filter(|c| matches!(c, '\'' | '"' | '`'))?;
+    let Some(inner) = src[1..].strip_suffix(quote) else {
+        return Some(Err(format!("unterminated string {src}")));
+    };
+    Some(Ok(inner.as_bytes().to_vec()))
+}
+fn is_label(src: &str) -> bool {
+    src.starts_with(|c: char| c.is_ascii_alphabetic() || matches!(c, '_' | '.' | '?' | '$'))
+        && src.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '?' | '$' | '@' | '#' | '~'))
+}
+fn parse_label_offset(src: &str) -> AsmResult<(&str, i64)> {
+    let (label, offset) = match src.find(['+', '-']) {
+        Some(i) => (src[..i].trim(), parse_number(&src[i..])?),
+        None => (src.trim(), 0),
+    };
+    if !is_label(label) { return Err(format!("expected a label, found {src}")) };
+    Ok((label, offset))
+}
+
+fn parse_reg(name: &str) -> Option<Reg> {
+    const NAMES_64: [&str; 8] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi"];
+    const NAMES_32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+    const NAMES_16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+    const NAMES_8: [&str; 8] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil"];
+
+    let name = name.to_ascii_lowercase();
+    for (names, size) in [(NAMES_64, 8), (NAMES_32, 4), (NAMES_16, 2), (NAMES_8, 1)] {
+        if let Some(id) = names.iter().position(|&n| n == name) {
+            return Some(Reg { id: id as u8, size });
+        }
+    }
+
+    let numbered = name.strip_prefix('r')?;
+    let digits = numbered.trim_end_matches(['d', 'w', 'b', 'l']);
+    let id: u8 = digits.parse().ok().filter(|id| (8..16).contains(id))?;
+    let size = match &numbered[digits.len()..] {
+        "" => 8,
+        "d" => 4,
+        "w" => 2,
+        "b" | "l" => 1,
+        _ => return None,
+    };
+    Some(Reg { id, size })
+}
+fn parse_size(word: &str) -> Option<u8> {
+    match word.to_ascii_lowercase().as_str() {
+        "byte" => Some(1),
+        "word" => Some(2),
+        "dword" => Some(4),
+        "qword" => Some(8),
+        _ => None,
+    }
+}
+
+fn parse_operand(src: &str) -> AsmResult<Operand> {
+    let mut src = src.trim();
+    let mut size = None;
+    let (word, rest) = split_word(src);
+    if let Some(s) = parse_size(word) {
+        size = Some(s);
+        src = rest.strip_prefix("ptr").map(str::trim_start).unwrap_or(rest);
+    }
+
+    if let Some(inner) = src.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        let mut mem = parse_mem(inner)?;
+        mem.size = size;
+        return Ok(Operand::Mem(mem));
+    }
+
+    // A size in front of anything else only confirms what the other operand says.
+    if let Some(reg) = parse_reg(src) {
+        return Ok(Operand::Reg(reg));
+    }
+    if let Ok(value) = parse_number(src) {
+        return Ok(Operand::Imm(value));
+    }
+    if is_label(src) {
+        return Ok(Operand::Label(src.to_string()));
+    }
+
+    Err(format!("invalid operand {src}"))
+}
+fn parse_mem(inner: &str) -> AsmResult<Mem> {
+    let (word, rest) = split_word(inner);
+    let inner = match word.to_ascii_lowercase().as_str() {
+        "rel" | "abs" => rest,
+        _ => inner,
+    };
+
+    let mut mem = Mem {
+        size: None,
+        base: None,
+        index: None,
+        disp: 0,
+        label: None,
+    };
+    let mut terms = Vec::new();
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        if (c == '+' || c == '-') && i > 0 {
+            terms.push(&inner[start..i]);
+            start = i;
+        }
+    }
+    terms.push(&inner[start..]);
+
+    for term in terms {
+        let term = term.trim();
+        let (negative, term) = match term.strip_prefix('-') {
+            Some(t) => (true, t.trim()),
+            None => (false, term.strip_prefix('+').unwrap_or(term).trim()),
+        };
+
+        if let Ok(value) = parse_number(term) {
+            mem.disp += if negative { -value } else { value };
+            continue;
+        }
+        if negative { return Err(format!("can't subtract {term}")) };
+
+        if let Some((a, b)) = term.split_once('*') {
+            let (reg, scale) = match (parse_reg(a.trim()), parse_reg(b.trim())) {
+                (Some(reg), None) => (reg, parse_number(b)?),
+                (None, Some(reg)) => (reg, parse_number(a)?),
+                _ => return Err(format!("invalid index {term}")),
+            };
+            if reg.size != 8 || !matches!(scale, 1 | 2 | 4 | 8) || reg.id == 4 {
+                return Err(format!("invalid index {term}"));
+            }
+            if mem.index.replace((reg.id, scale as u8)).is_some() {
+                return Err("more than one index".into());
+            }
+        }
+        else if let Some(reg) = parse_reg(term) {
+            if reg.size != 8 { return Err(format!("addresses need 64 bit registers, not {term}")) };
+            if mem.base.is_none() {
+                mem.base = Some(reg.id);
+            }
+            else if mem.index.is_none() && reg.id != 4 {
+                mem.index = Some((reg.id, 1));
+            }
+            else {
+                return Err("too many registers in address".into());
+            }
+        }
+        else if is_label(term) {
+            if mem.label.replace(term.to_string()).is_some() {
+                return Err("more than one label in address".into());
+            }
+        }
+        else {
+            return Err(format!("invalid address term {term}"));
+        }
+    }
+
+    Ok(mem)
+}
+
+fn condition_code(name: &str) -> Option<u8> {
+    let code = match name {
+        "o" => 0x0,
+        "no" => 0x1,
+        "b" | "c" | "nae" => 0x2,
+        "ae" | "nb" | "nc" => 0x3,
+        "e" | "z" => 0x4,
+        "ne" | "nz" => 0x5,
+        "be" | "na" => 0x6,
+        "a" | "nbe" => 0x7,
+        "s" => 0x8,
+        "ns" => 0x9,
+        "p" | "pe" => 0xA,
+        "np" | "po" => 0xB,
+        "l" | "nge" => 0xC,
+        "ge" | "nl" => 0xD,
+        "le" | "ng" => 0xE,
+        "g" | "nle" => 0xF,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// The opcode extension of the group 1 arithmetic instructions.
+fn alu_extension(mnemonic: &str) -> Option<u8> {
+    let ext = match mnemonic {
+        "add" => 0,
+        "or" => 1,
+        "adc" => 2,
+        "sbb" => 3,
+        "and" => 4,
+        "sub" => 5,
+        "xor" => 6,
+        "cmp" => 7,
+        _ => return None,
+    };
+    Some(ext)
+}
+fn shift_extension(mnemonic: &str) -> Option<u8> {
+    let ext = match mnemonic {
+        "rol" => 0,
+        "ror" => 1,
+        "shl" | "sal" => 4,
+        "shr" => 5,
+        "sar" => 7,
+        _ => return None,
+    };
+    Some(ext)
+}
+fn unary_extension(mnemonic: &str) -> Option<u8> {
+    let ext = match mnemonic {
+        "not" => 2,
+        "neg" => 3,
+        "mul" => 4,
+        "imul" => 5,
+        "div" => 6,
+        "idiv" => 7,
+        _ => return None,
+    };
+    Some(ext)
+}
+
+fn fits_i8(value: i64) -> bool {
+    i8::try_from(value).is_ok()
+}
+fn fits_i32(value: i64) -> bool {
+    i32::try_from(value).is_ok()
+}
+/// Truncates an immediate to the operand size, accepting both signed and unsigned spellings.
+fn imm_bytes(value: i64, size: u8) -> AsmResult<Vec<u8>> {
+    let fits = match size {
+        8 => fits_i32(value),
+        size => {
+            let bits = size as u32 * 8;
+            let min = -(1i64 << (bits - 1));
+            let max = (1i64 << bits) - 1;
+            min <= value && value <= max
+        }
+    };
+    if !fits { return Err(format!("immediate {value} doesn't fit into {size} bytes")) };
+
+    let len = size.min(4) as usize;
+    Ok(value.to_le_bytes()[..len].to_vec())
+}
+
+/// The ModRM reg field, holding either a register or an opcode extension.
+#[derive(Copy, Clone)]
+enum Field {
+    Reg(Reg),
+    Ext(u8),
+}
+
+/// A label reference inside the encoded instruction.
+struct LabelField {
+    at: usize,
+    label: String,
+    kind: RelocKind,
+    addend: i64,
+}
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+    field: Option<LabelField>,
+}
+impl Encoder {
+    fn instruction(&mut self, mnemonic: &str, ops: &[Operand]) -> AsmResult<()> {
+        use Operand::*;
+
+        if let Some(ext) = alu_extension(mnemonic) {
+            let base = ext * 8;
+            return match ops {
+                [dst @ (Reg(_) | Mem(_)), Reg(src)] => self.rm(src.size, &[base + op_w(src.size)], Field::Reg(*src), dst, &[]),
+                [Reg(dst), src @ Mem(_)] => self.rm(dst.size, &[base + 2 + op_w(dst.size)], Field::Reg(*dst), src, &[]),
+                [dst, Imm(value)] => {
+                    let size = rm_size(dst, None)?;
+                    if size == 1 {
+                        self.rm(size, &[0x80], Field::Ext(ext), dst, &imm_bytes(*value, 1)?)
+                    }
+                    else if fits_i8(*value) {
+                        self.rm(size, &[0x83], Field::Ext(ext), dst, &[*value as u8])
+                    }
+                    else {
+                        self.rm(size, &[0x81], Field::Ext(ext), dst, &imm_bytes(*value, size)?)
+                    }
+                }
+                _ => Err(operand_error(mnemonic)),
+            };
+        }
+        if let Some(ext) = shift_extension(mnemonic) {
+            return match ops {
+                [dst, Reg(self::Reg { id: 1, size: 1 })] => {
+                    let size = rm_size(dst, None)?;
+                    self.rm(size, &[0xD2 + op_w(size)], Field::Ext(ext), dst, &[])
+                }
+                [dst, Imm(1)] => {
+                    let size = rm_size(dst, None)?;
+                    self.rm(size, &[0xD0 + op_w(size)], Field::Ext(ext), dst, &[])
+                }
+                [dst, Imm(count)] => {
+                    let size = rm_size(dst, None)?;
+                    self.rm(size, &[0xC0 + op_w(size)], Field::Ext(ext), dst, &imm_bytes(*count, 1)?)
+                }
+                _ => Err(operand_error(mnemonic)),
+            };
+        }
+        if let Some(ext) = unary_extension(mnemonic) && ops.len() == 1 {
+            let size = rm_size(&ops[0], None)?;
+            return self.rm(size, &[0xF6 + op_w(size)], Field::Ext(ext), &ops[0], &[]);
+        }
+        if let Some(cc) = mnemonic.strip_prefix('j').and_then(condition_code) {
+            let [Label(label)] = ops else { return Err(operand_error(mnemonic)) };
+            self.bytes.extend([0x0F, 0x80 + cc]);
+            return self.rel32(label);
+        }
+        if let Some(cc) = mnemonic.strip_prefix("cmov").and_then(condition_code) {
+            let [Reg(dst), src] = ops else { return Err(operand_error(mnemonic)) };
+            if dst.size == 1 { return Err(operand_error(mnemonic)) };
+            rm_size(src, Some(dst.size))?;
+            return self.rm(dst.size, &[0x0F, 0x40 + cc], Field::Reg(*dst), src, &[]);
+        }
+        if let Some(cc) = mnemonic.strip_prefix("set").and_then(condition_code) {
+            let [dst] = ops else { return Err(operand_error(mnemonic)) };
+            rm_size(dst, Some(1))?;
+            return self.rm(1, &[0x0F, 0x90 + cc], Field::Ext(0), dst, &[]);
+        }
+
+        match (mnemonic, ops) {
+            ("mov", [dst @ (Reg(_) | Mem(_)), Reg(src)]) => {
+                rm_size(dst, Some(src.size))?;
+                self.rm(src.size, &[0x88 + op_w(src.size)], Field::Reg(*src), dst, &[])
+            }
+            ("mov", [Reg(dst), src @ Mem(_)]) => {
+                rm_size(src, Some(dst.size))?;
+                self.rm(dst.size, &[0x8A + op_w(dst.size)], Field::Reg(*dst), src, &[])
+            }
+            ("mov", [Reg(dst), Imm(value)]) => self.mov_reg_imm(*dst, *value),
+            ("mov", [dst @ Mem(_), Imm(value)]) => {
+                let size = rm_size(dst, None)?;
+                self.rm(size, &[0xC6 + op_w(size)], Field::Ext(0), dst, &imm_bytes(*value, size)?)
+            }
+            ("movsx" | "movsxd" | "movzx", [Reg(dst), src]) => {
+                let Some(src_size) = operand_size(src) else { return Err("unknown source size".into()) };
+                let opcode: &[u8] = match (mnemonic, src_size) {
+                    ("movsx", 1) => &[0x0F, 0xBE],
+                    ("movsx", 2) => &[0x0F, 0xBF],
+                    ("movzx", 1) => &[0x0F, 0xB6],
+                    ("movzx", 2) => &[0x0F, 0xB7],
+                    ("movsx" | "movsxd", 4) => &[0x63],
+                    _ => return Err(operand_error(mnemonic)),
+                };
+                if src_size >= dst.size { return Err(operand_error(mnemonic)) };
+                self.rm(dst.size, opcode, Field::Reg(*dst), src, &[])
+            }
+            ("lea", [Reg(dst), src @ Mem(_)]) => self.rm(dst.size, &[0x8D], Field::Reg(*dst), src, &[]),
+            ("test", [dst @ (Reg(_) | Mem(_)), Reg(src)]) => {
+                rm_size(dst, Some(src.size))?;
+                self.rm(src.size, &[0x84 + op_w(src.size)], Field::Reg(*src), dst, &[])
+            }
+            ("test", [dst, Imm(value)]) => {
+                let size = rm_size(dst, None)?;
+                self.rm(size, &[0xF6 + op_w(size)], Field::Ext(0), dst, &imm_bytes(*value, size)?)
+            }
+            ("xchg", [dst @ (Reg(_) | Mem(_)), Reg(src)]) | ("xchg", [Reg(src), dst @ Mem(_)]) => {
+                rm_size(dst, Some(src.size))?;
+                self.rm(src.size, &[0x86 + op_w(src.size)], Field::Reg(*src), dst, &[])
+            }
+            ("imul", [Reg(dst), src]) if dst.size != 1 => {
+                rm_size(src, Some(dst.size))?;
+                self.rm(dst.size, &[0x0F, 0xAF], Field::Reg(*dst), src, &[])
+            }
+            ("imul", [Reg(dst), src, Imm(value)]) if dst.size != 1 => {
+                rm_size(src, Some(dst.size))?;
+                if fits_i8(*value) {
+                    self.rm(dst.size, &[0x6B], Field::Reg(*dst), src, &[*value as u8])
+                }
+                else {
+                    self.rm(dst.size, &[0x69], Field::Reg(*dst), src, &imm_bytes(*value, dst.size)?)
+                }
+            }
+            ("push", [Reg(reg)]) if reg.size == 8 => {
+                self.rex(false, 0, 0, reg.id);
+                self.bytes.push(0x50 + (reg.id & 7));
+                Ok(())
+            }
+            ("pop", [Reg(reg)]) if reg.size == 8 => {
+                self.rex(false, 0, 0, reg.id);
+                self.bytes.push(0x58 + (reg.id & 7));
+                Ok(())
+            }
+            ("push", [Imm(value)]) if fits_i8(*value) => {
+                self.bytes.extend([0x6A, *value as u8]);
+                Ok(())
+            }
+            ("push", [Imm(value)]) => {
+                self.bytes.push(0x68);
+                self.bytes.extend(imm_bytes(*value, 8)?);
+                Ok(())
+            }
+            ("push", [src @ Mem(_)]) => self.rm(4, &[0xFF], Field::Ext(6), src, &[]),
+            ("pop", [dst @ Mem(_)]) => self.rm(4, &[0x8F], Field::Ext(0), dst, &[]),
+            ("jmp", [Label(label)]) => {
+                self.bytes.push(0xE9);
+                self.rel32(label)
+            }
+            ("call", [Label(label)]) => {
+                self.bytes.push(0xE8);
+                self.rel32(label)
+            }
+            // Near indirect branches always use 64 bit operands.
+            ("jmp", [target @ (Reg(_) | Mem(_))]) => {
+                rm_size(target, Some(8))?;
+                self.rm(4, &[0xFF], Field::Ext(4), target, &[])
+            }
+            ("call", [target @ (Reg(_) | Mem(_))]) => {
+                rm_size(target, Some(8))?;
+                self.rm(4, &[0xFF], Field::Ext(2), target, &[])
+            }
+            (_, []) => {
+                let bytes: &[u8] = match mnemonic {
+                    "ret" => &[0xC3],
+                    "leave" => &[0xC9],
+                    "nop" => &[0x90],
+                    "syscall" => &[0x0F, 0x05],
+                    "ud2" => &[0x0F, 0x0B],
+                    "cbw" => &[0x66, 0x98],
+                    "cwde" => &[0x98],
+                    "cdqe" => &[0x48, 0x98],
+                    "cwd" => &[0x66, 0x99],
+                    "cdq" => &[0x99],
+                    "cqo" => &[0x48, 0x99],
+                    "movsb" => &[0xA4],
+                    "movsq" => &[0x48, 0xA5],
+                    "stosb" => &[0xAA],
+                    "stosq" => &[0x48, 0xAB],
+                    _ => return Err(format!("unknown instruction {mnemonic}")),
+                };
+                self.bytes.extend(bytes);
+                Ok(())
+            }
+            _ => Err(operand_error(mnemonic)),
+        }
+    }
+
+    fn mov_reg_imm(&mut self, dst: Reg, value: i64) -> AsmResult<()> {
+        if dst.size == 8 && fits_i32(value) {
+            return self.rm(8, &[0xC7], Field::Ext(0), &Operand::Reg(dst), &value.to_le_bytes()[..4]);
+        }
+        // Writing the low half zeroes the upper one.
+        if dst.size == 8 && u32::try_from(value).is_ok() {
+            return self.mov_reg_imm(Reg { size: 4, ..dst }, value);
+        }
+
+        if dst.size == 2 {
+            self.bytes.push(0x66);
+        }
+        let byte_reg = dst.size == 1 && (4..8).contains(&dst.id);
+        if dst.size == 8 || dst.id >= 8 || byte_reg {
+            self.bytes.push(0x40 | ((dst.size == 8) as u8) << 3 | dst.id >> 3);
+        }
+        let opcode = if dst.size == 1 { 0xB0 } else { 0xB8 };
+        self.bytes.push(opcode + (dst.id & 7));
+        match dst.size {
+            8 => self.bytes.extend(value.to_le_bytes()),
+            size => self.bytes.extend(imm_bytes(value, size)?),
+        }
+        Ok(())
+    }
+
+    fn rel32(&mut self, label: &str) -> AsmResult<()> {
+        self.field = Some(LabelField {
+            at: self.bytes.len(),
+            label: label.to_string(),
+            kind: RelocKind::Plt32,
+            addend: 0,
+        });
+        self.bytes.extend([0; 4]);
+        Ok(())
+    }
+
+    fn rex(&mut self, w: bool, r: u8, x: u8, b: u8) {
+        let rex = (w as u8) << 3 | (r >> 3) << 2 | (x >> 3) << 1 | b >> 3;
+        if rex != 0 {
+            self.bytes.push(0x40 | rex);
+        }
+    }
+
+    /// Emits an instruction with a ModRM byte, including the prefixes its operand size needs.
+    fn rm(&mut self, size: u8, opcode: &[u8], reg: Field, rm: &Operand, imm: &[u8]) -> AsmResult<()> {
+        if size == 2 {
+            self.bytes.push(0x66);
+        }
+
+        let (reg_id, reg_needs_rex) = match reg {
+            Field::Reg(reg) => (reg.id, reg.size == 1 && (4..8).contains(&reg.id)),
+            Field::Ext(ext) => (ext, false),
+        };
+        let (x, b) = match rm {
+            Operand::Reg(r) => (0, r.id),
+            Operand::Mem(m) => (m.index.map_or(0, |(i, _)| i), m.base.unwrap_or(0)),
+            _ => return Err("expected a register or memory operand".into()),
+        };
+        let rm_needs_rex = matches!(rm, Operand::Reg(r) if r.size == 1 && (4..8).contains(&r.id));
+
+        let w = size == 8;
+        let rex = (w as u8) << 3 | (reg_id >> 3) << 2 | (x >> 3) << 1 | b >> 3;
+        if rex != 0 || reg_needs_rex || rm_needs_rex {
+            self.bytes.push(0x40 | rex);
+        }
+        self.bytes.extend(opcode);
+
+        let reg_bits = (reg_id & 7) << 3;
+        match rm {
+            Operand::Reg(r) => self.bytes.push(0xC0 | reg_bits | (r.id & 7)),
+            Operand::Mem(mem) => self.mem(reg_bits, mem)?,
+            _ => unreachable!(),
+        }
+        self.bytes.extend(imm);
+        Ok(())
+    }
+    fn mem(&mut self, reg_bits: u8, mem: &Mem) -> AsmResult<()> {
+        let disp = i32::try_from(mem.disp).map_err(|_| format!("displacement {} is too large", mem.disp))?;
+        let scale_bits = |scale: u8| scale.trailing_zeros() as u8;
+
+        if let Some(label) = &mem.label {
+            if mem.base.is_some() || mem.index.is_some() {
+                return Err("labels can't be combined with registers".into());
+            }
+            // Labels are always addressed relative to RIP, so the code can be loaded anywhere.
+            self.bytes.push(reg_bits | 0b101);
+            self.field = Some(LabelField {
+                at: self.bytes.len(),
+                label: label.clone(),
+                kind: RelocKind::Pc32,
+                addend: disp as i64,
+            });
+            self.bytes.extend([0; 4]);
+            return Ok(());
+        }
+
+        let Some(base) = mem.base else {
+            // Absolute address, encoded through a SIB byte without base.
+            self.bytes.push(reg_bits | 0b100);
+            let index = match mem.index {
+                Some((index, scale)) => scale_bits(scale) << 6 | (index & 7) << 3,
+                None => 0b100 << 3,
+            };
+            self.bytes.push(index | 0b101);
+            self.bytes.extend(disp.to_le_bytes());
+            return Ok(());
+        };
+
+        // RBP and R13 can't go without displacement, that encoding means RIP relative.
+        let mode = if disp == 0 && base & 7 != 5 {
+            0b00
+        }
+        else if fits_i8(disp as i64) {
+            0b01
+        }
+        else {
+            0b10
+        };
+
+        match mem.index {
+            Some((index, scale)) => {
+                self.bytes.push(mode << 6 | reg_bits | 0b100);
+                self.bytes.push(scale_bits(scale) << 6 | (index & 7) << 3 | (base & 7));
+            }
+            // RSP and R12 need a SIB byte.
+            None if base & 7 == 4 => {
+                self.bytes.push(mode << 6 | reg_bits | 0b100);
+                self.bytes.push(0b100 << 3 | 0b100);
+            }
+            None => self.bytes.push(mode << 6 | reg_bits | (base & 7)),
+        }
+
+        match mode {
+            0b01 => self.bytes.push(disp as u8),
+            0b10 => self.bytes.extend(disp.to_le_bytes()),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// The low opcode bit selecting between byte and full size operands.
+fn op_w(size: u8) -> u8 {
+    (size != 1) as u8
+}
+fn operand_size(op: &Operand) -> Option<u8> {
+    match op {
+        Operand::Reg(reg) => Some(reg.size),
+        Operand::Mem(mem) => mem.size,
+        _ => None,
+    }
+}
+/// The size of a register or memory operand, which has to agree with `expected` if both are known.
+fn rm_size(op: &Operand, expected: Option<u8>) -> AsmResult<u8> {
+    match (operand_size(op), expected) {
+        (Some(size), Some(expected)) if size != expected => Err(format!("operand sizes {size} and {expected} don't match")),
+        (Some(size), _) | (None, Some(size)) => Ok(size),
+        (None, None) => Err("operation size not specified".into()),
+    }
+}
+fn operand_error(mnemonic: &str) -> String {
+    format!("invalid operands for {mnemonic}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The machine code of `src`, which may not need any relocations.
+    fn encode(src: &str) -> Vec<u8> {
+        let assembly = assemble(src).unwrap_or_else(|e| panic!("{src}: {e}"));
+        assert_eq!(assembly.sections[0].relocs, [], "{src}");
+        assembly.sections[0].data.clone()
+    }
+
+    #[test]
+    fn encodes_instructions() {
+        let cases: &[(&str, &[u8])] = &[
+            ("add rax, rdx", &[0x48, 0x01, 0xD0]),
+            ("sub r9d, 1", &[0x41, 0x83, 0xE9, 0x01]),
+            ("and byte [rbx], 0x7f", &[0x80, 0x23, 0x7F]),
+            ("cmp qword [rbp-8], 1000", &[0x48, 0x81, 0x7D, 0xF8, 0xE8, 0x03, 0x00, 0x00]),
+            ("xor r10d, r10d", &[0x45, 0x31, 0xD2]),
+            ("shl rcx, cl", &[0x48, 0xD3, 0xE1]),
+            ("sar eax, 1", &[0xD1, 0xF8]),
+            ("shr r11w, 3", &[0x66, 0x41, 0xC1, 0xEB, 0x03]),
+            ("neg byte [rsp+16]", &[0xF6, 0x5C, 0x24, 0x10]),
+            ("idiv r8", &[0x49, 0xF7, 0xF8]),
+            ("cmovl rax, [rdi+rsi*8]", &[0x48, 0x0F, 0x4C, 0x04, 0xF7]),
+            ("sete sil", &[0x40, 0x0F, 0x94, 0xC6]),
+            ("mov [r12], r13", &[0x4D, 0x89, 0x2C, 0x24]),
+            ("mov ax, [r13]", &[0x66, 0x41, 0x8B, 0x45, 0x00]),
+            ("mov r15, -1", &[0x49, 0xC7, 0xC7, 0xFF, 0xFF, 0xFF, 0xFF]),
+            ("mov rax, 0xffffffff", &[0xB8, 0xFF, 0xFF, 0xFF, 0xFF]),
+            ("mov rax, 0x123456789", &[0x48, 0xB8, 0x89, 0x67, 0x45, 0x23, 0x01, 0x00, 0x00, 0x00]),
+            ("mov byte [rax+rcx*4+128], -2", &[0xC6, 0x84, 0x88, 0x80, 0x00, 0x00, 0x00, 0xFE]),
+            ("mov qword [0x1000], rax", &[0x48, 0x89, 0x04, 0x25, 0x00, 0x10, 0x00, 0x00]),
+            ("movsx rax, byte [rdx]", &[0x48, 0x0F, 0xBE, 0x02]),
+            ("movzx ecx, word [rbp-2]", &[0x0F, 0xB7, 0x4D, 0xFE]),
+            ("movsxd rdx, r9d", &[0x49, 0x63, 0xD1]),
+            ("lea rax, [rsp+rcx*2-4]", &[0x48, 0x8D, 0x44, 0x4C, 0xFC]),
+            ("test dil, dil", &[0x40, 0x84, 0xFF]),
+            ("imul r14, rdx, 10", &[0x4C, 0x6B, 0xF2, 0x0A]),
+            ("push r12", &[0x41, 0x54]),
+            ("pop rbp", &[0x5D]),
+            ("push 127", &[0x6A, 0x7F]),
+            ("call rax", &[0xFF, 0xD0]),
+            ("jmp qword [r11+8]", &[0x41, 0xFF, 0x63, 0x08]),
+            ("rep movsb", &[0xF3, 0xA4]),
+            ("cqo", &[0x48, 0x99]),
+            ("syscall", &[0x0F, 0x05]),
+        ];
+        for &(src, bytes) in cases {
+            assert_eq!(encode(src), bytes, "{src}");
+        }
+    }
+
+    #[test]
+    fn resolves_labels() {
+        // Jumps within a section are resolved right away.
+        let src = "f:\n    jne .done\n    call f\n.done:\n    ret";
+        assert_eq!(encode(src), [0x0F, 0x85, 0x05, 0x00, 0x00, 0x00, 0xE8, 0xF5, 0xFF, 0xFF, 0xFF, 0xC3]);
+
+        // Everything else is left to the linker, relative to the end of the instruction.
+        let src = "extern puts\nglobal g\nsection .data\ng: dq f, .local+2\n.local:\nsection .text\nf:\n    cmp qword [rel g], 1\n    call puts";
+        let assembly = assemble(src).unwrap();
+        let names: Vec<_> = assembly.symbols.iter().map(|s| (s.name.as_str(), s.definition, s.global)).collect();
+        assert_eq!(names, [("g", Some((1, 0)), true), ("f", Some((0, 0)), false), ("puts", None, true)]);
+        assert_eq!(assembly.sections[0].data, [0x48, 0x83, 0x3D, 0, 0, 0, 0, 0x01, 0xE8, 0, 0, 0, 0]);
+        let text = [
+            Reloc { offset: 3, kind: RelocKind::Pc32, target: RelocTarget::Symbol(0), addend: -5 },
+            Reloc { offset: 9, kind: RelocKind::Plt32, target: RelocTarget::Symbol(2), addend: -4 },
+        ];
+        assert_eq!(assembly.sections[0].relocs, text);
+        let data = [
+            Reloc { offset: 0, kind: RelocKind::Abs64, target: RelocTarget::Symbol(1), addend: 0 },
+            Reloc { offset: 8, kind: RelocKind::Abs64, target: RelocTarget::Section(1), addend: 18 },
+        ];
+        assert_eq!(assembly.sections[1].relocs, data);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let cases = [
+            ("frobnicate", "line 1: unknown instruction frobnicate: frobnicate"),
+            ("nop\nmov rax, ecx", "line 2: operand sizes 8 and 4 don't match: mov rax, ecx"),
+            ("mov [rax], 1", "line 1: operation size not specified: mov [rax], 1"),
+            ("lea rax, [rcx+rsp*2]", "line 1: invalid index rsp*2: lea rax, [rcx+rsp*2]"),
+            ("add al, 300", "line 1: immediate 300 doesn't fit into 1 bytes: add al, 300"),
+            ("jmp nowhere", "undefined label nowhere"),
+            ("a:\na:", "line 2: label a is defined twice: a:"),
+        ];
+        for (src, message) in cases {
+            let error = assemble(src).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
@@ -0,0 +1,662 @@
+use std::{fs::{self, File}, io, os::unix::process::ExitStatusExt, path::Path, process::{Command, Stdio}, thread, time::{Duration, Instant}};
+
+use crate::{frontend::{CallConvention, FunID, Module, Ty, Value}, interpreter::{Interpreter, Trap}};
+use super::{CodeGen, RegAllocMode, assembler, elf::write_elf, staging_layout};
+
+/// The entry point of the native executable calls the function under test,
+/// then writes `RETURN_MARKER` and the return value to stderr and exits with 0.
+/// The marker tells a return apart from the program exiting by itself.
+const ENTRY_LABEL: &str = "_start";
+const MARKER_LABEL: &str = "_CLEdiff_marker";
+const RETURN_MARKER: &[u8] = b"\0cir-return\0";
+const SIGFPE: i32 = 8;
+
+/// How many instructions the interpreter may run before the outcome counts as unknown.
+const DEFAULT_STEP_LIMIT: u64 = 10_000_000;
+/// How long the native executable may run, in case it miscompiled into an endless loop.
+const NATIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs a function both in the interpreter and natively through `backend_86`,
+/// and compares their return values, stdout and stderr bytes and exit codes.
+///
+/// The function must use the `Simple` convention, take no parameters
+/// and return `void`, `bool` or an integer.
+/// Native runs are linked into a standalone executable with `ld`, without libc.
+pub struct DiffTest<'a> {
+    module: &'a Module,
+    fun: FunID,
+    reg_alloc: RegAllocMode,
+    step_limit: u64,
+}
+impl<'a> DiffTest<'a> {
+    pub fn new(module: &'a Module, fun: FunID) -> Self {
+        let f = &module[fun];
+        assert_eq!(f.call_convention, CallConvention::Simple, "{} must use the Simple convention", f.name);
+        assert!(f.parameters.is_empty(), "{} must not take parameters", f.name);
+        assert!(matches!(f.ret_ty, Ty::Void | Ty::Bool | Ty::Int(_)), "{} must return a scalar", f.name);
+
+        Self {
+            module,
+            fun,
+            reg_alloc: RegAllocMode::LinearScan,
+            step_limit: DEFAULT_STEP_LIMIT,
+        }
+    }
+
+    pub fn with_reg_alloc(mut self, mode: RegAllocMode) -> Self {
+        self.reg_alloc = mode;
+        self
+    }
+    pub fn with_step_limit(mut self, steps: u64) -> Self {
+        self.step_limit = steps;
+        self
+    }
+
+    /// Interprets the function and, unless that was inconclusive,
+    /// builds and runs it natively inside `dir`.
+    pub fn run(&self, dir: &Path) -> io::Result<Verdict> {
+        let interpreted = self.interpret();
+        let native_end = match &interpreted.end {
+            End::Returned(_) | End::Exited(_) => interpreted.end.clone(),
+            End::Trapped(Trap::DivisionByZero | Trap::DivisionOverflow) => End::Signaled(SIGFPE),
+            _ => return Ok(Verdict::Inconclusive(interpreted)),
+        };
+
+        let native = self.run_native(dir)?;
+        let agree = native.end == native_end
+            && native.stdout == interpreted.stdout
+            && native.stderr == interpreted.stderr;
+        if agree {
+            Ok(Verdict::Agree(interpreted))
+        }
+        else {
+            Ok(Verdict::Disagree { interpreted, native })
+        }
+    }
+
+    pub fn interpret(&self) -> Outcome {
+        let mut interpreter = Interpreter::new(self.module).with_step_limit(self.step_limit);
+        let end = match interpreter.call(self.fun, &[]) {
+            Ok(value) => End::Returned(value),
+            // A process only sees the low byte of its exit code.
+            Err(Trap::Exit(code)) => End::Exited(code & 0xFF),
+            Err(trap) => End::Trapped(trap),
+        };
+
+        Outcome {
+            stdout: interpreter.stdout().to_vec(),
+            stderr: interpreter.stderr().to_vec(),
+            end,
+        }
+    }
+
+    pub fn run_native(&self, dir: &Path) -> io::Result<Outcome> {
+        fs::create_dir_all(dir)?;
+        let object = dir.join("diff.o");
+        let exe = dir.join("diff");
+        let stdout = dir.join("stdout");
+        let stderr = dir.join("stderr");
+
+        fs::write(&object, self.gen_object()?)?;
+        let link = Command::new("ld").arg("-o").arg(&exe).arg(&object).output()?;
+        if !link.status.success() {
+            let message = format!("ld failed: {}", String::from_utf8_lossy(&link.stderr));
+            return Err(io::Error::other(message));
+        }
+
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::null())
+            .stdout(File::create(&stdout)?)
+            .stderr(File::create(&stderr)?)
+            .spawn()?;
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if start.elapsed() > NATIVE_TIMEOUT {
+                child.kill()?;
+                child.wait()?;
+                break None;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        let stdout = fs::read(&stdout)?;
+        let mut stderr = fs::read(&stderr)?;
+        let end = match status {
+            None => End::TimedOut,
+            Some(status) => match (status.code(), status.signal()) {
+                (Some(0), _) => match self.split_return(&mut stderr) {
+                    Some(value) => End::Returned(value),
+                    None => End::Exited(0),
+                },
+                (Some(code), _) => End::Exited(code),
+                (None, Some(signal)) => End::Signaled(signal),
+                (None, None) => unreachable!(),
+            },
+        };
+
+        Ok(Outcome { stdout, stderr, end })
+    }
+
+    /// Generates the module's code together with the entry point and assembles it into an object.
+    fn gen_object(&self) -> io::Result<Vec<u8>> {
+        let mut src = Vec::new();
+        let labels = CodeGen::new(self.module, &mut src).with_reg_alloc(self.reg_alloc).gen_labelled_code()?;
+        let src = String::from_utf8(src).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let fun = &self.module[self.fun];
+        let (staging, _) = staging_layout(self.module, fun.ret_ty, &[]);
+        let ret_size = self.module.ty_layout(fun.ret_ty).size();
+        let marker: Vec<String> = RETURN_MARKER.iter().map(|b| b.to_string()).collect();
+        let entry = format!("
+section .rodata
+{MARKER_LABEL}:
+    db {marker}
+section .text
+global {ENTRY_LABEL}
+{ENTRY_LABEL}:
+    sub rsp, {staging}
+    call {label}
+    mov eax, 1
+    mov edi, 2
+    lea rsi, [rel {MARKER_LABEL}]
+    mov edx, {marker_len}
+    syscall
+    mov eax, 1
+    mov edi, 2
+    mov rsi, rsp
+    mov edx, {ret_size}
+    syscall
+    mov eax, 60
+    xor edi, edi
+    syscall
+",
+            marker = marker.join(", "),
+            staging = staging.size(),
+            label = labels.functions[&self.fun],
+            marker_len = RETURN_MARKER.len(),
+        );
+
+        let assembly = assembler::assemble(&(src + &entry))?;
+        Ok(write_elf(&assembly))
+    }
+    /// Takes the marker and the return value the entry point wrote off the end of stderr.
+    fn split_return(&self, stderr: &mut Vec<u8>) -> Option<Value> {
+        let ret_ty = self.module[self.fun].ret_ty;
+        let ret_size = self.module.ty_layout(ret_ty).size() as usize;
+        let start = stderr.len().checked_sub(RETURN_MARKER.len() + ret_size)?;
+        if &stderr[start..start + RETURN_MARKER.len()] != RETURN_MARKER { return None };
+
+        let bytes = stderr.split_off(start).split_off(RETURN_MARKER.len());
+        let mut buf = [0; 8];
+        buf[..ret_size].copy_from_slice(&bytes);
+        let raw = i64::from_le_bytes(buf);
+
+        Some(match ret_ty {
+            Ty::Void => Value::Void,
+            Ty::Bool => Value::Bool(raw & 0xFF != 0),
+            Ty::Int(ty) => {
+                let shift = 64 - 8 * ret_size as u32;
+                Value::Int(ty, raw << shift >> shift)
+            }
+            _ => unreachable!(),
+        })
+    }
+}
+
+/// What running a function did, as far as the harness can observe.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub end: End,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum End {
+    Returned(Value),
+    /// The program made an `exit` system call with this code.
+    Exited(i32),
+    /// The native executable was killed by a signal, like `SIGFPE` for a division by zero.
+    Signaled(i32),
+    /// The interpreter stopped on something that compiled code doesn't define.
+    Trapped(Trap),
+    /// The native executable ran for longer than the harness waits.
+    TimedOut,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Agree(Outcome),
+    Disagree {
+        interpreted: Outcome,
+        native: Outcome,
+    },
+    /// The interpreter trapped on undefined behaviour or ran out of steps,
+    /// so there is nothing the native run could be compared to.
+    Inconclusive(Outcome),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf, sync::atomic::{AtomicUsize, Ordering}};
+
+    use crate::{frontend::{BinOp, Builder, Instruction, IntTy, RegID, StructTyID, UnOp, VarID}, target::Target};
+    use super::*;
+
+    const WIDTHS: [IntTy; 4] = [IntTy::I8, IntTy::I16, IntTy::I32, IntTy::I64];
+    const BINOPS: [BinOp; 23] = [
+        BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::IDiv, BinOp::UDiv, BinOp::IMod, BinOp::UMod,
+        BinOp::And, BinOp::Or, BinOp::Xor, BinOp::Shl, BinOp::Shr, BinOp::Sar,
+        BinOp::Equal, BinOp::NotEqual, BinOp::Greater, BinOp::GreaterEqual, BinOp::Less, BinOp::LessEqual,
+        BinOp::Above, BinOp::AboveEqual, BinOp::Below, BinOp::BelowEqual,
+    ];
+
+    /// Values around the edges of every width, including the ones
+    /// that only fit an immediate when sign- or zero-extended.
+    fn edge_values(ty: IntTy) -> Vec<i64> {
+        let bits = match ty {
+            IntTy::I8 => 8,
+            IntTy::I16 => 16,
+            IntTy::I32 => 32,
+            IntTy::I64 => 64,
+        };
+        let min = i64::MIN >> (64 - bits);
+        let max = i64::MAX >> (64 - bits);
+        let mut values = vec![0, 1, -1, 2, 7, -100, min, max, min + 1, 0x5A5A_5A5A_5A5A_5A5A >> (64 - bits)];
+        if ty == IntTy::I64 {
+            values.extend([0xFFFF_FFFF, 0x8000_0000, -0x8000_0000, -0x8000_0001, 1 << 32]);
+        }
+        values
+    }
+
+    /// Builds one exported function in a fresh module and runs the harness on it
+    /// with every register allocator.
+    fn check(name: &str, ret_ty: impl Into<Ty>, build: impl FnOnce(&mut Corpus)) {
+        let mut corpus = Corpus {
+            b: Builder::new(Module::new(Target::LINUX_X64)),
+            vars: HashMap::new(),
+        };
+        let fun = corpus.b.begin_fun(name.into(), ret_ty);
+        corpus.b.begin_block();
+        corpus.b.set_entry_block();
+        build(&mut corpus);
+        let module = corpus.b.finish();
+        let errors = module.verify();
+        assert!(errors.is_empty(), "{name}: {errors:?}");
+
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        for mode in [RegAllocMode::LinearScan, RegAllocMode::StackSlots] {
+            let n = RUNS.fetch_add(1, Ordering::SeqCst);
+            let dir: PathBuf = std::env::temp_dir().join(format!("cir-difftest-{}-{n}", std::process::id()));
+            let verdict = DiffTest::new(&module, fun).with_reg_alloc(mode).run(&dir);
+            let _ = fs::remove_dir_all(&dir);
+            match verdict.unwrap() {
+                Verdict::Agree(_) => (),
+                Verdict::Disagree { interpreted, native } => {
+                    panic!("{name} with {mode:?}:\ninterpreted: {interpreted:?}\nnative: {native:?}")
+                }
+                Verdict::Inconclusive(outcome) => panic!("{name} is inconclusive: {outcome:?}"),
+            }
+        }
+    }
+
+    struct Corpus {
+        b: Builder,
+        vars: HashMap<Ty, VarID>,
+    }
+    impl Corpus {
+        /// Writes the bytes of a scalar to stdout, through a variable of its type.
+        fn emit(&mut self, value: impl Into<Value>) {
+            let value = value.into();
+            let ty = value.ty(&self.b.module);
+            let var = *self.vars.entry(ty).or_insert_with(|| self.b.create_var(ty));
+            let ptr = self.b.get_var_addr(var);
+            self.b.store(ptr, value);
+            let size = self.b.module.ty_layout(ty).size() as i64;
+            self.b.syscall_linux64(IntTy::I64, 1i64, [Value::from(1i64), ptr.into(), size.into()]);
+        }
+        /// Passes values through block parameters, so they live in registers or slots
+        /// instead of being immediates.
+        fn regs(&mut self, values: &[Value]) -> Vec<RegID> {
+            let block = self.b.create_block();
+            self.b.jump((block, values));
+            self.b.select_block(block);
+            values.iter().map(|&v| {
+                let ty = v.ty(&self.b.module);
+                self.b.create_block_param(ty)
+            }).collect()
+        }
+        fn binary(&mut self, op: BinOp, a: Value, b: Value) -> RegID {
+            let ty = match op {
+                BinOp::Equal | BinOp::NotEqual | BinOp::Greater | BinOp::GreaterEqual
+                | BinOp::Less | BinOp::LessEqual | BinOp::Above | BinOp::AboveEqual
+                | BinOp::Below | BinOp::BelowEqual => Ty::Bool,
+                _ => a.ty(&self.b.module),
+            };
+            let dst = self.b.create_reg(ty);
+            self.b.add_instr(Instruction::Binary(op, dst, a, b));
+            dst
+        }
+        fn unary(&mut self, op: UnOp, ty: impl Into<Ty>, a: Value) -> RegID {
+            let dst = self.b.create_reg(ty);
+            self.b.add_instr(Instruction::Unary(op, dst, a));
+            dst
+        }
+        /// Loops four times through a header with a parameter for every initial value,
+        /// writing them out on every iteration. The back edge passes `next` of the parameters.
+        fn cycle(&mut self, init: &[Value], next: impl Fn(&[RegID]) -> Vec<RegID>) {
+            let header = self.b.create_block();
+            let body = self.b.create_block();
+            let exit = self.b.create_block();
+            let mut args = vec![int(IntTy::I64, 0)];
+            args.extend(init);
+            self.b.jump((header, args));
+
+            self.b.select_block(header);
+            let i = self.b.create_block_param(IntTy::I64);
+            let params: Vec<_> = init.iter().map(|v| {
+                let ty = v.ty(&self.b.module);
+                self.b.create_block_param(ty)
+            }).collect();
+            for &param in &params {
+                match self.b.module[param].ty {
+                    Ty::Struct(s) => {
+                        for index in 0..self.b.module[s].members.len() as u64 {
+                            let member = self.b.get_struct_member(param, index);
+                            self.emit(member);
+                        }
+                    }
+                    _ => self.emit(param),
+                }
+            }
+            let i = self.b.add(i, 1i64);
+            let more = self.b.test_l(i, 4i64);
+            self.b.branch(more, body, exit);
+
+            self.b.select_block(body);
+            let mut args = vec![Value::Reg(i)];
+            args.extend(next(&params).into_iter().map(Value::Reg));
+            self.b.jump((header, args));
+
+            self.b.select_block(exit);
+        }
+    }
+    fn is_division(op: BinOp) -> bool {
+        matches!(op, BinOp::IDiv | BinOp::UDiv | BinOp::IMod | BinOp::UMod)
+    }
+    fn int(ty: IntTy, value: i64) -> Value {
+        Value::Int(ty, value)
+    }
+
+    #[test]
+    fn binary_ops() {
+        for ty in WIDTHS {
+            for op in BINOPS {
+                check(&format!("binary_{op:?}_{ty:?}"), Ty::Void, |c| {
+                    let values = edge_values(ty);
+                    let counts: Vec<i64> = [0, 1, 3, 7, 8, 15, 16, 31, 32, 63].into_iter().collect();
+                    let rhs = match op {
+                        BinOp::Shl | BinOp::Shr | BinOp::Sar => &counts,
+                        _ => &values,
+                    };
+                    for &x in &values {
+                        for &y in rhs {
+                            // Faulting divisions are covered by `division_traps`.
+                            let signed_overflow = y == -1 && x == values[6];
+                            if is_division(op) && (y == 0 || signed_overflow) { continue };
+
+                            let (a, b) = (int(ty, x), int(ty, y));
+                            let regs = c.regs(&[a, b]);
+                            let r = c.binary(op, regs[0].into(), regs[1].into());
+                            c.emit(r);
+                            let r = c.binary(op, regs[0].into(), b);
+                            c.emit(r);
+                            let r = c.binary(op, a, regs[1].into());
+                            c.emit(r);
+                        }
+                    }
+                    c.b.ret(());
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn division_traps() {
+        for ty in WIDTHS {
+            for op in [BinOp::IDiv, BinOp::IMod, BinOp::UDiv, BinOp::UMod] {
+                check(&format!("div_zero_{op:?}_{ty:?}"), ty, |c| {
+                    let regs = c.regs(&[int(ty, 7), int(ty, 0)]);
+                    c.emit(regs[0]);
+                    let r = c.binary(op, regs[0].into(), regs[1].into());
+                    c.b.ret(r);
+                });
+            }
+            for op in [BinOp::IDiv, BinOp::IMod] {
+                let min = edge_values(ty)[6];
+                check(&format!("div_overflow_{op:?}_{ty:?}"), ty, |c| {
+                    let regs = c.regs(&[int(ty, min), int(ty, -1)]);
+                    let r = c.binary(op, regs[0].into(), regs[1].into());
+                    c.b.ret(r);
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn unary_ops() {
+        check("unary", Ty::Void, |c| {
+            for from in WIDTHS {
+                for x in edge_values(from) {
+                    let a = c.regs(&[int(from, x)])[0];
+                    let r = c.unary(UnOp::Neg, from, a.into());
+                    c.emit(r);
+                    let r = c.unary(UnOp::Not, from, a.into());
+                    c.emit(r);
+                    let r = c.unary(UnOp::IntToPtr, Ty::Ptr, a.into());
+                    let r = c.unary(UnOp::PtrToInt, IntTy::I64, r.into());
+                    c.emit(r);
+                    for to in WIDTHS {
+                        let op = match (from as u8).cmp(&(to as u8)) {
+                            std::cmp::Ordering::Less => [UnOp::Sext, UnOp::Zext],
+                            _ => [UnOp::Trunc, UnOp::Trunc],
+                        };
+                        for op in op {
+                            let r = c.unary(op, to, a.into());
+                            c.emit(r);
+                        }
+                    }
+                }
+            }
+            for x in [false, true] {
+                let a = c.regs(&[Value::Bool(x)])[0];
+                let r = c.unary(UnOp::Not, Ty::Bool, a.into());
+                c.emit(r);
+                for to in WIDTHS {
+                    let r = c.unary(UnOp::Zext, to, r.into());
+                    c.emit(r);
+                }
+            }
+            c.b.ret(());
+        });
+    }
+
+    /// Stores of immediates go through `mov_value_to_mem`, which picks an encoding by value.
+    #[test]
+    fn stores() {
+        check("stores", IntTy::I64, |c| {
+            let mut last = c.b.set(0i64);
+            for ty in WIDTHS {
+                let var = c.b.create_var(ty);
+                for x in edge_values(ty) {
+                    let ptr = c.b.get_var_addr(var);
+                    c.b.store(ptr, int(ty, x));
+                    let r = c.b.load(ty, ptr);
+                    c.emit(r);
+                    let r = c.b.sext(IntTy::I64, r);
+                    last = c.b.add(last, r);
+                }
+            }
+            c.b.ret(last);
+        });
+    }
+
+    #[test]
+    fn aggregates() {
+        check("aggregates", IntTy::I32, |c| {
+            let s: StructTyID = c.b.module.add_struct_ty();
+            c.b.module.add_struct_member(s, IntTy::I8.into());
+            c.b.module.add_struct_member(s, IntTy::I64.into());
+            c.b.module.add_struct_member(s, IntTy::I16.into());
+            let arr = c.b.module.add_array_ty(3, s.into());
+
+            let v = c.b.set_struct(s, [int(IntTy::I8, -3), int(IntTy::I64, 0xFFFF_FFFF), int(IntTy::I16, -2)]);
+            let v = c.b.set_struct_member(v, 2, int(IntTy::I16, 0x1234));
+            for i in 0..3 {
+                let m = c.b.get_struct_member(v, i);
+                c.emit(m);
+            }
+
+            let var = c.b.create_var(arr);
+            let base = c.b.get_var_addr(var);
+            let splat = c.b.set_array_splat(3, v);
+            c.b.store(base, splat);
+            let i = c.regs(&[int(IntTy::I64, 2)])[0];
+            let elem = c.b.index_array(s, base, i);
+            let field = c.b.index_struct(s, elem, 1);
+            c.b.store(field, int(IntTy::I64, -0x8000_0001));
+            let whole = c.b.load(arr, base);
+            for i in 0..3 {
+                let e = c.b.get_array_element(whole, int(IntTy::I64, i));
+                let m = c.b.get_struct_member(e, 1);
+                c.emit(m);
+            }
+            let a = c.b.set_array(IntTy::I16.into(), [int(IntTy::I16, 10), int(IntTy::I16, 20), int(IntTy::I16, 30)]);
+            let j = c.regs(&[int(IntTy::I8, 1)])[0];
+            let a = c.b.set_array_element(a, j, int(IntTy::I16, -99));
+            for i in 0..3 {
+                let e = c.b.get_array_element(a, int(IntTy::I32, i));
+                c.emit(e);
+            }
+
+            let d = c.b.ptr_diff(IntTy::I32, s, elem, base);
+            c.b.ret(d);
+        });
+    }
+
+    #[test]
+    fn calls_and_control_flow() {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let fib = b.begin_fun("fib".into(), IntTy::I32);
+        let n = b.create_param(IntTy::I32);
+        b.begin_block();
+        b.set_entry_block();
+        let small = b.test_l(n, 2i32);
+        let recurse = b.create_block();
+        let done = b.create_block();
+        b.branch(small, (done, [n]), recurse);
+        b.select_block(recurse);
+        let n1 = b.sub(n, 1i32);
+        let n2 = b.sub(n, 2i32);
+        let f1 = b.call(fib, [n1]);
+        let f2 = b.call(fib, [n2]);
+        let sum = b.add(f1, f2);
+        b.jump((done, [sum]));
+        b.select_block(done);
+        let r = b.create_block_param(IntTy::I32);
+        b.ret(r);
+
+        let fun_ty = b.module.add_fun_ty(CallConvention::Simple, IntTy::I32.into(), [Ty::from(IntTy::I32)]);
+        let main = b.begin_fun("main".into(), IntTy::I64);
+        b.begin_block();
+        b.set_entry_block();
+        let ptr = b.set_fun_ptr(fib);
+        let f = b.call_ptr(fun_ty, ptr, [Value::from(15i32)]);
+        // Rotating three values through block parameters needs a parallel copy.
+        let head = b.create_block();
+        b.jump((head, [Value::from(0i64), 1i64.into(), 2i64.into(), 3i64.into()]));
+        b.select_block(head);
+        let i = b.create_block_param(IntTy::I64);
+        let x = b.create_block_param(IntTy::I64);
+        let y = b.create_block_param(IntTy::I64);
+        let z = b.create_block_param(IntTy::I64);
+        let i2 = b.add(i, 1i64);
+        let go = b.test_l(i2, 100i64);
+        let exit = b.create_block();
+        let x2 = b.mul(x, 3i64);
+        b.branch(go, (head, [i2.into(), Value::from(y), z.into(), x2.into()]), exit);
+        b.select_block(exit);
+        let f = b.sext(IntTy::I64, f);
+        let t = b.add(x, y);
+        let t = b.xor(t, z);
+        let t = b.select(go, t, f);
+        let t = b.add(t, f);
+        b.ret(t);
+
+        let module = b.finish();
+        assert!(module.verify().is_empty(), "{:?}", module.verify());
+        let dir = std::env::temp_dir().join(format!("cir-difftest-{}-calls", std::process::id()));
+        for mode in [RegAllocMode::LinearScan, RegAllocMode::StackSlots] {
+            let verdict = DiffTest::new(&module, main).with_reg_alloc(mode).run(&dir).unwrap();
+            assert!(matches!(verdict, Verdict::Agree(_)), "{mode:?}: {verdict:?}");
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn exit_codes() {
+        check("exit", IntTy::I64, |c| {
+            c.emit(int(IntTy::I32, 0x0102_0304));
+            let code = c.regs(&[int(IntTy::I64, 300)])[0];
+            let r = c.b.syscall_linux64(IntTy::I64, 60i64, [code]);
+            c.b.ret(r);
+        });
+    }
+
+    /// Back edges that permute the header's parameters can only be resolved through a temporary.
+    #[test]
+    fn parameter_cycles() {
+        check("swap", IntTy::I64, |c| {
+            c.cycle(&[int(IntTy::I64, 1), int(IntTy::I64, 2)], |p| vec![p[1], p[0]]);
+            c.b.ret(0i64);
+        });
+        check("rotation", IntTy::I64, |c| {
+            let init = [int(IntTy::I16, 1), int(IntTy::I16, 2), int(IntTy::I16, 3), int(IntTy::I64, -4)];
+            c.cycle(&init, |p| vec![p[1], p[2], p[0], p[3]]);
+            c.b.ret(0i64);
+        });
+        check("struct_swap", IntTy::I64, |c| {
+            let pair = c.b.module.add_struct_ty();
+            c.b.module.add_struct_member(pair, IntTy::I64.into());
+            c.b.module.add_struct_member(pair, IntTy::I32.into());
+            let a = c.b.set_struct(pair, [int(IntTy::I64, 1 << 40), int(IntTy::I32, -1)]);
+            let b = c.b.set_struct(pair, [int(IntTy::I64, -5), int(IntTy::I32, 6)]);
+            c.cycle(&[a.into(), int(IntTy::I8, 7), b.into()], |p| vec![p[2], p[1], p[0]]);
+            c.b.ret(0i64);
+        });
+    }
+
+    #[test]
+    fn syscall_arguments() {
+        check("syscall_arguments", IntTy::I64, |c| {
+            // rt_sigprocmask only succeeds if its fourth argument, the size of a signal set, is 8.
+            // Computed first, so nothing else competes for the first allocatable register.
+            let size = c.b.add(4i64, 4i64);
+            let how = c.regs(&[int(IntTy::I32, 0)])[0];
+            let set = c.b.create_var(IntTy::I64);
+            let set = c.b.get_var_addr(set);
+            c.b.store(set, 0i64);
+            let old = c.b.create_var(IntTy::I64);
+            let old = c.b.get_var_addr(old);
+            c.b.store(old, -1i64);
+            let r = c.b.syscall_linux64(IntTy::I64, 14i64, [how, set, old, size]);
+            c.emit(r);
+            let old = c.b.load(IntTy::I64, old);
+            c.emit(old);
+            c.b.ret(r);
+        });
+    }
+}
@@ -0,0 +1,498 @@
+use std::io;
+
+use crate::frontend::Module;
+use super::{CodeGen, RegAllocMode, assembler::{self, Assembly, RelocKind, RelocTarget}};
+
+/// Generates the same code as `CodeGen`, but assembles it itself
+/// and writes an ELF64 relocatable object that `ld` or `cc` can link without NASM.
+pub struct ObjectGen<'a, O> {
+    module: &'a Module,
+    o: O,
+    reg_alloc: RegAllocMode,
+}
+impl<'a, O: io::Write> ObjectGen<'a, O> {
+    pub fn new(module: &'a Module, o: O) -> Self {
+        Self {
+            module,
+            o,
+            reg_alloc: RegAllocMode::LinearScan,
+        }
+    }
+
+    pub fn with_reg_alloc(mut self, mode: RegAllocMode) -> Self {
+        self.reg_alloc = mode;
+        self
+    }
+
+    pub fn gen_object(mut self) -> io::Result<()> {
+        let mut src = Vec::new();
+        CodeGen::new(self.module, &mut src).with_reg_alloc(self.reg_alloc).gen_code()?;
+        let src = String::from_utf8(src).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let assembly = assembler::assemble(&src)?;
+        self.o.write_all(&write_elf(&assembly))
+    }
+}
+
+const EHDR_SIZE: u64 = 64;
+const SHDR_SIZE: u64 = 64;
+const SYM_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_64: u64 = 1;
+const R_X86_64_PC32: u64 = 2;
+const R_X86_64_PLT32: u64 = 4;
+
+struct SectionHeader {
+    name: u32,
+    ty: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+struct StringTable {
+    bytes: Vec<u8>,
+}
+impl StringTable {
+    fn new() -> Self {
+        Self { bytes: vec![0] }
+    }
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend(s.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+/// Lays out the assembly as an x86-64 ELF relocatable object.
+///
+/// The section headers are, in order: the null section, the assembled sections,
+/// one `.rela` section per assembled section with relocations,
+/// then `.symtab`, `.strtab`, `.shstrtab` and an empty `.note.GNU-stack`,
+/// which tells the linker the stack doesn't need to be executable.
+pub(super) fn write_elf(assembly: &Assembly) -> Vec<u8> {
+    let mut out = vec![0; EHDR_SIZE as usize];
+    let mut headers = vec![SectionHeader {
+        name: 0,
+        ty: 0,
+        flags: 0,
+        offset: 0,
+        size: 0,
+        link: 0,
+        info: 0,
+        align: 0,
+        entsize: 0,
+    }];
+    let mut shstrtab = StringTable::new();
+
+    let section_index = |i: usize| i as u32 + 1;
+    let rela_count = assembly.sections.iter().filter(|s| !s.relocs.is_empty()).count();
+    let symtab_index = 1 + assembly.sections.len() as u32 + rela_count as u32;
+
+    for section in &assembly.sections {
+        let offset = append_aligned(&mut out, &section.data, section.align);
+        let mut flags = SHF_ALLOC;
+        if section.is_code() {
+            flags |= SHF_EXECINSTR;
+        }
+        if section.is_writable() {
+            flags |= SHF_WRITE;
+        }
+        headers.push(SectionHeader {
+            name: shstrtab.add(&section.name),
+            ty: if section.is_bss() { SHT_NOBITS } else { SHT_PROGBITS },
+            flags,
+            offset,
+            size: section.size,
+            link: 0,
+            info: 0,
+            align: section.align,
+            entsize: 0,
+        });
+    }
+
+    // Section symbols come first, so local labels can be relocated against them.
+    let mut strtab = StringTable::new();
+    let mut symtab = vec![0; SYM_SIZE as usize];
+    for i in 0..assembly.sections.len() {
+        push_symbol(&mut symtab, 0, STB_LOCAL, STT_SECTION, section_index(i) as u16, 0);
+    }
+    let section_symbols = assembly.sections.len() as u32 + 1;
+
+    // Locals have to precede globals, so symbols are reordered.
+    let mut order: Vec<usize> = (0..assembly.symbols.len()).collect();
+    order.sort_by_key(|&i| assembly.symbols[i].global);
+    let mut symbol_index = vec![0; assembly.symbols.len()];
+    let mut first_global = section_symbols + order.len() as u32;
+    for (n, &i) in order.iter().enumerate() {
+        let symbol = &assembly.symbols[i];
+        let index = section_symbols + n as u32;
+        symbol_index[i] = index;
+        if symbol.global {
+            first_global = first_global.min(index);
+        }
+
+        let bind = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+        let (ty, shndx, value) = match symbol.definition {
+            Some((section, offset)) => {
+                let ty = if assembly.sections[section].is_code() { STT_FUNC } else { STT_OBJECT };
+                (ty, section_index(section) as u16, offset)
+            }
+            None => (STT_NOTYPE, 0, 0),
+        };
+        let name = strtab.add(&symbol.name);
+        push_symbol(&mut symtab, name, bind, ty, shndx, value);
+    }
+
+    for (i, section) in assembly.sections.iter().enumerate() {
+        if section.relocs.is_empty() { continue };
+
+        let mut rela = Vec::new();
+        for reloc in &section.relocs {
+            let symbol = match reloc.target {
+                RelocTarget::Symbol(s) => symbol_index[s],
+                RelocTarget::Section(s) => section_index(s),
+            } as u64;
+            let ty = match reloc.kind {
+                RelocKind::Abs64 => R_X86_64_64,
+                RelocKind::Pc32 => R_X86_64_PC32,
+                RelocKind::Plt32 => R_X86_64_PLT32,
+            };
+            rela.extend(reloc.offset.to_le_bytes());
+            rela.extend((symbol << 32 | ty).to_le_bytes());
+            rela.extend(reloc.addend.to_le_bytes());
+        }
+
+        let offset = append_aligned(&mut out, &rela, 8);
+        headers.push(SectionHeader {
+            name: shstrtab.add(&format!(".rela{}", section.name)),
+            ty: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            offset,
+            size: rela.len() as u64,
+            link: symtab_index,
+            info: section_index(i),
+            align: 8,
+            entsize: RELA_SIZE,
+        });
+    }
+
+    let offset = append_aligned(&mut out, &symtab, 8);
+    headers.push(SectionHeader {
+        name: shstrtab.add(".symtab"),
+        ty: SHT_SYMTAB,
+        flags: 0,
+        offset,
+        size: symtab.len() as u64,
+        link: symtab_index + 1,
+        info: first_global,
+        align: 8,
+        entsize: SYM_SIZE,
+    });
+
+    let offset = append_aligned(&mut out, &strtab.bytes, 1);
+    headers.push(SectionHeader {
+        name: shstrtab.add(".strtab"),
+        ty: SHT_STRTAB,
+        flags: 0,
+        offset,
+        size: strtab.bytes.len() as u64,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+
+    let shstrtab_index = headers.len() as u16;
+    let name = shstrtab.add(".shstrtab");
+    let note_name = shstrtab.add(".note.GNU-stack");
+    let offset = append_aligned(&mut out, &shstrtab.bytes, 1);
+    headers.push(SectionHeader {
+        name,
+        ty: SHT_STRTAB,
+        flags: 0,
+        offset,
+        size: shstrtab.bytes.len() as u64,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+    headers.push(SectionHeader {
+        name: note_name,
+        ty: SHT_PROGBITS,
+        flags: 0,
+        offset,
+        size: 0,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+
+    let shoff = append_aligned(&mut out, &[], 8);
+    for header in &headers {
+        out.extend(header.name.to_le_bytes());
+        out.extend(header.ty.to_le_bytes());
+        out.extend(header.flags.to_le_bytes());
+        out.extend(0u64.to_le_bytes());
+        out.extend(header.offset.to_le_bytes());
+        out.extend(header.size.to_le_bytes());
+        out.extend(header.link.to_le_bytes());
+        out.extend(header.info.to_le_bytes());
+        out.extend(header.align.to_le_bytes());
+        out.extend(header.entsize.to_le_bytes());
+    }
+
+    let mut ehdr = Vec::with_capacity(EHDR_SIZE as usize);
+    // Magic, 64 bit, little endian, version 1, System V ABI.
+    ehdr.extend([0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    ehdr.extend([0; 8]);
+    ehdr.extend(1u16.to_le_bytes()); // ET_REL
+    ehdr.extend(62u16.to_le_bytes()); // EM_X86_64
+    ehdr.extend(1u32.to_le_bytes());
+    ehdr.extend(0u64.to_le_bytes()); // entry
+    ehdr.extend(0u64.to_le_bytes()); // program headers
+    ehdr.extend(shoff.to_le_bytes());
+    ehdr.extend(0u32.to_le_bytes()); // flags
+    ehdr.extend((EHDR_SIZE as u16).to_le_bytes());
+    ehdr.extend(0u16.to_le_bytes());
+    ehdr.extend(0u16.to_le_bytes());
+    ehdr.extend((SHDR_SIZE as u16).to_le_bytes());
+    ehdr.extend((headers.len() as u16).to_le_bytes());
+    ehdr.extend(shstrtab_index.to_le_bytes());
+    out[..EHDR_SIZE as usize].copy_from_slice(&ehdr);
+
+    out
+}
+
+/// Pads `out` to `align` and appends `data`, returning where it starts.
+fn append_aligned(out: &mut Vec<u8>, data: &[u8], align: u64) -> u64 {
+    let offset = (out.len() as u64).next_multiple_of(align.max(1));
+    out.resize(offset as usize, 0);
+    out.extend(data);
+    offset
+}
+fn push_symbol(symtab: &mut Vec<u8>, name: u32, bind: u8, ty: u8, shndx: u16, value: u64) {
+    symtab.extend(name.to_le_bytes());
+    symtab.push(bind << 4 | ty);
+    symtab.push(0); // default visibility
+    symtab.extend(shndx.to_le_bytes());
+    symtab.extend(value.to_le_bytes());
+    symtab.extend(0u64.to_le_bytes()); // size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{frontend::Parser, target::Target};
+
+    const SRC: &str = r#"
+g0 = internal constant [i8 * 7] "hello\n\0"
+g1 = export global [ptr * 2] answers [ fun_ptr answer, global_ptr g0 ]
+g2 = internal global i64
+import sysv fun puts i32( %0 ptr );
+internal fun answer i64( ) {
+  @0:
+    ret 42 i64
+}
+export sysv fun main i32( ) {
+  @0:
+    %0 ptr = g0
+    %1 i32 = call puts( %0 ptr )
+    %2 ptr = g1
+    %3 ptr = load %2 ptr
+    %4 i64 = call %3 ptr( )
+    %5 ptr = g2
+    store %5 ptr, %4 i64
+    %6 i64 = call answer( )
+    %7 i64 = load %5 ptr
+    %8 i64 = add %6 i64, %7 i64
+    %9 i32 = trunc %8 i64
+    ret %9 i32
+}
+"#;
+
+    fn object() -> Vec<u8> {
+        let module = Parser::new(SRC, Module::new(Target::LINUX_X64)).unwrap().parse().unwrap();
+        assert!(module.verify().is_empty(), "{:?}", module.verify());
+        let mut object = Vec::new();
+        ObjectGen::new(&module, &mut object).gen_object().unwrap();
+        object
+    }
+
+    /// Just enough of an ELF reader to check what `write_elf` wrote.
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        /// Name, type, flags, offset, size, link and info of every section.
+        sections: Vec<(String, u32, u64, usize, usize, u32, u32)>,
+    }
+    impl<'a> Reader<'a> {
+        fn new(bytes: &'a [u8]) -> Self {
+            let mut reader = Self { bytes, sections: Vec::new() };
+            assert_eq!(bytes[..4], [0x7F, b'E', b'L', b'F']);
+            let shoff = reader.u64(0x28) as usize;
+            let count = reader.u16(0x3C) as usize;
+            let shstrndx = reader.u16(0x3E) as usize;
+            let header = |i: usize| shoff + i * SHDR_SIZE as usize;
+            let names = reader.u64(header(shstrndx) + 24) as usize;
+            for i in 0..count {
+                let h = header(i);
+                let section = (
+                    reader.str(names + reader.u32(h) as usize),
+                    reader.u32(h + 4),
+                    reader.u64(h + 8),
+                    reader.u64(h + 24) as usize,
+                    reader.u64(h + 32) as usize,
+                    reader.u32(h + 40),
+                    reader.u32(h + 44),
+                );
+                reader.sections.push(section);
+            }
+            reader
+        }
+        fn u16(&self, at: usize) -> u16 {
+            u16::from_le_bytes(self.bytes[at..at + 2].try_into().unwrap())
+        }
+        fn u32(&self, at: usize) -> u32 {
+            u32::from_le_bytes(self.bytes[at..at + 4].try_into().unwrap())
+        }
+        fn u64(&self, at: usize) -> u64 {
+            u64::from_le_bytes(self.bytes[at..at + 8].try_into().unwrap())
+        }
+        fn str(&self, at: usize) -> String {
+            let len = self.bytes[at..].iter().position(|&b| b == 0).unwrap();
+            String::from_utf8(self.bytes[at..at + len].to_vec()).unwrap()
+        }
+        fn section(&self, name: &str) -> usize {
+            self.sections.iter().position(|s| s.0 == name).unwrap()
+        }
+
+        /// Name, binding, type and section name of every symbol.
+        /// Section symbols are named after their section.
+        fn symbols(&self) -> Vec<(String, u8, u8, String)> {
+            let (_, _, _, offset, size, link, _) = self.sections[self.section(".symtab")];
+            let names = self.sections[link as usize].3;
+            (offset..offset + size).step_by(SYM_SIZE as usize).map(|s| {
+                let info = self.bytes[s + 4];
+                let section = &self.sections[self.u16(s + 6) as usize].0;
+                let name = if info & 0xF == STT_SECTION { section.clone() } else { self.str(names + self.u32(s) as usize) };
+                (name, info >> 4, info & 0xF, section.clone())
+            }).collect()
+        }
+        /// Offset, type, symbol name and addend of every relocation of the section.
+        fn relocs(&self, section: &str) -> Vec<(u64, u64, String, i64)> {
+            let (_, ty, _, offset, size, link, info) = self.sections[self.section(&format!(".rela{section}"))];
+            assert_eq!((ty, link as usize, info as usize), (SHT_RELA, self.section(".symtab"), self.section(section)));
+            let symbols = self.symbols();
+            (offset..offset + size).step_by(RELA_SIZE as usize).map(|r| {
+                let info = self.u64(r + 8);
+                let symbol = symbols[(info >> 32) as usize].0.clone();
+                (self.u64(r), info & 0xFFFF_FFFF, symbol, self.u64(r + 16) as i64)
+            }).collect()
+        }
+    }
+
+    #[test]
+    fn writes_sections_symbols_and_relocations() {
+        let object = object();
+        let elf = Reader::new(&object);
+
+        let sections: Vec<_> = elf.sections.iter().map(|s| (s.0.as_str(), s.1, s.2)).collect();
+        let expected = [
+            ("", 0, 0),
+            (".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR),
+            (".data", SHT_PROGBITS, SHF_ALLOC | SHF_WRITE),
+            (".rodata", SHT_PROGBITS, SHF_ALLOC),
+            (".bss", SHT_NOBITS, SHF_ALLOC | SHF_WRITE),
+            (".rela.text", SHT_RELA, SHF_INFO_LINK),
+            (".rela.data", SHT_RELA, SHF_INFO_LINK),
+            (".symtab", SHT_SYMTAB, 0),
+            (".strtab", SHT_STRTAB, 0),
+            (".shstrtab", SHT_STRTAB, 0),
+            (".note.GNU-stack", SHT_PROGBITS, 0),
+        ];
+        assert_eq!(sections, expected);
+        let rodata = elf.sections[elf.section(".rodata")].3;
+        assert_eq!(object[rodata..rodata + 7], *b"hello\n\0");
+
+        // Internal globals and functions stay local, and locals come first.
+        let symbols = elf.symbols();
+        let symbols: Vec<_> = symbols.iter().map(|(n, b, t, s)| (n.as_str(), *b, *t, s.as_str())).collect();
+        let expected = [
+            ("", STB_LOCAL, STT_NOTYPE, ""),
+            (".text", STB_LOCAL, STT_SECTION, ".text"),
+            (".data", STB_LOCAL, STT_SECTION, ".data"),
+            (".rodata", STB_LOCAL, STT_SECTION, ".rodata"),
+            (".bss", STB_LOCAL, STT_SECTION, ".bss"),
+            ("_CLEint1", STB_LOCAL, STT_OBJECT, ".rodata"),
+            ("_CLEint2", STB_LOCAL, STT_OBJECT, ".bss"),
+            ("_CLEint0", STB_LOCAL, STT_FUNC, ".text"),
+            ("answers", STB_GLOBAL, STT_OBJECT, ".data"),
+            ("main", STB_GLOBAL, STT_FUNC, ".text"),
+            ("puts", STB_GLOBAL, STT_NOTYPE, ""),
+        ];
+        assert_eq!(symbols, expected);
+        assert_eq!(elf.sections[elf.section(".symtab")].6, 8);
+
+        // Calls within .text are resolved by the assembler.
+        let text: Vec<_> = elf.relocs(".text").into_iter().map(|(_, ty, symbol, addend)| (ty, symbol, addend)).collect();
+        let expected = [
+            (R_X86_64_PC32, "_CLEint1".to_string(), -4),
+            (R_X86_64_PLT32, "puts".to_string(), -4),
+            (R_X86_64_PC32, "answers".to_string(), -4),
+            (R_X86_64_PC32, "_CLEint2".to_string(), -4),
+        ];
+        assert_eq!(text, expected);
+        let data = [(0, R_X86_64_64, "_CLEint0".to_string(), 0), (8, R_X86_64_64, "_CLEint1".to_string(), 0)];
+        assert_eq!(elf.relocs(".data"), data);
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn links_with_cc() {
+        use std::{fs, process::Command};
+
+        if Command::new("cc").arg("--version").output().is_err() {
+            return;
+        };
+        let dir = std::env::temp_dir().join(format!("cir-elf-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.o");
+        fs::write(&path, object()).unwrap();
+
+        for pie in ["-no-pie", "-pie"] {
+            let exe = dir.join(format!("main{pie}"));
+            let link = Command::new("cc").arg(pie).arg("-o").arg(&exe).arg(&path).output().unwrap();
+            assert!(link.status.success(), "{pie}: {}", String::from_utf8_lossy(&link.stderr));
+            let run = Command::new(&exe).output().unwrap();
+            assert_eq!(run.stdout, b"hello\n\n", "{pie}");
+            assert_eq!(run.status.code(), Some(84), "{pie}");
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,473 @@
+use std::{collections::{HashMap, HashSet}, ffi::{CString, c_char, c_int, c_void}, io, ptr};
+
+use crate::{frontend::{CallConvention, FunID, GlobalID, IntTy, Linkage, Module, Ty, Value}, layout::TyLayout};
+use super::{CodeGen, RegAllocMode, assembler::{self, Assembly, RelocKind, RelocTarget}, staging_layout};
+
+unsafe extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    fn sysconf(name: c_int) -> i64;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+}
+
+const PROT_READ: c_int = 0x1;
+const PROT_WRITE: c_int = 0x2;
+const PROT_EXEC: c_int = 0x4;
+const MAP_PRIVATE: c_int = 0x02;
+const MAP_ANONYMOUS: c_int = 0x20;
+const SC_PAGESIZE: c_int = 30;
+/// Searches every object loaded into the process.
+const RTLD_DEFAULT: *mut c_void = ptr::null_mut();
+
+const TRAMPOLINE_LABEL: &str = "_CLEjit_trampoline";
+/// Called as `extern "C" fn(fun, staging, size)`. Copies the staging area onto the stack,
+/// calls the `Simple` function and copies the area back.
+const TRAMPOLINE: &str = "
+section .text
+_CLEjit_trampoline:
+    push rbp
+    mov rbp, rsp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    sub rsp, 8
+    mov r12, rsi
+    mov r13, rdx
+    mov r14, rdi
+    sub rsp, rdx
+    mov rdi, rsp
+    mov rcx, rdx
+    rep movsb
+    call r14
+    mov rsi, rsp
+    mov rdi, r12
+    mov rcx, r13
+    rep movsb
+    add rsp, r13
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+";
+type Trampoline = unsafe extern "C" fn(*const u8, *mut u8, usize);
+
+/// An indirect jump through the address stored right behind it,
+/// for calls to functions too far away for a 32 bit displacement.
+const STUB_SIZE: usize = 16;
+const STUB_JUMP: [u8; 8] = [0xFF, 0x25, 0x02, 0x00, 0x00, 0x00, 0xCC, 0xCC];
+
+/// Compiles a module into executable memory of the running process.
+pub struct JitGen<'a> {
+    module: &'a Module,
+    reg_alloc: RegAllocMode,
+    symbols: HashMap<String, usize>,
+}
+impl<'a> JitGen<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        Self {
+            module,
+            reg_alloc: RegAllocMode::LinearScan,
+            symbols: HashMap::new(),
+        }
+    }
+
+    pub fn with_reg_alloc(mut self, mode: RegAllocMode) -> Self {
+        self.reg_alloc = mode;
+        self
+    }
+    /// Resolves the external symbol `name` to `addr`.
+    /// Symbols without an address are looked up in the process.
+    pub fn with_symbol(mut self, name: impl Into<String>, addr: *const u8) -> Self {
+        self.symbols.insert(name.into(), addr as usize);
+        self
+    }
+
+    pub fn gen_jit(self) -> io::Result<Jit> {
+        let mut src = Vec::new();
+        let labels = CodeGen::new(self.module, &mut src).with_reg_alloc(self.reg_alloc).gen_labelled_code()?;
+        src.extend(TRAMPOLINE.as_bytes());
+        let src = String::from_utf8(src).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let assembly = assembler::assemble(&src)?;
+
+        // Imported globals can't be reached through a stub.
+        let data: HashSet<_> = self.module.globals()
+            .iter()
+            .filter(|g| g.linkage == Linkage::Import)
+            .map(|g| labels.globals[&g.id].as_str())
+            .collect();
+        let image = self.load(&assembly, &data)?;
+
+        let address = |label: &str| {
+            let symbol = assembly.symbols.iter().position(|s| s.name == label).unwrap();
+            image.symbols[symbol]
+        };
+        let functions = self.module.functions()
+            .iter()
+            .map(|f| {
+                let params: Vec<_> = f.parameters.iter().map(|&p| self.module[p].ty).collect();
+                let (staging, offsets) = staging_layout(self.module, f.ret_ty, &params);
+                let fun = JitFun {
+                    addr: address(&labels.functions[&f.id]),
+                    call_convention: f.call_convention,
+                    ret_ty: f.ret_ty,
+                    params,
+                    staging,
+                    offsets,
+                };
+                (f.id, fun)
+            })
+            .collect();
+        let globals = labels.globals.iter().map(|(&gid, label)| (gid, address(label))).collect();
+        let trampoline = address(TRAMPOLINE_LABEL);
+
+        Ok(Jit {
+            _mapping: image.mapping,
+            functions,
+            globals,
+            trampoline,
+        })
+    }
+
+    /// Maps every section to its own pages, followed by a stub slot for every symbol,
+    /// and applies all relocations. Only the slots of external symbols are filled in.
+    fn load(&self, assembly: &Assembly, data: &HashSet<&str>) -> io::Result<Image> {
+        let page = unsafe { sysconf(SC_PAGESIZE) } as usize;
+
+        let mut offsets = Vec::new();
+        let mut len = 0;
+        for section in &assembly.sections {
+            offsets.push(len);
+            len = (len + section.size as usize).next_multiple_of(page);
+        }
+        let stubs = len;
+        len += (assembly.symbols.len() * STUB_SIZE).next_multiple_of(page);
+
+        let mapping = Mapping::new(len.max(page))?;
+        let base = mapping.ptr as usize;
+        let memory = unsafe { std::slice::from_raw_parts_mut(mapping.ptr, mapping.len) };
+
+        let mut symbols = Vec::new();
+        for (i, symbol) in assembly.symbols.iter().enumerate() {
+            let Some((section, offset)) = symbol.definition else {
+                let addr = self.resolve(&symbol.name)?;
+                symbols.push(addr);
+
+                let stub = stubs + i * STUB_SIZE;
+                memory[stub..stub + 8].copy_from_slice(&STUB_JUMP);
+                memory[stub + 8..stub + 16].copy_from_slice(&(addr as u64).to_le_bytes());
+                continue;
+            };
+            symbols.push(base + offsets[section] + offset as usize);
+        }
+
+        for (section, &offset) in assembly.sections.iter().zip(&offsets) {
+            memory[offset..offset + section.data.len()].copy_from_slice(&section.data);
+        }
+
+        for (section, &offset) in assembly.sections.iter().zip(&offsets) {
+            for reloc in &section.relocs {
+                let place = offset + reloc.offset as usize;
+                let target = match reloc.target {
+                    RelocTarget::Section(s) => base + offsets[s],
+                    RelocTarget::Symbol(s) => symbols[s],
+                };
+
+                if reloc.kind == RelocKind::Abs64 {
+                    let value = (target as i64).wrapping_add(reloc.addend);
+                    memory[place..place + 8].copy_from_slice(&value.to_le_bytes());
+                    continue;
+                }
+
+                let distance = |target: usize| target as i64 + reloc.addend - (base + place) as i64;
+                let mut value = distance(target);
+                if i32::try_from(value).is_err() && let RelocTarget::Symbol(s) = reloc.target {
+                    let symbol = &assembly.symbols[s];
+                    if symbol.definition.is_some() || data.contains(symbol.name.as_str()) {
+                        return Err(io::Error::other(format!("{} is out of reach of the compiled code", symbol.name)));
+                    }
+                    value = distance(base + stubs + s * STUB_SIZE);
+                }
+                let value = i32::try_from(value).map_err(|_| io::Error::other("relocation out of reach"))?;
+                memory[place..place + 4].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        for (section, &offset) in assembly.sections.iter().zip(&offsets) {
+            let prot = if section.is_code() {
+                PROT_READ | PROT_EXEC
+            }
+            else if section.is_writable() {
+                PROT_READ | PROT_WRITE
+            }
+            else {
+                PROT_READ
+            };
+            mapping.protect(offset, section.size as usize, prot)?;
+        }
+        mapping.protect(stubs, len - stubs, PROT_READ | PROT_EXEC)?;
+
+        Ok(Image { mapping, symbols })
+    }
+    fn resolve(&self, name: &str) -> io::Result<usize> {
+        if let Some(&addr) = self.symbols.get(name) {
+            return Ok(addr);
+        }
+
+        let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let addr = unsafe { dlsym(RTLD_DEFAULT, c_name.as_ptr()) };
+        if addr.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("undefined symbol {name}")));
+        }
+        Ok(addr as usize)
+    }
+}
+
+struct Image {
+    mapping: Mapping,
+    /// The address of every symbol of the assembly.
+    symbols: Vec<usize>,
+}
+
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+impl Mapping {
+    fn new(len: usize) -> io::Result<Self> {
+        let ptr = unsafe { mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+        // MAP_FAILED is all ones.
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr: ptr as *mut u8, len })
+    }
+    fn protect(&self, offset: usize, len: usize, prot: c_int) -> io::Result<()> {
+        if len == 0 { return Ok(()) };
+
+        let result = unsafe { mprotect(self.ptr.add(offset) as *mut c_void, len, prot) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr as *mut c_void, self.len) };
+    }
+}
+
+struct JitFun {
+    addr: usize,
+    call_convention: CallConvention,
+    ret_ty: Ty,
+    params: Vec<Ty>,
+    staging: TyLayout,
+    offsets: Vec<i64>,
+}
+
+/// A module loaded into executable memory. The code is unmapped when this is dropped.
+pub struct Jit {
+    /// Only held to keep the code mapped.
+    _mapping: Mapping,
+    functions: HashMap<FunID, JitFun>,
+    globals: HashMap<GlobalID, usize>,
+    trampoline: usize,
+}
+impl Jit {
+    /// The address of the function's code.
+    /// `SysV` functions can be transmuted into the matching `extern "C" fn`.
+    pub fn fun_ptr(&self, fid: FunID) -> *const u8 {
+        self.functions[&fid].addr as *const u8
+    }
+    pub fn global_ptr(&self, gid: GlobalID) -> *mut u8 {
+        self.globals[&gid] as *mut u8
+    }
+    /// The layout of the staging area of a `Simple` function,
+    /// with the offset of every parameter. The return value lives at offset 0.
+    pub fn staging_layout(&self, fid: FunID) -> (TyLayout, &[i64]) {
+        let fun = &self.functions[&fid];
+        (fun.staging, &fun.offsets)
+    }
+
+    /// Calls a `Simple` function with the parameters already in `staging`,
+    /// which receives the return value.
+    ///
+    /// # Safety
+    /// The staging area has to hold valid values for every parameter,
+    /// and whatever the function does has to be sound.
+    pub unsafe fn call_staged(&self, fid: FunID, staging: &mut [u8]) {
+        let fun = &self.functions[&fid];
+        assert_eq!(fun.call_convention, CallConvention::Simple, "{fid:?} isn't a Simple function");
+        let size = fun.staging.size() as usize;
+        assert!(staging.len() >= size, "the staging area needs {size} bytes");
+
+        let trampoline: Trampoline = unsafe { std::mem::transmute(self.trampoline) };
+        unsafe { trampoline(fun.addr as *const u8, staging.as_mut_ptr(), size) };
+    }
+    /// Calls a `Simple` function whose parameters and return value are integers or booleans.
+    /// Integers are returned sign extended.
+    ///
+    /// # Safety
+    /// Whatever the function does has to be sound.
+    pub unsafe fn call(&self, fid: FunID, args: &[Value]) -> Value {
+        let fun = &self.functions[&fid];
+        assert_eq!(args.len(), fun.params.len(), "{fid:?} takes {} parameters", fun.params.len());
+
+        let mut staging = vec![0; fun.staging.size() as usize];
+        for ((&arg, &ty), &offset) in args.iter().zip(&fun.params).zip(&fun.offsets) {
+            let offset = offset as usize;
+            match (arg, ty) {
+                (Value::Bool(value), Ty::Bool) => staging[offset] = value as u8,
+                (Value::Int(int_ty, value), Ty::Int(ty)) if int_ty == ty => {
+                    let size = int_size(int_ty);
+                    staging[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+                }
+                _ => panic!("{arg:?} can't be passed as {ty:?}"),
+            }
+        }
+
+        unsafe { self.call_staged(fid, &mut staging) };
+
+        match fun.ret_ty {
+            Ty::Void => Value::Void,
+            Ty::Bool => Value::Bool(staging[0] != 0),
+            Ty::Int(ty) => {
+                let size = int_size(ty);
+                let mut bytes = [0; 8];
+                bytes[..size].copy_from_slice(&staging[..size]);
+                let shift = 64 - 8 * size as u32;
+                Value::Int(ty, i64::from_le_bytes(bytes) << shift >> shift)
+            }
+            ty => panic!("{ty:?} can't be returned as a value"),
+        }
+    }
+}
+
+fn int_size(ty: IntTy) -> usize {
+    match ty {
+        IntTy::I8 => 1,
+        IntTy::I16 => 2,
+        IntTy::I32 => 4,
+        IntTy::I64 => 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::transmute;
+
+    use super::*;
+    use crate::{frontend::Parser, target::Target};
+
+    const SRC: &str = r#"
+g0 = export global i64 counter 40
+g1 = internal constant [ptr * 2] [ fun_ptr double, fun_ptr triple ]
+import sysv fun labs i64( %0 i64 );
+import sysv fun host_add i64( %0 i64, %1 i64 );
+internal fun double i64( %0 i64 ) {
+  @0:
+    %1 i64 = add %0 i64, %0 i64
+    ret %1 i64
+}
+internal fun triple i64( %0 i64 ) {
+  @0:
+    %1 i64 = mul %0 i64, 3 i64
+    ret %1 i64
+}
+export fun entry i64( %0 i64, %1 i8 ) {
+  @0:
+    %2 ptr = g0
+    %3 i64 = load %2 ptr
+    %4 i64 = add %3 i64, 2 i64
+    store %2 ptr, %4 i64
+    %5 i64 = call double( %0 i64 )
+    %6 i64 = call labs( %5 i64 )
+    %7 ptr = g1
+    %8 ptr = index_array ptr %7 ptr, 1 i64
+    %9 ptr = load %8 ptr
+    %10 i64 = call %9 ptr( %6 i64 )
+    %11 i64 = sext %1 i8
+    %12 i64 = call host_add( %10 i64, %11 i64 )
+    ret %12 i64
+}
+export fun negate i8( %0 i8 ) {
+  @0:
+    %1 i8 = neg %0 i8
+    ret %1 i8
+}
+export fun is_below bool( %0 i32, %1 i32 ) {
+  @0:
+    %2 bool = below %0 i32, %1 i32
+    ret %2 bool
+}
+export sysv fun scale i64( %0 i64, %1 i32 ) {
+  @0:
+    %2 ptr = g0
+    %3 i64 = load %2 ptr
+    %4 i64 = sext %1 i32
+    %5 i64 = mul %0 i64, %4 i64
+    %6 i64 = add %5 i64, %3 i64
+    ret %6 i64
+}
+"#;
+
+    extern "C" fn host_add(a: i64, b: i64) -> i64 {
+        a * 1000 + b
+    }
+
+    fn module() -> Module {
+        let module = Parser::new(SRC, Module::new(Target::LINUX_X64)).unwrap().parse().unwrap();
+        assert!(module.verify().is_empty(), "{:?}", module.verify());
+        module
+    }
+    fn fun(module: &Module, name: &str) -> FunID {
+        module.functions().iter().find(|f| f.name == name).unwrap().id
+    }
+
+    #[test]
+    fn calls_simple_and_sysv_functions() {
+        let module = module();
+        let counter = module.globals().iter().find(|g| g.name.as_deref() == Some("counter")).unwrap().id;
+        for mode in [RegAllocMode::LinearScan, RegAllocMode::StackSlots] {
+            // `labs` isn't given, so it comes from the libc the tests are linked against.
+            let jit = JitGen::new(&module)
+                .with_reg_alloc(mode)
+                .with_symbol("host_add", host_add as *const u8)
+                .gen_jit()
+                .unwrap();
+
+            let counter = jit.global_ptr(counter) as *mut i64;
+            assert_eq!(unsafe { *counter }, 40);
+            let args = [Value::Int(IntTy::I64, -7), Value::Int(IntTy::I8, -5)];
+            assert_eq!(unsafe { jit.call(fun(&module, "entry"), &args) }, Value::Int(IntTy::I64, 42 * 1000 - 5));
+            assert_eq!(unsafe { *counter }, 42);
+
+            let negate = fun(&module, "negate");
+            assert_eq!(unsafe { jit.call(negate, &[Value::Int(IntTy::I8, 100)]) }, Value::Int(IntTy::I8, -100));
+            assert_eq!(unsafe { jit.call(negate, &[Value::Int(IntTy::I8, -128)]) }, Value::Int(IntTy::I8, -128));
+            let args = [Value::Int(IntTy::I32, 1), Value::Int(IntTy::I32, -1)];
+            assert_eq!(unsafe { jit.call(fun(&module, "is_below"), &args) }, Value::Bool(true));
+
+            let scale: extern "C" fn(i64, i32) -> i64 = unsafe { transmute(jit.fun_ptr(fun(&module, "scale"))) };
+            assert_eq!(scale(6, -7), -42 + 42);
+            unsafe { *counter = -1 };
+            assert_eq!(scale(1 << 40, 2), (1 << 41) - 1);
+        }
+    }
+
+    #[test]
+    fn reports_undefined_symbols() {
+        let module = module();
+        let Err(error) = JitGen::new(&module).gen_jit() else { panic!("host_add was resolved") };
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(error.to_string(), "undefined symbol host_add");
+    }
+}
@@ -0,0 +1,154 @@
+use crate::frontend::{RegID, Value};
+
+/// One step of a sequentialized parallel copy.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum CopyStep {
+    Move(RegID, Value),
+    /// Copies the register into the temporary.
+    Save(RegID),
+    /// Copies the temporary into the register.
+    Restore(RegID),
+}
+
+/// Orders the copies `dst <- src`, which all have to appear to happen at once,
+/// so that no register is overwritten before it was read.
+/// Every cycle is broken by moving one value through a single temporary.
+///
+/// Every destination must be unique.
+pub(super) fn sequence(copies: &[(RegID, Value)]) -> Vec<CopyStep> {
+    #[derive(Copy, Clone, PartialEq)]
+    enum Source {
+        Value(Value),
+        Temp,
+    }
+
+    let mut pending: Vec<(RegID, Source)> = copies
+        .iter()
+        .filter(|&&(dst, src)| src != Value::Reg(dst))
+        .map(|&(dst, src)| (dst, Source::Value(src)))
+        .collect();
+    let mut steps = Vec::with_capacity(pending.len());
+
+    while !pending.is_empty() {
+        let is_read = |reg: RegID, pending: &[(RegID, Source)]| {
+            pending.iter().any(|&(_, src)| src == Source::Value(Value::Reg(reg)))
+        };
+
+        let ready = pending.iter().position(|&(dst, _)| !is_read(dst, &pending));
+        if let Some(i) = ready {
+            let (dst, src) = pending.remove(i);
+            steps.push(match src {
+                Source::Value(value) => CopyStep::Move(dst, value),
+                Source::Temp => CopyStep::Restore(dst),
+            });
+            continue;
+        }
+
+        // Every remaining destination is still read by another copy,
+        // so they all lie on cycles. Freeing one destination unrolls its cycle.
+        let (dst, _) = pending[0];
+        steps.push(CopyStep::Save(dst));
+        for (_, src) in &mut pending {
+            if *src == Source::Value(Value::Reg(dst)) {
+                *src = Source::Temp;
+            }
+        }
+    }
+
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::frontend::IntTy;
+
+    use super::*;
+
+    /// Runs the steps on registers holding their own index
+    /// and returns what every register ends up with.
+    fn run(copies: &[(RegID, Value)]) -> HashMap<RegID, i64> {
+        let mut regs: HashMap<RegID, i64> = (0..8).map(|i| (RegID(i), i as i64)).collect();
+        let mut temp = None;
+        for step in sequence(copies) {
+            match step {
+                CopyStep::Move(dst, Value::Reg(src)) => {
+                    let value = regs[&src];
+                    regs.insert(dst, value);
+                }
+                CopyStep::Move(dst, Value::Int(_, value)) => {
+                    regs.insert(dst, value);
+                }
+                CopyStep::Move(..) => unreachable!(),
+                CopyStep::Save(src) => {
+                    assert!(temp.is_none(), "the temporary is still in use");
+                    temp = Some(regs[&src]);
+                }
+                CopyStep::Restore(dst) => {
+                    regs.insert(dst, temp.take().unwrap());
+                }
+            }
+        }
+        assert!(temp.is_none(), "the temporary was never restored");
+
+        regs
+    }
+    fn reg(i: usize) -> RegID {
+        RegID(i)
+    }
+    fn check(copies: &[(RegID, Value)]) {
+        let regs = run(copies);
+        for &(dst, src) in copies {
+            let expected = match src {
+                Value::Reg(src) => src.0 as i64,
+                Value::Int(_, value) => value,
+                _ => unreachable!(),
+            };
+            assert_eq!(regs[&dst], expected, "{copies:?}");
+        }
+    }
+
+    #[test]
+    fn swap() {
+        check(&[(reg(0), reg(1).into()), (reg(1), reg(0).into())]);
+    }
+
+    #[test]
+    fn rotation() {
+        check(&[
+            (reg(0), reg(1).into()),
+            (reg(1), reg(2).into()),
+            (reg(2), reg(3).into()),
+            (reg(3), reg(0).into()),
+        ]);
+    }
+
+    #[test]
+    fn chain_into_cycle() {
+        check(&[
+            (reg(0), reg(1).into()),
+            (reg(1), reg(0).into()),
+            (reg(2), reg(1).into()),
+            (reg(3), reg(2).into()),
+        ]);
+    }
+
+    #[test]
+    fn two_cycles_and_constants() {
+        check(&[
+            (reg(0), reg(1).into()),
+            (reg(1), reg(0).into()),
+            (reg(2), reg(4).into()),
+            (reg(3), reg(2).into()),
+            (reg(4), reg(3).into()),
+            (reg(5), Value::Int(IntTy::I64, 42)),
+            (reg(6), reg(6).into()),
+        ]);
+    }
+
+    #[test]
+    fn fan_out() {
+        check(&[(reg(1), reg(0).into()), (reg(2), reg(0).into()), (reg(0), reg(2).into())]);
+    }
+}
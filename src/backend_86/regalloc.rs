@@ -0,0 +1,148 @@
+use std::collections::{HashMap, HashSet};
+
+use gen86::gp_regs::*;
+use crate::frontend::{FunID, Instruction, Liveness, Module, RegID, Ty};
+
+/// How `CodeGen` decides where registers live.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RegAllocMode {
+    /// Scalars are kept in general purpose registers and
+    /// only spilled to the stack under pressure.
+    LinearScan,
+    /// Every register gets its own stack slot.
+    /// Slow, but simple enough to debug the rest of the backend with.
+    StackSlots,
+}
+
+/// Caller saved registers come first so short lived values
+/// don't make the prologue save anything.
+const ALLOCATABLE: [Reg; 10] = [R10, R11, RSI, RDI, R8, R9, R12, R13, R14, R15];
+pub(super) const CALLEE_SAVED: [Reg; 4] = [R12, R13, R14, R15];
+/// Written while setting up calls and system calls and read on entry to SysV functions.
+const ARGUMENT: [Reg; 5] = [RDI, RSI, R8, R9, R10];
+
+/// Where the linear scan put the function's scalars.
+/// Registers without an entry need a stack slot.
+pub(super) struct Allocation {
+    pub regs: HashMap<RegID, Reg>,
+    pub callee_saved: Vec<Reg>,
+}
+
+struct Interval {
+    reg: RegID,
+    start: usize,
+    end: usize,
+    crosses_call: bool,
+    avoid_arguments: bool,
+}
+
+/// Allocates every scalar register not in `fixed` using the blocks in ID order,
+/// which has to match the order in which they are emitted.
+pub(super) fn linear_scan(module: &Module, fid: FunID, fixed: &HashSet<RegID>) -> Allocation {
+    let fun = &module[fid];
+    let liveness = Liveness::new(module, fid);
+    let mut blocks: Vec<_> = fun.blocks.iter().copied().collect();
+    blocks.sort_by_key(|b| b.0);
+
+    // Position 0 is the function entry, every block starts with
+    // the definition of its parameters.
+    let mut ranges: HashMap<RegID, (usize, usize)> = HashMap::new();
+    let mut extend = |reg: RegID, pos: usize| {
+        let range = ranges.entry(reg).or_insert((pos, pos));
+        range.0 = range.0.min(pos);
+        range.1 = range.1.max(pos);
+    };
+    for &param in &fun.parameters {
+        extend(param, 0);
+    }
+
+    let mut calls = Vec::new();
+    let mut pos = 1;
+    for &bid in &blocks {
+        let block = &module[bid];
+        let start = pos;
+        let end = pos + block.instructions.len();
+
+        for &reg in &liveness.live_in[&bid] {
+            extend(reg, start);
+        }
+        for &param in &block.parameters {
+            extend(param, start);
+        }
+        for (i, instr) in block.instructions.iter().enumerate() {
+            let pos = start + 1 + i;
+            for reg in instr.uses() {
+                extend(reg, pos);
+            }
+            if let Some(dst) = instr.dst() {
+                extend(dst, pos);
+            }
+            if matches!(instr, Instruction::Call(..) | Instruction::CallPtr(..) | Instruction::SyscallLinux64 { .. }) {
+                calls.push(pos);
+            }
+        }
+        for &reg in &liveness.live_out[&bid] {
+            extend(reg, end);
+        }
+        // Parameters are written by the jumps into their block.
+        for succ in block.successors() {
+            for &param in &module[succ].parameters {
+                extend(param, end);
+            }
+        }
+
+        pos = end + 1;
+    }
+
+    let mut intervals: Vec<_> = ranges
+        .into_iter()
+        .filter(|(reg, _)| !fixed.contains(reg))
+        .filter(|&(reg, _)| matches!(module[reg].ty, Ty::Bool | Ty::Int(_) | Ty::Ptr))
+        .map(|(reg, (start, end))| Interval {
+            reg,
+            start,
+            end,
+            crosses_call: calls.iter().any(|&c| start < c && c < end),
+            avoid_arguments: start == 0 || calls.iter().any(|&c| start <= c && c <= end),
+        })
+        .collect();
+    intervals.sort_by_key(|i| (i.start, i.reg.0));
+
+    let mut regs = HashMap::new();
+    let mut active: Vec<(usize, Reg)> = Vec::new();
+    for (i, interval) in intervals.iter().enumerate() {
+        active.retain(|&(a, _)| intervals[a].end >= interval.start);
+
+        let allowed = |reg: &Reg| {
+            (!interval.crosses_call || CALLEE_SAVED.contains(reg))
+                && (!interval.avoid_arguments || !ARGUMENT.contains(reg))
+        };
+        let free = ALLOCATABLE
+            .iter()
+            .filter(|r| allowed(r))
+            .find(|r| active.iter().all(|(_, a)| a != *r));
+
+        if let Some(&reg) = free {
+            regs.insert(interval.reg, reg);
+            active.push((i, reg));
+            continue;
+        }
+
+        // Spill whichever interval ends last, so the register is free for longest.
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, r))| allowed(r))
+            .max_by_key(|(_, (a, _))| intervals[*a].end);
+        if let Some((slot, &(a, reg))) = victim && intervals[a].end > interval.end {
+            regs.remove(&intervals[a].reg);
+            regs.insert(interval.reg, reg);
+            active[slot] = (i, reg);
+        }
+    }
+
+    let used: HashSet<_> = regs.values().copied().collect();
+    let callee_saved = CALLEE_SAVED.into_iter().filter(|r| used.contains(r)).collect();
+
+    Allocation { regs, callee_saved }
+}
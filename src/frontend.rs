@@ -16,15 +16,27 @@ pub(crate) mod builder;
 
 pub(crate) mod printer;
 
+pub(crate) mod parser;
+
 pub(crate) mod global;
 
+pub(crate) mod linkage;
+
+pub(crate) mod verify;
+
+pub(crate) mod analysis;
+
 pub use block::*;
 pub use builder::*;
 pub use function::*;
 pub use instruction::*;
 pub use module::*;
+pub use parser::*;
 pub use printer::*;
 pub use register::*;
 pub use types::*;
 pub use variable::*;
 pub use global::*;
+pub use linkage::*;
+pub use verify::*;
+pub use analysis::*;
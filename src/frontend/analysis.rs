@@ -0,0 +1,480 @@
+use std::{
+    any::{Any, TypeId},
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use super::*;
+
+/// Facts about a function that are derived from its code alone.
+pub trait Analysis: Any {
+    /// May ask `analyses` for the analyses this one builds on.
+    fn compute(module: &Module, fun: FunID, analyses: &mut Analyses) -> Self;
+}
+
+/// Caches analyses of functions until they change.
+///
+/// Results are keyed by `Module::revision`, so a stale result is never handed out,
+/// even if the function was changed behind the cache's back.
+#[derive(Default)]
+pub struct Analyses {
+    results: HashMap<(TypeId, FunID), (u64, Rc<dyn Any>)>,
+}
+impl Analyses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get<A: Analysis>(&mut self, module: &Module, fun: FunID) -> Rc<A> {
+        let key = (TypeId::of::<A>(), fun);
+        let revision = module.revision(fun);
+        if let Some((cached, result)) = self.results.get(&key)
+            && *cached == revision
+        {
+            return result.clone().downcast().unwrap();
+        }
+
+        let result = Rc::new(A::compute(module, fun, self));
+        self.results.insert(key, (revision, result.clone()));
+        result
+    }
+    pub fn cfg(&mut self, module: &Module, fun: FunID) -> Rc<Cfg> {
+        self.get(module, fun)
+    }
+    pub fn dom_tree(&mut self, module: &Module, fun: FunID) -> Rc<DomTree> {
+        self.get(module, fun)
+    }
+    pub fn liveness(&mut self, module: &Module, fun: FunID) -> Rc<Liveness> {
+        self.get(module, fun)
+    }
+
+    /// Drops everything known about `fun`.
+    pub fn invalidate(&mut self, fun: FunID) {
+        self.results.retain(|&(_, f), _| f != fun);
+    }
+    pub fn clear(&mut self) {
+        self.results.clear();
+    }
+}
+
+/// The control flow graph of a function, following the targets of its terminators.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cfg {
+    entry: Option<BlockID>,
+    successors: HashMap<BlockID, Vec<BlockID>>,
+    predecessors: HashMap<BlockID, Vec<BlockID>>,
+    reverse_postorder: Vec<BlockID>,
+    rpo_index: HashMap<BlockID, usize>,
+}
+impl Cfg {
+    pub fn new(module: &Module, fun: FunID) -> Self {
+        let fun = &module[fun];
+        let mut blocks: Vec<_> = fun.blocks.iter().copied().collect();
+        blocks.sort_by_key(|b| b.0);
+
+        let mut successors = HashMap::new();
+        let mut predecessors: HashMap<BlockID, Vec<BlockID>> = HashMap::new();
+        for &block in &blocks {
+            let succs = module[block].successors();
+            for &succ in &succs {
+                predecessors.entry(succ).or_default().push(block);
+            }
+            successors.insert(block, succs);
+        }
+
+        // Postorder by an explicit DFS, so deep CFGs can't overflow the stack.
+        let mut postorder = Vec::new();
+        if let Some(entry) = fun.entry_block {
+            let mut visited = HashSet::from([entry]);
+            let mut stack = vec![(entry, 0)];
+            while let Some((block, next)) = stack.last_mut() {
+                if let Some(&succ) = successors[block].get(*next) {
+                    *next += 1;
+                    if visited.insert(succ) {
+                        stack.push((succ, 0));
+                    }
+                }
+                else {
+                    postorder.push(*block);
+                    stack.pop();
+                }
+            }
+        }
+        let reverse_postorder: Vec<_> = postorder.into_iter().rev().collect();
+        let rpo_index = reverse_postorder.iter().enumerate().map(|(i, &b)| (b, i)).collect();
+
+        Self {
+            entry: fun.entry_block,
+            successors,
+            predecessors,
+            reverse_postorder,
+            rpo_index,
+        }
+    }
+
+    pub fn entry(&self) -> Option<BlockID> {
+        self.entry
+    }
+    /// Each successor once, even if a branch goes there either way.
+    pub fn successors(&self, block: BlockID) -> &[BlockID] {
+        self.successors.get(&block).map_or(&[], |s| s)
+    }
+    /// Every block that can jump to this one, reachable or not, in the order they were created.
+    pub fn predecessors(&self, block: BlockID) -> &[BlockID] {
+        self.predecessors.get(&block).map_or(&[], |p| p)
+    }
+    /// The blocks reachable from the entry, each before its successors unless the edge closes a loop.
+    pub fn reverse_postorder(&self) -> &[BlockID] {
+        &self.reverse_postorder
+    }
+    pub fn is_reachable(&self, block: BlockID) -> bool {
+        self.rpo_index.contains_key(&block)
+    }
+}
+impl Analysis for Cfg {
+    fn compute(module: &Module, fun: FunID, _: &mut Analyses) -> Self {
+        Self::new(module, fun)
+    }
+}
+
+/// The dominator tree of the blocks reachable from a function's entry,
+/// built with the algorithm by Cooper, Harvey and Kennedy.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DomTree {
+    root: Option<BlockID>,
+    idom: HashMap<BlockID, BlockID>,
+    children: HashMap<BlockID, Vec<BlockID>>,
+    frontiers: HashMap<BlockID, Vec<BlockID>>,
+    /// Preorder and postorder numbers of every block in the tree.
+    intervals: HashMap<BlockID, (usize, usize)>,
+}
+impl DomTree {
+    pub fn new(cfg: &Cfg) -> Self {
+        let Some(root) = cfg.entry() else {
+            return Self::default();
+        };
+        let rpo = cfg.reverse_postorder();
+        let preds = |block| cfg.predecessors(block).iter().copied().filter(|&p| cfg.is_reachable(p));
+
+        let mut idom = HashMap::from([(root, root)]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &rpo[1..] {
+                let mut new_idom = None;
+                for pred in preds(block).filter(|p| idom.contains_key(p)) {
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(other) => intersect(&idom, &cfg.rpo_index, pred, other),
+                    });
+                }
+                let new_idom = new_idom.unwrap();
+                if idom.insert(block, new_idom) != Some(new_idom) {
+                    changed = true;
+                }
+            }
+        }
+        idom.remove(&root);
+
+        let mut children: HashMap<BlockID, Vec<BlockID>> = HashMap::new();
+        for &block in &rpo[1..] {
+            children.entry(idom[&block]).or_default().push(block);
+        }
+
+        let mut frontiers: HashMap<BlockID, Vec<BlockID>> = HashMap::new();
+        for &block in rpo {
+            // The root is also entered from outside.
+            let preds: Vec<_> = preds(block).collect();
+            if preds.len() + usize::from(block == root) < 2 {
+                continue;
+            }
+            for pred in preds {
+                let mut runner = Some(pred);
+                while let Some(r) = runner
+                    && Some(r) != idom.get(&block).copied()
+                {
+                    let frontier = frontiers.entry(r).or_default();
+                    if !frontier.contains(&block) {
+                        frontier.push(block);
+                    }
+                    runner = idom.get(&r).copied();
+                }
+            }
+        }
+        for frontier in frontiers.values_mut() {
+            frontier.sort_by_key(|b| b.0);
+        }
+
+        let mut tree = Self {
+            root: Some(root),
+            idom,
+            children,
+            frontiers,
+            intervals: HashMap::new(),
+        };
+        let mut post = 0;
+        for (pre, block) in tree.preorder().into_iter().enumerate() {
+            tree.intervals.insert(block, (pre, 0));
+        }
+        let mut stack = vec![(root, 0)];
+        while let Some((block, next)) = stack.last_mut() {
+            if let Some(&child) = tree.children(*block).get(*next) {
+                *next += 1;
+                stack.push((child, 0));
+            }
+            else {
+                tree.intervals.get_mut(block).unwrap().1 = post;
+                post += 1;
+                stack.pop();
+            }
+        }
+
+        tree
+    }
+
+    pub fn root(&self) -> Option<BlockID> {
+        self.root
+    }
+    /// Whether the block is reachable and so part of the tree.
+    pub fn contains(&self, block: BlockID) -> bool {
+        self.intervals.contains_key(&block)
+    }
+    /// The closest block other than `block` itself that dominates it.
+    /// Only the root and unreachable blocks have none.
+    pub fn idom(&self, block: BlockID) -> Option<BlockID> {
+        self.idom.get(&block).copied()
+    }
+    /// The blocks this one immediately dominates, in reverse postorder of the CFG.
+    pub fn children(&self, block: BlockID) -> &[BlockID] {
+        self.children.get(&block).map_or(&[], |c| c)
+    }
+    /// Where the blocks this one dominates meet control flow from elsewhere.
+    pub fn frontier(&self, block: BlockID) -> &[BlockID] {
+        self.frontiers.get(&block).map_or(&[], |f| f)
+    }
+    /// The closure of the dominance frontier of `blocks`,
+    /// which is where definitions in them have to be merged.
+    pub fn iterated_frontier(&self, blocks: impl IntoIterator<Item = BlockID>) -> Vec<BlockID> {
+        let mut work: Vec<_> = blocks.into_iter().collect();
+        let mut result = Vec::new();
+        let mut found = HashSet::new();
+        while let Some(block) = work.pop() {
+            for &frontier in self.frontier(block) {
+                if found.insert(frontier) {
+                    result.push(frontier);
+                    work.push(frontier);
+                }
+            }
+        }
+
+        result.sort_by_key(|b| b.0);
+        result
+    }
+    /// Whether every path from the entry to `b` passes through `a`.
+    /// Every block dominates itself, and unreachable blocks take no part.
+    pub fn dominates(&self, a: BlockID, b: BlockID) -> bool {
+        let (Some(&(a_pre, a_post)), Some(&(b_pre, b_post))) = (self.intervals.get(&a), self.intervals.get(&b)) else {
+            return false;
+        };
+        a_pre <= b_pre && b_post <= a_post
+    }
+    /// Every block of the tree, each before the blocks it dominates.
+    pub fn preorder(&self) -> Vec<BlockID> {
+        let mut order = Vec::new();
+        let mut stack: Vec<_> = self.root.into_iter().collect();
+        while let Some(block) = stack.pop() {
+            order.push(block);
+            stack.extend(self.children(block).iter().rev());
+        }
+        order
+    }
+}
+impl Analysis for DomTree {
+    fn compute(module: &Module, fun: FunID, analyses: &mut Analyses) -> Self {
+        Self::new(&analyses.cfg(module, fun))
+    }
+}
+
+/// The registers live on entry to and exit from every block of a function.
+/// Block parameters are defined by the block and count as live only in the predecessors,
+/// through the arguments of their jumps.
+pub struct Liveness {
+    pub live_in: HashMap<BlockID, HashSet<RegID>>,
+    pub live_out: HashMap<BlockID, HashSet<RegID>>,
+}
+impl Liveness {
+    pub fn new(module: &Module, fid: FunID) -> Self {
+        let blocks: Vec<_> = module[fid].blocks.iter().copied().collect();
+
+        let mut uses = HashMap::new();
+        let mut defs = HashMap::new();
+        for &bid in &blocks {
+            let block = &module[bid];
+            let mut block_uses = HashSet::new();
+            let mut block_defs: HashSet<_> = block.parameters.iter().copied().collect();
+            for instr in &block.instructions {
+                for reg in instr.uses() {
+                    if !block_defs.contains(&reg) {
+                        block_uses.insert(reg);
+                    }
+                }
+                if let Some(dst) = instr.dst() {
+                    block_defs.insert(dst);
+                }
+            }
+            uses.insert(bid, block_uses);
+            defs.insert(bid, block_defs);
+        }
+
+        let mut live_in: HashMap<_, HashSet<RegID>> = blocks.iter().map(|&b| (b, HashSet::new())).collect();
+        let mut live_out: HashMap<_, HashSet<RegID>> = blocks.iter().map(|&b| (b, HashSet::new())).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &bid in blocks.iter().rev() {
+                let mut out = HashSet::new();
+                for succ in module[bid].successors() {
+                    out.extend(live_in[&succ].iter().copied());
+                }
+
+                let mut inp = uses[&bid].clone();
+                inp.extend(out.difference(&defs[&bid]).copied());
+
+                if out != live_out[&bid] || inp != live_in[&bid] {
+                    changed = true;
+                    live_out.insert(bid, out);
+                    live_in.insert(bid, inp);
+                }
+            }
+        }
+
+        Self { live_in, live_out }
+    }
+}
+impl Analysis for Liveness {
+    fn compute(module: &Module, fun: FunID, _: &mut Analyses) -> Self {
+        Self::new(module, fun)
+    }
+}
+
+fn intersect(
+    idom: &HashMap<BlockID, BlockID>,
+    rpo_index: &HashMap<BlockID, usize>,
+    mut a: BlockID,
+    mut b: BlockID,
+) -> BlockID {
+    while a != b {
+        while rpo_index[&a] > rpo_index[&b] {
+            a = idom[&a];
+        }
+        while rpo_index[&b] > rpo_index[&a] {
+            b = idom[&b];
+        }
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::Target;
+
+    /// A loop around a diamond, plus a block nothing reaches.
+    fn looping() -> (Module, FunID, [BlockID; 7]) {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let fid = b.begin_fun("f".into(), IntTy::I64);
+        let entry = b.begin_block();
+        b.set_entry_block();
+        let [header, body, then, els, latch, exit, dead] = [(); 7].map(|_| b.create_block());
+
+        b.jump(header);
+        b.select_block(header);
+        b.branch(true, body, exit);
+        b.select_block(body);
+        b.branch(false, then, els);
+        b.select_block(then);
+        b.jump(latch);
+        b.select_block(els);
+        b.jump(latch);
+        b.select_block(latch);
+        b.jump(header);
+        b.select_block(exit);
+        b.ret(0i64);
+        b.select_block(dead);
+        b.jump(header);
+
+        (b.finish(), fid, [entry, header, body, then, els, latch, exit])
+    }
+
+    #[test]
+    fn cfg_of_a_loop() {
+        let (module, fid, [entry, header, body, then, els, latch, exit]) = looping();
+        let cfg = Cfg::new(&module, fid);
+        let dead = BlockID(exit.0 + 1);
+
+        assert_eq!(cfg.successors(body), [then, els]);
+        assert_eq!(cfg.predecessors(header), [entry, latch, dead]);
+        assert_eq!(cfg.reverse_postorder()[..2], [entry, header]);
+        assert_eq!(cfg.reverse_postorder().len(), 7);
+        assert!(!cfg.is_reachable(dead));
+
+        let position = |b| cfg.reverse_postorder().iter().position(|&x| x == b).unwrap();
+        assert!(position(body) < position(then) && position(then) < position(latch));
+        assert!(position(els) < position(latch));
+    }
+
+    #[test]
+    fn dominators_of_a_loop() {
+        let (module, fid, [entry, header, body, then, els, latch, exit]) = looping();
+        let dom = DomTree::new(&Cfg::new(&module, fid));
+        let dead = BlockID(exit.0 + 1);
+
+        assert_eq!(dom.root(), Some(entry));
+        assert_eq!(dom.idom(entry), None);
+        assert_eq!(dom.idom(header), Some(entry));
+        assert_eq!(dom.idom(latch), Some(body));
+        assert_eq!(dom.idom(exit), Some(header));
+        assert_eq!(dom.idom(dead), None);
+        let mut children = dom.children(body).to_vec();
+        children.sort_by_key(|b| b.0);
+        assert_eq!(children, [then, els, latch]);
+        assert_eq!(dom.preorder().len(), 7);
+
+        assert!(dom.dominates(header, latch));
+        assert!(dom.dominates(latch, latch));
+        assert!(!dom.dominates(then, latch));
+        assert!(!dom.dominates(exit, header));
+        assert!(!dom.dominates(entry, dead));
+
+        assert_eq!(dom.frontier(then), [latch]);
+        assert_eq!(dom.frontier(latch), [header]);
+        assert_eq!(dom.frontier(body), [header]);
+        assert_eq!(dom.frontier(header), [header]);
+        assert_eq!(dom.frontier(exit), []);
+        assert_eq!(dom.iterated_frontier([then]), [header, latch]);
+    }
+
+    #[test]
+    fn analyses_are_cached_until_the_function_changes() {
+        let (mut module, fid, [_, header, ..]) = looping();
+        let other = module.add_function("g".into(), Ty::Void);
+        let block = module.add_block(other);
+        module.set_entry_block(other, block);
+        module.add_instruction(block, Instruction::Ret(Value::Void));
+
+        let mut analyses = Analyses::new();
+        let dom = analyses.dom_tree(&module, fid);
+        let other_dom = analyses.dom_tree(&module, other);
+        assert!(Rc::ptr_eq(&dom, &analyses.dom_tree(&module, fid)));
+
+        module[header].instructions.clear();
+        module.add_instruction(header, Instruction::Ret(Value::Int(IntTy::I64, 0)));
+        let changed = analyses.dom_tree(&module, fid);
+        assert!(!Rc::ptr_eq(&dom, &changed));
+        assert_eq!(changed.preorder().len(), 2);
+        assert!(Rc::ptr_eq(&other_dom, &analyses.dom_tree(&module, other)));
+
+        analyses.invalidate(other);
+        assert!(!Rc::ptr_eq(&other_dom, &analyses.dom_tree(&module, other)));
+    }
+}
@@ -16,6 +16,25 @@ impl Block {
             instructions: Vec::new(),
         }
     }
+
+    /// The instruction that ends this block.
+    /// Blocks that are still being built have none.
+    pub fn terminator(&self) -> Option<&Instruction> {
+        self.instructions.last().filter(|i| i.is_terminator())
+    }
+    pub fn is_terminated(&self) -> bool {
+        self.terminator().is_some()
+    }
+    pub fn successors(&self) -> Vec<BlockID> {
+        let Some((first, second)) = self.terminator().and_then(|t| t.next_blocks()) else {
+            return Vec::new();
+        };
+
+        match second {
+            Some(second) if second != first => vec![first, second],
+            _ => vec![first],
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
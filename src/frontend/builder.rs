@@ -25,6 +25,14 @@ impl Builder {
         self.block = None;
         id
     }
+    pub fn set_linkage(&mut self, linkage: Linkage) {
+        let fun = self.fun.unwrap();
+        self.module.set_fun_linkage(fun, linkage);
+    }
+    pub fn set_call_convention(&mut self, convention: CallConvention) {
+        let fun = self.fun.unwrap();
+        self.module.set_call_convention(fun, convention);
+    }
     pub fn select_fun(&mut self, fun: FunID) {
         self.fun = Some(fun);
         self.block = None;
@@ -83,12 +91,22 @@ impl Builder {
         let block = self.block.unwrap();
         self.module.add_instruction(block, instr);
     }
+    pub fn is_terminated(&self) -> bool {
+        let block = self.block.unwrap();
+        self.module[block].is_terminated()
+    }
     pub fn create_global(&mut self, name: Option<String>, ty: impl Into<Ty>) -> GlobalID {
         self.module.add_global(name, ty)
     }
     pub fn set_global(&mut self, gid: GlobalID, value: impl Into<GlobalValue>) {
         self.module.set_global_value(gid, value.into());
     }
+    pub fn set_global_constant(&mut self, gid: GlobalID, constant: bool) {
+        self.module.set_global_constant(gid, constant);
+    }
+    pub fn set_global_linkage(&mut self, gid: GlobalID, linkage: Linkage) {
+        self.module.set_global_linkage(gid, linkage);
+    }
 
     pub fn set(&mut self, value: impl Into<Value>) -> RegID {
         let value = value.into();
@@ -377,6 +395,22 @@ impl Builder {
         });
         dst
     }
+    pub fn set_array_element(
+        &mut self,
+        array: RegID,
+        index: impl Into<Value>,
+        value: impl Into<Value>,
+    ) -> RegID {
+        let ty = self.module[array].ty;
+        let dst = self.create_reg(ty);
+        self.add_instr(Instruction::SetArrayElement {
+            dst,
+            array,
+            value: value.into(),
+            index: index.into(),
+        });
+        dst
+    }
     pub fn index_struct(&mut self, struct_ty: StructTyID, ptr: RegID, index: u64) -> RegID {
         let dst = self.create_reg(Ty::Ptr);
         self.add_instr(Instruction::IndexStruct {
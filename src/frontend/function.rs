@@ -1,11 +1,12 @@
 use std::collections::HashSet;
 
-use super::{block::BlockID, register::RegID, types::Ty, variable::VarID};
+use super::{block::BlockID, linkage::Linkage, register::RegID, types::Ty, variable::VarID};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Function {
     pub id: FunID,
     pub name: String,
+    pub linkage: Linkage,
     pub call_convention: CallConvention,
     pub ret_ty: Ty,
     pub registers: HashSet<RegID>,
@@ -19,6 +20,7 @@ impl Function {
         Self {
             id,
             name,
+            linkage: Linkage::Export,
             call_convention: CallConvention::Simple,
             ret_ty,
             registers: HashSet::new(),
@@ -35,5 +37,8 @@ pub struct FunID(pub(crate) usize);
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum CallConvention {
+    /// Return value and parameters are passed in a staging area on the stack.
     Simple,
+    /// The System V AMD64 ABI, for calling and being called from C.
+    SysV,
 }
@@ -1,11 +1,14 @@
-use crate::frontend::Ty;
+use crate::frontend::{FunID, Linkage, Ty};
 
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Global {
     pub id: GlobalID,
     pub name: Option<String>,
+    pub linkage: Linkage,
     pub ty: Ty,
     pub value: Option<GlobalValue>,
+    pub constant: bool,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -16,9 +19,37 @@ pub enum GlobalValue {
     Bool(bool),
     Int(i64),
     String(String),
+    /// Members in declaration order of a struct type.
+    Struct(Vec<GlobalValue>),
+    /// One value for every element of an array type.
+    Array(Vec<GlobalValue>),
+    /// The address of a global, resolved by the linker.
+    GlobalAddr(GlobalID),
+    /// The address of a function, resolved by the linker.
+    FunAddr(FunID),
 }
 impl From<&str> for GlobalValue {
     fn from(value: &str) -> Self {
         Self::String(value.to_string())
     }
 }
+impl From<bool> for GlobalValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+impl From<i64> for GlobalValue {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+impl From<GlobalID> for GlobalValue {
+    fn from(value: GlobalID) -> Self {
+        Self::GlobalAddr(value)
+    }
+}
+impl From<FunID> for GlobalValue {
+    fn from(value: FunID) -> Self {
+        Self::FunAddr(value)
+    }
+}
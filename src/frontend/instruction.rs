@@ -80,6 +80,9 @@ pub enum Instruction {
     },
 }
 impl Instruction {
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, Self::Jump(_) | Self::Branch(_, _, _) | Self::Ret(_))
+    }
     pub fn next_blocks(&self) -> Option<(BlockID, Option<BlockID>)> {
         match self {
             Self::Jump(tgt) => Some((tgt.block, None)),
@@ -87,6 +90,115 @@ impl Instruction {
             _ => None,
         }
     }
+
+    /// The register this instruction defines, if any.
+    pub fn dst(&self) -> Option<RegID> {
+        use Instruction::*;
+
+        match *self {
+            Set(dst, _)
+            | SetFunPtr(dst, _)
+            | SetGlobalPtr(dst, _)
+            | SetStruct(dst, _)
+            | SetArray(dst, _)
+            | SetArraySplat(dst, _)
+            | Binary(_, dst, _, _)
+            | Unary(_, dst, _)
+            | Poison(dst)
+            | Select(dst, _, _, _)
+            | Freeze(dst, _)
+            | GetVarAddr(dst, _)
+            | Load { dst, .. }
+            | PtrDiff(dst, _, _, _)
+            | Call(dst, _, _)
+            | CallPtr(dst, _, _, _)
+            | GetStructMember { dst, .. }
+            | SetStructMember { dst, .. }
+            | GetArrayElement { dst, .. }
+            | SetArrayElement { dst, .. }
+            | IndexStruct { dst, .. }
+            | IndexArray { dst, .. }
+            | SyscallLinux64 { dst, .. } => Some(dst),
+            Store { .. } | Jump(_) | Branch(_, _, _) | Ret(_) => None,
+        }
+    }
+    /// Every register this instruction reads, in operand order.
+    pub fn uses(&self) -> Vec<RegID> {
+        // Walks a copy so the operand lists only live in `operands_mut`.
+        self.clone().uses_mut().into_iter().map(|reg| *reg).collect()
+    }
+    /// Every register this instruction reads, for rewriting them in place.
+    pub fn uses_mut(&mut self) -> Vec<&mut RegID> {
+        let (mut regs, values) = self.operands_mut();
+        regs.extend(values.into_iter().filter_map(|value| match value {
+            Value::Reg(reg) => Some(reg),
+            _ => None,
+        }));
+        regs
+    }
+    /// Every operand that may be a constant as well as a register.
+    pub fn values_mut(&mut self) -> Vec<&mut Value> {
+        self.operands_mut().1
+    }
+    fn operands_mut(&mut self) -> (Vec<&mut RegID>, Vec<&mut Value>) {
+        use Instruction::*;
+
+        let mut regs = Vec::new();
+        let mut values = Vec::new();
+        match self {
+            Set(_, a) | SetArraySplat(_, a) | Unary(_, _, a) | Freeze(_, a) | Ret(a) => values.push(a),
+            SetStruct(_, args) | SetArray(_, args) | Call(_, _, args) => values.extend(&mut args.0),
+            Binary(_, _, a, b) => values.extend([a, b]),
+            Select(_, c, a, b) => values.extend([c, a, b]),
+            Store { ptr, value } => {
+                regs.push(ptr);
+                values.push(value);
+            }
+            Load { ptr, .. } | IndexStruct { ptr, .. } => regs.push(ptr),
+            PtrDiff(_, _, a, b) => regs.extend([a, b]),
+            Jump(tgt) => values.extend(&mut tgt.args.0),
+            Branch(c, t, f) => {
+                values.push(c);
+                values.extend(&mut t.args.0);
+                values.extend(&mut f.args.0);
+            }
+            CallPtr(_, ptr, _, args) => {
+                regs.push(ptr);
+                values.extend(&mut args.0);
+            }
+            GetStructMember { strct, .. } => regs.push(strct),
+            SetStructMember { strct, value, .. } => {
+                regs.push(strct);
+                values.push(value);
+            }
+            GetArrayElement { array, index, .. } => {
+                regs.push(array);
+                values.push(index);
+            }
+            SetArrayElement {
+                array,
+                value,
+                index,
+                ..
+            } => {
+                regs.push(array);
+                values.extend([index, value]);
+            }
+            IndexArray { ptr, index, .. } => {
+                regs.push(ptr);
+                values.push(index);
+            }
+            SyscallLinux64 {
+                call_number, args, ..
+            } => {
+                values.push(call_number);
+                values.extend(&mut args.0);
+            }
+            SetFunPtr(_, _) | SetGlobalPtr(_, _) | Poison(_) | GetVarAddr(_, _) => (),
+        }
+
+        (regs, values)
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -203,6 +315,12 @@ impl Value {
             _ => panic!(),
         }
     }
+    pub fn as_reg(self) -> Option<RegID> {
+        match self {
+            Self::Reg(reg) => Some(reg),
+            _ => None,
+        }
+    }
 
     pub fn ty(self, module: &Module) -> Ty {
         match self {
@@ -0,0 +1,10 @@
+/// How a function or global is seen from outside the module.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Linkage {
+    /// Defined here and only visible inside the module.
+    Internal,
+    /// Defined here and visible to other objects.
+    Export,
+    /// Defined by another object.
+    Import,
+}
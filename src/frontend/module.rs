@@ -1,6 +1,9 @@
-use std::ops::Index;
+use std::{
+    ops::{Index, IndexMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use crate::{frontend::global::{Global, GlobalID, GlobalValue}, layout::TyLayout, target::Target};
+use crate::{frontend::{Linkage, global::{Global, GlobalID, GlobalValue}}, layout::TyLayout, target::Target};
 
 use super::{
     block::{Block, BlockID},
@@ -11,6 +14,7 @@ use super::{
     variable::{VarID, Variable},
 };
 
+#[derive(Clone)]
 pub struct Module {
     types: Types,
 
@@ -23,6 +27,9 @@ pub struct Module {
 
 
     blocks: Vec<Block>,
+
+    /// Changes whenever a function is mutated, for invalidating cached analyses.
+    revisions: Vec<u64>,
 }
 impl Module {
     pub fn new(target: Target) -> Self {
@@ -36,6 +43,8 @@ impl Module {
             variables: Vec::new(),
 
             blocks: Vec::new(),
+
+            revisions: Vec::new(),
         }
     }
 
@@ -78,31 +87,46 @@ impl Module {
 
     pub fn add_global(&mut self, name: Option<String>, ty: impl Into<Ty>) -> GlobalID {
         let id = GlobalID(self.globals.len());
-        self.globals.push(Global { id, name, ty: ty.into(), value: None });
+        let linkage = if name.is_some() { Linkage::Export } else { Linkage::Internal };
+        self.globals.push(Global { id, name, linkage, ty: ty.into(), value: None, constant: false });
         id
     }
     pub fn set_global_value(&mut self, gid: GlobalID, value: GlobalValue) {
         self.globals[gid.0].value = Some(value);
     }
+    pub fn set_global_constant(&mut self, gid: GlobalID, constant: bool) {
+        self.globals[gid.0].constant = constant;
+    }
+    pub fn set_global_linkage(&mut self, gid: GlobalID, linkage: Linkage) {
+        self.globals[gid.0].linkage = linkage;
+    }
 
     pub fn add_function(&mut self, name: String, ret_ty: Ty) -> FunID {
         let id = FunID(self.functions.len());
         self.functions.push(Function::new(id, name, ret_ty));
+        self.revisions.push(next_revision());
         id
     }
     pub fn set_call_convention(&mut self, fun: FunID, convention: CallConvention) {
+        self.touch(fun);
         self.functions[fun.0].call_convention = convention;
     }
+    pub fn set_fun_linkage(&mut self, fun: FunID, linkage: Linkage) {
+        self.touch(fun);
+        self.functions[fun.0].linkage = linkage;
+    }
     pub fn add_register(&mut self, fun: FunID, ty: Ty) -> RegID {
         let id = RegID(self.registers.len());
         self.registers.push(Register::new(id, fun, ty));
 
         self.functions[fun.0].registers.insert(id);
+        self.touch(fun);
 
         id
     }
     pub fn add_parameter(&mut self, fun: FunID, reg: RegID) {
         assert_eq!(self[reg].fun, fun);
+        self.touch(fun);
         let func = &mut self.functions[fun.0];
         func.parameters.push(reg);
     }
@@ -111,12 +135,14 @@ impl Module {
         self.variables.push(Variable::new(id, fun, ty));
 
         self.functions[fun.0].variables.insert(id);
+        self.touch(fun);
 
         id
     }
     pub fn set_entry_block(&mut self, fun: FunID, block: BlockID) {
         assert_eq!(self[block].fun, fun);
         assert!(self[fun].blocks.contains(&block));
+        self.touch(fun);
         self.functions[fun.0].entry_block = Some(block);
     }
 
@@ -124,13 +150,18 @@ impl Module {
         let id = BlockID(self.blocks.len());
         self.blocks.push(Block::new(id, fun));
         self.functions[fun.0].blocks.insert(id);
+        self.touch(fun);
         id
     }
     pub fn add_block_parameter(&mut self, block: BlockID, param: RegID) {
+        self.touch(self.blocks[block.0].fun);
         self.blocks[block.0].parameters.push(param);
     }
     pub fn add_instruction(&mut self, block: BlockID, instruction: Instruction) {
-        self.blocks[block.0].instructions.push(instruction);
+        self.touch(self.blocks[block.0].fun);
+        let block = &mut self.blocks[block.0];
+        assert!(!block.is_terminated(), "cannot append to {:?} after its terminator", block.id);
+        block.instructions.push(instruction);
     }
 
     pub fn globals(&self) -> &[Global] {
@@ -139,6 +170,25 @@ impl Module {
     pub fn functions(&self) -> &[Function] {
         &self.functions
     }
+    /// The block, or `None` if the ID doesn't belong to this module.
+    pub fn block(&self, block: BlockID) -> Option<&Block> {
+        self.blocks.get(block.0)
+    }
+
+    /// Identifies the current contents of a function.
+    /// Every mutation, including every mutable borrow of the function or one of its blocks,
+    /// gives it a revision no function of any module has had before.
+    pub fn revision(&self, fun: FunID) -> u64 {
+        self.revisions[fun.0]
+    }
+    fn touch(&mut self, fun: FunID) {
+        self.revisions[fun.0] = next_revision();
+    }
+}
+
+fn next_revision() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
 }
 impl Index<FunTyID> for Module {
     type Output = FunTy;
@@ -159,6 +209,11 @@ impl Index<StructTyID> for Module {
         &self.types[index]
     }
 }
+impl IndexMut<StructTyID> for Module {
+    fn index_mut(&mut self, index: StructTyID) -> &mut Self::Output {
+        &mut self.types[index]
+    }
+}
 impl Index<FunID> for Module {
     type Output = Function;
 
@@ -166,6 +221,12 @@ impl Index<FunID> for Module {
         &self.functions[index.0]
     }
 }
+impl IndexMut<FunID> for Module {
+    fn index_mut(&mut self, index: FunID) -> &mut Self::Output {
+        self.touch(index);
+        &mut self.functions[index.0]
+    }
+}
 impl Index<RegID> for Module {
     type Output = Register;
     fn index(&self, index: RegID) -> &Self::Output {
@@ -184,3 +245,9 @@ impl Index<BlockID> for Module {
         &self.blocks[index.0]
     }
 }
+impl IndexMut<BlockID> for Module {
+    fn index_mut(&mut self, index: BlockID) -> &mut Self::Output {
+        self.touch(self.blocks[index.0].fun);
+        &mut self.blocks[index.0]
+    }
+}
@@ -0,0 +1,1114 @@
+use std::{collections::HashMap, error::Error, fmt};
+
+use crate::frontend::global::{GlobalID, GlobalValue};
+
+use super::*;
+
+/// Identifiers that start a global value rather than name the global.
+const GLOBAL_VALUE_KEYWORDS: [&str; 4] = ["true", "false", "global_ptr", "fun_ptr"];
+
+/// Reads the textual form written by [`Printer`] back into a [`Module`].
+pub struct Parser<'a> {
+    module: Module,
+    tokens: Vec<(usize, Token<'a>)>,
+    pos: usize,
+
+    functions: HashMap<&'a str, FunID>,
+    struct_tys: HashMap<Vec<Ty>, StructTyID>,
+    global_refs: Vec<(usize, usize)>,
+
+    fun: Option<FunID>,
+    regs: HashMap<usize, RegID>,
+    vars: HashMap<usize, VarID>,
+    blocks: HashMap<usize, BlockID>,
+}
+impl<'a> Parser<'a> {
+    pub fn new(src: &'a str, module: Module) -> Result<Self, ParseError> {
+        let tokens = Lexer::new(src).lex()?;
+        Ok(Self {
+            module,
+            tokens,
+            pos: 0,
+
+            functions: HashMap::new(),
+            struct_tys: HashMap::new(),
+            global_refs: Vec::new(),
+
+            fun: None,
+            regs: HashMap::new(),
+            vars: HashMap::new(),
+            blocks: HashMap::new(),
+        })
+    }
+
+    pub fn parse(mut self) -> Result<Module, ParseError> {
+        self.declare_functions()?;
+
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Ident("internal" | "export" | "import") => self.parse_function()?,
+                Token::Ident(_) => self.parse_global()?,
+                _ => return Err(self.unexpected("a global or function")),
+            }
+        }
+
+        let globals = self.module.globals().len();
+        if let Some(&(pos, gid)) = self.global_refs.iter().find(|&&(_, gid)| gid >= globals) {
+            return Err(self.error_at(pos, format!("unknown global g{gid}")));
+        }
+
+        Ok(self.module)
+    }
+
+    /// Functions may be referenced before they are defined,
+    /// so every header is parsed up front.
+    fn declare_functions(&mut self) -> Result<(), ParseError> {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Punct('{') | Token::Punct('[') | Token::Punct('(') => depth += 1,
+                Token::Punct('}') | Token::Punct(']') | Token::Punct(')') => {
+                    depth = depth.saturating_sub(1)
+                }
+                Token::Ident("fun") if depth == 0 => {
+                    let start = self.pos;
+                    self.pos += 1;
+                    let name = self.expect_ident()?;
+                    let ret_ty = self.parse_ty()?;
+                    if self.functions.contains_key(name) {
+                        return Err(self.error_at(start, format!("function {name} is defined twice")));
+                    }
+                    let fid = self.module.add_function(name.to_string(), ret_ty);
+                    self.functions.insert(name, fid);
+                    continue;
+                }
+                _ => (),
+            }
+            self.pos += 1;
+        }
+
+        self.pos = 0;
+        Ok(())
+    }
+
+    fn parse_global(&mut self) -> Result<(), ParseError> {
+        let start = self.pos;
+        let id = self.expect_ident()?;
+        let index = id
+            .strip_prefix('g')
+            .and_then(|i| i.parse::<usize>().ok())
+            .ok_or_else(|| self.error_at(start, format!("expected a global, found {id}")))?;
+        if index != self.module.globals().len() {
+            return Err(self.error_at(start, format!("global {id} is out of order")));
+        }
+
+        self.expect_punct('=')?;
+        let linkage = self.parse_linkage()?;
+        let constant = match self.peek() {
+            Some(Token::Ident("global")) => false,
+            Some(Token::Ident("constant")) => true,
+            _ => return Err(self.unexpected("global or constant")),
+        };
+        self.pos += 1;
+        let ty = self.parse_ty()?;
+
+        // Names and values are optional, so they have to share the line
+        // to not be mistaken for the next global.
+        let line = self.tokens[start].0;
+        let name = match self.peek_on_line(line) {
+            Some(Token::Ident(name)) if !GLOBAL_VALUE_KEYWORDS.contains(&name) => {
+                self.pos += 1;
+                Some(name.to_string())
+            }
+            _ => None,
+        };
+        let gid = self.module.add_global(name, ty);
+        self.module.set_global_constant(gid, constant);
+        self.module.set_global_linkage(gid, linkage);
+
+        if self.peek_on_line(line).is_some() {
+            let value = self.parse_global_value()?;
+            self.module.set_global_value(gid, value);
+        }
+
+        Ok(())
+    }
+    fn parse_global_value(&mut self) -> Result<GlobalValue, ParseError> {
+        let start = self.pos;
+        let value = match self.next() {
+            Some(Token::Int(value)) => GlobalValue::Int(value),
+            Some(Token::Ident("true")) => GlobalValue::Bool(true),
+            Some(Token::Ident("false")) => GlobalValue::Bool(false),
+            Some(Token::Str(src)) => GlobalValue::String(src),
+            Some(Token::Punct('{')) => GlobalValue::Struct(self.parse_global_values('}')?),
+            Some(Token::Punct('[')) => GlobalValue::Array(self.parse_global_values(']')?),
+            Some(Token::Ident("global_ptr")) => {
+                let id_start = self.pos;
+                let id = self.expect_ident()?;
+                let gid = id
+                    .strip_prefix('g')
+                    .and_then(|i| i.parse::<usize>().ok())
+                    .ok_or_else(|| self.error_at(id_start, format!("expected a global, found {id}")))?;
+                // Globals may point at each other, so the target is checked
+                // once every global has been declared.
+                self.global_refs.push((id_start, gid));
+                GlobalValue::GlobalAddr(GlobalID(gid))
+            }
+            Some(Token::Ident("fun_ptr")) => GlobalValue::FunAddr(self.parse_fun_name()?),
+            _ => {
+                self.pos = start;
+                return Err(self.unexpected("a global value"));
+            }
+        };
+
+        Ok(value)
+    }
+    fn parse_global_values(&mut self, end: char) -> Result<Vec<GlobalValue>, ParseError> {
+        let mut values = Vec::new();
+        while !self.eat_punct(end) {
+            values.push(self.parse_global_value()?);
+            if !self.eat_punct(',') {
+                self.expect_punct(end)?;
+                break;
+            }
+        }
+
+        Ok(values)
+    }
+
+    fn parse_function(&mut self) -> Result<(), ParseError> {
+        let linkage = self.parse_linkage()?;
+        let convention = self.parse_call_convention();
+        self.expect_keyword("fun")?;
+        let name = self.expect_ident()?;
+        let fid = self.functions[name];
+        self.module.set_fun_linkage(fid, linkage);
+        self.module.set_call_convention(fid, convention);
+        self.parse_ty()?;
+
+        self.fun = Some(fid);
+        self.regs.clear();
+        self.vars.clear();
+        self.blocks.clear();
+
+        self.expect_punct('(')?;
+        while !self.eat_punct(')') {
+            let reg = self.parse_reg()?;
+            self.module.add_parameter(fid, reg);
+            if !self.eat_punct(',') {
+                self.expect_punct(')')?;
+                break;
+            }
+        }
+
+        if self.eat_punct(';') {
+            return Ok(());
+        }
+        self.expect_punct('{')?;
+
+        while let Some(Token::Var(_)) = self.peek() {
+            self.parse_var_decl()?;
+        }
+
+        let mut block = None;
+        while !self.eat_punct('}') {
+            match self.peek() {
+                Some(Token::Block(_)) => {
+                    let bid = self.parse_block_label()?;
+                    if block.is_none() {
+                        self.module.set_entry_block(fid, bid);
+                    }
+                    block = Some(bid);
+                }
+                Some(_) => {
+                    let Some(bid) = block else {
+                        return Err(self.unexpected("a block label"));
+                    };
+                    if self.module[bid].is_terminated() {
+                        return Err(self.unexpected("a block label"));
+                    }
+                    let instr = self.parse_instr()?;
+                    self.module.add_instruction(bid, instr);
+                }
+                None => return Err(self.unexpected("'}'")),
+            }
+        }
+
+        Ok(())
+    }
+    fn parse_linkage(&mut self) -> Result<Linkage, ParseError> {
+        let linkage = match self.peek() {
+            Some(Token::Ident("internal")) => Linkage::Internal,
+            Some(Token::Ident("export")) => Linkage::Export,
+            Some(Token::Ident("import")) => Linkage::Import,
+            _ => return Err(self.unexpected("a linkage")),
+        };
+        self.pos += 1;
+
+        Ok(linkage)
+    }
+    fn parse_call_convention(&mut self) -> CallConvention {
+        if self.peek() == Some(Token::Ident("sysv")) {
+            self.pos += 1;
+            CallConvention::SysV
+        } else {
+            CallConvention::Simple
+        }
+    }
+    fn parse_var_decl(&mut self) -> Result<(), ParseError> {
+        let start = self.pos;
+        let Some(Token::Var(name)) = self.next() else {
+            unreachable!()
+        };
+        self.expect_punct('=')?;
+        self.expect_keyword("var")?;
+        let ty = self.parse_ty()?;
+
+        if self.vars.contains_key(&name) {
+            return Err(self.error_at(start, format!("variable &{name} is declared twice")));
+        }
+        let var = self.module.add_variable(self.fun.unwrap(), ty);
+        self.vars.insert(name, var);
+
+        Ok(())
+    }
+    fn parse_block_label(&mut self) -> Result<BlockID, ParseError> {
+        let bid = self.parse_block_id()?;
+        if self.eat_punct('(') {
+            while !self.eat_punct(')') {
+                let reg = self.parse_reg()?;
+                self.module.add_block_parameter(bid, reg);
+                if !self.eat_punct(',') {
+                    self.expect_punct(')')?;
+                    break;
+                }
+            }
+        }
+        self.expect_punct(':')?;
+
+        Ok(bid)
+    }
+
+    fn parse_instr(&mut self) -> Result<Instruction, ParseError> {
+        let start = self.pos;
+        if let Some(Token::Ident(keyword)) = self.peek() {
+            self.pos += 1;
+            return match keyword {
+                "store" => {
+                    let ptr = self.parse_reg()?;
+                    self.expect_punct(',')?;
+                    let value = self.parse_value()?;
+                    Ok(Instruction::Store { ptr, value })
+                }
+                "jump" => Ok(Instruction::Jump(self.parse_jump_target()?)),
+                "branch" => {
+                    let c = self.parse_value()?;
+                    self.expect_punct(',')?;
+                    let t = self.parse_jump_target()?;
+                    self.expect_punct(',')?;
+                    let f = self.parse_jump_target()?;
+                    Ok(Instruction::Branch(c, t, f))
+                }
+                "ret" => Ok(Instruction::Ret(self.parse_value()?)),
+                _ => Err(self.error_at(start, format!("unknown instruction {keyword}"))),
+            };
+        }
+
+        let dst = self.parse_reg()?;
+        self.expect_punct('=')?;
+        self.parse_assignment(dst)
+    }
+    fn parse_assignment(&mut self, dst: RegID) -> Result<Instruction, ParseError> {
+        let start = self.pos;
+        let keyword = match self.peek() {
+            Some(Token::Punct('{')) => {
+                self.pos += 1;
+                let values = self.parse_values('}')?;
+                return Ok(Instruction::SetStruct(dst, values));
+            }
+            Some(Token::Punct('[')) => return self.parse_array(dst),
+            Some(Token::Ident(keyword)) if !matches!(keyword, "true" | "false" | "void") => keyword,
+            _ => return Ok(Instruction::Set(dst, self.parse_value()?)),
+        };
+        self.pos += 1;
+
+        if let Some(op) = bin_op(keyword) {
+            let a = self.parse_value()?;
+            self.expect_punct(',')?;
+            let b = self.parse_value()?;
+            return Ok(Instruction::Binary(op, dst, a, b));
+        }
+        if let Some(op) = un_op(keyword) {
+            let a = self.parse_value()?;
+            return Ok(Instruction::Unary(op, dst, a));
+        }
+
+        let instr = match keyword {
+            "fun_ptr" => {
+                let fid = self.parse_fun_name()?;
+                Instruction::SetFunPtr(dst, fid)
+            }
+            "poison" => Instruction::Poison(dst),
+            "select" => {
+                let c = self.parse_value()?;
+                self.expect_punct(',')?;
+                let a = self.parse_value()?;
+                self.expect_punct(',')?;
+                let b = self.parse_value()?;
+                Instruction::Select(dst, c, a, b)
+            }
+            "freeze" => Instruction::Freeze(dst, self.parse_value()?),
+            "get_var_addr" => {
+                let var = self.parse_var()?;
+                Instruction::GetVarAddr(dst, var)
+            }
+            "load" => {
+                let ptr = self.parse_reg()?;
+                Instruction::Load { dst, ptr }
+            }
+            "ptrdiff" => {
+                let ty = self.parse_ty()?;
+                let a = self.parse_reg()?;
+                self.expect_punct(',')?;
+                let b = self.parse_reg()?;
+                Instruction::PtrDiff(dst, ty, a, b)
+            }
+            "call" => self.parse_call(dst)?,
+            "get_struct_member" => {
+                let strct = self.parse_reg()?;
+                self.expect_punct(',')?;
+                let index = self.parse_index()?;
+                Instruction::GetStructMember { dst, strct, index }
+            }
+            "set_struct_member" => {
+                let strct = self.parse_reg()?;
+                self.expect_punct(',')?;
+                let index = self.parse_index()?;
+                self.expect_punct(',')?;
+                let value = self.parse_value()?;
+                Instruction::SetStructMember {
+                    dst,
+                    strct,
+                    value,
+                    index,
+                }
+            }
+            "get_array_element" => {
+                let array = self.parse_reg()?;
+                self.expect_punct(',')?;
+                let index = self.parse_value()?;
+                Instruction::GetArrayElement { dst, array, index }
+            }
+            "set_array_element" => {
+                let array = self.parse_reg()?;
+                self.expect_punct(',')?;
+                let index = self.parse_value()?;
+                self.expect_punct(',')?;
+                let value = self.parse_value()?;
+                Instruction::SetArrayElement {
+                    dst,
+                    array,
+                    value,
+                    index,
+                }
+            }
+            "index_struct" => {
+                let ty_start = self.pos;
+                let Ty::Struct(struct_ty) = self.parse_ty()? else {
+                    return Err(self.error_at(ty_start, "expected a struct type".into()));
+                };
+                let ptr = self.parse_reg()?;
+                self.expect_punct(',')?;
+                let index = self.parse_index()?;
+                Instruction::IndexStruct {
+                    dst,
+                    ptr,
+                    struct_ty,
+                    index,
+                }
+            }
+            "index_array" => {
+                let element_ty = self.parse_ty()?;
+                let ptr = self.parse_reg()?;
+                self.expect_punct(',')?;
+                let index = self.parse_value()?;
+                Instruction::IndexArray {
+                    dst,
+                    ptr,
+                    element_ty,
+                    index,
+                }
+            }
+            "syscall" => {
+                let call_number = self.parse_value()?;
+                self.expect_punct('(')?;
+                let args = self.parse_values(')')?;
+                Instruction::SyscallLinux64 {
+                    dst,
+                    call_number,
+                    args,
+                }
+            }
+            global => {
+                let gid = global
+                    .strip_prefix('g')
+                    .and_then(|i| i.parse::<usize>().ok())
+                    .filter(|&i| i < self.module.globals().len())
+                    .ok_or_else(|| self.error_at(start, format!("unknown instruction {global}")))?;
+                Instruction::SetGlobalPtr(dst, GlobalID(gid))
+            }
+        };
+
+        Ok(instr)
+    }
+    fn parse_array(&mut self, dst: RegID) -> Result<Instruction, ParseError> {
+        self.expect_punct('[')?;
+        if self.eat_punct(']') {
+            return Ok(Instruction::SetArray(dst, Values::new()));
+        }
+
+        let first = self.parse_value()?;
+        if self.eat_punct('*') {
+            self.parse_index()?;
+            self.expect_punct(']')?;
+            return Ok(Instruction::SetArraySplat(dst, first));
+        }
+
+        let mut values = vec![first];
+        if self.eat_punct(',') {
+            values.extend(self.parse_values(']')?);
+        } else {
+            self.expect_punct(']')?;
+        }
+
+        Ok(Instruction::SetArray(dst, values.into()))
+    }
+    fn parse_call(&mut self, dst: RegID) -> Result<Instruction, ParseError> {
+        let convention = self.parse_call_convention();
+        if convention != CallConvention::Simple || matches!(self.peek(), Some(Token::Reg(_))) {
+            let ptr = self.parse_reg()?;
+            self.expect_punct('(')?;
+            let args = self.parse_values(')')?;
+
+            let ret = self.module[dst].ty;
+            let params: Vec<_> = args.0.iter().map(|a| a.ty(&self.module)).collect();
+            let fun_ty = self.module.add_fun_ty(convention, ret, params);
+            return Ok(Instruction::CallPtr(dst, ptr, fun_ty, args));
+        }
+
+        let fid = self.parse_fun_name()?;
+        self.expect_punct('(')?;
+        let args = self.parse_values(')')?;
+        Ok(Instruction::Call(dst, fid, args))
+    }
+    fn parse_jump_target(&mut self) -> Result<JumpTarget, ParseError> {
+        let block = self.parse_block_id()?;
+        let args = if self.eat_punct('(') {
+            self.parse_values(')')?
+        } else {
+            Values::new()
+        };
+
+        Ok(JumpTarget { block, args })
+    }
+
+    /// Parses a comma separated list of values up to and including `end`.
+    fn parse_values(&mut self, end: char) -> Result<Values, ParseError> {
+        let mut values = Vec::new();
+        while !self.eat_punct(end) {
+            values.push(self.parse_value()?);
+            if !self.eat_punct(',') {
+                self.expect_punct(end)?;
+                break;
+            }
+        }
+
+        Ok(values.into())
+    }
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some(Token::Reg(_)) => Ok(Value::Reg(self.parse_reg()?)),
+            Some(Token::Int(value)) => {
+                self.pos += 1;
+                let ty_start = self.pos;
+                let Ty::Int(ty) = self.parse_ty()? else {
+                    return Err(self.error_at(ty_start, "expected an integer type".into()));
+                };
+                Ok(Value::Int(ty, value))
+            }
+            Some(Token::Ident("true")) => {
+                self.pos += 1;
+                Ok(Value::Bool(true))
+            }
+            Some(Token::Ident("false")) => {
+                self.pos += 1;
+                Ok(Value::Bool(false))
+            }
+            Some(Token::Ident("void")) => {
+                self.pos += 1;
+                Ok(Value::Void)
+            }
+            _ => Err(self.unexpected("a value")),
+        }
+    }
+    fn parse_reg(&mut self) -> Result<RegID, ParseError> {
+        let start = self.pos;
+        let Some(Token::Reg(name)) = self.next() else {
+            return Err(self.error_at(start, "expected a register".into()));
+        };
+        let ty = self.parse_ty()?;
+
+        if let Some(&reg) = self.regs.get(&name) {
+            if self.module[reg].ty != ty {
+                return Err(self.error_at(start, format!("register %{name} used with two types")));
+            }
+            return Ok(reg);
+        }
+
+        let reg = self.module.add_register(self.fun.unwrap(), ty);
+        self.regs.insert(name, reg);
+        Ok(reg)
+    }
+    fn parse_var(&mut self) -> Result<VarID, ParseError> {
+        let start = self.pos;
+        let Some(Token::Var(name)) = self.next() else {
+            return Err(self.error_at(start, "expected a variable".into()));
+        };
+        self.vars
+            .get(&name)
+            .copied()
+            .ok_or_else(|| self.error_at(start, format!("variable &{name} is not declared")))
+    }
+    fn parse_block_id(&mut self) -> Result<BlockID, ParseError> {
+        let start = self.pos;
+        let Some(Token::Block(name)) = self.next() else {
+            return Err(self.error_at(start, "expected a block".into()));
+        };
+        if let Some(&block) = self.blocks.get(&name) {
+            return Ok(block);
+        }
+
+        let block = self.module.add_block(self.fun.unwrap());
+        self.blocks.insert(name, block);
+        Ok(block)
+    }
+    fn parse_fun_name(&mut self) -> Result<FunID, ParseError> {
+        let start = self.pos;
+        let name = self.expect_ident()?;
+        self.functions
+            .get(name)
+            .copied()
+            .ok_or_else(|| self.error_at(start, format!("unknown function {name}")))
+    }
+    fn parse_index(&mut self) -> Result<u64, ParseError> {
+        let start = self.pos;
+        match self.next() {
+            Some(Token::Int(value)) if value >= 0 => Ok(value as u64),
+            _ => Err(self.error_at(start, "expected an index".into())),
+        }
+    }
+
+    fn parse_ty(&mut self) -> Result<Ty, ParseError> {
+        let start = self.pos;
+        let ty = match self.next() {
+            Some(Token::Ident("void")) => Ty::Void,
+            Some(Token::Ident("bool")) => Ty::Bool,
+            Some(Token::Ident("ptr")) => Ty::Ptr,
+            Some(Token::Ident("i8")) => IntTy::I8.into(),
+            Some(Token::Ident("i16")) => IntTy::I16.into(),
+            Some(Token::Ident("i32")) => IntTy::I32.into(),
+            Some(Token::Ident("i64")) => IntTy::I64.into(),
+            Some(Token::Punct('[')) => {
+                let element = self.parse_ty()?;
+                self.expect_punct('*')?;
+                let size = self.parse_index()?;
+                self.expect_punct(']')?;
+                self.module.add_array_ty(size, element).into()
+            }
+            Some(Token::Punct('{')) => {
+                let mut members = Vec::new();
+                while !self.eat_punct('}') {
+                    members.push(self.parse_ty()?);
+                    if !self.eat_punct(',') {
+                        self.expect_punct('}')?;
+                        break;
+                    }
+                }
+                self.struct_ty(members).into()
+            }
+            _ => return Err(self.error_at(start, "expected a type".into())),
+        };
+
+        Ok(ty)
+    }
+    fn struct_ty(&mut self, members: Vec<Ty>) -> StructTyID {
+        if let Some(&id) = self.struct_tys.get(&members) {
+            return id;
+        }
+
+        let id = self.module.add_struct_ty();
+        for &member in &members {
+            self.module.add_struct_member(id, member);
+        }
+        self.struct_tys.insert(members, id);
+        id
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).map(|(_, t)| t.clone())
+    }
+    fn peek_on_line(&self, line: usize) -> Option<Token<'a>> {
+        self.tokens
+            .get(self.pos)
+            .filter(|&&(l, _)| l == line)
+            .map(|(_, t)| t.clone())
+    }
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+    fn eat_punct(&mut self, punct: char) -> bool {
+        if self.peek() == Some(Token::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expect_punct(&mut self, punct: char) -> Result<(), ParseError> {
+        if self.eat_punct(punct) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{punct}'")))
+        }
+    }
+    fn expect_ident(&mut self) -> Result<&'a str, ParseError> {
+        match self.peek() {
+            Some(Token::Ident(ident)) => {
+                self.pos += 1;
+                Ok(ident)
+            }
+            _ => Err(self.unexpected("an identifier")),
+        }
+    }
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(Token::Ident(ident)) if ident == keyword => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.unexpected(keyword)),
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        let found = match self.tokens.get(self.pos) {
+            Some((_, token)) => format!("{token:?}"),
+            None => "end of input".to_string(),
+        };
+        self.error_at(self.pos, format!("expected {expected}, found {found}"))
+    }
+    fn error_at(&self, pos: usize, message: String) -> ParseError {
+        let line = self
+            .tokens
+            .get(pos)
+            .or(self.tokens.last())
+            .map(|&(line, _)| line)
+            .unwrap_or(1);
+        ParseError { line, message }
+    }
+}
+
+fn bin_op(name: &str) -> Option<BinOp> {
+    let op = match name {
+        "add" => BinOp::Add,
+        "sub" => BinOp::Sub,
+        "mul" => BinOp::Mul,
+        "idiv" => BinOp::IDiv,
+        "udiv" => BinOp::UDiv,
+        "imod" => BinOp::IMod,
+        "umod" => BinOp::UMod,
+        "and" => BinOp::And,
+        "or" => BinOp::Or,
+        "xor" => BinOp::Xor,
+        "shl" => BinOp::Shl,
+        "shr" => BinOp::Shr,
+        "sar" => BinOp::Sar,
+        "equal" => BinOp::Equal,
+        "not_equal" => BinOp::NotEqual,
+        "greater" => BinOp::Greater,
+        "greater_equal" => BinOp::GreaterEqual,
+        "less" => BinOp::Less,
+        "less_equal" => BinOp::LessEqual,
+        "above" => BinOp::Above,
+        "above_equal" => BinOp::AboveEqual,
+        "below" => BinOp::Below,
+        "below_equal" => BinOp::BelowEqual,
+        _ => return None,
+    };
+
+    Some(op)
+}
+fn un_op(name: &str) -> Option<UnOp> {
+    let op = match name {
+        "neg" => UnOp::Neg,
+        "not" => UnOp::Not,
+        "int_to_ptr" => UnOp::IntToPtr,
+        "ptr_to_int" => UnOp::PtrToInt,
+        "sext" => UnOp::Sext,
+        "zext" => UnOp::Zext,
+        "trunc" => UnOp::Trunc,
+        _ => return None,
+    };
+
+    Some(op)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+impl Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Int(i64),
+    Str(String),
+    Reg(usize),
+    Var(usize),
+    Block(usize),
+    Punct(char),
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    line: usize,
+}
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            pos: 0,
+            line: 1,
+        }
+    }
+
+    fn lex(mut self) -> Result<Vec<(usize, Token<'a>)>, ParseError> {
+        let mut tokens = Vec::new();
+
+        while let Some(c) = self.peek() {
+            let line = self.line;
+            let token = match c {
+                '\n' => {
+                    self.line += 1;
+                    self.pos += 1;
+                    continue;
+                }
+                c if c.is_whitespace() => {
+                    self.pos += c.len_utf8();
+                    continue;
+                }
+                '%' | '&' | '@' => {
+                    self.pos += 1;
+                    let name = self.lex_number()?;
+                    let name = usize::try_from(name).map_err(|_| self.error("invalid name"))?;
+                    match c {
+                        '%' => Token::Reg(name),
+                        '&' => Token::Var(name),
+                        _ => Token::Block(name),
+                    }
+                }
+                '"' => Token::Str(self.lex_string()?),
+                '-' | '0'..='9' => Token::Int(self.lex_number()?),
+                c if is_ident_start(c) => {
+                    let start = self.pos;
+                    while self.peek().is_some_and(is_ident_continue) {
+                        self.pos += 1;
+                    }
+                    Token::Ident(&self.src[start..self.pos])
+                }
+                '(' | ')' | '[' | ']' | '{' | '}' | ',' | '=' | ':' | ';' | '*' => {
+                    self.pos += 1;
+                    Token::Punct(c)
+                }
+                c => return Err(self.error(&format!("unexpected character {c:?}"))),
+            };
+            tokens.push((line, token));
+        }
+
+        Ok(tokens)
+    }
+    fn lex_number(&mut self) -> Result<i64, ParseError> {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+
+        self.src[start..self.pos]
+            .parse()
+            .map_err(|_| self.error("invalid number"))
+    }
+    fn lex_string(&mut self) -> Result<String, ParseError> {
+        self.pos += 1;
+        let mut value = String::new();
+
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => break,
+                '\\' => value.push(self.lex_escape()?),
+                '\n' => {
+                    self.line += 1;
+                    value.push(c);
+                }
+                c => value.push(c),
+            }
+        }
+
+        Ok(value)
+    }
+    fn lex_escape(&mut self) -> Result<char, ParseError> {
+        let c = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+        self.pos += 1;
+        let c = match c {
+            't' => '\t',
+            'r' => '\r',
+            'n' => '\n',
+            '0' => '\0',
+            '\\' | '\'' | '"' => c,
+            'u' => {
+                let rest = &self.src[self.pos..];
+                let end = rest.find('}').ok_or_else(|| self.error("invalid escape"))?;
+                let hex = rest
+                    .strip_prefix('{')
+                    .map(|r| &r[..end - 1])
+                    .ok_or_else(|| self.error("invalid escape"))?;
+                self.pos += end + 1;
+                u32::from_str_radix(hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| self.error("invalid escape"))?
+            }
+            _ => return Err(self.error("invalid escape")),
+        };
+
+        Ok(c)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            line: self.line,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '$'
+}
+fn is_ident_continue(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::Target;
+
+    fn print(module: &Module) -> String {
+        let mut out = Vec::new();
+        Printer::new(module, &mut out).print().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+    fn parse(src: &str) -> Result<Module, ParseError> {
+        Parser::new(src, Module::new(Target::LINUX_X64))?.parse()
+    }
+
+    /// A module with every instruction, global value, linkage and calling convention.
+    fn everything() -> Module {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let pair = b.module.add_struct_ty();
+        b.module.add_struct_member(pair, IntTy::I32.into());
+        b.module.add_struct_member(pair, Ty::Ptr);
+        let bytes = b.module.add_array_ty(8, IntTy::I8.into());
+
+        let puts = b.begin_fun("puts".into(), IntTy::I32);
+        b.set_linkage(Linkage::Import);
+        b.set_call_convention(CallConvention::SysV);
+        b.create_param(Ty::Ptr);
+
+        let helper = b.begin_fun("helper".into(), IntTy::I64);
+        b.set_linkage(Linkage::Internal);
+        let x = b.create_param(IntTy::I64);
+        b.begin_block();
+        b.set_entry_block();
+        b.ret(x);
+
+        let text = b.create_global(Some("text".into()), bytes);
+        b.set_global(text, "hi\n\"\u{e9}");
+        b.set_global_constant(text, true);
+        b.set_global_linkage(text, Linkage::Export);
+        // Unnamed globals without a value must not swallow the next line.
+        let counter = b.create_global(None, IntTy::I64);
+        b.create_global(None, IntTy::I64);
+        let flag = b.create_global(Some("flag".into()), Ty::Bool);
+        b.set_global(flag, true);
+        let table = b.create_global(Some("table".into()), pair);
+        b.set_global(table, GlobalValue::Struct(vec![(-7).into(), text.into()]));
+        let ptrs = b.module.add_array_ty(2, Ty::Ptr);
+        let funs = b.create_global(None, ptrs);
+        b.set_global(funs, GlobalValue::Array(vec![helper.into(), puts.into()]));
+        let import = b.create_global(Some("environ".into()), Ty::Ptr);
+        b.set_global_linkage(import, Linkage::Import);
+        let _ = counter;
+
+        let main = b.begin_fun("main".into(), IntTy::I32);
+        b.set_call_convention(CallConvention::SysV);
+        let a = b.create_param(IntTy::I32);
+        let entry = b.begin_block();
+        b.set_entry_block();
+        let loop_block = b.create_block();
+        let exit = b.create_block();
+
+        let var = b.create_var(pair);
+        let set = b.set(5i32);
+        let wide = b.sext(IntTy::I64, a);
+        for op in [
+            BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::IDiv, BinOp::UDiv, BinOp::IMod, BinOp::UMod,
+            BinOp::And, BinOp::Or, BinOp::Xor, BinOp::Shl, BinOp::Shr, BinOp::Sar,
+        ] {
+            let dst = b.create_reg(IntTy::I64);
+            b.add_instr(Instruction::Binary(op, dst, wide.into(), Value::Int(IntTy::I64, 3)));
+        }
+        for op in [
+            BinOp::Equal, BinOp::NotEqual, BinOp::Greater, BinOp::GreaterEqual, BinOp::Less,
+            BinOp::LessEqual, BinOp::Above, BinOp::AboveEqual, BinOp::Below, BinOp::BelowEqual,
+        ] {
+            let dst = b.create_reg(Ty::Bool);
+            b.add_instr(Instruction::Binary(op, dst, wide.into(), Value::Int(IntTy::I64, -3)));
+        }
+        let negative = b.neg(a);
+        let cond = b.test_l(negative, set);
+        let not = b.not(cond);
+        let widened = b.zext(IntTy::I64, not);
+        b.trunc(IntTy::I8, widened);
+        let ptr = b.int_to_ptr(widened);
+        b.ptr_to_int(IntTy::I64, ptr);
+        let poison = b.poison(IntTy::I16);
+        b.freeze(poison);
+        b.select(cond, a, 1i32);
+
+        let text_ptr = b.set_global_ptr(text);
+        let fun_ptr = b.set_fun_ptr(puts);
+        let strct = b.set_struct(pair, [Value::Reg(a), Value::Reg(text_ptr)]);
+        let strct = b.set_struct_member(strct, 0, 9i32);
+        b.get_struct_member(strct, 1);
+        let array = b.set_array(IntTy::I8.into(), [Value::Int(IntTy::I8, 1), Value::Int(IntTy::I8, 2)]);
+        let array = b.set_array_element(array, 1i64, 3i8);
+        b.get_array_element(array, wide);
+        b.set_array_splat(4, false);
+        b.set_array(Ty::Bool, Values::new());
+
+        let addr = b.get_var_addr(var);
+        let member = b.index_struct(pair, addr, 1);
+        b.store(member, text_ptr);
+        let loaded = b.load(Ty::Ptr, member);
+        let element = b.index_array(IntTy::I8, loaded, wide);
+        b.ptr_diff(IntTy::I64, IntTy::I8, element, loaded);
+        b.call(helper, [wide]);
+        b.call(puts, [text_ptr]);
+        let fun_ty = b.module.add_fun_ty(CallConvention::SysV, IntTy::I32.into(), vec![Ty::Ptr]);
+        b.call_ptr(fun_ty, fun_ptr, [text_ptr]);
+        let simple_ty = b.module.add_fun_ty(CallConvention::Simple, IntTy::I32.into(), vec![Ty::Ptr]);
+        b.call_ptr(simple_ty, fun_ptr, [text_ptr]);
+        b.syscall_linux64(IntTy::I64, 1i64, [Value::Int(IntTy::I64, 1), Value::Reg(text_ptr)]);
+        b.branch(cond, (loop_block, [Value::Reg(a), Value::Bool(true)]), exit);
+
+        b.select_block(loop_block);
+        let i = b.create_block_param(IntTy::I32);
+        b.create_block_param(Ty::Bool);
+        let next = b.add(i, 1i32);
+        b.jump((loop_block, [Value::Reg(next), Value::Bool(false)]));
+
+        b.select_block(exit);
+        b.ret(a);
+        let _ = entry;
+        let _ = main;
+        b.finish()
+    }
+
+    #[test]
+    fn round_trips_everything() {
+        let module = everything();
+        assert!(module.verify().is_empty(), "{:?}", module.verify());
+
+        let text = print(&module);
+        let parsed = parse(&text).unwrap_or_else(|e| panic!("{e}\n{text}"));
+        assert_eq!(print(&parsed), text);
+        assert!(parsed.verify().is_empty(), "{:?}", parsed.verify());
+
+        assert_eq!(parsed.globals().len(), module.globals().len());
+        for (parsed, global) in parsed.globals().iter().zip(module.globals()) {
+            assert_eq!(parsed, global);
+        }
+        for (parsed, fun) in parsed.functions().iter().zip(module.functions()) {
+            assert_eq!((&parsed.name, parsed.linkage, parsed.call_convention), (&fun.name, fun.linkage, fun.call_convention));
+            assert_eq!(parsed.variables.len(), fun.variables.len());
+            assert_eq!(parsed.blocks.len(), fun.blocks.len());
+        }
+    }
+
+    #[test]
+    fn optional_global_parts_stay_on_their_line() {
+        let module = parse("g0 = internal global i64\ng1 = internal global i64 5\ng2 = export constant bool x true\n").unwrap();
+        let globals = module.globals();
+        assert_eq!((&globals[0].name, &globals[0].value), (&None, &None));
+        assert_eq!((&globals[1].name, &globals[1].value), (&None, &Some(GlobalValue::Int(5))));
+        assert_eq!((globals[2].name.as_deref(), &globals[2].value), (Some("x"), &Some(GlobalValue::Bool(true))));
+        assert!(globals[2].constant);
+        assert_eq!(globals[2].linkage, Linkage::Export);
+    }
+
+    #[test]
+    fn reports_malformed_input() {
+        let error = |src: &str| parse(src).err().unwrap();
+
+        assert_eq!(error("g0 = internal global i64 \"open").message, "unterminated string");
+        assert_eq!(error("g0 = internal global i64 #").message, "unexpected character '#'");
+        assert_eq!(error("g1 = internal global i64").message, "global g1 is out of order");
+        assert_eq!(error("g0 = internal global ptr global_ptr g3").message, "unknown global g3");
+        assert_eq!(error("g0 = shared global i64").message, "expected a linkage, found Ident(\"shared\")");
+
+        let fun = |body: &str| format!("export fun f i64() {{\n  @0:\n{body}\n}}\n");
+        let e = error(&fun("    %0 i64 = frobnicate 1 i64\n    ret %0 i64"));
+        assert_eq!((e.line, e.message.as_str()), (3, "unknown instruction frobnicate"));
+        let e = error(&fun("    %0 i64 = add 1 i64, 2 i64\n    %1 i32 = add %0 i32, 2 i32\n    ret %0 i64"));
+        assert_eq!((e.line, e.message.as_str()), (4, "register %0 used with two types"));
+        let e = error(&fun("    %0 ptr = get_var_addr &0\n    ret 0 i64"));
+        assert_eq!(e.message, "variable &0 is not declared");
+        let e = error(&fun("    %0 i64 = call g(  )\n    ret %0 i64"));
+        assert_eq!(e.message, "unknown function g");
+        let e = error(&fun("    ret 0 i64\n    ret 1 i64"));
+        assert_eq!((e.line, e.message.as_str()), (4, "expected a block label, found Ident(\"ret\")"));
+        let e = error(&fun("    %0 i64 = add 1 bool, 2 i64\n    ret %0 i64"));
+        assert_eq!(e.message, "expected an integer type");
+        assert_eq!(error("export fun f i64() {\n  @0:\n    ret 0 i64\n").message, "expected '}', found end of input");
+        assert_eq!(error("export fun f i64();\nexport fun f i64();\n").message, "function f is defined twice");
+    }
+}
@@ -45,7 +45,13 @@ impl<'a, O: Write> Printer<'a, O> {
     fn print_global(&mut self, global: &Global) -> io::Result<()> {
         self.print_global_id(global.id)?;
         write!(self.out, " ")?;
-        write!(self.out, " = global ")?;
+        write!(self.out, " = ")?;
+        self.print_linkage(global.linkage)?;
+        if global.constant {
+            write!(self.out, " constant ")?;
+        } else {
+            write!(self.out, " global ")?;
+        }
         self.print_ty(global.ty)?;
         if let Some(name) = &global.name {
             write!(self.out, " {name}")?;
@@ -67,15 +73,40 @@ impl<'a, O: Write> Printer<'a, O> {
                 let src = src.escape_default();
                 write!(self.out, "\"{src}\"")?;
             }
+            GlobalValue::Struct(values) => self.print_global_values("{ ", values, " }")?,
+            GlobalValue::Array(values) => self.print_global_values("[ ", values, " ]")?,
+            GlobalValue::GlobalAddr(gid) => {
+                write!(self.out, "global_ptr ")?;
+                self.print_global_id(*gid)?;
+            }
+            GlobalValue::FunAddr(fid) => {
+                let name = &self.module[*fid].name;
+                write!(self.out, "fun_ptr {name}")?;
+            }
         }
 
         Ok(())
     }
 
+    fn print_global_values(&mut self, open: &str, values: &[GlobalValue], close: &str) -> io::Result<()> {
+        write!(self.out, "{open}")?;
+        for (i, value) in values.iter().enumerate() {
+            self.print_global_value(value)?;
+            if i != values.len() - 1 {
+                write!(self.out, ", ")?;
+            }
+        }
+        write!(self.out, "{close}")?;
+
+        Ok(())
+    }
+
     fn print_function(&mut self, fun: &'a Function) -> io::Result<()> {
         self.clear_names();
 
-        write!(self.out, "fun {} ", fun.name)?;
+        self.print_linkage(fun.linkage)?;
+        self.print_call_convention(fun.call_convention)?;
+        write!(self.out, " fun {} ", fun.name)?;
         self.print_ty(fun.ret_ty)?;
         write!(self.out, "( ")?;
         for (i, &param) in fun.parameters.iter().enumerate() {
@@ -92,6 +123,16 @@ impl<'a, O: Write> Printer<'a, O> {
         };
 
         writeln!(self.out, " {{")?;
+        let mut vars: Vec<_> = fun.variables.iter().copied().collect();
+        vars.sort_by_key(|v| v.0);
+        for var in vars {
+            write!(self.out, "  ")?;
+            self.print_var(var)?;
+            write!(self.out, " = var ")?;
+            self.print_ty(self.module[var].ty)?;
+            writeln!(self.out)?;
+        }
+
         let mut blocks = VecDeque::new();
         blocks.push_back(entry);
         let mut done = HashSet::new();
@@ -110,14 +151,11 @@ impl<'a, O: Write> Printer<'a, O> {
             let instructions = &self.module[block].instructions;
             for instruction in instructions {
                 self.print_instr(instruction)?;
-                if let Some(next) = instruction.next_blocks() {
-                    to_insert.push(next.0);
-                    if let Some(next) = next.1 {
-                        to_insert.push(next)
-                    }
+                if instruction.is_terminator() {
                     break;
                 }
             }
+            to_insert.extend(self.module[block].successors());
 
             writeln!(self.out)?;
 
@@ -127,6 +165,25 @@ impl<'a, O: Write> Printer<'a, O> {
                 }
             }
         }
+        writeln!(self.out, "}}")?;
+
+        Ok(())
+    }
+    fn print_linkage(&mut self, linkage: Linkage) -> io::Result<()> {
+        match linkage {
+            Linkage::Internal => write!(self.out, "internal")?,
+            Linkage::Export => write!(self.out, "export")?,
+            Linkage::Import => write!(self.out, "import")?,
+        }
+
+        Ok(())
+    }
+    /// The default convention is left out.
+    fn print_call_convention(&mut self, convention: CallConvention) -> io::Result<()> {
+        match convention {
+            CallConvention::Simple => (),
+            CallConvention::SysV => write!(self.out, " sysv")?,
+        }
 
         Ok(())
     }
@@ -204,9 +261,11 @@ impl<'a, O: Write> Printer<'a, O> {
                 write!(self.out, "call {name}")?;
                 self.print_values("( ", args, " )")?;
             }
-            CallPtr(dst, ptr, _fun_ty, ref args) => {
+            CallPtr(dst, ptr, fun_ty, ref args) => {
                 self.print_assign(dst)?;
-                write!(self.out, "call ")?;
+                write!(self.out, "call")?;
+                self.print_call_convention(self.module[fun_ty].call_convention)?;
+                write!(self.out, " ")?;
                 self.print_reg(ptr)?;
                 self.print_values("( ", args, " )")?;
             }
@@ -223,8 +282,28 @@ impl<'a, O: Write> Printer<'a, O> {
                 ref args,
             } => self.print_syscall_x86_64(dst, call_number, &args)?,
             GetStructMember { dst, strct, index } => self.print_get_struct_member(dst, strct, index)?,
+            SetStructMember {
+                dst,
+                strct,
+                value,
+                index,
+            } => self.print_set_struct_member(dst, strct, value, index)?,
+            GetArrayElement { dst, array, index } => {
+                self.print_get_array_element(dst, array, index)?
+            }
+            SetArrayElement {
+                dst,
+                array,
+                value,
+                index,
+            } => self.print_set_array_element(dst, array, value, index)?,
+            IndexStruct {
+                dst,
+                ptr,
+                struct_ty,
+                index,
+            } => self.print_index_struct(dst, ptr, struct_ty, index)?,
             PtrDiff(dst, ty, a, b) => self.print_ptr_diff(dst, ty, a, b)?,
-            ref or => write!(self.out, "UNPRINTABLE {or:?}")?,
         }
 
         writeln!(self.out)?;
@@ -366,6 +445,66 @@ impl<'a, O: Write> Printer<'a, O> {
 
         Ok(())
     }
+    fn print_set_struct_member(
+        &mut self,
+        dst: RegID,
+        strct: RegID,
+        value: Value,
+        index: u64,
+    ) -> io::Result<()> {
+        self.print_assign(dst)?;
+
+        write!(self.out, "set_struct_member ")?;
+        self.print_reg(strct)?;
+        write!(self.out, ", {index}, ")?;
+        self.print_value(value)?;
+
+        Ok(())
+    }
+    fn print_get_array_element(&mut self, dst: RegID, array: RegID, index: Value) -> io::Result<()> {
+        self.print_assign(dst)?;
+
+        write!(self.out, "get_array_element ")?;
+        self.print_reg(array)?;
+        write!(self.out, ", ")?;
+        self.print_value(index)?;
+
+        Ok(())
+    }
+    fn print_set_array_element(
+        &mut self,
+        dst: RegID,
+        array: RegID,
+        value: Value,
+        index: Value,
+    ) -> io::Result<()> {
+        self.print_assign(dst)?;
+
+        write!(self.out, "set_array_element ")?;
+        self.print_reg(array)?;
+        write!(self.out, ", ")?;
+        self.print_value(index)?;
+        write!(self.out, ", ")?;
+        self.print_value(value)?;
+
+        Ok(())
+    }
+    fn print_index_struct(
+        &mut self,
+        dst: RegID,
+        ptr: RegID,
+        struct_ty: StructTyID,
+        index: u64,
+    ) -> io::Result<()> {
+        self.print_assign(dst)?;
+        write!(self.out, "index_struct ")?;
+        self.print_ty(struct_ty)?;
+        write!(self.out, " ")?;
+        self.print_reg(ptr)?;
+        write!(self.out, ", {index}")?;
+
+        Ok(())
+    }
     fn print_ptr_diff(&mut self, dst: RegID, ty: Ty, a: RegID, b: RegID) -> io::Result<()> {
         self.print_assign(dst)?;
         write!(self.out, "ptrdiff ")?;
@@ -1,9 +1,10 @@
-use std::ops::Index;
+use std::ops::{Index, IndexMut};
 
 use crate::{layout::TyLayout, target::Target};
 
 use super::CallConvention;
 
+#[derive(Clone)]
 pub struct Types {
     func_types: Vec<FunTy>,
     array_types: Vec<ArrayTy>,
@@ -111,6 +112,11 @@ impl Index<StructTyID> for Types {
         &self.struct_types[index.0]
     }
 }
+impl IndexMut<StructTyID> for Types {
+    fn index_mut(&mut self, index: StructTyID) -> &mut Self::Output {
+        &mut self.struct_types[index.0]
+    }
+}
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Ty {
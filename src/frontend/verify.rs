@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+
+use super::*;
+
+impl Module {
+    /// Checks the module for type and structural errors,
+    /// returning every problem that was found.
+    pub fn verify(&self) -> Vec<Diagnostic> {
+        let mut verifier = Verifier {
+            module: self,
+            diagnostics: Vec::new(),
+            fun: None,
+            block: None,
+            instr: None,
+        };
+
+        for global in self.globals() {
+            verifier.verify_global(global);
+        }
+        for fun in self.functions() {
+            verifier.verify_function(fun);
+        }
+
+        verifier.diagnostics
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub fun: Option<FunID>,
+    pub block: Option<BlockID>,
+    pub instr: Option<usize>,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// An operand or result has a different type than required.
+    TypeMismatch { expected: Ty, found: Ty },
+    /// An operand or result is not of the kind the instruction works on,
+    /// e.g. a struct where an integer is needed.
+    InvalidType(Ty),
+    InvalidCast { from: Ty, to: Ty },
+    ArgCount { expected: usize, found: usize },
+    IndexOutOfBounds { index: u64, len: u64 },
+
+    UndefinedRegister(RegID),
+    RedefinedRegister(RegID),
+    ForeignRegister(RegID),
+    ForeignVariable(VarID),
+    ForeignBlock(BlockID),
+    EntryBlockParameters(BlockID),
+    MissingTerminator(BlockID),
+    InstructionAfterTerminator(BlockID),
+
+    /// A function body or global value that contradicts the linkage,
+    /// or a public symbol without a name.
+    InvalidFunLinkage(FunID),
+    InvalidGlobalLinkage(GlobalID),
+    /// A global's value, or a part of it, does not fit the type.
+    InvalidGlobalValue { global: GlobalID, ty: Ty },
+    UndefinedGlobal(GlobalID),
+    UndefinedFunction(FunID),
+}
+
+struct Verifier<'a> {
+    module: &'a Module,
+    diagnostics: Vec<Diagnostic>,
+
+    fun: Option<FunID>,
+    block: Option<BlockID>,
+    instr: Option<usize>,
+}
+impl Verifier<'_> {
+    fn verify_global(&mut self, global: &Global) {
+        self.fun = None;
+        self.block = None;
+        self.instr = None;
+
+        let valid = match global.linkage {
+            Linkage::Internal => true,
+            Linkage::Export => global.name.is_some(),
+            Linkage::Import => global.name.is_some() && global.value.is_none(),
+        };
+        if !valid {
+            self.report(DiagnosticKind::InvalidGlobalLinkage(global.id));
+        }
+
+        if let Some(value) = &global.value {
+            self.check_global_value(global.id, global.ty, value);
+        }
+    }
+    fn check_global_value(&mut self, global: GlobalID, ty: Ty, value: &GlobalValue) {
+        let valid = match (ty, value) {
+            (Ty::Bool, GlobalValue::Bool(_)) => true,
+            (Ty::Int(_), GlobalValue::Int(_)) => true,
+            // A string may start at a single byte and run past it.
+            (Ty::Int(IntTy::I8), GlobalValue::String(_)) => true,
+            (Ty::Array(aid), GlobalValue::String(src)) => {
+                let array = &self.module[aid];
+                array.element == Ty::Int(IntTy::I8) && src.len() as u64 <= array.size
+            }
+            (Ty::Ptr, &GlobalValue::GlobalAddr(gid)) => {
+                if gid.0 >= self.module.globals().len() {
+                    self.report(DiagnosticKind::UndefinedGlobal(gid));
+                }
+                true
+            }
+            (Ty::Ptr, &GlobalValue::FunAddr(fid)) => {
+                if fid.0 >= self.module.functions().len() {
+                    self.report(DiagnosticKind::UndefinedFunction(fid));
+                }
+                true
+            }
+            (Ty::Struct(sid), GlobalValue::Struct(values)) => {
+                let members = &self.module[sid].members;
+                if members.len() == values.len() {
+                    for (&member, value) in members.iter().zip(values) {
+                        self.check_global_value(global, member, value);
+                    }
+                    true
+                }
+                else {
+                    false
+                }
+            }
+            (Ty::Array(aid), GlobalValue::Array(values)) => {
+                let array = &self.module[aid];
+                if array.size == values.len() as u64 {
+                    for value in values {
+                        self.check_global_value(global, array.element, value);
+                    }
+                    true
+                }
+                else {
+                    false
+                }
+            }
+            _ => false,
+        };
+
+        if !valid {
+            self.report(DiagnosticKind::InvalidGlobalValue { global, ty });
+        }
+    }
+    fn verify_function(&mut self, fun: &Function) {
+        self.fun = Some(fun.id);
+        self.block = None;
+        self.instr = None;
+
+        // Functions without a body are always imported,
+        // but an internal one could never be resolved.
+        let valid = match fun.linkage {
+            Linkage::Internal => fun.entry_block.is_some(),
+            Linkage::Export => true,
+            Linkage::Import => fun.entry_block.is_none(),
+        };
+        if !valid {
+            self.report(DiagnosticKind::InvalidFunLinkage(fun.id));
+        }
+
+        let mut defs: HashMap<RegID, usize> = HashMap::new();
+        for &param in &fun.parameters {
+            self.check_reg(param);
+            *defs.entry(param).or_default() += 1;
+        }
+
+        let mut blocks: Vec<_> = fun.blocks.iter().copied().collect();
+        blocks.sort_by_key(|b| b.0);
+
+        for &block in &blocks {
+            self.block = Some(block);
+            self.instr = None;
+            if self.module.block(block).is_none_or(|b| b.fun != fun.id) {
+                self.report(DiagnosticKind::ForeignBlock(block));
+                continue;
+            }
+
+            for &param in &self.module[block].parameters {
+                self.check_reg(param);
+                *defs.entry(param).or_default() += 1;
+            }
+            let instructions = &self.module[block].instructions;
+            for (i, instr) in instructions.iter().enumerate() {
+                self.instr = Some(i);
+                if let Some(dst) = instr.dst() {
+                    *defs.entry(dst).or_default() += 1;
+                }
+                self.verify_instr(fun, instr);
+            }
+
+            for (instr, kind) in terminator_diagnostics(&self.module[block]) {
+                self.instr = instr;
+                self.report(kind);
+            }
+        }
+
+        self.block = None;
+        self.instr = None;
+        if let Some(entry) = fun.entry_block
+            && let Some(block) = self.module.block(entry)
+            && !block.parameters.is_empty()
+        {
+            self.report(DiagnosticKind::EntryBlockParameters(entry));
+        }
+
+        let mut redefined: Vec<_> = defs.iter().filter(|&(_, &n)| n > 1).map(|(&r, _)| r).collect();
+        redefined.sort_by_key(|r| r.0);
+        for reg in redefined {
+            self.report(DiagnosticKind::RedefinedRegister(reg));
+        }
+
+        for &block in &blocks {
+            self.block = Some(block);
+            let Some(block) = self.module.block(block) else {
+                continue;
+            };
+            for (i, instr) in block.instructions.iter().enumerate() {
+                self.instr = Some(i);
+                for reg in instr.uses() {
+                    if self.module[reg].fun == fun.id && !defs.contains_key(&reg) {
+                        self.report(DiagnosticKind::UndefinedRegister(reg));
+                    }
+                }
+            }
+        }
+    }
+
+    fn verify_instr(&mut self, fun: &Function, instr: &Instruction) {
+        use Instruction::*;
+
+        if let Some(dst) = instr.dst() {
+            self.check_reg(dst);
+        }
+        for reg in instr.uses() {
+            self.check_reg(reg);
+        }
+
+        match *instr {
+            Set(dst, value) => self.expect_value(value, self.ty(dst)),
+            SetFunPtr(dst, _) | SetGlobalPtr(dst, _) => self.expect(self.ty(dst), Ty::Ptr),
+            SetStruct(dst, ref values) => {
+                let Ty::Struct(sty) = self.ty(dst) else {
+                    return self.report(DiagnosticKind::InvalidType(self.ty(dst)));
+                };
+                let members = &self.module[sty].members;
+                self.expect_args(values, members);
+            }
+            SetArray(dst, ref values) => {
+                let Ty::Array(aty) = self.ty(dst) else {
+                    return self.report(DiagnosticKind::InvalidType(self.ty(dst)));
+                };
+                let ArrayTy { size, element } = self.module[aty];
+                if values.len() as u64 != size {
+                    self.report(DiagnosticKind::ArgCount {
+                        expected: size as usize,
+                        found: values.len(),
+                    });
+                }
+                for &value in values {
+                    self.expect_value(value, element);
+                }
+            }
+            SetArraySplat(dst, value) => {
+                let Ty::Array(aty) = self.ty(dst) else {
+                    return self.report(DiagnosticKind::InvalidType(self.ty(dst)));
+                };
+                self.expect_value(value, self.module[aty].element);
+            }
+
+            Binary(op, dst, a, b) => self.verify_binary(op, dst, a, b),
+            Unary(op, dst, a) => self.verify_unary(op, dst, a),
+
+            Poison(_) => (),
+            Select(dst, c, a, b) => {
+                self.expect_value(c, Ty::Bool);
+                self.expect_value(a, self.ty(dst));
+                self.expect_value(b, self.ty(dst));
+            }
+            Freeze(dst, a) => self.expect_value(a, self.ty(dst)),
+
+            GetVarAddr(dst, var) => {
+                self.expect(self.ty(dst), Ty::Ptr);
+                if self.module[var].fun != fun.id || !fun.variables.contains(&var) {
+                    self.report(DiagnosticKind::ForeignVariable(var));
+                }
+            }
+            Store { ptr, value: _ } => self.expect(self.ty(ptr), Ty::Ptr),
+            Load { dst: _, ptr } => self.expect(self.ty(ptr), Ty::Ptr),
+            PtrDiff(dst, _, a, b) => {
+                self.expect_int(self.ty(dst));
+                self.expect(self.ty(a), Ty::Ptr);
+                self.expect(self.ty(b), Ty::Ptr);
+            }
+
+            Jump(ref tgt) => self.verify_jump_target(fun, tgt),
+            Branch(c, ref t, ref f) => {
+                self.expect_value(c, Ty::Bool);
+                self.verify_jump_target(fun, t);
+                self.verify_jump_target(fun, f);
+            }
+            Ret(value) => self.expect_value(value, fun.ret_ty),
+            Call(dst, fid, ref args) => {
+                let Some(callee) = self.module.functions().get(fid.0) else {
+                    return self.report(DiagnosticKind::UndefinedFunction(fid));
+                };
+                let params: Vec<_> = callee.parameters.iter().map(|&p| self.ty(p)).collect();
+                self.expect_args(args, &params);
+                self.expect(self.ty(dst), callee.ret_ty);
+            }
+            CallPtr(dst, ptr, fun_ty, ref args) => {
+                self.expect(self.ty(ptr), Ty::Ptr);
+                let fun_ty = &self.module[fun_ty];
+                self.expect_args(args, &fun_ty.params);
+                self.expect(self.ty(dst), fun_ty.ret);
+            }
+
+            GetStructMember { dst, strct, index } => {
+                if let Some(member) = self.struct_member(self.ty(strct), index) {
+                    self.expect(self.ty(dst), member);
+                }
+            }
+            SetStructMember {
+                dst,
+                strct,
+                value,
+                index,
+            } => {
+                self.expect(self.ty(dst), self.ty(strct));
+                if let Some(member) = self.struct_member(self.ty(strct), index) {
+                    self.expect_value(value, member);
+                }
+            }
+            GetArrayElement { dst, array, index } => {
+                self.expect_int(index.ty(self.module));
+                if let Some(element) = self.array_element(self.ty(array)) {
+                    self.expect(self.ty(dst), element);
+                }
+            }
+            SetArrayElement {
+                dst,
+                array,
+                value,
+                index,
+            } => {
+                self.expect(self.ty(dst), self.ty(array));
+                self.expect_int(index.ty(self.module));
+                if let Some(element) = self.array_element(self.ty(array)) {
+                    self.expect_value(value, element);
+                }
+            }
+            IndexStruct {
+                dst,
+                ptr,
+                struct_ty,
+                index,
+            } => {
+                self.expect(self.ty(dst), Ty::Ptr);
+                self.expect(self.ty(ptr), Ty::Ptr);
+                self.struct_member(struct_ty.into(), index);
+            }
+            IndexArray {
+                dst,
+                ptr,
+                element_ty: _,
+                index,
+            } => {
+                self.expect(self.ty(dst), Ty::Ptr);
+                self.expect(self.ty(ptr), Ty::Ptr);
+                self.expect_int(index.ty(self.module));
+            }
+
+            SyscallLinux64 {
+                dst,
+                call_number,
+                ref args,
+            } => {
+                self.expect_int(call_number.ty(self.module));
+                if args.len() > 6 {
+                    self.report(DiagnosticKind::ArgCount {
+                        expected: 6,
+                        found: args.len(),
+                    });
+                }
+                for &arg in args {
+                    self.expect_int_or_ptr(arg.ty(self.module));
+                }
+                self.expect_int_or_ptr(self.ty(dst));
+            }
+        }
+    }
+    fn verify_binary(&mut self, op: BinOp, dst: RegID, a: Value, b: Value) {
+        use BinOp::*;
+
+        let a_ty = a.ty(self.module);
+        match op {
+            Add | Sub | Mul | IDiv | UDiv | IMod | UMod | And | Or | Xor | Shl | Shr | Sar => {
+                self.expect_int(self.ty(dst));
+                self.expect(a_ty, self.ty(dst));
+            }
+            Equal | NotEqual | Greater | GreaterEqual | Less | LessEqual | Above | AboveEqual
+            | Below | BelowEqual => {
+                self.expect(self.ty(dst), Ty::Bool);
+                self.expect_int(a_ty);
+            }
+        }
+        self.expect_value(b, a_ty);
+    }
+    fn verify_unary(&mut self, op: UnOp, dst: RegID, a: Value) {
+        let from = a.ty(self.module);
+        let to = self.ty(dst);
+
+        let valid = match (op, from, to) {
+            (UnOp::Neg, Ty::Int(_), _) => from == to,
+            (UnOp::Not, Ty::Int(_) | Ty::Bool, _) => from == to,
+            (UnOp::IntToPtr, Ty::Int(_), Ty::Ptr) => true,
+            (UnOp::PtrToInt, Ty::Ptr, Ty::Int(_)) => true,
+            (UnOp::Sext, Ty::Int(from), Ty::Int(to)) => int_width(from) <= int_width(to),
+            (UnOp::Zext, Ty::Int(from), Ty::Int(to)) => int_width(from) <= int_width(to),
+            (UnOp::Zext, Ty::Bool, Ty::Int(_)) => true,
+            (UnOp::Trunc, Ty::Int(from), Ty::Int(to)) => int_width(from) >= int_width(to),
+            _ => false,
+        };
+
+        if !valid {
+            self.report(DiagnosticKind::InvalidCast { from, to });
+        }
+    }
+    fn verify_jump_target(&mut self, fun: &Function, tgt: &JumpTarget) {
+        let Some(block) = self.module.block(tgt.block).filter(|b| b.fun == fun.id && fun.blocks.contains(&b.id)) else {
+            return self.report(DiagnosticKind::ForeignBlock(tgt.block));
+        };
+
+        let params: Vec<_> = block
+            .parameters
+            .iter()
+            .map(|&p| self.ty(p))
+            .collect();
+        self.expect_args(&tgt.args, &params);
+    }
+
+    fn struct_member(&mut self, ty: Ty, index: u64) -> Option<Ty> {
+        let Ty::Struct(sty) = ty else {
+            self.report(DiagnosticKind::InvalidType(ty));
+            return None;
+        };
+
+        let members = &self.module[sty].members;
+        let member = members.get(index as usize).copied();
+        if member.is_none() {
+            self.report(DiagnosticKind::IndexOutOfBounds {
+                index,
+                len: members.len() as u64,
+            });
+        }
+        member
+    }
+    fn array_element(&mut self, ty: Ty) -> Option<Ty> {
+        let Ty::Array(aty) = ty else {
+            self.report(DiagnosticKind::InvalidType(ty));
+            return None;
+        };
+        Some(self.module[aty].element)
+    }
+
+    fn check_reg(&mut self, reg: RegID) {
+        if Some(self.module[reg].fun) != self.fun {
+            self.report(DiagnosticKind::ForeignRegister(reg));
+        }
+    }
+    fn expect_args(&mut self, args: &Values, params: &[Ty]) {
+        if args.len() != params.len() {
+            self.report(DiagnosticKind::ArgCount {
+                expected: params.len(),
+                found: args.len(),
+            });
+        }
+        for (&arg, &param) in args.0.iter().zip(params) {
+            self.expect_value(arg, param);
+        }
+    }
+    fn expect_value(&mut self, value: Value, expected: Ty) {
+        self.expect(value.ty(self.module), expected);
+    }
+    fn expect(&mut self, found: Ty, expected: Ty) {
+        if found != expected {
+            self.report(DiagnosticKind::TypeMismatch { expected, found });
+        }
+    }
+    fn expect_int(&mut self, found: Ty) {
+        if !matches!(found, Ty::Int(_)) {
+            self.report(DiagnosticKind::InvalidType(found));
+        }
+    }
+    fn expect_int_or_ptr(&mut self, found: Ty) {
+        if !matches!(found, Ty::Int(_) | Ty::Ptr) {
+            self.report(DiagnosticKind::InvalidType(found));
+        }
+    }
+
+    fn ty(&self, reg: RegID) -> Ty {
+        self.module[reg].ty
+    }
+    fn report(&mut self, kind: DiagnosticKind) {
+        self.diagnostics.push(Diagnostic {
+            fun: self.fun,
+            block: self.block,
+            instr: self.instr,
+            kind,
+        });
+    }
+}
+
+/// Where a block breaks the rule that it ends in its only terminator,
+/// with the index of every misplaced terminator.
+/// The backend relies on this rule, so it checks blocks the same way.
+pub(crate) fn terminator_diagnostics(block: &Block) -> Vec<(Option<usize>, DiagnosticKind)> {
+    let last = block.instructions.len().saturating_sub(1);
+    let mut diagnostics: Vec<_> = block
+        .instructions
+        .iter()
+        .enumerate()
+        .filter(|&(i, instr)| instr.is_terminator() && i != last)
+        .map(|(i, _)| (Some(i), DiagnosticKind::InstructionAfterTerminator(block.id)))
+        .collect();
+    if !block.is_terminated() {
+        diagnostics.push((None, DiagnosticKind::MissingTerminator(block.id)));
+    }
+    diagnostics
+}
+
+fn int_width(ty: IntTy) -> u32 {
+    match ty {
+        IntTy::I8 => 8,
+        IntTy::I16 => 16,
+        IntTy::I32 => 32,
+        IntTy::I64 => 64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::Target;
+
+    /// Starts a module with `callee(i64) -> i64` and the entry block of `f() -> i64`.
+    fn begin() -> (Builder, FunID, FunID) {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let callee = b.begin_fun("callee".into(), IntTy::I64);
+        let x = b.create_param(IntTy::I64);
+        b.begin_block();
+        b.set_entry_block();
+        b.ret(x);
+
+        let f = b.begin_fun("f".into(), IntTy::I64);
+        b.begin_block();
+        b.set_entry_block();
+        (b, callee, f)
+    }
+    fn diagnostics(b: Builder) -> Vec<(Option<usize>, DiagnosticKind)> {
+        b.finish().verify().into_iter().map(|d| (d.instr, d.kind)).collect()
+    }
+
+    #[test]
+    fn type_mismatch() {
+        let (mut b, _, _) = begin();
+        b.ret(1i32);
+        let expected = DiagnosticKind::TypeMismatch {
+            expected: IntTy::I64.into(),
+            found: IntTy::I32.into(),
+        };
+        assert_eq!(diagnostics(b), [(Some(0), expected)]);
+    }
+
+    #[test]
+    fn wrong_arg_count() {
+        let (mut b, callee, _) = begin();
+        let result = b.call(callee, Values::new());
+        b.ret(result);
+        assert_eq!(diagnostics(b), [(Some(0), DiagnosticKind::ArgCount { expected: 1, found: 0 })]);
+
+        let (mut b, _, _) = begin();
+        // Far too large to spell out element by element.
+        let huge = b.module.add_array_ty(1 << 40, IntTy::I8.into());
+        let dst = b.create_reg(huge);
+        b.add_instr(Instruction::SetArray(dst, [Value::Int(IntTy::I8, 1)].into()));
+        b.ret(0i64);
+        assert_eq!(diagnostics(b), [(Some(0), DiagnosticKind::ArgCount { expected: 1 << 40, found: 1 })]);
+    }
+
+    #[test]
+    fn redefined_register() {
+        let (mut b, _, _) = begin();
+        let reg = b.create_reg(IntTy::I64);
+        b.add_instr(Instruction::Set(reg, Value::Int(IntTy::I64, 1)));
+        b.add_instr(Instruction::Set(reg, Value::Int(IntTy::I64, 2)));
+        b.ret(reg);
+        assert_eq!(diagnostics(b), [(None, DiagnosticKind::RedefinedRegister(reg))]);
+    }
+
+    #[test]
+    fn register_from_another_function() {
+        let (mut b, callee, _) = begin();
+        let foreign = b.module.add_register(callee, IntTy::I64.into());
+        b.ret(foreign);
+        assert_eq!(diagnostics(b), [(Some(0), DiagnosticKind::ForeignRegister(foreign))]);
+    }
+
+    #[test]
+    fn undefined_register() {
+        let (mut b, _, _) = begin();
+        let reg = b.create_reg(IntTy::I64);
+        b.ret(reg);
+        assert_eq!(diagnostics(b), [(Some(0), DiagnosticKind::UndefinedRegister(reg))]);
+    }
+
+    #[test]
+    fn missing_terminator() {
+        let (mut b, _, f) = begin();
+        b.set(1i64);
+        let entry = b.module[f].entry_block.unwrap();
+        assert_eq!(diagnostics(b), [(None, DiagnosticKind::MissingTerminator(entry))]);
+    }
+
+    #[test]
+    fn instruction_after_terminator() {
+        let (mut b, _, f) = begin();
+        b.ret(0i64);
+        let mut module = b.finish();
+        let entry = module[f].entry_block.unwrap();
+        module[entry].instructions.push(Instruction::Ret(Value::Int(IntTy::I64, 1)));
+
+        let diagnostics: Vec<_> = module.verify().into_iter().map(|d| (d.instr, d.kind)).collect();
+        assert_eq!(diagnostics, [(Some(0), DiagnosticKind::InstructionAfterTerminator(entry))]);
+
+        let reg = module.add_register(f, IntTy::I64.into());
+        module[entry].instructions.push(Instruction::Set(reg, Value::Int(IntTy::I64, 1)));
+        let kinds: Vec<_> = module.verify().into_iter().map(|d| d.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                DiagnosticKind::InstructionAfterTerminator(entry),
+                DiagnosticKind::InstructionAfterTerminator(entry),
+                DiagnosticKind::MissingTerminator(entry),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "after its terminator")]
+    fn builder_rejects_instructions_after_the_terminator() {
+        let (mut b, _, _) = begin();
+        b.ret(0i64);
+        b.set(1i64);
+    }
+
+    #[test]
+    fn bad_jump_target() {
+        let (mut b, callee, _) = begin();
+        let foreign = b.module[callee].entry_block.unwrap();
+        b.jump(foreign);
+        assert_eq!(diagnostics(b), [(Some(0), DiagnosticKind::ForeignBlock(foreign))]);
+
+        let (mut b, _, _) = begin();
+        b.jump(BlockID(usize::MAX));
+        assert_eq!(diagnostics(b), [(Some(0), DiagnosticKind::ForeignBlock(BlockID(usize::MAX)))]);
+    }
+}
@@ -0,0 +1,759 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    frontend::{
+        ArrayTy, ArrayTyID, BinOp, BlockID, Builder, CallConvention, FunID, Instruction, IntTy, Linkage,
+        Module, Printer, RegID, StructTyID, Ty, Value, VarID,
+    },
+    target::Target,
+};
+
+const INT_TYS: [IntTy; 4] = [IntTy::I8, IntTy::I16, IntTy::I32, IntTy::I64];
+const BINOPS: [BinOp; 23] = [
+    BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::IDiv, BinOp::UDiv, BinOp::IMod, BinOp::UMod,
+    BinOp::And, BinOp::Or, BinOp::Xor, BinOp::Shl, BinOp::Shr, BinOp::Sar,
+    BinOp::Equal, BinOp::NotEqual, BinOp::Greater, BinOp::GreaterEqual, BinOp::Less, BinOp::LessEqual,
+    BinOp::Above, BinOp::AboveEqual, BinOp::Below, BinOp::BelowEqual,
+];
+
+/// A generated module and the function that runs it.
+pub struct Program {
+    pub module: Module,
+    /// Takes no parameters, returns an `i64` and writes intermediate results to stdout.
+    pub entry: FunID,
+}
+/// Prints the module as IR, so shrunk proptest failures are readable.
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = Vec::new();
+        Printer::new(&self.module, &mut text).print().map_err(|_| fmt::Error)?;
+        writeln!(f, "entry {:?}", self.entry)?;
+        f.write_str(&String::from_utf8_lossy(&text))
+    }
+}
+
+/// Generates random, well-typed modules for hunting crashes and miscompiles in `backend_86`.
+///
+/// Generation is deterministic: the same seed or input bytes always give the same module.
+/// Programs only use defined behaviour apart from the odd division by zero,
+/// which `DiffTest` expects to fault natively too.
+/// Control flow is structured, loops run a constant number of times
+/// and functions only call functions generated before them, so every program terminates.
+/// Memory is only touched inside variables, and no address is ever observed.
+///
+/// Mapping `any::<u64>()` through `Generator::new` or byte vectors through `Generator::from_bytes`
+/// gives a proptest strategy; the tests below use the latter, so shrinking simplifies the choices.
+pub struct Generator {
+    rng: Rng,
+    functions: usize,
+    instructions: usize,
+    depth: usize,
+}
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Self::with_rng(Rng::new(seed, Vec::new()))
+    }
+    /// Takes the generator's choices from `data` and continues pseudo-randomly once it runs out.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        // FNV-1a, so different inputs continue differently.
+        let seed = data.iter().fold(0xCBF2_9CE4_8422_2325, |hash: u64, &byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01B3));
+        Self::with_rng(Rng::new(seed, data.to_vec()))
+    }
+    fn with_rng(rng: Rng) -> Self {
+        Self {
+            rng,
+            functions: 4,
+            instructions: 8,
+            depth: 2,
+        }
+    }
+
+    /// At most this many functions besides the entry.
+    pub fn with_functions(mut self, functions: usize) -> Self {
+        self.functions = functions;
+        self
+    }
+    /// At most this many instructions in a row without control flow.
+    pub fn with_instructions(mut self, instructions: usize) -> Self {
+        self.instructions = instructions.max(1);
+        self
+    }
+    /// How deeply branches and loops may nest.
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    pub fn generate(self) -> Program {
+        let mut state = State {
+            rng: self.rng,
+            b: Builder::new(Module::new(Target::LINUX_X64)),
+            max_instructions: self.instructions,
+            max_depth: self.depth,
+            callees: Vec::new(),
+            vars: Vec::new(),
+            out_vars: HashMap::new(),
+            emit: false,
+            loops: 0,
+        };
+
+        let functions = state.rng.below(self.functions as u64 + 1);
+        for i in 0..functions {
+            let fid = state.function(format!("f{i}"));
+            state.callees.push(fid);
+        }
+        let entry = state.entry();
+
+        Program {
+            module: state.b.finish(),
+            entry,
+        }
+    }
+}
+
+/// The values a piece of code may use: the ones defined in blocks that dominate it.
+#[derive(Clone, Default)]
+struct Pool {
+    /// Booleans and integers.
+    scalars: Vec<RegID>,
+    /// Structs and arrays.
+    aggregates: Vec<RegID>,
+    /// Pointers made from integers, which are never dereferenced.
+    fake_ptrs: Vec<RegID>,
+}
+
+/// One step from an aggregate to one of its members.
+#[derive(Copy, Clone)]
+enum Step {
+    Member(StructTyID, u64),
+    Element(ArrayTyID),
+}
+
+struct State {
+    rng: Rng,
+    b: Builder,
+    max_instructions: usize,
+    max_depth: usize,
+    /// The functions generated so far, which later functions may call.
+    callees: Vec<FunID>,
+    /// The current function's variables.
+    vars: Vec<VarID>,
+    /// The entry's variables that values are written to stdout from, one per type.
+    out_vars: HashMap<Ty, VarID>,
+    /// Whether the current function writes values to stdout.
+    emit: bool,
+    /// How many loops the current code is nested in.
+    loops: usize,
+}
+impl State {
+    fn function(&mut self, name: String) -> FunID {
+        let ret = self.scalar_ty();
+        let fid = self.b.begin_fun(name, ret);
+        self.b.set_linkage(Linkage::Internal);
+        let mut pool = Pool::default();
+        for _ in 0..self.rng.below(5) {
+            let ty = self.scalar_ty();
+            pool.scalars.push(self.b.create_param(ty));
+        }
+        self.body(pool, ret);
+        fid
+    }
+    fn entry(&mut self) -> FunID {
+        let fid = self.b.begin_fun("main".into(), IntTy::I64);
+        self.out_vars.clear();
+        self.emit = true;
+        self.body(Pool::default(), IntTy::I64.into());
+        fid
+    }
+    fn body(&mut self, mut pool: Pool, ret: Ty) {
+        self.b.begin_block();
+        self.b.set_entry_block();
+
+        // Variables start out initialized, so loads never see stale stack contents.
+        self.vars.clear();
+        for _ in 0..self.rng.below(3) {
+            let ty = self.any_ty(2);
+            let var = self.b.create_var(ty);
+            let ptr = self.b.get_var_addr(var);
+            let value = self.value(&pool, ty);
+            self.b.store(ptr, value);
+            self.vars.push(var);
+        }
+
+        self.region(&mut pool, 0);
+        if self.emit {
+            for _ in 0..self.rng.below(4) {
+                let value = self.any_scalar(&pool);
+                self.emit(value);
+            }
+        }
+        let value = self.operand(&pool, ret);
+        self.b.ret(value);
+    }
+
+    /// Generates a sequence of straight-line code, branches and loops.
+    fn region(&mut self, pool: &mut Pool, depth: usize) {
+        for _ in 0..self.rng.range(1, 3) {
+            match self.rng.below(if depth < self.max_depth { 4 } else { 1 }) {
+                0 | 1 => self.straight(pool),
+                2 => self.diamond(pool, depth),
+                _ => self.loop_(pool, depth),
+            }
+        }
+    }
+    fn straight(&mut self, pool: &mut Pool) {
+        for _ in 0..self.rng.range(1, self.max_instructions as u64) {
+            self.instruction(pool);
+        }
+    }
+    /// Branches into two arms that meet again, passing values to the join block.
+    /// Sometimes one arm is empty, and the branch passes its values itself.
+    fn diamond(&mut self, pool: &mut Pool, depth: usize) {
+        let c = self.operand(pool, Ty::Bool);
+        let join_tys: Vec<Ty> = (0..self.rng.range(1, 3)).map(|_| self.scalar_ty()).collect();
+        let else_block = self.b.create_block();
+        let join = self.b.create_block();
+
+        if self.rng.chance(1, 3) {
+            let args = self.operands(pool, &join_tys);
+            self.b.branch(c, (join, args), else_block);
+        }
+        else {
+            let then_block = self.b.create_block();
+            self.b.branch(c, then_block, else_block);
+            self.arm(pool, depth, then_block, join, &join_tys);
+        }
+        self.arm(pool, depth, else_block, join, &join_tys);
+
+        self.b.select_block(join);
+        for ty in join_tys {
+            pool.scalars.push(self.b.create_block_param(ty));
+        }
+    }
+    fn arm(&mut self, pool: &Pool, depth: usize, block: BlockID, join: BlockID, join_tys: &[Ty]) {
+        self.b.select_block(block);
+        let mut arm_pool = pool.clone();
+        self.region(&mut arm_pool, depth + 1);
+        let args = self.operands(&arm_pool, join_tys);
+        self.b.jump((join, args));
+    }
+    /// A counted loop carrying values around in block parameters.
+    fn loop_(&mut self, pool: &mut Pool, depth: usize) {
+        let counter_ty = *self.rng.pick(&INT_TYS);
+        let trips = self.rng.below(5) as i64;
+        let acc_tys: Vec<Ty> = (0..self.rng.range(1, 3)).map(|_| self.scalar_ty()).collect();
+        let header = self.b.create_block();
+        let body = self.b.create_block();
+        let exit = self.b.create_block();
+
+        let mut init = vec![Value::Int(counter_ty, 0)];
+        init.extend(self.operands(pool, &acc_tys));
+        self.b.jump((header, init));
+
+        self.b.select_block(header);
+        let i = self.b.create_block_param(counter_ty);
+        let accs: Vec<RegID> = acc_tys.iter().map(|&ty| self.b.create_block_param(ty)).collect();
+        let go = self.b.test_l(i, Value::Int(counter_ty, trips));
+        self.b.branch(go, body, (exit, accs.as_slice()));
+
+        self.b.select_block(body);
+        let mut body_pool = pool.clone();
+        body_pool.scalars.push(i);
+        body_pool.scalars.extend(&accs);
+        self.loops += 1;
+        self.region(&mut body_pool, depth + 1);
+        self.loops -= 1;
+        let next = self.b.add(i, Value::Int(counter_ty, 1));
+        let mut args = vec![Value::Reg(next)];
+        args.extend(self.operands(&body_pool, &acc_tys));
+        self.b.jump((header, args));
+
+        self.b.select_block(exit);
+        for ty in acc_tys {
+            pool.scalars.push(self.b.create_block_param(ty));
+        }
+    }
+
+    fn instruction(&mut self, pool: &mut Pool) {
+        match self.rng.below(12) {
+            0..=3 => {
+                let reg = self.binary(pool);
+                pool.scalars.push(reg);
+            }
+            4 | 5 => {
+                let reg = self.unary(pool);
+                pool.scalars.push(reg);
+            }
+            6 => {
+                let ty = self.scalar_ty();
+                let c = self.operand(pool, Ty::Bool);
+                let a = self.operand(pool, ty);
+                let b = self.operand(pool, ty);
+                pool.scalars.push(self.b.select(c, a, b));
+            }
+            7 => {
+                let a = self.any_scalar(pool);
+                pool.scalars.push(self.b.freeze(a));
+            }
+            8 => self.call(pool),
+            9 => self.memory(pool),
+            10 => self.aggregate(pool),
+            _ => {
+                if self.emit {
+                    let value = self.any_scalar(pool);
+                    self.emit(value);
+                }
+                else {
+                    self.pointer(pool);
+                }
+            }
+        }
+    }
+    fn binary(&mut self, pool: &Pool) -> RegID {
+        let ty = *self.rng.pick(&INT_TYS);
+        let op = *self.rng.pick(&BINOPS);
+        let mut a = self.operand(pool, ty.into());
+        let mut b = self.operand(pool, ty.into());
+        match op {
+            // A cleared sign bit rules out MIN / -1, a set low bit rules out division by zero.
+            BinOp::IDiv | BinOp::IMod => {
+                a = self.b.shr(a, Value::Int(ty, 1)).into();
+                b = self.b.or(b, Value::Int(ty, 1)).into();
+            }
+            BinOp::UDiv | BinOp::UMod => b = self.b.or(b, Value::Int(ty, 1)).into(),
+            _ => (),
+        }
+
+        let dst_ty = match op {
+            BinOp::Equal | BinOp::NotEqual | BinOp::Greater | BinOp::GreaterEqual | BinOp::Less
+            | BinOp::LessEqual | BinOp::Above | BinOp::AboveEqual | BinOp::Below | BinOp::BelowEqual => Ty::Bool,
+            _ => ty.into(),
+        };
+        let dst = self.b.create_reg(dst_ty);
+        self.b.add_instr(Instruction::Binary(op, dst, a, b));
+        dst
+    }
+    fn unary(&mut self, pool: &Pool) -> RegID {
+        let a = self.any_scalar(pool);
+        let Ty::Int(from) = a.ty(&self.b.module) else {
+            return match self.rng.below(2) {
+                0 => self.b.not(a),
+                _ => {
+                    let to = *self.rng.pick(&INT_TYS);
+                    self.b.zext(to, a)
+                }
+            };
+        };
+
+        let to = *self.rng.pick(&INT_TYS);
+        let widens = width(from) <= width(to);
+        match self.rng.below(4) {
+            0 => self.b.neg(a),
+            1 => self.b.not(a),
+            _ if !widens => self.b.trunc(to, a),
+            2 => self.b.sext(to, a),
+            _ => self.b.zext(to, a),
+        }
+    }
+    fn call(&mut self, pool: &mut Pool) {
+        // Calls in loops would multiply the running time with every level of nesting.
+        if self.loops > 0 || self.callees.is_empty() { return };
+
+        let callee = *self.rng.pick(&self.callees);
+        let ret = self.b.module[callee].ret_ty;
+        let params: Vec<Ty> = self.b.module[callee].parameters.iter().map(|&p| self.b.module[p].ty).collect();
+        let args = self.operands(pool, &params);
+        let result = if self.rng.chance(1, 3) {
+            let fun_ty = self.b.module.add_fun_ty(CallConvention::Simple, ret, params);
+            let ptr = self.b.set_fun_ptr(callee);
+            self.b.call_ptr(fun_ty, ptr, args)
+        }
+        else {
+            self.b.call(callee, args)
+        };
+        pool.scalars.push(result);
+    }
+    /// Loads or stores a scalar somewhere inside a variable, or loads all of it.
+    fn memory(&mut self, pool: &mut Pool) {
+        if self.vars.is_empty() { return };
+
+        let var = *self.rng.pick(&self.vars);
+        let var_ty = self.b.module[var].ty;
+        let base = self.b.get_var_addr(var);
+        if is_aggregate(var_ty) && self.rng.chance(1, 4) {
+            pool.aggregates.push(self.b.load(var_ty, base));
+            return;
+        }
+
+        let (steps, ty) = self.path(var_ty);
+        let mut ptr = base;
+        for step in steps {
+            ptr = match step {
+                Step::Member(sid, index) => self.b.index_struct(sid, ptr, index),
+                Step::Element(aid) => {
+                    let index = self.index(pool, aid);
+                    self.b.index_array(self.b.module[aid].element, ptr, index)
+                }
+            };
+        }
+
+        if ptr != base && self.rng.chance(1, 4) {
+            let ty = *self.rng.pick(&INT_TYS);
+            let element = self.scalar_ty();
+            pool.scalars.push(self.b.ptr_diff(ty, element, ptr, base));
+        }
+        else if self.rng.chance(1, 2) {
+            pool.scalars.push(self.b.load(ty, ptr));
+        }
+        else {
+            let value = self.operand(pool, ty);
+            self.b.store(ptr, value);
+        }
+    }
+    /// Builds or updates a struct or array value, or reads a scalar out of one.
+    fn aggregate(&mut self, pool: &mut Pool) {
+        if pool.aggregates.is_empty() || self.rng.chance(1, 3) {
+            let ty = self.aggregate_ty(2);
+            let value = self.value(pool, ty);
+            pool.aggregates.push(value.reg());
+            return;
+        }
+
+        let mut agg = *self.rng.pick(&pool.aggregates);
+        if self.rng.chance(1, 3) {
+            let updated = match self.b.module[agg].ty {
+                Ty::Struct(sid) => {
+                    let index = self.rng.below(self.b.module[sid].members.len() as u64);
+                    let member = self.b.module[sid].members[index as usize];
+                    let value = self.value(pool, member);
+                    self.b.set_struct_member(agg, index, value)
+                }
+                Ty::Array(aid) => {
+                    let index = self.index(pool, aid);
+                    let value = self.value(pool, self.b.module[aid].element);
+                    self.b.set_array_element(agg, index, value)
+                }
+                _ => unreachable!(),
+            };
+            pool.aggregates.push(updated);
+            return;
+        }
+
+        let (steps, _) = self.path(self.b.module[agg].ty);
+        for step in steps {
+            agg = match step {
+                Step::Member(_, index) => self.b.get_struct_member(agg, index),
+                Step::Element(aid) => {
+                    let index = self.index(pool, aid);
+                    self.b.get_array_element(agg, index)
+                }
+            };
+        }
+        pool.scalars.push(agg);
+    }
+    /// Round trips integers through pointers and takes differences of such pointers.
+    fn pointer(&mut self, pool: &mut Pool) {
+        if pool.fake_ptrs.is_empty() || self.rng.chance(1, 3) {
+            let ty = *self.rng.pick(&INT_TYS);
+            let a = self.operand(pool, ty.into());
+            pool.fake_ptrs.push(self.b.int_to_ptr(a));
+            return;
+        }
+
+        let a = *self.rng.pick(&pool.fake_ptrs);
+        let to = *self.rng.pick(&INT_TYS);
+        let result = if self.rng.chance(1, 2) {
+            self.b.ptr_to_int(to, a)
+        }
+        else {
+            let b = *self.rng.pick(&pool.fake_ptrs);
+            let element = self.any_ty(1);
+            self.b.ptr_diff(to, element, a, b)
+        };
+        pool.scalars.push(result);
+    }
+    /// Writes a scalar's bytes to stdout.
+    fn emit(&mut self, value: Value) {
+        let ty = value.ty(&self.b.module);
+        let var = match self.out_vars.get(&ty) {
+            Some(&var) => var,
+            None => {
+                let var = self.b.create_var(ty);
+                self.out_vars.insert(ty, var);
+                var
+            }
+        };
+        let ptr = self.b.get_var_addr(var);
+        self.b.store(ptr, value);
+        let size = self.b.module.ty_layout(ty).size() as i64;
+        self.b.syscall_linux64(IntTy::I64, 1i64, [Value::from(1i64), ptr.into(), size.into()]);
+    }
+
+    /// A random way down to a scalar inside `ty`.
+    fn path(&mut self, mut ty: Ty) -> (Vec<Step>, Ty) {
+        let mut steps = Vec::new();
+        loop {
+            match ty {
+                Ty::Struct(sid) => {
+                    let index = self.rng.below(self.b.module[sid].members.len() as u64);
+                    steps.push(Step::Member(sid, index));
+                    ty = self.b.module[sid].members[index as usize];
+                }
+                Ty::Array(aid) => {
+                    steps.push(Step::Element(aid));
+                    ty = self.b.module[aid].element;
+                }
+                _ => return (steps, ty),
+            }
+        }
+    }
+    /// An index that is always in bounds of the array,
+    /// either a constant or a value of the pool reduced modulo the length.
+    fn index(&mut self, pool: &Pool, aid: ArrayTyID) -> Value {
+        let len = self.b.module[aid].size;
+        let ints: Vec<RegID> = pool.scalars.iter().copied().filter(|&r| matches!(self.b.module[r].ty, Ty::Int(_))).collect();
+        if ints.is_empty() || self.rng.chance(1, 2) {
+            let ty = *self.rng.pick(&INT_TYS);
+            return Value::Int(ty, self.rng.below(len) as i64);
+        }
+
+        let x = *self.rng.pick(&ints);
+        let x = self.b.zext(IntTy::I64, x);
+        self.b.umod(x, Value::Int(IntTy::I64, len as i64)).into()
+    }
+
+    /// A value of any type: a scalar from the pool or a constant, or a freshly built aggregate.
+    fn value(&mut self, pool: &Pool, ty: Ty) -> Value {
+        match ty {
+            Ty::Struct(sid) => {
+                let same: Vec<RegID> = pool.aggregates.iter().copied().filter(|&r| self.b.module[r].ty == ty).collect();
+                if !same.is_empty() && self.rng.chance(1, 2) {
+                    return (*self.rng.pick(&same)).into();
+                }
+                let members = self.b.module[sid].members.clone();
+                let values: Vec<Value> = members.into_iter().map(|m| self.value(pool, m)).collect();
+                self.b.set_struct(sid, values).into()
+            }
+            Ty::Array(aid) => {
+                let same: Vec<RegID> = pool.aggregates.iter().copied().filter(|&r| self.b.module[r].ty == ty).collect();
+                if !same.is_empty() && self.rng.chance(1, 2) {
+                    return (*self.rng.pick(&same)).into();
+                }
+                let ArrayTy { size, element } = self.b.module[aid].clone();
+                if self.rng.chance(1, 2) {
+                    let value = self.value(pool, element);
+                    let reg = self.b.create_reg(ty);
+                    self.b.add_instr(Instruction::SetArraySplat(reg, value));
+                    reg.into()
+                }
+                else {
+                    let values: Vec<Value> = (0..size).map(|_| self.value(pool, element)).collect();
+                    let reg = self.b.create_reg(ty);
+                    self.b.add_instr(Instruction::SetArray(reg, values.into()));
+                    reg.into()
+                }
+            }
+            _ => self.operand(pool, ty),
+        }
+    }
+    fn operands(&mut self, pool: &Pool, tys: &[Ty]) -> Vec<Value> {
+        tys.iter().map(|&ty| self.operand(pool, ty)).collect()
+    }
+    /// A scalar of type `ty`, usually from the pool.
+    fn operand(&mut self, pool: &Pool, ty: Ty) -> Value {
+        let same: Vec<RegID> = pool.scalars.iter().copied().filter(|&r| self.b.module[r].ty == ty).collect();
+        if !same.is_empty() && !self.rng.chance(1, 4) {
+            return (*self.rng.pick(&same)).into();
+        }
+        self.constant(ty)
+    }
+    fn any_scalar(&mut self, pool: &Pool) -> Value {
+        if !pool.scalars.is_empty() && !self.rng.chance(1, 5) {
+            return (*self.rng.pick(&pool.scalars)).into();
+        }
+        let ty = self.scalar_ty();
+        self.constant(ty)
+    }
+    /// A constant, biased towards the edges of its type.
+    fn constant(&mut self, ty: Ty) -> Value {
+        let Ty::Int(int_ty) = ty else {
+            return Value::Bool(self.rng.chance(1, 2));
+        };
+
+        let shift = 64 - width(int_ty);
+        let value = match self.rng.below(6) {
+            0 => *self.rng.pick(&[0, 1, -1, 2]),
+            1 => i64::MIN >> shift,
+            2 => i64::MAX >> shift,
+            3 => self.rng.below(16) as i64,
+            // Values that only fit an immediate when sign- or zero-extended.
+            4 if int_ty == IntTy::I64 => *self.rng.pick(&[0xFFFF_FFFF, 0x8000_0000, -0x8000_0001, 1 << 32]),
+            _ => (self.rng.next() as i64) << shift >> shift,
+        };
+        Value::Int(int_ty, value)
+    }
+
+    fn scalar_ty(&mut self) -> Ty {
+        match self.rng.below(5) {
+            0 => Ty::Bool,
+            _ => (*self.rng.pick(&INT_TYS)).into(),
+        }
+    }
+    fn any_ty(&mut self, depth: usize) -> Ty {
+        if depth == 0 || self.rng.chance(1, 2) {
+            return self.scalar_ty();
+        }
+        self.aggregate_ty(depth)
+    }
+    fn aggregate_ty(&mut self, depth: usize) -> Ty {
+        if self.rng.chance(1, 2) {
+            let sid = self.b.module.add_struct_ty();
+            for _ in 0..self.rng.range(1, 4) {
+                let member = self.any_ty(depth - 1);
+                self.b.module.add_struct_member(sid, member);
+            }
+            sid.into()
+        }
+        else {
+            let element = self.any_ty(depth - 1);
+            let size = self.rng.range(1, 4);
+            self.b.module.add_array_ty(size, element).into()
+        }
+    }
+}
+fn width(ty: IntTy) -> u32 {
+    match ty {
+        IntTy::I8 => 8,
+        IntTy::I16 => 16,
+        IntTy::I32 => 32,
+        IntTy::I64 => 64,
+    }
+}
+fn is_aggregate(ty: Ty) -> bool {
+    matches!(ty, Ty::Struct(_) | Ty::Array(_))
+}
+
+/// SplitMix64, optionally reading its first outputs from input bytes.
+struct Rng {
+    state: u64,
+    input: Vec<u8>,
+    pos: usize,
+}
+impl Rng {
+    fn new(seed: u64, input: Vec<u8>) -> Self {
+        Self { state: seed, input, pos: 0 }
+    }
+
+    fn next(&mut self) -> u64 {
+        if self.pos < self.input.len() {
+            let end = (self.pos + 8).min(self.input.len());
+            let mut bytes = [0; 8];
+            bytes[..end - self.pos].copy_from_slice(&self.input[self.pos..end]);
+            self.pos = end;
+            return u64::from_le_bytes(bytes);
+        }
+
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    /// A number in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 { return 0 };
+        self.next() % n
+    }
+    /// A number in `lo..=hi`.
+    fn range(&mut self, lo: u64, hi: u64) -> u64 {
+        lo + self.below(hi - lo + 1)
+    }
+    fn chance(&mut self, numerator: u64, denominator: u64) -> bool {
+        self.below(denominator) < numerator
+    }
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn blocks(module: &Module) -> Vec<crate::frontend::Block> {
+        let mut ids: Vec<BlockID> = module.functions().iter().flat_map(|f| f.blocks.iter().copied()).collect();
+        ids.sort_by_key(|b| b.0);
+        ids.into_iter().map(|b| module[b].clone()).collect()
+    }
+
+    #[test]
+    fn programs_verify() {
+        for seed in 0..500 {
+            let program = Generator::new(seed).generate();
+            let errors = program.module.verify();
+            assert!(errors.is_empty(), "seed {seed}: {errors:?}");
+        }
+        for len in 0..64 {
+            let data: Vec<u8> = (0..len).map(|i| (i * 37 + len) as u8).collect();
+            let program = Generator::from_bytes(&data).with_depth(3).generate();
+            assert!(program.module.verify().is_empty(), "input {data:?}");
+        }
+    }
+
+    #[test]
+    fn generation_is_deterministic() {
+        for seed in 0..20 {
+            let a = Generator::new(seed).generate();
+            let b = Generator::new(seed).generate();
+            assert_eq!(blocks(&a.module), blocks(&b.module), "seed {seed}");
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn native_agrees_with_interpreter() {
+        use crate::backend_86::{DiffTest, RegAllocMode, Verdict};
+
+        for seed in 0..40 {
+            let program = Generator::new(seed).generate();
+            let dir = std::env::temp_dir().join(format!("cir-generator-{}-{seed}", std::process::id()));
+            for mode in [RegAllocMode::LinearScan, RegAllocMode::StackSlots] {
+                let verdict = DiffTest::new(&program.module, program.entry).with_reg_alloc(mode).run(&dir);
+                let verdict = verdict.unwrap_or_else(|e| panic!("seed {seed} with {mode:?}: {e}"));
+                assert!(!matches!(verdict, Verdict::Disagree { .. }), "seed {seed} with {mode:?}: {verdict:?}");
+            }
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    fn programs() -> impl Strategy<Value = Program> {
+        proptest::collection::vec(any::<u8>(), 0..256).prop_map(|data| Generator::from_bytes(&data).generate())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn generated_programs_verify_and_agree(program in programs()) {
+            let errors = program.module.verify();
+            prop_assert!(errors.is_empty(), "{errors:?}");
+
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            {
+                use std::sync::atomic::{AtomicUsize, Ordering};
+
+                use crate::backend_86::{DiffTest, Verdict};
+
+                static RUNS: AtomicUsize = AtomicUsize::new(0);
+                let n = RUNS.fetch_add(1, Ordering::SeqCst);
+                let dir = std::env::temp_dir().join(format!("cir-proptest-{}-{n}", std::process::id()));
+                let verdict = DiffTest::new(&program.module, program.entry).run(&dir);
+                let _ = std::fs::remove_dir_all(&dir);
+                let verdict = verdict.map_err(|e| TestCaseError::fail(e.to_string()))?;
+                prop_assert!(!matches!(verdict, Verdict::Disagree { .. }), "{verdict:?}");
+            }
+        }
+    }
+}
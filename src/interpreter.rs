@@ -0,0 +1,698 @@
+use std::{collections::HashMap, error::Error, fmt};
+
+use crate::frontend::{
+    BinOp, BlockID, FunID, GlobalID, GlobalValue, Instruction, IntTy, JumpTarget, Linkage, Module,
+    RegID, Ty, UnOp, Value, Values, VarID,
+};
+
+/// Memory starts here, so null and other small addresses are never valid.
+const MEMORY_BASE: u64 = 0x10000;
+/// Globals and the stack together may not grow beyond this many bytes.
+const MEMORY_LIMIT: usize = 1 << 28;
+/// Functions get addresses far above memory, `FUNCTION_STRIDE` apart,
+/// so function pointers can be stored, compared and called like on hardware.
+const FUNCTION_BASE: u64 = 0x4000_0000_0000;
+const FUNCTION_STRIDE: u64 = 16;
+/// How deep calls may nest before the interpreter reports a stack overflow.
+const MAX_CALL_DEPTH: usize = 100_000;
+
+const SYS_WRITE: u64 = 1;
+const SYS_RT_SIGPROCMASK: u64 = 14;
+const SYS_EXIT: u64 = 60;
+const SYS_EXIT_GROUP: u64 = 231;
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+
+/// Why the interpreter stopped before the called function returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    /// The program made an `exit` or `exit_group` system call.
+    Exit(i32),
+    DivisionByZero,
+    /// A signed division whose quotient doesn't fit its type, like `-128 i8 / -1 i8`.
+    DivisionOverflow,
+    /// A load or store that touches bytes outside of the globals and the live stack.
+    InvalidAddress(u64),
+    /// A call through a pointer that doesn't point to a function.
+    InvalidCall(u64),
+    /// A dynamic index outside of an array value.
+    IndexOutOfBounds { index: i64, len: u64 },
+    /// A function or global that is imported, so the interpreter has no definition for it.
+    Unresolved(String),
+    UnsupportedSyscall(u64),
+    StackOverflow,
+    /// The step limit set with `Interpreter::with_step_limit` ran out.
+    OutOfSteps,
+}
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exit(code) => write!(f, "exited with code {code}"),
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::DivisionOverflow => write!(f, "division overflow"),
+            Self::InvalidAddress(addr) => write!(f, "invalid memory access at {addr:#x}"),
+            Self::InvalidCall(addr) => write!(f, "call to non-function address {addr:#x}"),
+            Self::IndexOutOfBounds { index, len } => write!(f, "index {index} out of bounds for array of length {len}"),
+            Self::Unresolved(name) => write!(f, "unresolved symbol {name}"),
+            Self::UnsupportedSyscall(number) => write!(f, "unsupported system call {number}"),
+            Self::StackOverflow => write!(f, "stack overflow"),
+            Self::OutOfSteps => write!(f, "step limit exceeded"),
+        }
+    }
+}
+impl Error for Trap {}
+
+/// Runs a module directly on the IR, without generating any code.
+///
+/// Values are kept as bytes laid out like `backend_86` lays them out,
+/// and globals, variables and pointers live in one flat, byte-addressed memory,
+/// so address arithmetic, `PtrDiff` and integer overflow behave the same as in compiled code.
+/// Writes to stdout and stderr are captured instead of printed.
+pub struct Interpreter<'a> {
+    module: &'a Module,
+    memory: Memory,
+    globals: HashMap<GlobalID, u64>,
+    /// Where the stack starts, after the globals.
+    stack_base: usize,
+    frames: Vec<Frame>,
+    steps: Option<u64>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+impl<'a> Interpreter<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        let mut interpreter = Self {
+            module,
+            memory: Memory::new(),
+            globals: HashMap::new(),
+            stack_base: 0,
+            frames: Vec::new(),
+            steps: None,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        };
+        interpreter.init_globals();
+        interpreter
+    }
+
+    /// Stops every call with `Trap::OutOfSteps` once this many instructions have run in total.
+    pub fn with_step_limit(mut self, steps: u64) -> Self {
+        self.steps = Some(steps);
+        self
+    }
+
+    pub fn stdout(&self) -> &[u8] {
+        &self.stdout
+    }
+    pub fn stderr(&self) -> &[u8] {
+        &self.stderr
+    }
+    pub fn global_addr(&self, gid: GlobalID) -> Option<u64> {
+        self.globals.get(&gid).copied()
+    }
+    pub fn fun_addr(&self, fid: FunID) -> u64 {
+        FUNCTION_BASE + fid.0 as u64 * FUNCTION_STRIDE
+    }
+    /// Reads `len` bytes of the interpreter's memory, like the contents of a global.
+    pub fn read_memory(&self, addr: u64, len: u64) -> Result<&[u8], Trap> {
+        self.memory.read(addr, len)
+    }
+
+    /// Calls `fid` with scalar arguments, like `Jit::call`.
+    ///
+    /// Integer results are sign-extended into the returned value.
+    /// Globals keep their contents between calls, the stack does not.
+    pub fn call(&mut self, fid: FunID, args: &[Value]) -> Result<Value, Trap> {
+        let fun = &self.module[fid];
+        assert_eq!(fun.parameters.len(), args.len(), "wrong number of arguments for {}", fun.name);
+        let args = fun.parameters.iter().zip(args).map(|(&param, &arg)| {
+            match (self.module[param].ty, arg) {
+                (Ty::Bool, Value::Bool(value)) => vec![value as u8],
+                (Ty::Int(ty), Value::Int(arg_ty, value)) if ty == arg_ty => int_bytes(value as u64, int_size(ty)),
+                (ty, arg) => panic!("cannot pass {arg:?} as {ty:?}"),
+            }
+        }).collect();
+
+        let result = self.enter(fid, args, None).and_then(|()| self.run());
+        self.frames.clear();
+        self.memory.release(self.stack_base);
+        let ret = result?;
+
+        Ok(match self.module[fid].ret_ty {
+            Ty::Void => Value::Void,
+            Ty::Bool => Value::Bool(ret[0] != 0),
+            Ty::Int(ty) => Value::Int(ty, sign_extend(read_uint(&ret), int_size(ty))),
+            ty => panic!("cannot return {ty:?} as a value"),
+        })
+    }
+
+    fn init_globals(&mut self) {
+        // Globals can refer to each other, so every address is known before any value is written.
+        let mut contents = Vec::new();
+        for global in self.module.globals() {
+            if global.linkage == Linkage::Import { continue };
+
+            let layout = self.module.ty_layout(global.ty);
+            let mut bytes = vec![0; layout.size() as usize];
+            let mut addrs = Vec::new();
+            if let Some(value) = &global.value {
+                self.lay_out_global_value(global.ty, value, 0, &mut bytes, &mut addrs);
+            }
+            let addr = self.memory.alloc(bytes.len() as u64, layout.align()).expect("globals exceed the interpreter's memory");
+            self.globals.insert(global.id, addr);
+            contents.push((addr, bytes, addrs));
+        }
+
+        for (addr, mut bytes, addrs) in contents {
+            for (offset, target) in addrs {
+                let target = match target {
+                    GlobalValue::GlobalAddr(gid) => self.globals.get(&gid).copied().unwrap_or(0),
+                    GlobalValue::FunAddr(fid) => self.fun_addr(fid),
+                    _ => unreachable!(),
+                };
+                bytes[offset..offset + 8].copy_from_slice(&target.to_le_bytes());
+            }
+            self.memory.write(addr, &bytes).unwrap();
+        }
+        self.stack_base = self.memory.top();
+    }
+    /// Lays out a global's initial value the same way `backend_86` does,
+    /// collecting the offsets of addresses, which are patched in once every global is placed.
+    fn lay_out_global_value(&self, ty: Ty, value: &GlobalValue, offset: usize, bytes: &mut Vec<u8>, addrs: &mut Vec<(usize, GlobalValue)>) {
+        let mut write = |src: &[u8]| {
+            let end = offset + src.len();
+            if bytes.len() < end {
+                bytes.resize(end, 0);
+            }
+            bytes[offset..end].copy_from_slice(src);
+        };
+
+        match value {
+            &GlobalValue::Bool(value) => write(&[value as u8]),
+            GlobalValue::Int(value) => {
+                let size = self.module.ty_layout(ty).size() as usize;
+                write(&value.to_le_bytes()[..size.min(8)]);
+            }
+            GlobalValue::String(src) => write(src.as_bytes()),
+            GlobalValue::Struct(values) => {
+                let Ty::Struct(sid) = ty else { unreachable!() };
+                let members = &self.module[sid].members;
+                let offsets = self.module.struct_member_offsets(sid);
+                for ((&member, member_offset), value) in members.iter().zip(offsets).zip(values) {
+                    self.lay_out_global_value(member, value, offset + member_offset as usize, bytes, addrs);
+                }
+            }
+            GlobalValue::Array(values) => {
+                let Ty::Array(aid) = ty else { unreachable!() };
+                let element = self.module[aid].element;
+                let stride = self.module.ty_layout(element).pad_to_align().size() as usize;
+                for (i, value) in values.iter().enumerate() {
+                    self.lay_out_global_value(element, value, offset + i * stride, bytes, addrs);
+                }
+            }
+            GlobalValue::GlobalAddr(_) | GlobalValue::FunAddr(_) => {
+                write(&[0; 8]);
+                addrs.push((offset, value.clone()));
+            }
+        }
+    }
+
+    /// Pushes a frame for `fid`, with fresh, zeroed variables.
+    fn enter(&mut self, fid: FunID, args: Vec<Vec<u8>>, ret: Option<RegID>) -> Result<(), Trap> {
+        let fun = &self.module[fid];
+        let Some(entry) = fun.entry_block else {
+            return Err(Trap::Unresolved(fun.name.clone()));
+        };
+        if self.frames.len() >= MAX_CALL_DEPTH { return Err(Trap::StackOverflow) };
+
+        let stack = self.memory.top();
+        let mut variables: Vec<VarID> = fun.variables.iter().copied().collect();
+        variables.sort_by_key(|var| var.0);
+        let mut vars = HashMap::new();
+        for var in variables {
+            let (size, align) = self.module.ty_layout(self.module[var].ty).bytes();
+            vars.insert(var, self.memory.alloc(size, align)?);
+        }
+
+        let regs = fun.parameters.iter().copied().zip(args).collect();
+        self.frames.push(Frame {
+            block: entry,
+            pc: 0,
+            regs,
+            vars,
+            stack,
+            ret,
+        });
+        Ok(())
+    }
+    /// Runs until the outermost frame returns, and gives back its return value.
+    fn run(&mut self) -> Result<Vec<u8>, Trap> {
+        loop {
+            if let Some(steps) = &mut self.steps {
+                if *steps == 0 { return Err(Trap::OutOfSteps) };
+                *steps -= 1;
+            }
+
+            let module = self.module;
+            let frame = self.frames.last_mut().unwrap();
+            let block = &module[frame.block];
+            let Some(instruction) = block.instructions.get(frame.pc) else {
+                panic!("{:?} has no terminator", block.id);
+            };
+            frame.pc += 1;
+
+            if let Some(ret) = self.exec(instruction)? {
+                return Ok(ret);
+            }
+        }
+    }
+    /// Executes one instruction.
+    /// Returns the return value once the outermost frame returns.
+    fn exec(&mut self, instruction: &Instruction) -> Result<Option<Vec<u8>>, Trap> {
+        use Instruction::*;
+
+        match *instruction {
+            Set(dst, value) => {
+                let value = self.value(value);
+                self.set(dst, value);
+            }
+            SetFunPtr(dst, fid) => self.set(dst, self.fun_addr(fid).to_le_bytes().into()),
+            SetGlobalPtr(dst, gid) => {
+                let Some(addr) = self.global_addr(gid) else {
+                    return Err(Trap::Unresolved(self.global_name(gid)));
+                };
+                self.set(dst, addr.to_le_bytes().into());
+            }
+            SetStruct(dst, ref values) => {
+                let Ty::Struct(sid) = self.module[dst].ty else { unreachable!() };
+                let mut bytes = self.zeroed(dst);
+                let offsets = self.module.struct_member_offsets(sid);
+                for (offset, &value) in offsets.into_iter().zip(&values.0) {
+                    let value = self.value(value);
+                    let offset = offset as usize;
+                    bytes[offset..offset + value.len()].copy_from_slice(&value);
+                }
+                self.set(dst, bytes);
+            }
+            SetArray(dst, ref values) => {
+                let Ty::Array(aid) = self.module[dst].ty else { unreachable!() };
+                let stride = self.stride(self.module[aid].element);
+                let mut bytes = self.zeroed(dst);
+                for (i, &value) in values.0.iter().enumerate() {
+                    let value = self.value(value);
+                    let offset = i * stride as usize;
+                    bytes[offset..offset + value.len()].copy_from_slice(&value);
+                }
+                self.set(dst, bytes);
+            }
+            SetArraySplat(dst, value) => {
+                let Ty::Array(aid) = self.module[dst].ty else { unreachable!() };
+                let stride = self.stride(self.module[aid].element) as usize;
+                let value = self.value(value);
+                let mut bytes = self.zeroed(dst);
+                for offset in (0..bytes.len()).step_by(stride.max(1)) {
+                    bytes[offset..offset + value.len()].copy_from_slice(&value);
+                }
+                self.set(dst, bytes);
+            }
+
+            Binary(op, dst, a, b) => {
+                let ty = a.ty(self.module);
+                let a = read_uint(&self.value(a));
+                let b = read_uint(&self.value(b));
+                let result = binary(op, scalar_size(ty), a, b)?;
+                self.set_uint(dst, result);
+            }
+            Unary(op, dst, a) => {
+                let from = scalar_size(a.ty(self.module));
+                let a = read_uint(&self.value(a));
+                let result = match op {
+                    UnOp::Neg => a.wrapping_neg(),
+                    UnOp::Not if self.module[dst].ty == Ty::Bool => a ^ 1,
+                    UnOp::Not => !a,
+                    UnOp::Sext => sign_extend(a, from) as u64,
+                    UnOp::IntToPtr | UnOp::PtrToInt | UnOp::Zext | UnOp::Trunc => a,
+                };
+                self.set_uint(dst, result);
+            }
+
+            Poison(dst) => self.set(dst, self.zeroed(dst)),
+            Select(dst, c, a, b) => {
+                let value = if self.value(c)[0] != 0 { a } else { b };
+                let value = self.value(value);
+                self.set(dst, value);
+            }
+            Freeze(dst, value) => {
+                let value = self.value(value);
+                self.set(dst, value);
+            }
+
+            GetVarAddr(dst, var) => {
+                let addr = self.frame().vars[&var];
+                self.set(dst, addr.to_le_bytes().into());
+            }
+            Store { ptr, value } => {
+                let addr = self.addr(ptr);
+                let value = self.value(value);
+                self.memory.write(addr, &value)?;
+            }
+            Load { dst, ptr } => {
+                let addr = self.addr(ptr);
+                let size = self.module.ty_layout(self.module[dst].ty).size();
+                let value = self.memory.read(addr, size)?.to_vec();
+                self.set(dst, value);
+            }
+            PtrDiff(dst, ty, a, b) => {
+                let diff = self.addr(a).wrapping_sub(self.addr(b));
+                let size = self.module.ty_layout(ty).size();
+                let diff = if size < 2 { diff } else { diff / size };
+                self.set_uint(dst, diff);
+            }
+
+            Jump(ref tgt) => self.jump(tgt),
+            Branch(c, ref t, ref f) => {
+                let tgt = if self.value(c)[0] != 0 { t } else { f };
+                self.jump(tgt);
+            }
+            Ret(value) => {
+                let value = self.value(value);
+                let frame = self.frames.pop().unwrap();
+                self.memory.release(frame.stack);
+                let Some(caller) = self.frames.last_mut() else {
+                    return Ok(Some(value));
+                };
+                caller.regs.insert(frame.ret.unwrap(), value);
+            }
+            Call(dst, fid, ref args) => {
+                let args = self.values(args);
+                self.enter(fid, args, Some(dst))?;
+            }
+            CallPtr(dst, ptr, _, ref args) => {
+                let addr = self.addr(ptr);
+                let Some(fid) = self.fun_at(addr) else {
+                    return Err(Trap::InvalidCall(addr));
+                };
+                let args = self.values(args);
+                self.enter(fid, args, Some(dst))?;
+            }
+
+            GetStructMember { dst, strct, index } => {
+                let Ty::Struct(sid) = self.module[strct].ty else { unreachable!() };
+                let offset = self.module.struct_member_offsets(sid)[index as usize] as usize;
+                let size = self.module.ty_layout(self.module[dst].ty).size() as usize;
+                let value = self.reg(strct)[offset..offset + size].to_vec();
+                self.set(dst, value);
+            }
+            SetStructMember { dst, strct, value, index } => {
+                let Ty::Struct(sid) = self.module[strct].ty else { unreachable!() };
+                let offset = self.module.struct_member_offsets(sid)[index as usize] as usize;
+                let value = self.value(value);
+                let mut bytes = self.reg(strct);
+                bytes[offset..offset + value.len()].copy_from_slice(&value);
+                self.set(dst, bytes);
+            }
+            GetArrayElement { dst, array, index } => {
+                let offset = self.element_offset(array, index)?;
+                let size = self.module.ty_layout(self.module[dst].ty).size() as usize;
+                let value = self.reg(array)[offset..offset + size].to_vec();
+                self.set(dst, value);
+            }
+            SetArrayElement { dst, array, value, index } => {
+                let offset = self.element_offset(array, index)?;
+                let value = self.value(value);
+                let mut bytes = self.reg(array);
+                bytes[offset..offset + value.len()].copy_from_slice(&value);
+                self.set(dst, bytes);
+            }
+            IndexStruct { dst, ptr, struct_ty, index } => {
+                let offset = self.module.struct_member_offsets(struct_ty)[index as usize];
+                let addr = self.addr(ptr).wrapping_add(offset);
+                self.set(dst, addr.to_le_bytes().into());
+            }
+            IndexArray { dst, ptr, element_ty, index } => {
+                let index = self.index(index);
+                let offset = index.wrapping_mul(self.stride(element_ty) as i64);
+                let addr = self.addr(ptr).wrapping_add(offset as u64);
+                self.set(dst, addr.to_le_bytes().into());
+            }
+
+            SyscallLinux64 { dst, call_number, ref args } => {
+                let call_number = read_uint(&self.value(call_number));
+                let args: Vec<u64> = args.0.iter().map(|&arg| read_uint(&self.value(arg))).collect();
+                let result = self.syscall(call_number, &args)?;
+                self.set_uint(dst, result as u64);
+            }
+        }
+
+        Ok(None)
+    }
+    /// Models the Linux system calls the interpreter supports.
+    /// Failures are reported like the kernel does, as negated error numbers.
+    fn syscall(&mut self, number: u64, args: &[u64]) -> Result<i64, Trap> {
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+
+        match number {
+            SYS_WRITE => {
+                let out = match arg(0) {
+                    1 => &mut self.stdout,
+                    2 => &mut self.stderr,
+                    _ => return Ok(-EBADF),
+                };
+                let Ok(bytes) = self.memory.read(arg(1), arg(2)) else {
+                    return Ok(-EFAULT);
+                };
+                out.extend(bytes);
+                Ok(arg(2) as i64)
+            }
+            // Nothing is ever blocked, since the interpreter never delivers signals.
+            SYS_RT_SIGPROCMASK => {
+                if arg(3) != 8 || (arg(1) != 0 && arg(0) > 2) {
+                    return Ok(-EINVAL);
+                };
+                if arg(1) != 0 && self.memory.read(arg(1), 8).is_err() {
+                    return Ok(-EFAULT);
+                };
+                if arg(2) != 0 && self.memory.write(arg(2), &[0; 8]).is_err() {
+                    return Ok(-EFAULT);
+                };
+                Ok(0)
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => Err(Trap::Exit(arg(0) as i32)),
+            _ => Err(Trap::UnsupportedSyscall(number)),
+        }
+    }
+
+    /// Continues at a block, after evaluating every argument,
+    /// since a block may pass its own parameters on in a different order.
+    fn jump(&mut self, tgt: &JumpTarget) {
+        let args = self.values(&tgt.args);
+        let params = &self.module[tgt.block].parameters;
+        let frame = self.frames.last_mut().unwrap();
+        for (&param, arg) in params.iter().zip(args) {
+            frame.regs.insert(param, arg);
+        }
+        frame.block = tgt.block;
+        frame.pc = 0;
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().unwrap()
+    }
+    /// The bytes of a register.
+    /// Registers that were never written read as zero, like `Poison`.
+    fn reg(&self, reg: RegID) -> Vec<u8> {
+        match self.frame().regs.get(&reg) {
+            Some(bytes) => bytes.clone(),
+            None => self.zeroed(reg),
+        }
+    }
+    fn value(&self, value: Value) -> Vec<u8> {
+        match value {
+            Value::Void => Vec::new(),
+            Value::Bool(value) => vec![value as u8],
+            Value::Int(ty, value) => int_bytes(value as u64, int_size(ty)),
+            Value::Reg(reg) => self.reg(reg),
+        }
+    }
+    fn values(&self, values: &Values) -> Vec<Vec<u8>> {
+        values.0.iter().map(|&value| self.value(value)).collect()
+    }
+    fn addr(&self, reg: RegID) -> u64 {
+        read_uint(&self.reg(reg))
+    }
+    /// Array indices are signed, whatever their width.
+    fn index(&self, index: Value) -> i64 {
+        let size = scalar_size(index.ty(self.module));
+        sign_extend(read_uint(&self.value(index)), size)
+    }
+    fn element_offset(&self, array: RegID, index: Value) -> Result<usize, Trap> {
+        let Ty::Array(aid) = self.module[array].ty else { unreachable!() };
+        let array_ty = &self.module[aid];
+        let index = self.index(index);
+        if index < 0 || index as u64 >= array_ty.size {
+            return Err(Trap::IndexOutOfBounds { index, len: array_ty.size });
+        }
+        Ok((index as u64 * self.stride(array_ty.element)) as usize)
+    }
+    fn stride(&self, ty: Ty) -> u64 {
+        self.module.ty_layout(ty).pad_to_align().size()
+    }
+    fn zeroed(&self, reg: RegID) -> Vec<u8> {
+        vec![0; self.module.ty_layout(self.module[reg].ty).size() as usize]
+    }
+    fn set(&mut self, dst: RegID, bytes: Vec<u8>) {
+        self.frames.last_mut().unwrap().regs.insert(dst, bytes);
+    }
+    /// Sets a scalar register to the low bytes of `value`.
+    fn set_uint(&mut self, dst: RegID, value: u64) {
+        let size = scalar_size(self.module[dst].ty);
+        self.set(dst, int_bytes(value, size));
+    }
+
+    fn fun_at(&self, addr: u64) -> Option<FunID> {
+        let offset = addr.checked_sub(FUNCTION_BASE)?;
+        if offset % FUNCTION_STRIDE != 0 { return None };
+        let fun = self.module.functions().get((offset / FUNCTION_STRIDE) as usize)?;
+        Some(fun.id)
+    }
+    fn global_name(&self, gid: GlobalID) -> String {
+        match &self.module.globals()[gid.0].name {
+            Some(name) => name.clone(),
+            None => format!("g{}", gid.0),
+        }
+    }
+}
+
+struct Frame {
+    block: BlockID,
+    /// The index of the next instruction in `block`.
+    pc: usize,
+    regs: HashMap<RegID, Vec<u8>>,
+    vars: HashMap<VarID, u64>,
+    /// The top of the stack before this frame's variables were allocated.
+    stack: usize,
+    /// The caller's register that receives the return value.
+    ret: Option<RegID>,
+}
+
+/// Globals followed by a stack of variables, starting at `MEMORY_BASE`.
+struct Memory {
+    bytes: Vec<u8>,
+}
+impl Memory {
+    fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    fn top(&self) -> usize {
+        self.bytes.len()
+    }
+    /// Allocates zeroed bytes at the top of memory.
+    fn alloc(&mut self, size: u64, align: u64) -> Result<u64, Trap> {
+        let start = (self.bytes.len() as u64).next_multiple_of(align.max(1));
+        let end = start + size;
+        if end > MEMORY_LIMIT as u64 { return Err(Trap::StackOverflow) };
+        self.bytes.resize(end as usize, 0);
+        Ok(MEMORY_BASE + start)
+    }
+    /// Frees everything allocated after `top`.
+    fn release(&mut self, top: usize) {
+        self.bytes.truncate(top);
+    }
+
+    fn range(&self, addr: u64, len: u64) -> Result<std::ops::Range<usize>, Trap> {
+        let start = addr.checked_sub(MEMORY_BASE).ok_or(Trap::InvalidAddress(addr))?;
+        let end = start.checked_add(len).ok_or(Trap::InvalidAddress(addr))?;
+        if end > self.bytes.len() as u64 {
+            return Err(Trap::InvalidAddress(addr));
+        }
+        Ok(start as usize..end as usize)
+    }
+    fn read(&self, addr: u64, len: u64) -> Result<&[u8], Trap> {
+        let range = self.range(addr, len)?;
+        Ok(&self.bytes[range])
+    }
+    fn write(&mut self, addr: u64, bytes: &[u8]) -> Result<(), Trap> {
+        let range = self.range(addr, bytes.len() as u64)?;
+        self.bytes[range].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Evaluates an operation on integers of `size` bytes, mirroring what x86 does:
+/// arithmetic wraps, shift counts are masked and division faults.
+/// The operands and the result are zero-extended; comparisons give 0 or 1.
+pub(crate) fn binary(op: BinOp, size: u64, a: u64, b: u64) -> Result<u64, Trap> {
+    let sa = sign_extend(a, size);
+    let sb = sign_extend(b, size);
+    let count = b & if size == 8 { 63 } else { 31 };
+
+    let result = match op {
+        BinOp::Add => a.wrapping_add(b),
+        BinOp::Sub => a.wrapping_sub(b),
+        BinOp::Mul => a.wrapping_mul(b),
+        BinOp::IDiv | BinOp::IMod => {
+            if sb == 0 { return Err(Trap::DivisionByZero) };
+            let Some(quotient) = sa.checked_div(sb) else {
+                return Err(Trap::DivisionOverflow);
+            };
+            if sign_extend(quotient as u64, size) != quotient {
+                return Err(Trap::DivisionOverflow);
+            }
+            if op == BinOp::IDiv { quotient as u64 } else { (sa % sb) as u64 }
+        }
+        BinOp::UDiv | BinOp::UMod => {
+            if b == 0 { return Err(Trap::DivisionByZero) };
+            if op == BinOp::UDiv { a / b } else { a % b }
+        }
+        BinOp::And => a & b,
+        BinOp::Or => a | b,
+        BinOp::Xor => a ^ b,
+        BinOp::Shl => a << count,
+        BinOp::Shr => a >> count,
+        BinOp::Sar => (sa >> count) as u64,
+        BinOp::Equal => (a == b) as u64,
+        BinOp::NotEqual => (a != b) as u64,
+        BinOp::Greater => (sa > sb) as u64,
+        BinOp::GreaterEqual => (sa >= sb) as u64,
+        BinOp::Less => (sa < sb) as u64,
+        BinOp::LessEqual => (sa <= sb) as u64,
+        BinOp::Above => (a > b) as u64,
+        BinOp::AboveEqual => (a >= b) as u64,
+        BinOp::Below => (a < b) as u64,
+        BinOp::BelowEqual => (a <= b) as u64,
+    };
+
+    Ok(result)
+}
+
+pub(crate) fn int_size(ty: IntTy) -> u64 {
+    match ty {
+        IntTy::I8 => 1,
+        IntTy::I16 => 2,
+        IntTy::I32 => 4,
+        IntTy::I64 => 8,
+    }
+}
+fn scalar_size(ty: Ty) -> u64 {
+    match ty {
+        Ty::Bool => 1,
+        Ty::Ptr => 8,
+        Ty::Int(ty) => int_size(ty),
+        ty => panic!("{ty:?} is not a scalar"),
+    }
+}
+fn int_bytes(value: u64, size: u64) -> Vec<u8> {
+    value.to_le_bytes()[..size as usize].to_vec()
+}
+fn read_uint(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    let len = bytes.len().min(8);
+    buf[..len].copy_from_slice(&bytes[..len]);
+    u64::from_le_bytes(buf)
+}
+pub(crate) fn sign_extend(value: u64, size: u64) -> i64 {
+    let shift = 64 - size * 8;
+    ((value << shift) as i64) >> shift
+}
@@ -2,3 +2,7 @@ pub mod frontend;
 pub mod target;
 pub mod layout;
 pub mod backend_86;
+pub mod interpreter;
+pub mod generator;
+pub mod reducer;
+pub mod passes;
@@ -0,0 +1,317 @@
+use std::{error::Error, fmt};
+
+use crate::frontend::{Analyses, Diagnostic, FunID, Module};
+
+mod const_fold;
+mod dce;
+mod gvn;
+mod mem2reg;
+
+pub use const_fold::ConstFold;
+pub use dce::DeadCodeElim;
+pub use gvn::Gvn;
+pub use mem2reg::Mem2Reg;
+
+/// A transformation that works on one function at a time.
+pub trait FunctionPass {
+    fn name(&self) -> &'static str;
+    /// Returns whether the function changed.
+    /// Analyses of other functions must stay valid.
+    fn run_on_function(&mut self, module: &mut Module, fun: FunID, analyses: &mut Analyses) -> bool;
+}
+
+/// A transformation that needs to see the whole module, like one that removes functions.
+pub trait ModulePass {
+    fn name(&self) -> &'static str;
+    /// Returns whether the module changed.
+    fn run_on_module(&mut self, module: &mut Module, analyses: &mut Analyses) -> bool;
+}
+
+enum Pass {
+    Function(Box<dyn FunctionPass>),
+    Module(Box<dyn ModulePass>),
+}
+impl Pass {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Function(pass) => pass.name(),
+            Self::Module(pass) => pass.name(),
+        }
+    }
+}
+
+/// Runs a pipeline of passes over a module, in the order they were added.
+///
+/// Function passes visit every function with a body in turn.
+/// Analyses are shared by all passes and kept between runs,
+/// and whatever a pass reports to have changed is analysed afresh.
+pub struct PassManager {
+    passes: Vec<Pass>,
+    verify: bool,
+    analyses: Analyses,
+}
+impl PassManager {
+    pub fn new() -> Self {
+        Self {
+            passes: Vec::new(),
+            verify: false,
+            analyses: Analyses::new(),
+        }
+    }
+
+    pub fn with_function_pass(mut self, pass: impl FunctionPass + 'static) -> Self {
+        self.passes.push(Pass::Function(Box::new(pass)));
+        self
+    }
+    pub fn with_module_pass(mut self, pass: impl ModulePass + 'static) -> Self {
+        self.passes.push(Pass::Module(Box::new(pass)));
+        self
+    }
+    /// Verifies the module before the first pass and after every pass that changed it,
+    /// so a broken module is blamed on the pass that broke it.
+    pub fn with_verifier(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Runs every pass once and returns whether any of them changed the module.
+    pub fn run(&mut self, module: &mut Module) -> Result<bool, VerifyError> {
+        if self.verify {
+            check(module, None)?;
+        }
+
+        let mut changed = false;
+        for pass in &mut self.passes {
+            let pass_changed = match pass {
+                Pass::Function(pass) => {
+                    let mut pass_changed = false;
+                    for fun in 0..module.functions().len() {
+                        let fun = FunID(fun);
+                        if module[fun].entry_block.is_none() {
+                            continue;
+                        }
+                        if pass.run_on_function(module, fun, &mut self.analyses) {
+                            self.analyses.invalidate(fun);
+                            pass_changed = true;
+                        }
+                    }
+                    pass_changed
+                }
+                Pass::Module(pass) => {
+                    let pass_changed = pass.run_on_module(module, &mut self.analyses);
+                    if pass_changed {
+                        self.analyses.clear();
+                    }
+                    pass_changed
+                }
+            };
+
+            if pass_changed && self.verify {
+                check(module, Some(pass.name()))?;
+            }
+            changed |= pass_changed;
+        }
+
+        Ok(changed)
+    }
+    /// Runs the pipeline until it stops changing the module, at most `max_runs` times.
+    pub fn run_to_fixpoint(&mut self, module: &mut Module, max_runs: usize) -> Result<bool, VerifyError> {
+        let mut changed = false;
+        for _ in 0..max_runs {
+            if !self.run(module)? {
+                break;
+            }
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn check(module: &Module, pass: Option<&'static str>) -> Result<(), VerifyError> {
+    let diagnostics = module.verify();
+    if diagnostics.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(VerifyError { pass, diagnostics })
+    }
+}
+
+/// The module did not verify between passes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyError {
+    /// The pass that ran last, or `None` if the module was broken from the start.
+    pub pass: Option<&'static str>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.diagnostics.len();
+        match self.pass {
+            Some(pass) => write!(f, "{count} verifier errors after {pass}"),
+            None => write!(f, "{count} verifier errors before the first pass"),
+        }
+    }
+}
+impl Error for VerifyError {}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+    use super::*;
+    use crate::{
+        frontend::{Builder, DiagnosticKind, DomTree, Instruction, IntTy, Linkage, Value},
+        generator::Generator,
+        interpreter::Interpreter,
+        target::Target,
+    };
+
+    /// Runs the pipeline with the verifier over generated programs and checks that
+    /// the interpreter still gets the same result and output from them.
+    /// More than `min_changed` of the programs have to change.
+    pub(super) fn check_pipeline_preserves_behaviour(make: impl Fn() -> PassManager, min_changed: usize) {
+        let mut changed = 0;
+        for seed in 0..200 {
+            let mut program = Generator::new(seed).with_depth(3).generate();
+            let mut interpreter = Interpreter::new(&program.module);
+            let expected = interpreter.call(program.entry, &[]);
+            let expected_out = interpreter.stdout().to_vec();
+
+            let result = make().with_verifier(true).run(&mut program.module);
+            changed += result.unwrap_or_else(|e| panic!("seed {seed}: {e}: {:?}", e.diagnostics)) as usize;
+
+            let mut interpreter = Interpreter::new(&program.module);
+            assert_eq!(interpreter.call(program.entry, &[]), expected, "seed {seed}");
+            assert_eq!(interpreter.stdout(), expected_out, "seed {seed}");
+        }
+        assert!(changed > min_changed, "only {changed} programs changed");
+    }
+
+    /// Runs the pipeline to a fixpoint over generated programs and checks that
+    /// the code `backend_86` generates for them agrees with the interpreter.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    pub(super) fn check_pipeline_agrees_natively(make: impl Fn() -> PassManager) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::backend_86::{DiffTest, Verdict};
+
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        for seed in 0..20 {
+            let mut program = Generator::new(seed).generate();
+            make().run_to_fixpoint(&mut program.module, 4).unwrap();
+
+            let n = RUNS.fetch_add(1, Ordering::SeqCst);
+            let dir = std::env::temp_dir().join(format!("cir-passes-{}-{n}", std::process::id()));
+            let verdict = DiffTest::new(&program.module, program.entry).run(&dir);
+            let verdict = verdict.unwrap_or_else(|e| panic!("seed {seed}: {e}"));
+            assert!(matches!(verdict, Verdict::Agree(_)), "seed {seed}: {verdict:?}");
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    fn module() -> Module {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        b.begin_fun("imported".into(), IntTy::I64);
+        b.set_linkage(Linkage::Import);
+        for name in ["a", "b"] {
+            b.begin_fun(name.into(), IntTy::I64);
+            b.begin_block();
+            b.set_entry_block();
+            b.ret(0i64);
+        }
+        b.finish()
+    }
+
+    type Log = Rc<RefCell<Vec<(FunID, bool)>>>;
+
+    /// Logs every function it visits and whether its dominator tree had to be built anew.
+    struct Visit {
+        log: Log,
+        trees: HashMap<FunID, Rc<DomTree>>,
+        change: bool,
+    }
+    impl Visit {
+        fn new(change: bool) -> (Self, Log) {
+            let log = Rc::default();
+            let pass = Self {
+                log: Rc::clone(&log),
+                trees: HashMap::new(),
+                change,
+            };
+            (pass, log)
+        }
+    }
+    impl FunctionPass for Visit {
+        fn name(&self) -> &'static str {
+            "visit"
+        }
+        fn run_on_function(&mut self, module: &mut Module, fun: FunID, analyses: &mut Analyses) -> bool {
+            let tree = analyses.dom_tree(module, fun);
+            let built = !self.trees.get(&fun).is_some_and(|old| Rc::ptr_eq(old, &tree));
+            self.trees.insert(fun, tree);
+            self.log.borrow_mut().push((fun, built));
+
+            if self.change {
+                let entry = module[fun].entry_block.unwrap();
+                module[entry].instructions[0] = Instruction::Ret(Value::Int(IntTy::I64, 1));
+            }
+            self.change
+        }
+    }
+
+    /// Leaves the first function without a terminator.
+    struct Break;
+    impl ModulePass for Break {
+        fn name(&self) -> &'static str {
+            "break"
+        }
+        fn run_on_module(&mut self, module: &mut Module, _: &mut Analyses) -> bool {
+            let entry = module[FunID(1)].entry_block.unwrap();
+            module[entry].instructions.clear();
+            true
+        }
+    }
+
+    #[test]
+    fn analyses_are_kept_until_a_pass_changes_something() {
+        let (a, b) = (FunID(1), FunID(2));
+        let mut module = module();
+
+        let (visit, log) = Visit::new(false);
+        let mut passes = PassManager::new().with_function_pass(visit);
+        assert_eq!(passes.run(&mut module), Ok(false));
+        assert_eq!(passes.run(&mut module), Ok(false));
+        assert_eq!(*log.borrow(), [(a, true), (b, true), (a, false), (b, false)]);
+
+        let (visit, log) = Visit::new(true);
+        let mut passes = PassManager::new().with_function_pass(visit);
+        assert_eq!(passes.run_to_fixpoint(&mut module, 2), Ok(true));
+        assert_eq!(*log.borrow(), [(a, true), (b, true), (a, true), (b, true)]);
+    }
+
+    #[test]
+    fn verifier_blames_the_breaking_pass() {
+        let (visit, _) = Visit::new(false);
+        let mut passes = PassManager::new().with_function_pass(visit).with_module_pass(Break);
+        assert_eq!(passes.run(&mut module()), Ok(true));
+
+        let (visit, _) = Visit::new(false);
+        let mut passes = PassManager::new()
+            .with_function_pass(visit)
+            .with_module_pass(Break)
+            .with_verifier(true);
+        let mut broken = module();
+        let error = passes.run(&mut broken).unwrap_err();
+        assert_eq!(error.pass, Some("break"));
+        assert_eq!(error.diagnostics[0].kind, DiagnosticKind::MissingTerminator(broken[FunID(1)].entry_block.unwrap()));
+
+        let error = PassManager::new().with_verifier(true).run(&mut broken).unwrap_err();
+        assert_eq!(error.pass, None);
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+
+use crate::{
+    frontend::{Analyses, BinOp, BlockID, FunID, Instruction, Module, RegID, Ty, UnOp, Value},
+    interpreter::{binary, int_size, sign_extend},
+};
+
+use super::FunctionPass;
+
+/// Folds operations on constants and simplifies those whose result doesn't depend on
+/// a register, like `x + 0`, `x * 1`, `x - x` or `select true, a, b`.
+///
+/// Integers wrap around like they do on the target, and comparisons fold into booleans.
+/// Divisions that would fault are left for the program to run into.
+/// Folded instructions are removed and their uses get the folded value instead,
+/// and branches on a constant condition become jumps.
+pub struct ConstFold;
+impl FunctionPass for ConstFold {
+    fn name(&self) -> &'static str {
+        "const-fold"
+    }
+    fn run_on_function(&mut self, module: &mut Module, fun: FunID, analyses: &mut Analyses) -> bool {
+        let cfg = analyses.cfg(module, fun);
+
+        let mut folder = Folder {
+            module,
+            replaced: HashMap::new(),
+            changed: false,
+        };
+        // Definitions come before their uses in reverse postorder, so operands are folded first.
+        for &block in cfg.reverse_postorder() {
+            folder.fold_block(block);
+        }
+
+        let mut unreachable: Vec<_> = folder.module[fun].blocks.iter().copied().filter(|&b| !cfg.is_reachable(b)).collect();
+        unreachable.sort_by_key(|b| b.0);
+        for block in unreachable {
+            for i in 0..folder.module[block].instructions.len() {
+                let mut instr = folder.module[block].instructions[i].clone();
+                if folder.replace_operands(&mut instr) {
+                    folder.module[block].instructions[i] = instr;
+                }
+            }
+        }
+
+        folder.changed
+    }
+}
+
+struct Folder<'a> {
+    module: &'a mut Module,
+    /// Folded registers and their values.
+    replaced: HashMap<RegID, Value>,
+    changed: bool,
+}
+impl Folder<'_> {
+    fn fold_block(&mut self, block: BlockID) {
+        let instructions = std::mem::take(&mut self.module[block].instructions);
+        let mut folded = Vec::with_capacity(instructions.len());
+        for mut instr in instructions {
+            self.replace_operands(&mut instr);
+
+            if let Instruction::Branch(Value::Bool(c), t, f) = instr {
+                instr = Instruction::Jump(if c { t } else { f });
+                self.changed = true;
+            }
+            else if let Some(dst) = instr.dst()
+                && let Some(value) = self.fold(&instr)
+            {
+                self.replaced.insert(dst, value);
+                self.changed = true;
+                continue;
+            }
+            folded.push(instr);
+        }
+        self.module[block].instructions = folded;
+    }
+    /// Returns whether any operand was replaced.
+    fn replace_operands(&self, instr: &mut Instruction) -> bool {
+        let mut replaced = false;
+        for value in instr.values_mut() {
+            if let Value::Reg(reg) = *value
+                && let Some(&new) = self.replaced.get(&reg)
+            {
+                *value = new;
+                replaced = true;
+            }
+        }
+        // Only registers of pointer or aggregate type are left, and those never fold to a constant.
+        for reg in instr.uses_mut() {
+            if let Some(&Value::Reg(new)) = self.replaced.get(reg) {
+                *reg = new;
+                replaced = true;
+            }
+        }
+        replaced
+    }
+
+    /// The value the instruction's result can be replaced with.
+    fn fold(&self, instr: &Instruction) -> Option<Value> {
+        match *instr {
+            Instruction::Set(_, value) => Some(value),
+            Instruction::Freeze(_, value @ (Value::Bool(_) | Value::Int(..))) => Some(value),
+            Instruction::Select(_, Value::Bool(c), a, b) => Some(if c { a } else { b }),
+            Instruction::Select(_, _, a, b) if a == b => Some(a),
+            Instruction::Binary(op, dst, a, b) => self.fold_binary(op, dst, a, b),
+            Instruction::Unary(op, dst, a) => self.fold_unary(op, dst, a),
+            _ => None,
+        }
+    }
+    fn fold_binary(&self, op: BinOp, dst: RegID, a: Value, b: Value) -> Option<Value> {
+        use BinOp::*;
+
+        if let (Value::Int(ty, a), Value::Int(_, b)) = (a, b) {
+            let size = int_size(ty);
+            let result = binary(op, size, zero_extend(a, size), zero_extend(b, size)).ok()?;
+            return Some(constant(self.module[dst].ty, result));
+        };
+
+        let ty = match (a, b) {
+            (Value::Int(ty, _), _) | (_, Value::Int(ty, _)) => ty,
+            _ => match self.module[a.reg()].ty {
+                Ty::Int(ty) => ty,
+                _ => return None,
+            },
+        };
+        let is = |value: Value, n: i64| matches!(value, Value::Int(_, v) if sign_extend(v as u64, int_size(ty)) == n);
+        let count_is_zero = matches!(b, Value::Int(_, count) if count & if int_size(ty) == 8 { 63 } else { 31 } == 0);
+        let zero = Value::Int(ty, 0);
+
+        let value = match op {
+            Add | Or | Xor if is(a, 0) => b,
+            Add | Sub | Or | Xor if is(b, 0) => a,
+            Shl | Shr | Sar if count_is_zero => a,
+            Mul if is(a, 1) => b,
+            Mul | IDiv | UDiv if is(b, 1) => a,
+            Mul | And if is(a, 0) || is(b, 0) => zero,
+            IMod | UMod if is(b, 1) => zero,
+            And if is(a, -1) => b,
+            And if is(b, -1) => a,
+            Or if is(a, -1) || is(b, -1) => Value::Int(ty, -1),
+            Sub | Xor if a == b => zero,
+            And | Or if a == b => a,
+            Equal | GreaterEqual | LessEqual | AboveEqual | BelowEqual if a == b => Value::Bool(true),
+            NotEqual | Greater | Less | Above | Below if a == b => Value::Bool(false),
+            _ => return None,
+        };
+        Some(value)
+    }
+    fn fold_unary(&self, op: UnOp, dst: RegID, a: Value) -> Option<Value> {
+        let (a, size) = match a {
+            Value::Bool(a) => (a as u64, 1),
+            Value::Int(ty, a) => (zero_extend(a, int_size(ty)), int_size(ty)),
+            _ => return None,
+        };
+
+        let to = self.module[dst].ty;
+        let result = match op {
+            UnOp::Neg => a.wrapping_neg(),
+            UnOp::Not if to == Ty::Bool => a ^ 1,
+            UnOp::Not => !a,
+            UnOp::Sext => sign_extend(a, size) as u64,
+            UnOp::Zext | UnOp::Trunc => a,
+            UnOp::IntToPtr | UnOp::PtrToInt => return None,
+        };
+        Some(constant(to, result))
+    }
+}
+
+fn zero_extend(value: i64, size: u64) -> u64 {
+    if size == 8 { value as u64 } else { value as u64 & ((1 << (size * 8)) - 1) }
+}
+/// The constant of type `ty` whose bits are the low bits of `value`.
+fn constant(ty: Ty, value: u64) -> Value {
+    match ty {
+        Ty::Bool => Value::Bool(value & 1 != 0),
+        Ty::Int(ty) => Value::Int(ty, sign_extend(value, int_size(ty))),
+        ty => panic!("{ty:?} is not a scalar"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frontend::{Builder, IntTy},
+        interpreter::{Interpreter, Trap},
+        passes::{Mem2Reg, PassManager, tests::*},
+        target::Target,
+    };
+
+    #[test]
+    fn folds_constants_and_identities() {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let fid = b.begin_fun("fold".into(), IntTy::I64);
+        let x = b.create_param(IntTy::I64);
+        let then = b.create_block();
+        let other = b.create_block();
+
+        b.begin_block();
+        b.set_entry_block();
+        let wrapped = b.add(127i8, 1i8);
+        let wide = b.sext(IntTy::I64, wrapped);
+        let negative = b.test_l(wrapped, 0i8);
+        let same = b.add(x, 0i64);
+        let same = b.mul(1i64, same);
+        let sum = b.add(same, wide);
+        let chosen = b.select(negative, sum, x);
+        let faulting = b.udiv(1i64, 0i64);
+        b.branch(negative, (then, [Value::Reg(chosen)]), other);
+
+        b.select_block(then);
+        let param = b.create_block_param(IntTy::I64);
+        let result = b.add(param, faulting);
+        b.ret(result);
+
+        b.select_block(other);
+        b.ret(0i64);
+        let mut module = b.finish();
+
+        assert!(ConstFold.run_on_function(&mut module, fid, &mut Analyses::new()));
+        assert!(module.verify().is_empty(), "{:?}", module.verify());
+        let entry = module[fid].entry_block.unwrap();
+        let expected = [
+            Instruction::Binary(BinOp::Add, sum, Value::Reg(x), Value::Int(IntTy::I64, -128)),
+            Instruction::Binary(BinOp::UDiv, faulting, Value::Int(IntTy::I64, 1), Value::Int(IntTy::I64, 0)),
+            Instruction::Jump((then, [Value::Reg(sum)]).into()),
+        ];
+        assert_eq!(module[entry].instructions, expected);
+
+        let result = Interpreter::new(&module).call(fid, &[Value::Int(IntTy::I64, 1000)]);
+        assert_eq!(result, Err(Trap::DivisionByZero));
+        assert!(!ConstFold.run_on_function(&mut module, fid, &mut Analyses::new()));
+    }
+
+    #[test]
+    fn wraps_like_the_target() {
+        let cases = [
+            (BinOp::Mul, IntTy::I16, 300, 300, Value::Int(IntTy::I16, 24464)),
+            (BinOp::Shl, IntTy::I8, 1, 9, Value::Int(IntTy::I8, 0)),
+            (BinOp::Shl, IntTy::I32, 1, 33, Value::Int(IntTy::I32, 2)),
+            (BinOp::Sar, IntTy::I8, -128, 3, Value::Int(IntTy::I8, -16)),
+            (BinOp::Shr, IntTy::I8, -128, 3, Value::Int(IntTy::I8, 16)),
+            (BinOp::IDiv, IntTy::I8, -7, 2, Value::Int(IntTy::I8, -3)),
+            (BinOp::UMod, IntTy::I8, -1, 10, Value::Int(IntTy::I8, 5)),
+            (BinOp::Less, IntTy::I32, -1, 0, Value::Bool(true)),
+            (BinOp::Below, IntTy::I32, -1, 0, Value::Bool(false)),
+            // Faults at run time, so it stays.
+            (BinOp::IDiv, IntTy::I8, -128, -1, Value::Int(IntTy::I8, 0)),
+        ];
+        for (op, ty, a, b, expected) in cases {
+            let ret_ty = if let Value::Bool(_) = expected { Ty::Bool } else { Ty::Int(ty) };
+            let mut builder = Builder::new(Module::new(Target::LINUX_X64));
+            let fid = builder.begin_fun("wrap".into(), ret_ty);
+            builder.begin_block();
+            builder.set_entry_block();
+            let dst = builder.create_reg(ret_ty);
+            builder.add_instr(Instruction::Binary(op, dst, Value::Int(ty, a), Value::Int(ty, b)));
+            builder.ret(dst);
+            let mut module = builder.finish();
+
+            let interpreted = Interpreter::new(&module).call(fid, &[]);
+            let folded = ConstFold.run_on_function(&mut module, fid, &mut Analyses::new());
+            let entry = module[fid].entry_block.unwrap();
+            if let Err(trap) = interpreted {
+                assert_eq!(trap, Trap::DivisionOverflow);
+                assert!(!folded);
+            }
+            else {
+                assert_eq!(interpreted, Ok(expected), "{op:?}");
+                assert_eq!(module[entry].instructions, [Instruction::Ret(expected)], "{op:?}");
+            }
+        }
+    }
+
+    fn pipeline() -> PassManager {
+        PassManager::new().with_function_pass(Mem2Reg).with_function_pass(ConstFold)
+    }
+
+    #[test]
+    fn generated_programs_keep_their_behaviour() {
+        check_pipeline_preserves_behaviour(pipeline, 100);
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn native_agrees_after_folding() {
+        check_pipeline_agrees_natively(pipeline);
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::frontend::{Analyses, BlockID, FunID, Instruction, JumpTarget, Module, RegID, Value};
+
+use super::FunctionPass;
+
+/// Removes blocks that can't be reached from the entry block,
+/// instructions whose result is never used and block parameters that are never used,
+/// together with the arguments passed to them.
+///
+/// Everything but stores, calls and system calls counts as free of side effects,
+/// so a dead division is removed even if it would fault.
+/// Values that only feed each other, like a counter no one reads, are removed as a whole.
+pub struct DeadCodeElim;
+impl FunctionPass for DeadCodeElim {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+    fn run_on_function(&mut self, module: &mut Module, fun: FunID, analyses: &mut Analyses) -> bool {
+        let cfg = analyses.cfg(module, fun);
+        let blocks = cfg.reverse_postorder();
+
+        let mut changed = false;
+        if blocks.len() != module[fun].blocks.len() {
+            module[fun].blocks.retain(|&b| cfg.is_reachable(b));
+            changed = true;
+        }
+
+        let live = live_registers(module, blocks);
+        let is_live = |instr: &Instruction| has_side_effects(instr) || instr.dst().is_none_or(|dst| live.contains(&dst));
+
+        // Which parameters every block keeps, for blocks that lose some.
+        let mut kept_params = HashMap::new();
+        for &block in blocks {
+            let params = &module[block].parameters;
+            if !params.iter().all(|p| live.contains(p)) {
+                kept_params.insert(block, params.iter().map(|p| live.contains(p)).collect::<Vec<_>>());
+                module[block].parameters.retain(|p| live.contains(p));
+                changed = true;
+            }
+            if !module[block].instructions.iter().all(is_live) {
+                module[block].instructions.retain(is_live);
+                changed = true;
+            }
+        }
+
+        if kept_params.is_empty() {
+            return changed;
+        };
+        let drop_args = |tgt: &mut JumpTarget| {
+            if let Some(kept) = kept_params.get(&tgt.block) {
+                let mut kept = kept.iter();
+                tgt.args.0.retain(|_| *kept.next().unwrap());
+            }
+        };
+        for &block in blocks {
+            match module[block].instructions.last_mut() {
+                Some(Instruction::Jump(tgt)) => drop_args(tgt),
+                Some(Instruction::Branch(_, t, f)) => {
+                    drop_args(t);
+                    drop_args(f);
+                }
+                _ => (),
+            }
+        }
+        changed
+    }
+}
+
+/// Whether the instruction must stay even if its result is unused.
+fn has_side_effects(instr: &Instruction) -> bool {
+    use Instruction::*;
+
+    instr.is_terminator() || matches!(instr, Store { .. } | Call(..) | CallPtr(..) | SyscallLinux64 { .. })
+}
+
+/// The registers that instructions with side effects depend on, directly or through
+/// other instructions and block parameters.
+fn live_registers(module: &Module, blocks: &[BlockID]) -> HashSet<RegID> {
+    let mut defs = HashMap::new();
+    let mut params = HashMap::new();
+    let mut incoming: HashMap<BlockID, Vec<&JumpTarget>> = HashMap::new();
+    let mut work = Vec::new();
+
+    for &block in blocks {
+        for (i, &param) in module[block].parameters.iter().enumerate() {
+            params.insert(param, (block, i));
+        }
+        for instr in &module[block].instructions {
+            if let Some(dst) = instr.dst() {
+                defs.insert(dst, instr);
+            }
+            match instr {
+                // Arguments are only live if the parameter they are passed to is.
+                Instruction::Jump(tgt) => incoming.entry(tgt.block).or_default().push(tgt),
+                Instruction::Branch(c, t, f) => {
+                    work.extend(c.as_reg());
+                    incoming.entry(t.block).or_default().push(t);
+                    incoming.entry(f.block).or_default().push(f);
+                }
+                _ if has_side_effects(instr) => work.extend(instr.uses()),
+                _ => (),
+            }
+        }
+    }
+
+    let mut live = HashSet::new();
+    while let Some(reg) = work.pop() {
+        if !live.insert(reg) {
+            continue;
+        }
+        if let Some(instr) = defs.get(&reg) {
+            work.extend(instr.uses());
+        }
+        else if let Some(&(block, i)) = params.get(&reg) {
+            let args = incoming.get(&block).into_iter().flatten().map(|tgt| tgt.args.0[i]);
+            work.extend(args.filter_map(Value::as_reg));
+        }
+    }
+    live
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frontend::{Builder, IntTy},
+        interpreter::Interpreter,
+        passes::{ConstFold, Mem2Reg, PassManager, tests::*},
+        target::Target,
+    };
+
+    #[test]
+    fn removes_dead_code() {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let fid = b.begin_fun("count".into(), IntTy::I64);
+        let header = b.create_block();
+        let body = b.create_block();
+        let exit = b.create_block();
+        let unreachable = b.create_block();
+
+        b.begin_block();
+        b.set_entry_block();
+        let var = b.create_var(IntTy::I64);
+        let ptr = b.get_var_addr(var);
+        let unused = b.load(IntTy::I64, ptr);
+        b.add(unused, 1i64);
+        b.jump((header, [Value::Int(IntTy::I64, 0), Value::Int(IntTy::I64, 0)]));
+
+        b.select_block(header);
+        let i = b.create_block_param(IntTy::I64);
+        let ignored = b.create_block_param(IntTy::I64);
+        let cond = b.test_l(i, 10i64);
+        b.branch(cond, body, exit);
+
+        b.select_block(body);
+        b.store(ptr, i);
+        let i = b.add(i, 1i64);
+        let ignored = b.mul(ignored, 3i64);
+        b.jump((header, [Value::Reg(i), Value::Reg(ignored)]));
+
+        b.select_block(exit);
+        let result = b.load(IntTy::I64, ptr);
+        b.ret(result);
+
+        b.select_block(unreachable);
+        b.jump((header, [Value::Int(IntTy::I64, 1), Value::Int(IntTy::I64, 1)]));
+        let mut module = b.finish();
+
+        assert!(DeadCodeElim.run_on_function(&mut module, fid, &mut Analyses::new()));
+        assert!(module.verify().is_empty(), "{:?}", module.verify());
+        assert!(!module[fid].blocks.contains(&unreachable));
+        let entry = module[fid].entry_block.unwrap();
+        assert_eq!(module[entry].instructions.len(), 2);
+        assert_eq!(module[header].parameters.len(), 1);
+        assert_eq!(module[body].instructions.len(), 3);
+        assert_eq!(Interpreter::new(&module).call(fid, &[]), Ok(Value::Int(IntTy::I64, 9)));
+        assert!(!DeadCodeElim.run_on_function(&mut module, fid, &mut Analyses::new()));
+    }
+
+    #[test]
+    fn generated_programs_keep_their_behaviour() {
+        let pipeline = || {
+            PassManager::new()
+                .with_function_pass(Mem2Reg)
+                .with_function_pass(ConstFold)
+                .with_function_pass(DeadCodeElim)
+        };
+        check_pipeline_preserves_behaviour(pipeline, 100);
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use crate::frontend::{Analyses, BinOp, BlockID, FunID, Instruction, Module, RegID, StructTyID, Ty, UnOp, Value};
+
+use super::FunctionPass;
+
+/// Finds binary and unary operations and address computations that compute the same value
+/// as one that dominates them, removes them and redirects their uses to the dominating one.
+///
+/// Operands of commutative operations match in either order, so `b + a` reuses `a + b`.
+pub struct Gvn;
+impl FunctionPass for Gvn {
+    fn name(&self) -> &'static str {
+        "gvn"
+    }
+    fn run_on_function(&mut self, module: &mut Module, fun: FunID, analyses: &mut Analyses) -> bool {
+        enum Step {
+            Enter(BlockID),
+            Leave(Vec<Expr>),
+        }
+
+        let Some(entry) = module[fun].entry_block else {
+            return false;
+        };
+        let dom = analyses.dom_tree(module, fun);
+
+        let mut numbering = Numbering {
+            module,
+            available: HashMap::new(),
+            replaced: HashMap::new(),
+        };
+        // Walks the dominator tree so an expression is available exactly in the blocks its first computation dominates.
+        let mut steps = vec![Step::Enter(entry)];
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Enter(block) => {
+                    let computed = numbering.number_block(block);
+                    steps.push(Step::Leave(computed));
+                    for &child in dom.children(block).iter().rev() {
+                        steps.push(Step::Enter(child));
+                    }
+                }
+                Step::Leave(computed) => {
+                    for expr in computed {
+                        numbering.available.remove(&expr);
+                    }
+                }
+            }
+        }
+        if numbering.replaced.is_empty() {
+            return false;
+        };
+
+        let mut unreachable: Vec<_> = numbering.module[fun].blocks.iter().copied().filter(|&b| !dom.contains(b)).collect();
+        unreachable.sort_by_key(|b| b.0);
+        for block in unreachable {
+            let mut instructions = std::mem::take(&mut numbering.module[block].instructions);
+            instructions.iter_mut().for_each(|instr| numbering.replace_operands(instr));
+            numbering.module[block].instructions = instructions;
+        }
+        true
+    }
+}
+
+/// An operation and its operands, which computes the same value wherever its operands are available.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Expr {
+    Binary(BinOp, Value, Value),
+    /// The result type tells apart casts of the same value to different types.
+    Unary(UnOp, Value, Ty),
+    IndexStruct(StructTyID, RegID, u64),
+    IndexArray(Ty, RegID, Value),
+}
+impl Expr {
+    fn new(module: &Module, instr: &Instruction) -> Option<Self> {
+        match *instr {
+            Instruction::Binary(op, _, a, b) if is_commutative(op) && order(b) < order(a) => Some(Self::Binary(op, b, a)),
+            Instruction::Binary(op, _, a, b) => Some(Self::Binary(op, a, b)),
+            Instruction::Unary(op, dst, a) => Some(Self::Unary(op, a, module[dst].ty)),
+            Instruction::IndexStruct { ptr, struct_ty, index, .. } => Some(Self::IndexStruct(struct_ty, ptr, index)),
+            Instruction::IndexArray { ptr, element_ty, index, .. } => Some(Self::IndexArray(element_ty, ptr, index)),
+            _ => None,
+        }
+    }
+}
+
+fn is_commutative(op: BinOp) -> bool {
+    use BinOp::*;
+
+    matches!(op, Add | Mul | And | Or | Xor | Equal | NotEqual)
+}
+/// Any total order on values, to put the operands of commutative operations in a fixed order.
+fn order(value: Value) -> (u8, i64) {
+    match value {
+        Value::Void => (0, 0),
+        Value::Bool(value) => (1, value as i64),
+        Value::Int(_, value) => (2, value),
+        Value::Reg(reg) => (3, reg.0 as i64),
+    }
+}
+
+struct Numbering<'a> {
+    module: &'a mut Module,
+    /// The register holding every expression computed in a block that dominates the current one.
+    available: HashMap<Expr, RegID>,
+    /// Removed registers and the ones that hold the same value.
+    replaced: HashMap<RegID, RegID>,
+}
+impl Numbering<'_> {
+    /// Returns the expressions this block made available.
+    fn number_block(&mut self, block: BlockID) -> Vec<Expr> {
+        let mut computed = Vec::new();
+
+        let instructions = std::mem::take(&mut self.module[block].instructions);
+        let mut numbered = Vec::with_capacity(instructions.len());
+        for mut instr in instructions {
+            self.replace_operands(&mut instr);
+            if let Some(expr) = Expr::new(self.module, &instr) {
+                let dst = instr.dst().unwrap();
+                if let Some(&first) = self.available.get(&expr) {
+                    self.replaced.insert(dst, first);
+                    continue;
+                }
+                self.available.insert(expr, dst);
+                computed.push(expr);
+            }
+            numbered.push(instr);
+        }
+        self.module[block].instructions = numbered;
+
+        computed
+    }
+    fn replace_operands(&self, instr: &mut Instruction) {
+        for reg in instr.uses_mut() {
+            if let Some(&new) = self.replaced.get(reg) {
+                *reg = new;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frontend::{Builder, IntTy},
+        interpreter::Interpreter,
+        passes::{ConstFold, DeadCodeElim, Mem2Reg, PassManager, tests::*},
+        target::Target,
+    };
+
+    #[test]
+    fn reuses_dominating_expressions() {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let fid = b.begin_fun("gvn".into(), IntTy::I64);
+        let x = b.create_param(IntTy::I64);
+        let y = b.create_param(IntTy::I64);
+        let then = b.create_block();
+        let other = b.create_block();
+        let join = b.create_block();
+
+        b.begin_block();
+        b.set_entry_block();
+        let sum = b.add(x, y);
+        let equal = b.test_eq(x, y);
+        b.branch(equal, then, other);
+
+        b.select_block(then);
+        let swapped = b.add(y, x);
+        let difference = b.sub(y, x);
+        let product = b.mul(swapped, 2i64);
+        let again = b.mul(sum, 2i64);
+        let total = b.add(product, again);
+        b.jump(join);
+
+        b.select_block(other);
+        let equal_again = b.test_eq(y, x);
+        let wide = b.zext(IntTy::I64, equal_again);
+        b.mul(sum, 2i64);
+        b.jump(join);
+
+        b.select_block(join);
+        let product_after = b.mul(sum, 2i64);
+        b.ret(product_after);
+        let mut module = b.finish();
+
+        assert!(Gvn.run_on_function(&mut module, fid, &mut Analyses::new()));
+        assert!(module.verify().is_empty(), "{:?}", module.verify());
+        let expected = [
+            Instruction::Binary(BinOp::Sub, difference, Value::Reg(y), Value::Reg(x)),
+            Instruction::Binary(BinOp::Mul, product, Value::Reg(sum), Value::Int(IntTy::I64, 2)),
+            Instruction::Binary(BinOp::Add, total, Value::Reg(product), Value::Reg(product)),
+            Instruction::Jump(join.into()),
+        ];
+        assert_eq!(module[then].instructions, expected);
+        assert_eq!(module[other].instructions[0], Instruction::Unary(UnOp::Zext, wide, Value::Reg(equal)));
+        assert_eq!(module[other].instructions.len(), 3);
+        // Neither of the blocks computing `sum * 2` before dominates the join.
+        assert_eq!(module[join].instructions.len(), 2);
+        assert!(!Gvn.run_on_function(&mut module, fid, &mut Analyses::new()));
+
+        let args = [Value::Int(IntTy::I64, 3), Value::Int(IntTy::I64, 3)];
+        assert_eq!(Interpreter::new(&module).call(fid, &args), Ok(Value::Int(IntTy::I64, 12)));
+    }
+
+    #[test]
+    fn reuses_dominating_addresses() {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let pair = b.module.add_struct_ty();
+        b.module.add_struct_member(pair, IntTy::I64.into());
+        b.module.add_struct_member(pair, IntTy::I64.into());
+        let fid = b.begin_fun("gvn".into(), IntTy::I64);
+        let i = b.create_param(IntTy::I64);
+        let body = b.create_block();
+
+        b.begin_block();
+        b.set_entry_block();
+        let var = b.create_var(pair);
+        let ptr = b.get_var_addr(var);
+        let second = b.index_struct(pair, ptr, 1);
+        b.store(second, 5i64);
+        let element = b.index_array(IntTy::I64, ptr, i);
+        b.store(element, 2i64);
+        b.jump(body);
+
+        b.select_block(body);
+        let first = b.index_struct(pair, ptr, 0);
+        let second_again = b.index_struct(pair, ptr, 1);
+        let element_again = b.index_array(IntTy::I64, ptr, i);
+        let byte = b.index_array(IntTy::I8, ptr, i);
+        let a = b.load(IntTy::I64, second_again);
+        let c = b.load(IntTy::I64, element_again);
+        let sum = b.add(a, c);
+        b.store(byte, Value::Int(IntTy::I8, 0));
+        b.store(first, 0i64);
+        b.ret(sum);
+        let mut module = b.finish();
+
+        assert!(Gvn.run_on_function(&mut module, fid, &mut Analyses::new()));
+        assert!(module.verify().is_empty(), "{:?}", module.verify());
+        let kept: Vec<_> = module[body].instructions.iter().filter_map(Instruction::dst).collect();
+        assert!(!kept.contains(&second_again) && !kept.contains(&element_again));
+        assert!(kept.contains(&first) && kept.contains(&byte));
+        assert!(module[body].instructions.contains(&Instruction::Load { dst: a, ptr: second }));
+        assert!(module[body].instructions.contains(&Instruction::Load { dst: c, ptr: element }));
+
+        let args = [Value::Int(IntTy::I64, 0)];
+        assert_eq!(Interpreter::new(&module).call(fid, &args), Ok(Value::Int(IntTy::I64, 7)));
+    }
+
+    #[test]
+    fn generated_programs_keep_their_behaviour() {
+        check_pipeline_preserves_behaviour(|| PassManager::new().with_function_pass(Mem2Reg).with_function_pass(Gvn), 20);
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn native_agrees_after_the_whole_pipeline() {
+        check_pipeline_agrees_natively(|| {
+            PassManager::new()
+                .with_function_pass(Mem2Reg)
+                .with_function_pass(ConstFold)
+                .with_function_pass(Gvn)
+                .with_function_pass(DeadCodeElim)
+        });
+    }
+}
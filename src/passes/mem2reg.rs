@@ -0,0 +1,352 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::frontend::{Analyses, BlockID, DomTree, FunID, Instruction, JumpTarget, Module, RegID, Ty, Value, VarID};
+
+use super::FunctionPass;
+
+/// Promotes variables whose address never escapes into SSA registers.
+///
+/// Stores become definitions, loads are replaced by the value stored last,
+/// and block parameters carry values where stores from different paths meet,
+/// at the iterated dominance frontiers of the stores.
+/// Loads before any store read a `Poison`.
+pub struct Mem2Reg;
+impl FunctionPass for Mem2Reg {
+    fn name(&self) -> &'static str {
+        "mem2reg"
+    }
+    fn run_on_function(&mut self, module: &mut Module, fun: FunID, analyses: &mut Analyses) -> bool {
+        let Some(entry) = module[fun].entry_block else {
+            return false;
+        };
+        let dom = analyses.dom_tree(module, fun);
+
+        let mut vars: Vec<_> = module[fun].variables.iter().copied().collect();
+        vars.sort_by_key(|v| v.0);
+        vars.retain(|&var| is_promotable(module, fun, var));
+
+        // Values can't be handed to the entry block, so anything that would need it stays in memory.
+        let params: Vec<_> = vars.iter().map(|&var| dom.iterated_frontier(store_blocks(module, fun, var, &dom))).collect();
+        let (vars, params): (Vec<_>, Vec<_>) = vars
+            .into_iter()
+            .zip(params)
+            .filter(|(_, blocks)| !blocks.contains(&entry))
+            .unzip();
+        if vars.is_empty() {
+            return false;
+        };
+
+        let mut unreachable: Vec<_> = module[fun].blocks.iter().copied().filter(|&b| !dom.contains(b)).collect();
+        unreachable.sort_by_key(|b| b.0);
+        let tys = vars.iter().map(|&var| module[var].ty).collect();
+
+        let mut renamer = Renamer {
+            module,
+            fun,
+            vars: vars.iter().enumerate().map(|(i, &var)| (var, i)).collect(),
+            tys,
+            params: HashMap::new(),
+            addrs: HashMap::new(),
+            replaced: HashMap::new(),
+            current: vec![Vec::new(); vars.len()],
+            undefined: HashMap::new(),
+        };
+        for (i, blocks) in params.into_iter().enumerate() {
+            let ty = renamer.tys[i];
+            for block in blocks {
+                let param = renamer.module.add_register(fun, ty);
+                renamer.module.add_block_parameter(block, param);
+                renamer.params.entry(block).or_default().push((i, param));
+            }
+        }
+
+        renamer.rename_tree(entry, &dom);
+        // Unreachable blocks are left alone otherwise, but must not touch the variables anymore.
+        for block in unreachable {
+            renamer.rename_tree(block, &DomTree::default());
+        }
+
+        for var in vars {
+            renamer.module[fun].variables.remove(&var);
+        }
+        true
+    }
+}
+
+/// Whether every access to the variable is a load or store of its whole value through its address.
+fn is_promotable(module: &Module, fun: FunID, var: VarID) -> bool {
+    let ty = module[var].ty;
+    let mut blocks: Vec<_> = module[fun].blocks.iter().copied().collect();
+    blocks.sort_by_key(|b| b.0);
+
+    let mut addrs = HashSet::new();
+    for &block in &blocks {
+        for instr in &module[block].instructions {
+            if let Instruction::GetVarAddr(dst, v) = *instr
+                && v == var
+            {
+                addrs.insert(dst);
+            }
+        }
+    }
+
+    for &block in &blocks {
+        for instr in &module[block].instructions {
+            let uses = instr.uses();
+            if !uses.iter().any(|reg| addrs.contains(reg)) {
+                continue;
+            }
+            let valid = match *instr {
+                Instruction::Load { dst, ptr } => addrs.contains(&ptr) && module[dst].ty == ty,
+                Instruction::Store { ptr, value } => {
+                    let escapes = matches!(value, Value::Reg(reg) if addrs.contains(&reg));
+                    addrs.contains(&ptr) && !escapes && value.ty(module) == ty
+                }
+                _ => false,
+            };
+            if !valid {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+struct Renamer<'a> {
+    module: &'a mut Module,
+    fun: FunID,
+
+    /// Index of every promoted variable.
+    vars: HashMap<VarID, usize>,
+    tys: Vec<Ty>,
+    /// The parameters added to a block, by variable index.
+    params: HashMap<BlockID, Vec<(usize, RegID)>>,
+    /// Registers holding the address of a promoted variable.
+    addrs: HashMap<RegID, usize>,
+    /// Loaded registers and what they are replaced by.
+    replaced: HashMap<RegID, Value>,
+    /// The value every variable holds at the current point of the walk.
+    current: Vec<Vec<Value>>,
+    /// The `Poison` read by loads that no store reaches.
+    undefined: HashMap<usize, RegID>,
+}
+impl Renamer<'_> {
+    /// Rewrites the blocks dominated by `root` in dominator tree preorder,
+    /// so every load sees the stores that dominate it.
+    fn rename_tree(&mut self, root: BlockID, dom: &DomTree) {
+        enum Step {
+            Enter(BlockID),
+            Leave(Vec<usize>),
+        }
+
+        self.undefined.clear();
+        let mut steps = vec![Step::Enter(root)];
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Enter(block) => {
+                    let defined = self.rename_block(block);
+                    steps.push(Step::Leave(defined));
+                    for &child in dom.children(block).iter().rev() {
+                        steps.push(Step::Enter(child));
+                    }
+                }
+                Step::Leave(defined) => {
+                    for var in defined {
+                        self.current[var].pop();
+                    }
+                }
+            }
+        }
+
+        let mut undefined: Vec<_> = self.undefined.drain().collect();
+        undefined.sort_by_key(|&(var, _)| var);
+        for (i, (_, reg)) in undefined.into_iter().enumerate() {
+            self.module[root].instructions.insert(i, Instruction::Poison(reg));
+        }
+    }
+    /// Returns the variables this block gave a new value, once for every value.
+    fn rename_block(&mut self, block: BlockID) -> Vec<usize> {
+        let mut defined = Vec::new();
+        for &(var, param) in self.params.get(&block).into_iter().flatten() {
+            self.current[var].push(Value::Reg(param));
+            defined.push(var);
+        }
+
+        let instructions = std::mem::take(&mut self.module[block].instructions);
+        let mut renamed = Vec::with_capacity(instructions.len());
+        for mut instr in instructions {
+            self.replace_operands(&mut instr);
+            match instr {
+                Instruction::GetVarAddr(dst, var) if self.vars.contains_key(&var) => {
+                    self.addrs.insert(dst, self.vars[&var]);
+                    continue;
+                }
+                Instruction::Load { dst, ptr } if self.addrs.contains_key(&ptr) => {
+                    let value = self.value(self.addrs[&ptr]);
+                    self.replaced.insert(dst, value);
+                    continue;
+                }
+                Instruction::Store { ptr, value } if self.addrs.contains_key(&ptr) => {
+                    let var = self.addrs[&ptr];
+                    self.current[var].push(value);
+                    defined.push(var);
+                    continue;
+                }
+                Instruction::Jump(ref mut tgt) => self.pass_values(tgt),
+                Instruction::Branch(_, ref mut t, ref mut f) => {
+                    self.pass_values(t);
+                    self.pass_values(f);
+                }
+                _ => (),
+            }
+            renamed.push(instr);
+        }
+        self.module[block].instructions = renamed;
+
+        defined
+    }
+    fn replace_operands(&self, instr: &mut Instruction) {
+        for value in instr.values_mut() {
+            if let Value::Reg(reg) = *value
+                && let Some(&new) = self.replaced.get(&reg)
+            {
+                *value = new;
+            }
+        }
+        for reg in instr.uses_mut() {
+            if let Some(&Value::Reg(new)) = self.replaced.get(reg) {
+                *reg = new;
+            }
+        }
+    }
+    fn pass_values(&mut self, tgt: &mut JumpTarget) {
+        let Some(params) = self.params.get(&tgt.block) else {
+            return;
+        };
+
+        let vars: Vec<_> = params.iter().map(|&(var, _)| var).collect();
+        for var in vars {
+            let value = self.value(var);
+            tgt.args.0.push(value);
+        }
+    }
+    fn value(&mut self, var: usize) -> Value {
+        if let Some(&value) = self.current[var].last() {
+            return value;
+        };
+
+        let (module, fun, ty) = (&mut *self.module, self.fun, self.tys[var]);
+        let reg = *self.undefined.entry(var).or_insert_with(|| module.add_register(fun, ty));
+        Value::Reg(reg)
+    }
+}
+
+/// The blocks of the dominator tree that store to the variable.
+fn store_blocks(module: &Module, fun: FunID, var: VarID, dom: &DomTree) -> Vec<BlockID> {
+    let mut blocks: Vec<_> = module[fun].blocks.iter().copied().filter(|&b| dom.contains(b)).collect();
+    blocks.sort_by_key(|b| b.0);
+
+    let mut addrs = HashSet::new();
+    for &block in &blocks {
+        for instr in &module[block].instructions {
+            if let Instruction::GetVarAddr(dst, v) = *instr
+                && v == var
+            {
+                addrs.insert(dst);
+            }
+        }
+    }
+    blocks.retain(|&block| {
+        let instrs = &module[block].instructions;
+        instrs.iter().any(|i| matches!(*i, Instruction::Store { ptr, .. } if addrs.contains(&ptr)))
+    });
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frontend::{Builder, IntTy},
+        interpreter::Interpreter,
+        passes::{PassManager, tests::*},
+        target::Target,
+    };
+
+    fn memory_accesses(module: &Module, fun: FunID) -> usize {
+        let blocks = module[fun].blocks.iter();
+        let instrs = blocks.flat_map(|&b| module[b].instructions.iter());
+        instrs.filter(|i| matches!(i, Instruction::GetVarAddr(..) | Instruction::Load { .. } | Instruction::Store { .. })).count()
+    }
+
+    #[test]
+    fn promotes_loop_variables() {
+        let mut b = Builder::new(Module::new(Target::LINUX_X64));
+        let fid = b.begin_fun("sum".into(), IntTy::I64);
+        let header = b.create_block();
+        let body = b.create_block();
+        let exit = b.create_block();
+
+        b.begin_block();
+        b.set_entry_block();
+        let i = b.create_var(IntTy::I64);
+        let acc = b.create_var(IntTy::I64);
+        let escaped = b.create_var(IntTy::I64);
+        let ptr = b.get_var_addr(i);
+        b.store(ptr, 0i64);
+        let ptr = b.get_var_addr(acc);
+        b.store(ptr, 0i64);
+        let escaped_ptr = b.get_var_addr(escaped);
+        b.store(escaped_ptr, 100i64);
+        b.jump(header);
+
+        b.select_block(header);
+        let ptr = b.get_var_addr(i);
+        let value = b.load(IntTy::I64, ptr);
+        let cond = b.test_l(value, 10i64);
+        b.branch(cond, body, exit);
+
+        b.select_block(body);
+        let i_ptr = b.get_var_addr(i);
+        let acc_ptr = b.get_var_addr(acc);
+        let value = b.load(IntTy::I64, i_ptr);
+        let sum = b.load(IntTy::I64, acc_ptr);
+        let sum = b.add(sum, value);
+        b.store(acc_ptr, sum);
+        let value = b.add(value, 1i64);
+        b.store(i_ptr, value);
+        b.jump(header);
+
+        b.select_block(exit);
+        let ptr = b.get_var_addr(acc);
+        let sum = b.load(IntTy::I64, ptr);
+        let element = b.index_array(IntTy::I64, escaped_ptr, 0i64);
+        let extra = b.load(IntTy::I64, element);
+        let sum = b.add(sum, extra);
+        b.ret(sum);
+        let mut module = b.finish();
+
+        assert!(Mem2Reg.run_on_function(&mut module, fid, &mut Analyses::new()));
+        assert!(module.verify().is_empty(), "{:?}", module.verify());
+        assert_eq!(module[fid].variables.iter().copied().collect::<Vec<_>>(), [escaped]);
+        // Only the escaped variable's address, store and two loads remain.
+        assert_eq!(memory_accesses(&module, fid), 3);
+        assert_eq!(module[header].parameters.len(), 2);
+
+        let result = Interpreter::new(&module).call(fid, &[]);
+        assert_eq!(result, Ok(Value::Int(IntTy::I64, 145)));
+        assert!(!Mem2Reg.run_on_function(&mut module, fid, &mut Analyses::new()));
+    }
+
+    #[test]
+    fn generated_programs_keep_their_behaviour() {
+        check_pipeline_preserves_behaviour(|| PassManager::new().with_function_pass(Mem2Reg), 100);
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn native_agrees_after_promotion() {
+        check_pipeline_agrees_natively(|| PassManager::new().with_function_pass(Mem2Reg));
+    }
+}
@@ -0,0 +1,1127 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, Write},
+};
+
+use crate::frontend::{
+    BlockID, FunID, FunTyID, Function, GlobalID, GlobalValue, Instruction, IntTy, JumpTarget, Module, Printer,
+    RegID, StructTyID, Ty, UnOp, Value, Values, VarID,
+};
+
+/// Shrinks a module while a predicate keeps holding,
+/// to turn a large miscompiled program into a small test case.
+///
+/// Every candidate is the current module with a set of edits applied.
+/// Sets start large and are halved whenever they fail, as in delta debugging,
+/// and all kinds of edits are retried until none of them makes progress.
+/// Candidates that do not verify are thrown away without running the predicate.
+///
+/// Removing functions and globals renumbers the rest,
+/// so predicates should look up functions by name rather than by id.
+pub struct Reducer<P> {
+    module: Module,
+    predicate: P,
+    tests: usize,
+}
+impl<P: FnMut(&Module) -> bool> Reducer<P> {
+    pub fn new(module: Module, predicate: P) -> Self {
+        Self {
+            module,
+            predicate,
+            tests: 0,
+        }
+    }
+
+    /// Reduces until no edit keeps the predicate true.
+    /// Panics if the predicate does not hold for the original module.
+    pub fn reduce(&mut self) -> &Module {
+        self.tests += 1;
+        assert!((self.predicate)(&self.module), "the predicate does not hold for the original module");
+
+        let mut progress = true;
+        while progress {
+            progress = false;
+            for edit in EDITS {
+                progress |= self.reduce_with(edit);
+            }
+        }
+
+        // Drops what the edits orphaned and numbers everything densely.
+        let compacted = compact(&self.module, &HashSet::new(), &HashSet::new());
+        if self.test(&compacted) {
+            self.module = compacted;
+        }
+
+        &self.module
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+    pub fn into_module(self) -> Module {
+        self.module
+    }
+    /// How often the predicate has been run.
+    pub fn tests(&self) -> usize {
+        self.tests
+    }
+    pub fn print(&self, out: impl Write) -> io::Result<()> {
+        Printer::new(&self.module, out).print()
+    }
+
+    fn reduce_with(&mut self, edit: Edit) -> bool {
+        let mut sites = edit.sites(&self.module);
+        let mut progress = false;
+
+        let mut chunk = sites.len();
+        while chunk > 0 {
+            let mut start = 0;
+            while start < sites.len() {
+                let end = (start + chunk).min(sites.len());
+                let mut candidate = self.module.clone();
+                if edit.apply(&mut candidate, &sites[start..end]) && self.test(&candidate) {
+                    self.module = candidate;
+                    sites = edit.sites(&self.module);
+                    progress = true;
+                }
+                else {
+                    start += chunk;
+                }
+            }
+            chunk /= 2;
+        }
+
+        progress
+    }
+    fn test(&mut self, candidate: &Module) -> bool {
+        if !candidate.verify().is_empty() {
+            return false;
+        };
+
+        self.tests += 1;
+        (self.predicate)(candidate)
+    }
+}
+
+const EDITS: [Edit; 12] = [
+    Edit::RemoveFunctions,
+    Edit::RemoveGlobals,
+    Edit::FoldBranches(true),
+    Edit::FoldBranches(false),
+    Edit::RemoveInstructions,
+    Edit::MergeBlocks,
+    Edit::RemoveBlockParams,
+    Edit::RemoveFunParams,
+    Edit::RemoveStructMembers,
+    Edit::SimplifyConstants(0),
+    Edit::SimplifyConstants(1),
+    Edit::SimplifyGlobalValues,
+];
+
+#[derive(Copy, Clone, Debug)]
+enum Edit {
+    /// Functions nothing else refers to.
+    RemoveFunctions,
+    /// Globals nothing else refers to.
+    RemoveGlobals,
+    /// Turns branches into jumps to one side, dropping blocks that become unreachable.
+    FoldBranches(bool),
+    /// Replaces the results of removed instructions by one of their operands or by zero.
+    RemoveInstructions,
+    /// Merges blocks into their only predecessor.
+    MergeBlocks,
+    RemoveBlockParams,
+    /// Parameters of functions whose address is never taken.
+    RemoveFunParams,
+    /// Members that are never accessed directly.
+    RemoveStructMembers,
+    /// Replaces integer constants by the given one.
+    SimplifyConstants(i64),
+    /// Sets every integer in a global's value to zero and its strings to empty ones.
+    SimplifyGlobalValues,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Site {
+    Fun(FunID),
+    Global(GlobalID),
+    Block(BlockID),
+    Instr(BlockID, usize),
+    Operand(BlockID, usize, usize),
+    BlockParam(BlockID, usize),
+    FunParam(FunID, usize),
+    Member(StructTyID, usize),
+}
+
+impl Edit {
+    /// Sites are ordered so that applying them back to front
+    /// never shifts the position of one that has yet to be applied.
+    fn sites(self, module: &Module) -> Vec<Site> {
+        let mut sites = Vec::new();
+        match self {
+            Edit::RemoveFunctions => {
+                let referenced = referenced_functions(module, false);
+                let funs = module.functions().iter().map(|f| f.id);
+                sites.extend(funs.filter(|f| !referenced.contains(f)).map(Site::Fun));
+            }
+            Edit::RemoveGlobals => {
+                let referenced = referenced_globals(module);
+                let globals = module.globals().iter().map(|g| g.id);
+                sites.extend(globals.filter(|g| !referenced.contains(g)).map(Site::Global));
+            }
+            Edit::FoldBranches(_) => {
+                for block in all_blocks(module) {
+                    if let Some(Instruction::Branch(..)) = module[block].terminator() {
+                        sites.push(Site::Block(block));
+                    }
+                }
+            }
+            Edit::RemoveInstructions => {
+                for block in all_blocks(module) {
+                    let instructions = &module[block].instructions;
+                    let removable = instructions.iter().take_while(|i| !i.is_terminator()).count();
+                    sites.extend((0..removable).map(|i| Site::Instr(block, i)));
+                }
+            }
+            Edit::MergeBlocks => {
+                for block in all_blocks(module) {
+                    if merge_target(module, block).is_some() {
+                        sites.push(Site::Block(block));
+                    }
+                }
+            }
+            Edit::RemoveBlockParams => {
+                for block in all_blocks(module) {
+                    let params = &module[block].parameters;
+                    for (i, &param) in params.iter().enumerate() {
+                        if zero_instr(module, param).is_some() {
+                            sites.push(Site::BlockParam(block, i));
+                        }
+                    }
+                }
+            }
+            Edit::RemoveFunParams => {
+                let address_taken = referenced_functions(module, true);
+                for fun in module.functions() {
+                    if fun.entry_block.is_none() || address_taken.contains(&fun.id) {
+                        continue;
+                    }
+                    for (i, &param) in fun.parameters.iter().enumerate() {
+                        if zero_instr(module, param).is_some() {
+                            sites.push(Site::FunParam(fun.id, i));
+                        }
+                    }
+                }
+            }
+            Edit::RemoveStructMembers => {
+                let accessed = accessed_members(module);
+                for strct in struct_tys(module) {
+                    for i in 0..module[strct].members.len() {
+                        if !accessed.contains(&(strct, i)) {
+                            sites.push(Site::Member(strct, i));
+                        }
+                    }
+                }
+            }
+            Edit::SimplifyConstants(to) => {
+                for block in all_blocks(module) {
+                    for (i, instr) in module[block].instructions.iter().enumerate() {
+                        let mut instr = instr.clone();
+                        for (k, value) in instr.values_mut().into_iter().enumerate() {
+                            if simplify_constant(*value, to).is_some() {
+                                sites.push(Site::Operand(block, i, k));
+                            }
+                        }
+                    }
+                }
+            }
+            Edit::SimplifyGlobalValues => {
+                for global in module.globals() {
+                    if let Some(value) = &global.value
+                        && zero_global_value(value) != *value
+                    {
+                        sites.push(Site::Global(global.id));
+                    }
+                }
+            }
+        }
+
+        sites
+    }
+    /// Applies the edit at every site and reports whether the module changed.
+    fn apply(self, module: &mut Module, sites: &[Site]) -> bool {
+        let mut changed = false;
+        match self {
+            Edit::RemoveFunctions | Edit::RemoveGlobals => {
+                let mut funs = HashSet::new();
+                let mut globals = HashSet::new();
+                for &site in sites {
+                    match site {
+                        Site::Fun(fun) => funs.insert(fun),
+                        Site::Global(global) => globals.insert(global),
+                        _ => unreachable!(),
+                    };
+                }
+                *module = compact(module, &funs, &globals);
+                changed = true;
+            }
+            Edit::FoldBranches(taken) => {
+                for &site in sites {
+                    let Site::Block(block) = site else { unreachable!() };
+                    let instructions = &mut module[block].instructions;
+                    let Some(Instruction::Branch(_, t, f)) = instructions.pop() else { unreachable!() };
+                    instructions.push(Instruction::Jump(if taken { t } else { f }));
+                }
+                remove_unreachable_blocks(module);
+                changed = true;
+            }
+            Edit::RemoveInstructions => {
+                for &site in sites.iter().rev() {
+                    let Site::Instr(block, i) = site else { unreachable!() };
+                    changed |= remove_instr(module, block, i);
+                }
+            }
+            Edit::MergeBlocks => {
+                for &site in sites {
+                    let Site::Block(block) = site else { unreachable!() };
+                    changed |= merge_blocks(module, block);
+                }
+            }
+            Edit::RemoveBlockParams => {
+                for &site in sites.iter().rev() {
+                    let Site::BlockParam(block, i) = site else { unreachable!() };
+                    let fun = module[block].fun;
+                    let param = module[block].parameters.remove(i);
+                    replace_by_zero(module, fun, block, param);
+                    for_each_instr(module, fun, |instr| {
+                        for tgt in jump_targets(instr) {
+                            if tgt.block == block {
+                                tgt.args.0.remove(i);
+                            }
+                        }
+                    });
+                }
+                changed = true;
+            }
+            Edit::RemoveFunParams => {
+                for &site in sites.iter().rev() {
+                    let Site::FunParam(fun, i) = site else { unreachable!() };
+                    let param = module[fun].parameters.remove(i);
+                    let entry = module[fun].entry_block.unwrap();
+                    replace_by_zero(module, fun, entry, param);
+                    for caller in all_funs(module) {
+                        for_each_instr(module, caller, |instr| {
+                            if let Instruction::Call(_, callee, args) = instr
+                                && *callee == fun
+                            {
+                                args.0.remove(i);
+                            }
+                        });
+                    }
+                }
+                changed = true;
+            }
+            Edit::RemoveStructMembers => {
+                for &site in sites.iter().rev() {
+                    let Site::Member(strct, i) = site else { unreachable!() };
+                    remove_struct_member(module, strct, i);
+                }
+                changed = true;
+            }
+            Edit::SimplifyConstants(to) => {
+                for &site in sites {
+                    let Site::Operand(block, i, k) = site else { unreachable!() };
+                    let mut values = module[block].instructions[i].values_mut();
+                    if let Some(value) = simplify_constant(*values[k], to) {
+                        *values[k] = value;
+                        changed = true;
+                    }
+                }
+            }
+            Edit::SimplifyGlobalValues => {
+                for &site in sites {
+                    let Site::Global(global) = site else { unreachable!() };
+                    let value = module.globals()[global.0].value.as_ref().map(zero_global_value);
+                    module.set_global_value(global, value.unwrap());
+                }
+                changed = true;
+            }
+        }
+
+        changed
+    }
+}
+
+/// Deletes an instruction and reroutes its uses,
+/// or at least replaces it by one without operands.
+fn remove_instr(module: &mut Module, block: BlockID, i: usize) -> bool {
+    let fun = module[block].fun;
+    let instr = module[block].instructions[i].clone();
+    let Some(dst) = instr.dst() else {
+        module[block].instructions.remove(i);
+        return true;
+    };
+
+    let used = all_blocks_of(&module[fun])
+        .into_iter()
+        .any(|b| module[b].instructions.iter().any(|instr| instr.uses().contains(&dst)));
+    let ty = module[dst].ty;
+    let replacement = if !used {
+        None
+    }
+    else if let Some(src) = passthrough(&instr).filter(|&src| module[src].ty == ty) {
+        Some(Value::Reg(src))
+    }
+    else if let Some(zero) = zero_value(ty) {
+        Some(zero)
+    }
+    else {
+        let Some(zero) = zero_instr(module, dst).filter(|zero| *zero != instr) else {
+            return false;
+        };
+        module[block].instructions[i] = zero;
+        return true;
+    };
+
+    module[block].instructions.remove(i);
+    if let Some(value) = replacement {
+        replace_uses(module, fun, dst, value);
+    }
+    true
+}
+/// An operand of the same kind as the result, which can stand in for it.
+fn passthrough(instr: &Instruction) -> Option<RegID> {
+    use Instruction::*;
+
+    match *instr {
+        Freeze(_, Value::Reg(src))
+        | Unary(_, _, Value::Reg(src))
+        | Binary(_, _, Value::Reg(src), _)
+        | Select(_, _, Value::Reg(src), _) => Some(src),
+        SetStructMember { strct: src, .. }
+        | SetArrayElement { array: src, .. }
+        | IndexStruct { ptr: src, .. }
+        | IndexArray { ptr: src, .. } => Some(src),
+        _ => None,
+    }
+}
+/// Moves the block a block jumps to into it,
+/// if that block is not the entry and has no other predecessors.
+fn merge_blocks(module: &mut Module, block: BlockID) -> bool {
+    let Some(target) = merge_target(module, block) else {
+        return false;
+    };
+    let fun = module[block].fun;
+    let Some(Instruction::Jump(tgt)) = module[block].instructions.pop() else { unreachable!() };
+
+    let params = std::mem::take(&mut module[target].parameters);
+    for (param, arg) in params.into_iter().zip(tgt.args) {
+        replace_uses(module, fun, param, arg);
+    }
+    let instructions = std::mem::take(&mut module[target].instructions);
+    module[block].instructions.extend(instructions);
+    module[fun].blocks.remove(&target);
+
+    true
+}
+fn merge_target(module: &Module, block: BlockID) -> Option<BlockID> {
+    let Some(Instruction::Jump(tgt)) = module[block].terminator() else {
+        return None;
+    };
+    let fun = &module[module[block].fun];
+    if tgt.block == block || fun.entry_block == Some(tgt.block) || !fun.blocks.contains(&tgt.block) {
+        return None;
+    };
+
+    let mut predecessors = 0;
+    for b in all_blocks_of(fun) {
+        if let Some((first, second)) = module[b].terminator().and_then(|t| t.next_blocks()) {
+            predecessors += (first == tgt.block) as usize + (second == Some(tgt.block)) as usize;
+        }
+    }
+    (predecessors == 1).then_some(tgt.block)
+}
+/// Removes a member that no instruction accesses directly,
+/// shifting the indices of the ones after it.
+fn remove_struct_member(module: &mut Module, strct: StructTyID, index: usize) {
+    for gid in 0..module.globals().len() {
+        let global = &module.globals()[gid];
+        if let Some(mut value) = global.value.clone() {
+            remove_global_member(module, global.ty, &mut value, strct, index);
+            module.set_global_value(GlobalID(gid), value);
+        }
+    }
+
+    let shifted = |i: &mut u64| {
+        if *i > index as u64 {
+            *i -= 1;
+        }
+    };
+    for fun in all_funs(module) {
+        for block in all_blocks_of(&module[fun]) {
+            for i in 0..module[block].instructions.len() {
+                let is_strct = |reg: RegID| module[reg].ty == Ty::Struct(strct);
+                let shift = match &module[block].instructions[i] {
+                    Instruction::SetStruct(dst, _) => is_strct(*dst),
+                    Instruction::GetStructMember { strct, .. } | Instruction::SetStructMember { strct, .. } => {
+                        is_strct(*strct)
+                    }
+                    Instruction::IndexStruct { struct_ty, .. } => *struct_ty == strct,
+                    _ => false,
+                };
+                if !shift {
+                    continue;
+                }
+
+                match &mut module[block].instructions[i] {
+                    Instruction::SetStruct(_, values) => {
+                        values.0.remove(index);
+                    }
+                    Instruction::GetStructMember { index, .. }
+                    | Instruction::SetStructMember { index, .. }
+                    | Instruction::IndexStruct { index, .. } => shifted(index),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    module[strct].members.remove(index);
+}
+fn remove_global_member(module: &Module, ty: Ty, value: &mut GlobalValue, strct: StructTyID, index: usize) {
+    match (ty, value) {
+        (Ty::Struct(sid), GlobalValue::Struct(values)) => {
+            for (&member, value) in module[sid].members.iter().zip(values.iter_mut()) {
+                remove_global_member(module, member, value, strct, index);
+            }
+            if sid == strct && index < values.len() {
+                values.remove(index);
+            }
+        }
+        (Ty::Array(aid), GlobalValue::Array(values)) => {
+            for value in values {
+                remove_global_member(module, module[aid].element, value, strct, index);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Replaces a register that is about to lose its definition by zero,
+/// defining it at the start of `block` if it cannot be a constant.
+fn replace_by_zero(module: &mut Module, fun: FunID, block: BlockID, reg: RegID) {
+    if let Some(zero) = zero_value(module[reg].ty) {
+        replace_uses(module, fun, reg, zero);
+    }
+    else {
+        let zero = zero_instr(module, reg).unwrap();
+        module[block].instructions.insert(0, zero);
+    }
+}
+fn replace_uses(module: &mut Module, fun: FunID, reg: RegID, value: Value) {
+    for_each_instr(module, fun, |instr| match value {
+            Value::Reg(src) => {
+                for use_ in instr.uses_mut() {
+                    if *use_ == reg {
+                        *use_ = src;
+                    }
+                }
+            }
+            _ => {
+                for operand in instr.values_mut() {
+                    if *operand == Value::Reg(reg) {
+                        *operand = value;
+                    }
+                }
+            }
+    });
+}
+fn zero_value(ty: Ty) -> Option<Value> {
+    match ty {
+        Ty::Void => Some(Value::Void),
+        Ty::Bool => Some(Value::Bool(false)),
+        Ty::Int(int_ty) => Some(Value::Int(int_ty, 0)),
+        _ => None,
+    }
+}
+/// Defines `dst` as zero, as long as no part of it is a pointer.
+/// A null pointer is fine by itself.
+fn zero_instr(module: &Module, dst: RegID) -> Option<Instruction> {
+    match module[dst].ty {
+        Ty::Ptr => Some(Instruction::Unary(UnOp::IntToPtr, dst, Value::Int(IntTy::I64, 0))),
+        Ty::Struct(sid) => {
+            let values: Option<Vec<_>> = module[sid].members.iter().map(|&m| zero_value(m)).collect();
+            values.map(|values| Instruction::SetStruct(dst, values.into()))
+        }
+        Ty::Array(aid) => zero_value(module[aid].element).map(|zero| Instruction::SetArraySplat(dst, zero)),
+        ty => zero_value(ty).map(|zero| Instruction::Set(dst, zero)),
+    }
+}
+fn simplify_constant(value: Value, to: i64) -> Option<Value> {
+    match value {
+        Value::Int(int_ty, value) if value != to && value != 0 => Some(Value::Int(int_ty, to)),
+        Value::Bool(true) if to == 0 => Some(Value::Bool(false)),
+        _ => None,
+    }
+}
+fn zero_global_value(value: &GlobalValue) -> GlobalValue {
+    match value {
+        GlobalValue::Bool(_) => GlobalValue::Bool(false),
+        GlobalValue::Int(_) => GlobalValue::Int(0),
+        GlobalValue::String(_) => GlobalValue::String(String::new()),
+        GlobalValue::Struct(values) => GlobalValue::Struct(values.iter().map(zero_global_value).collect()),
+        GlobalValue::Array(values) => GlobalValue::Array(values.iter().map(zero_global_value).collect()),
+        GlobalValue::GlobalAddr(_) | GlobalValue::FunAddr(_) => value.clone(),
+    }
+}
+
+fn remove_unreachable_blocks(module: &mut Module) {
+    for fun in all_funs(module) {
+        let Some(entry) = module[fun].entry_block else {
+            continue;
+        };
+
+        let mut reachable = HashSet::from([entry]);
+        let mut queue = VecDeque::from([entry]);
+        while let Some(block) = queue.pop_front() {
+            for next in module[block].successors() {
+                if reachable.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+        module[fun].blocks.retain(|b| reachable.contains(b));
+    }
+}
+
+/// Functions that are called or whose address is taken by another function or a global.
+/// With `address_taken`, calls are not counted, but recursive references are.
+fn referenced_functions(module: &Module, address_taken: bool) -> HashSet<FunID> {
+    let mut referenced = HashSet::new();
+    for fun in module.functions() {
+        for block in all_blocks_of(fun) {
+            for instr in &module[block].instructions {
+                let callee = match *instr {
+                    Instruction::SetFunPtr(_, callee) => callee,
+                    Instruction::Call(_, callee, _) if !address_taken => callee,
+                    _ => continue,
+                };
+                if address_taken || callee != fun.id {
+                    referenced.insert(callee);
+                }
+            }
+        }
+    }
+    for global in module.globals() {
+        if let Some(value) = &global.value {
+            global_value_refs(value, &mut |value| {
+                if let GlobalValue::FunAddr(fun) = *value {
+                    referenced.insert(fun);
+                }
+            });
+        }
+    }
+
+    referenced
+}
+fn referenced_globals(module: &Module) -> HashSet<GlobalID> {
+    let mut referenced = HashSet::new();
+    for block in all_blocks(module) {
+        for instr in &module[block].instructions {
+            if let Instruction::SetGlobalPtr(_, global) = *instr {
+                referenced.insert(global);
+            }
+        }
+    }
+    for global in module.globals() {
+        if let Some(value) = &global.value {
+            global_value_refs(value, &mut |value| {
+                if let GlobalValue::GlobalAddr(gid) = *value
+                    && gid != global.id
+                {
+                    referenced.insert(gid);
+                }
+            });
+        }
+    }
+
+    referenced
+}
+fn global_value_refs(value: &GlobalValue, f: &mut impl FnMut(&GlobalValue)) {
+    match value {
+        GlobalValue::Struct(values) | GlobalValue::Array(values) => {
+            values.iter().for_each(|value| global_value_refs(value, f));
+        }
+        _ => f(value),
+    }
+}
+fn accessed_members(module: &Module) -> HashSet<(StructTyID, usize)> {
+    let mut accessed = HashSet::new();
+    for block in all_blocks(module) {
+        for instr in &module[block].instructions {
+            let (strct, index) = match *instr {
+                Instruction::GetStructMember { strct, index, .. } | Instruction::SetStructMember { strct, index, .. } => {
+                    let Ty::Struct(sid) = module[strct].ty else { continue };
+                    (sid, index)
+                }
+                Instruction::IndexStruct { struct_ty, index, .. } => (struct_ty, index),
+                _ => continue,
+            };
+            accessed.insert((strct, index as usize));
+        }
+    }
+
+    accessed
+}
+/// Every struct type the module mentions, in the order they are first found.
+fn struct_tys(module: &Module) -> Vec<StructTyID> {
+    fn visit(module: &Module, ty: Ty, found: &mut Vec<StructTyID>) {
+        match ty {
+            Ty::Struct(sid) if !found.contains(&sid) => {
+                found.push(sid);
+                for &member in &module[sid].members {
+                    visit(module, member, found);
+                }
+            }
+            Ty::Array(aid) => visit(module, module[aid].element, found),
+            _ => (),
+        }
+    }
+
+    let mut found = Vec::new();
+    for global in module.globals() {
+        visit(module, global.ty, &mut found);
+    }
+    for fun in module.functions() {
+        visit(module, fun.ret_ty, &mut found);
+        let mut registers: Vec<_> = fun.registers.iter().copied().collect();
+        registers.sort_by_key(|r| r.0);
+        let mut variables: Vec<_> = fun.variables.iter().copied().collect();
+        variables.sort_by_key(|v| v.0);
+
+        for reg in registers {
+            visit(module, module[reg].ty, &mut found);
+        }
+        for var in variables {
+            visit(module, module[var].ty, &mut found);
+        }
+        for block in all_blocks_of(fun) {
+            for instr in &module[block].instructions {
+                match *instr {
+                    Instruction::IndexStruct { struct_ty, .. } => visit(module, struct_ty.into(), &mut found),
+                    Instruction::IndexArray { element_ty: ty, .. } | Instruction::PtrDiff(_, ty, _, _) => {
+                        visit(module, ty, &mut found)
+                    }
+                    Instruction::CallPtr(_, _, fun_ty, _) => {
+                        visit(module, module[fun_ty].ret, &mut found);
+                        for &param in &module[fun_ty].params {
+                            visit(module, param, &mut found);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    found
+}
+
+fn all_funs(module: &Module) -> Vec<FunID> {
+    module.functions().iter().map(|f| f.id).collect()
+}
+fn all_blocks(module: &Module) -> Vec<BlockID> {
+    module.functions().iter().flat_map(all_blocks_of).collect()
+}
+fn all_blocks_of(fun: &Function) -> Vec<BlockID> {
+    let mut blocks: Vec<_> = fun.blocks.iter().copied().collect();
+    blocks.sort_by_key(|b| b.0);
+    blocks
+}
+fn for_each_instr(module: &mut Module, fun: FunID, mut f: impl FnMut(&mut Instruction)) {
+    for block in all_blocks_of(&module[fun]) {
+        module[block].instructions.iter_mut().for_each(&mut f);
+    }
+}
+fn jump_targets(instr: &mut Instruction) -> Vec<&mut JumpTarget> {
+    match instr {
+        Instruction::Jump(tgt) => vec![tgt],
+        Instruction::Branch(_, t, f) => vec![t, f],
+        _ => Vec::new(),
+    }
+}
+
+/// Copies the module without the given functions and globals, which must be unreferenced,
+/// and without any blocks, registers, variables or types that are no longer part of it.
+fn compact(module: &Module, funs: &HashSet<FunID>, globals: &HashSet<GlobalID>) -> Module {
+    let mut compactor = Compactor {
+        old: module,
+        new: Module::new(module.target()),
+        funs: HashMap::new(),
+        globals: HashMap::new(),
+        structs: HashMap::new(),
+        fun: None,
+        regs: HashMap::new(),
+        vars: HashMap::new(),
+        blocks: HashMap::new(),
+    };
+    compactor.copy(funs, globals);
+    compactor.new
+}
+
+struct Compactor<'a> {
+    old: &'a Module,
+    new: Module,
+
+    funs: HashMap<FunID, FunID>,
+    globals: HashMap<GlobalID, GlobalID>,
+    structs: HashMap<StructTyID, StructTyID>,
+
+    fun: Option<FunID>,
+    regs: HashMap<RegID, RegID>,
+    vars: HashMap<VarID, VarID>,
+    blocks: HashMap<BlockID, BlockID>,
+}
+impl Compactor<'_> {
+    fn copy(&mut self, removed_funs: &HashSet<FunID>, removed_globals: &HashSet<GlobalID>) {
+        let old = self.old;
+
+        for global in old.globals().iter().filter(|g| !removed_globals.contains(&g.id)) {
+            let ty = self.ty(global.ty);
+            let gid = self.new.add_global(global.name.clone(), ty);
+            self.new.set_global_linkage(gid, global.linkage);
+            self.new.set_global_constant(gid, global.constant);
+            self.globals.insert(global.id, gid);
+        }
+        for fun in old.functions().iter().filter(|f| !removed_funs.contains(&f.id)) {
+            let ret_ty = self.ty(fun.ret_ty);
+            let fid = self.new.add_function(fun.name.clone(), ret_ty);
+            self.new.set_call_convention(fid, fun.call_convention);
+            self.new.set_fun_linkage(fid, fun.linkage);
+            self.funs.insert(fun.id, fid);
+        }
+
+        for global in old.globals().iter().filter(|g| !removed_globals.contains(&g.id)) {
+            if let Some(value) = &global.value {
+                let value = self.global_value(value);
+                self.new.set_global_value(self.globals[&global.id], value);
+            }
+        }
+        for fun in old.functions().iter().filter(|f| !removed_funs.contains(&f.id)) {
+            self.copy_function(fun);
+        }
+    }
+    fn copy_function(&mut self, fun: &Function) {
+        let old = self.old;
+        self.fun = Some(self.funs[&fun.id]);
+        self.regs.clear();
+        self.vars.clear();
+        self.blocks.clear();
+
+        for &param in &fun.parameters {
+            let param = self.reg(param);
+            self.new.add_parameter(self.fun.unwrap(), param);
+        }
+        let blocks = all_blocks_of(fun);
+        for &block in &blocks {
+            let new = self.new.add_block(self.fun.unwrap());
+            self.blocks.insert(block, new);
+        }
+        for &block in &blocks {
+            let new = self.blocks[&block];
+            for &param in &old[block].parameters {
+                let param = self.reg(param);
+                self.new.add_block_parameter(new, param);
+            }
+            for instr in &old[block].instructions {
+                let instr = self.instr(instr);
+                self.new.add_instruction(new, instr);
+            }
+        }
+        if let Some(entry) = fun.entry_block {
+            self.new.set_entry_block(self.fun.unwrap(), self.blocks[&entry]);
+        }
+    }
+
+    fn instr(&mut self, instr: &Instruction) -> Instruction {
+        use Instruction::*;
+
+        match *instr {
+            Set(dst, a) => Set(self.reg(dst), self.value(a)),
+            SetFunPtr(dst, fun) => SetFunPtr(self.reg(dst), self.funs[&fun]),
+            SetGlobalPtr(dst, global) => SetGlobalPtr(self.reg(dst), self.globals[&global]),
+            SetStruct(dst, ref values) => SetStruct(self.reg(dst), self.values(values)),
+            SetArray(dst, ref values) => SetArray(self.reg(dst), self.values(values)),
+            SetArraySplat(dst, a) => SetArraySplat(self.reg(dst), self.value(a)),
+            Binary(op, dst, a, b) => Binary(op, self.reg(dst), self.value(a), self.value(b)),
+            Unary(op, dst, a) => Unary(op, self.reg(dst), self.value(a)),
+            Poison(dst) => Poison(self.reg(dst)),
+            Select(dst, c, a, b) => Select(self.reg(dst), self.value(c), self.value(a), self.value(b)),
+            Freeze(dst, a) => Freeze(self.reg(dst), self.value(a)),
+            GetVarAddr(dst, var) => GetVarAddr(self.reg(dst), self.var(var)),
+            Store { ptr, value } => Store {
+                ptr: self.reg(ptr),
+                value: self.value(value),
+            },
+            Load { dst, ptr } => Load {
+                dst: self.reg(dst),
+                ptr: self.reg(ptr),
+            },
+            PtrDiff(dst, ty, a, b) => PtrDiff(self.reg(dst), self.ty(ty), self.reg(a), self.reg(b)),
+            Jump(ref tgt) => Jump(self.target(tgt)),
+            Branch(c, ref t, ref f) => Branch(self.value(c), self.target(t), self.target(f)),
+            Ret(a) => Ret(self.value(a)),
+            Call(dst, fun, ref args) => Call(self.reg(dst), self.funs[&fun], self.values(args)),
+            CallPtr(dst, ptr, fun_ty, ref args) => {
+                CallPtr(self.reg(dst), self.reg(ptr), self.fun_ty(fun_ty), self.values(args))
+            }
+            GetStructMember { dst, strct, index } => GetStructMember {
+                dst: self.reg(dst),
+                strct: self.reg(strct),
+                index,
+            },
+            SetStructMember {
+                dst,
+                strct,
+                value,
+                index,
+            } => SetStructMember {
+                dst: self.reg(dst),
+                strct: self.reg(strct),
+                value: self.value(value),
+                index,
+            },
+            GetArrayElement { dst, array, index } => GetArrayElement {
+                dst: self.reg(dst),
+                array: self.reg(array),
+                index: self.value(index),
+            },
+            SetArrayElement {
+                dst,
+                array,
+                value,
+                index,
+            } => SetArrayElement {
+                dst: self.reg(dst),
+                array: self.reg(array),
+                value: self.value(value),
+                index: self.value(index),
+            },
+            IndexStruct {
+                dst,
+                ptr,
+                struct_ty,
+                index,
+            } => IndexStruct {
+                dst: self.reg(dst),
+                ptr: self.reg(ptr),
+                struct_ty: self.struct_ty(struct_ty),
+                index,
+            },
+            IndexArray {
+                dst,
+                ptr,
+                element_ty,
+                index,
+            } => IndexArray {
+                dst: self.reg(dst),
+                ptr: self.reg(ptr),
+                element_ty: self.ty(element_ty),
+                index: self.value(index),
+            },
+            SyscallLinux64 {
+                dst,
+                call_number,
+                ref args,
+            } => SyscallLinux64 {
+                dst: self.reg(dst),
+                call_number: self.value(call_number),
+                args: self.values(args),
+            },
+        }
+    }
+    fn target(&mut self, tgt: &JumpTarget) -> JumpTarget {
+        JumpTarget {
+            block: self.blocks[&tgt.block],
+            args: self.values(&tgt.args),
+        }
+    }
+    fn values(&mut self, values: &Values) -> Values {
+        Values(values.0.iter().map(|&value| self.value(value)).collect())
+    }
+    fn value(&mut self, value: Value) -> Value {
+        match value {
+            Value::Reg(reg) => Value::Reg(self.reg(reg)),
+            _ => value,
+        }
+    }
+    /// Registers are created when first seen, so unused ones disappear.
+    fn reg(&mut self, reg: RegID) -> RegID {
+        if let Some(&new) = self.regs.get(&reg) {
+            return new;
+        };
+
+        let ty = self.ty(self.old[reg].ty);
+        let new = self.new.add_register(self.fun.unwrap(), ty);
+        self.regs.insert(reg, new);
+        new
+    }
+    fn var(&mut self, var: VarID) -> VarID {
+        if let Some(&new) = self.vars.get(&var) {
+            return new;
+        };
+
+        let ty = self.ty(self.old[var].ty);
+        let new = self.new.add_variable(self.fun.unwrap(), ty);
+        self.vars.insert(var, new);
+        new
+    }
+    fn global_value(&mut self, value: &GlobalValue) -> GlobalValue {
+        match value {
+            GlobalValue::Struct(values) => GlobalValue::Struct(values.iter().map(|v| self.global_value(v)).collect()),
+            GlobalValue::Array(values) => GlobalValue::Array(values.iter().map(|v| self.global_value(v)).collect()),
+            GlobalValue::GlobalAddr(gid) => GlobalValue::GlobalAddr(self.globals[gid]),
+            GlobalValue::FunAddr(fid) => GlobalValue::FunAddr(self.funs[fid]),
+            _ => value.clone(),
+        }
+    }
+
+    fn ty(&mut self, ty: Ty) -> Ty {
+        match ty {
+            Ty::Array(aid) => {
+                let array = &self.old[aid];
+                let element = self.ty(array.element);
+                self.new.add_array_ty(array.size, element).into()
+            }
+            Ty::Struct(sid) => self.struct_ty(sid).into(),
+            _ => ty,
+        }
+    }
+    fn struct_ty(&mut self, sid: StructTyID) -> StructTyID {
+        if let Some(&new) = self.structs.get(&sid) {
+            return new;
+        };
+
+        let new = self.new.add_struct_ty();
+        self.structs.insert(sid, new);
+        for &member in &self.old[sid].members {
+            let member = self.ty(member);
+            self.new.add_struct_member(new, member);
+        }
+        new
+    }
+    fn fun_ty(&mut self, fun_ty: FunTyID) -> FunTyID {
+        let fun_ty = &self.old[fun_ty];
+        let ret = self.ty(fun_ty.ret);
+        let params: Vec<_> = fun_ty.params.iter().map(|&param| self.ty(param)).collect();
+        self.new.add_fun_ty(fun_ty.call_convention, ret, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frontend::{BinOp, Builder, Parser},
+        generator::Generator,
+        interpreter::Interpreter,
+        target::Target,
+    };
+
+    fn main_result(module: &Module) -> Option<Value> {
+        let main = module.functions().iter().find(|f| f.name == "main")?;
+        Interpreter::new(module).with_step_limit(10_000).call(main.id, &[]).ok()
+    }
+    fn instructions(module: &Module) -> usize {
+        all_blocks(module).into_iter().map(|b| module[b].instructions.len()).sum()
+    }
+
+    #[test]
+    fn keeps_what_the_predicate_needs() {
+        let has_mul = |module: &Module| {
+            let instrs = all_blocks(module).into_iter().flat_map(|b| module[b].instructions.clone());
+            instrs.into_iter().any(|i| matches!(i, Instruction::Binary(BinOp::Mul, ..)))
+        };
+
+        let mut reduced = 0;
+        for seed in 0..20 {
+            let program = Generator::new(seed).generate();
+            if !has_mul(&program.module) {
+                continue;
+            }
+
+            let mut reducer = Reducer::new(program.module, has_mul);
+            let module = reducer.reduce();
+            assert!(module.verify().is_empty(), "seed {seed}");
+            assert!(has_mul(module), "seed {seed}");
+            assert_eq!(module.functions().len(), 1, "seed {seed}");
+            assert!(module.globals().is_empty(), "seed {seed}");
+            // The multiplication has to be used, so it is returned or passed on.
+            assert!(instructions(module) <= 3, "seed {seed}: {} instructions", instructions(module));
+
+            let mut out = Vec::new();
+            reducer.print(&mut out).unwrap();
+            let src = String::from_utf8(out).unwrap();
+            let parsed = Parser::new(&src, Module::new(Target::LINUX_X64)).and_then(|p| p.parse());
+            assert!(parsed.is_ok_and(|m| m.verify().is_empty()), "seed {seed}:\n{src}");
+            reduced += 1;
+        }
+        assert!(reduced > 5);
+    }
+
+    #[test]
+    fn preserves_interpreted_results() {
+        for seed in 0..10 {
+            let program = Generator::new(seed).generate();
+            let expected = main_result(&program.module);
+            assert!(expected.is_some(), "seed {seed}");
+            let before = instructions(&program.module);
+
+            let mut reducer = Reducer::new(program.module, |m: &Module| main_result(m) == expected);
+            let module = reducer.reduce();
+            assert!(module.verify().is_empty(), "seed {seed}");
+            assert_eq!(main_result(module), expected, "seed {seed}");
+            assert!(instructions(module) < before, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn removes_struct_members_and_globals() {
+        let mut module = Module::new(Target::LINUX_X64);
+        let strct = module.add_struct_ty();
+        module.add_struct_member(strct, IntTy::I8.into());
+        module.add_struct_member(strct, IntTy::I64.into());
+        module.add_struct_member(strct, IntTy::I32.into());
+        let mut b = Builder::new(module);
+
+        let unused = b.create_global(None, IntTy::I64);
+        b.set_global(unused, 5);
+        let global = b.create_global(None, strct);
+        b.set_global(global, GlobalValue::Struct(vec![1.into(), 2.into(), 3.into()]));
+
+        b.begin_fun("unused".into(), IntTy::I64);
+        b.begin_block();
+        b.set_entry_block();
+        b.ret(1i64);
+
+        b.begin_fun("main".into(), IntTy::I64);
+        b.begin_block();
+        b.set_entry_block();
+        let ptr = b.set_global_ptr(global);
+        let member = b.index_struct(strct, ptr, 2);
+        let value = b.load(IntTy::I32, member);
+        let value = b.sext(IntTy::I64, value);
+        b.ret(value);
+        let module = b.finish();
+        assert!(module.verify().is_empty());
+
+        let mut reducer = Reducer::new(module, |m: &Module| main_result(m) == Some(Value::Int(IntTy::I64, 3)));
+        let module = reducer.reduce();
+        assert_eq!(module.functions().len(), 1);
+        assert_eq!(module.globals().len(), 1);
+        let global = &module.globals()[0];
+        assert_eq!(global.value, Some(GlobalValue::Struct(vec![3.into()])));
+        let Ty::Struct(strct) = global.ty else { panic!("{:?}", global.ty) };
+        assert_eq!(module[strct].members, [Ty::Int(IntTy::I32)]);
+    }
+}